@@ -0,0 +1,250 @@
+// Copyright (C) 2024 Nydus Developers. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reconstruct blob meta from chunk records in RAFS metadata.
+//!
+//! Data blobs generated by old builders may lack the blob meta, or carry a broken one, so the
+//! runtime can't use blob meta based data paths for them. The chunk compression information
+//! array only contains information also recorded in RAFS metadata, so it may be rebuilt from the
+//! bootstrap and saved as a `$blob_id.blob.meta` cache file.
+//!
+//! For legacy data blobs without blob meta information recorded in RAFS metadata, the generated
+//! header describes an uncompressed chunk compression information array at offset zero, which
+//! doesn't exist in the data blob. The runtime adopts such cache files for blobs without blob meta
+//! information, and never tries to fetch them from the data blob.
+
+use std::collections::{BTreeMap, HashMap};
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use nydus_rafs::metadata::chunk::ChunkWrapper;
+use nydus_rafs::metadata::RafsSuper;
+use nydus_storage::device::{BlobFeatures, BlobInfo};
+use nydus_storage::meta::{
+    BlobChunkInfoV1Ondisk, BlobChunkInfoV2Ondisk, BlobCompressionContextHeader, BlobMetaChunkArray,
+};
+use nydus_utils::compress;
+
+use crate::{ArtifactStorage, BlobCacheGenerator, Tree};
+
+/// Struct to reconstruct blob meta of data blobs from RAFS metadata.
+pub struct BlobMetaGenerator {
+    blobs: Vec<Arc<BlobInfo>>,
+    // Chunk records indexed by blob index and chunk index.
+    chunks: HashMap<u32, BTreeMap<u32, Arc<ChunkWrapper>>>,
+}
+
+impl BlobMetaGenerator {
+    /// Collect chunk records of all data blobs referenced by the RAFS filesystem.
+    pub fn new(rs: &RafsSuper) -> Result<Self> {
+        let mut chunks: HashMap<u32, BTreeMap<u32, Arc<ChunkWrapper>>> = HashMap::new();
+        let tree = Tree::from_bootstrap(rs, &mut ()).context("failed to load bootstrap")?;
+        tree.walk_dfs_pre(&mut |t: &Tree| {
+            let node = t.borrow_mut_node();
+            for chunk in node.chunks.iter() {
+                let blob_chunks = chunks.entry(chunk.inner.blob_index()).or_default();
+                match blob_chunks.get(&chunk.inner.index()) {
+                    None => {
+                        blob_chunks.insert(chunk.inner.index(), chunk.inner.clone());
+                    }
+                    Some(c)
+                        if c.compressed_offset() != chunk.inner.compressed_offset()
+                            || c.compressed_size() != chunk.inner.compressed_size()
+                            || c.uncompressed_offset() != chunk.inner.uncompressed_offset()
+                            || c.uncompressed_size() != chunk.inner.uncompressed_size() =>
+                    {
+                        bail!(
+                            "inconsistent records for chunk {} of blob index {}",
+                            chunk.inner.index(),
+                            chunk.inner.blob_index()
+                        );
+                    }
+                    Some(_) => {}
+                }
+            }
+            Ok(())
+        })?;
+
+        Ok(BlobMetaGenerator {
+            blobs: rs.superblock.get_blob_infos(),
+            chunks,
+        })
+    }
+
+    /// Get data blobs referenced by the RAFS filesystem.
+    pub fn blobs(&self) -> &[Arc<BlobInfo>] {
+        &self.blobs
+    }
+
+    /// Reconstruct the chunk compression information array and the blob meta header for `blob`.
+    ///
+    /// The header matches blob meta information recorded in the RAFS metadata, so the runtime
+    /// accepts the reconstructed blob meta as a valid cache file. For legacy blobs without blob
+    /// meta information, the header describes an uncompressed array not stored in the blob.
+    pub fn generate(
+        &self,
+        blob: &BlobInfo,
+    ) -> Result<(BlobMetaChunkArray, BlobCompressionContextHeader)> {
+        let blob_id = blob.blob_id();
+        if blob.has_feature(BlobFeatures::ZRAN) || blob.has_feature(BlobFeatures::BATCH) {
+            bail!(
+                "can't reconstruct blob meta for blob {} with ZRAN or batch chunks",
+                blob_id
+            );
+        }
+
+        let empty = BTreeMap::new();
+        let chunks = self.chunks.get(&blob.blob_index()).unwrap_or(&empty);
+        let chunk_count = blob.chunk_count();
+        if chunk_count == 0 || chunks.len() != chunk_count as usize {
+            bail!(
+                "only {} of {} chunks of blob {} are referenced by RAFS metadata",
+                chunks.len(),
+                chunk_count,
+                blob_id
+            );
+        }
+
+        let v2 = blob.has_feature(BlobFeatures::CHUNK_INFO_V2);
+        let mut ci_array = if v2 {
+            BlobMetaChunkArray::new_v2()
+        } else {
+            BlobMetaChunkArray::new_v1()
+        };
+        for (idx, chunk) in chunks.iter() {
+            // Chunk indexes are in range [0, chunk_count) once the count matches.
+            if *idx as usize != ci_array.len() {
+                bail!("invalid chunk index {} of blob {}", idx, blob_id);
+            }
+            if v2 {
+                ci_array.add_v2(
+                    chunk.compressed_offset(),
+                    chunk.compressed_size(),
+                    chunk.uncompressed_offset(),
+                    chunk.uncompressed_size(),
+                    chunk.is_compressed(),
+                    chunk.compressor(),
+                    chunk.is_encrypted(),
+                    false,
+                    0,
+                )?;
+            } else {
+                ci_array.add_v1(
+                    chunk.compressed_offset(),
+                    chunk.compressed_size(),
+                    chunk.uncompressed_offset(),
+                    chunk.uncompressed_size(),
+                );
+            }
+        }
+
+        let entry_size = if v2 {
+            size_of::<BlobChunkInfoV2Ondisk>()
+        } else {
+            size_of::<BlobChunkInfoV1Ondisk>()
+        };
+        let ci_size = (chunk_count as usize * entry_size) as u64;
+        let mut header = BlobCompressionContextHeader::default();
+        header.set_features(blob.features().bits());
+        header.set_ci_entries(chunk_count);
+        header.set_ci_uncompressed_size(ci_size);
+        if blob.meta_ci_is_valid() {
+            if ci_size != blob.meta_ci_uncompressed_size() {
+                bail!(
+                    "size of reconstructed blob meta 0x{:x} doesn't match 0x{:x} recorded for blob {}",
+                    ci_size,
+                    blob.meta_ci_uncompressed_size(),
+                    blob_id
+                );
+            }
+            header.set_ci_compressor(blob.meta_ci_compressor());
+            header.set_ci_compressed_offset(blob.meta_ci_offset());
+            header.set_ci_compressed_size(blob.meta_ci_compressed_size());
+        } else {
+            header.set_ci_compressor(compress::Algorithm::None);
+            header.set_ci_compressed_offset(0);
+            header.set_ci_compressed_size(ci_size);
+        }
+
+        Ok((ci_array, header))
+    }
+
+    /// Reconstruct blob meta for `blob` and write it as `$blob_id.blob.meta` into `dir`.
+    pub fn generate_to_dir(&self, blob: &BlobInfo, dir: &Path) -> Result<PathBuf> {
+        let (ci_array, header) = self.generate(blob)?;
+        let generator = BlobCacheGenerator::new_meta_only(ArtifactStorage::FileDir(dir.into()))?;
+        generator.write_blob_meta(ci_array.as_byte_slice(), &header)?;
+        generator.finalize(&blob.blob_id())?;
+
+        Ok(dir.join(format!("{}.blob.meta", blob.blob_id())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nydus_api::ConfigV2;
+    use nydus_storage::meta::BlobCompressionContextInfo;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_generate_blob_meta() {
+        let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
+        let bootstrap = Path::new(root_dir).join("../tests/texture/bootstrap/rafs-v6-2.2.boot");
+        let (rs, _) =
+            RafsSuper::load_from_file(&bootstrap, Arc::new(ConfigV2::default()), false).unwrap();
+        let generator = BlobMetaGenerator::new(&rs).unwrap();
+        assert_eq!(generator.blobs().len(), 1);
+
+        let tmpdir = TempDir::new().unwrap();
+        let blob = generator.blobs()[0].clone();
+        let path = generator.generate_to_dir(&blob, tmpdir.as_path()).unwrap();
+        assert!(path.is_file());
+
+        // The reconstructed blob meta is accepted without downloading from the data blob.
+        let blob_path = tmpdir.as_path().join(blob.blob_id());
+        let meta =
+            BlobCompressionContextInfo::new(&blob_path.display().to_string(), &blob, None, false)
+                .unwrap();
+        assert_eq!(meta.get_chunk_count(), blob.chunk_count() as usize);
+        let chunks = &generator.chunks[&blob.blob_index()];
+        for (idx, chunk) in chunks.iter() {
+            let c = meta.get_chunk_info(*idx as usize);
+            assert_eq!(c.compressed_offset(), chunk.compressed_offset());
+            assert_eq!(c.compressed_size(), chunk.compressed_size());
+            assert_eq!(c.uncompressed_offset(), chunk.uncompressed_offset());
+            assert_eq!(c.uncompressed_size(), chunk.uncompressed_size());
+        }
+    }
+    #[test]
+    fn test_generate_legacy_blob_meta() {
+        let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
+        let bootstrap = Path::new(root_dir).join("../tests/texture/bootstrap/rafs-v6-2.2.boot");
+        let (rs, _) =
+            RafsSuper::load_from_file(&bootstrap, Arc::new(ConfigV2::default()), false).unwrap();
+        let generator = BlobMetaGenerator::new(&rs).unwrap();
+
+        // Simulate a legacy blob without blob meta information.
+        let mut blob = generator.blobs()[0].as_ref().clone();
+        blob.set_blob_meta_info(0, 0, 0, 0);
+        let (ci_array, header) = generator.generate(&blob).unwrap();
+        let ci_size = header.ci_uncompressed_size();
+        assert_eq!(ci_size, ci_array.as_byte_slice().len() as u64);
+        assert_eq!(header.ci_compressor(), compress::Algorithm::None);
+        assert_eq!(header.ci_compressed_offset(), 0);
+        assert_eq!(header.ci_compressed_size(), ci_size);
+
+        // The runtime adopts the cache file with blob meta information from its header.
+        let tmpdir = TempDir::new().unwrap();
+        generator.generate_to_dir(&blob, tmpdir.as_path()).unwrap();
+        blob.set_blob_meta_info(0, ci_size, ci_size, compress::Algorithm::None as u32);
+        let blob_path = tmpdir.as_path().join(blob.blob_id());
+        let meta =
+            BlobCompressionContextInfo::new(&blob_path.display().to_string(), &blob, None, false)
+                .unwrap();
+        assert_eq!(meta.get_chunk_count(), blob.chunk_count() as usize);
+    }
+}
@@ -154,7 +154,7 @@ impl ChunkSet {
 
             let mut new_chunk = chunk.clone();
             // file offset field is useless
-            new_chunk.set_index(new_blob_ctx.chunk_count);
+            new_chunk.set_index(new_blob_ctx.alloc_chunk_index()?);
             new_chunk.set_blob_index(new_blob_idx);
             new_chunk.set_compressed_offset(new_blob_ctx.current_compressed_offset);
             new_chunk.set_uncompressed_offset(new_blob_ctx.current_uncompressed_offset);
@@ -163,7 +163,6 @@ impl ChunkSet {
             changed_chunks.push((chunk.clone(), new_chunk));
 
             new_blob_ctx.blob_hash.update(&buf);
-            new_blob_ctx.chunk_count += 1;
             new_blob_ctx.current_compressed_offset += chunk.compressed_size() as u64;
             new_blob_ctx.compressed_blob_size += chunk.compressed_size() as u64;
 
@@ -175,10 +174,11 @@ impl ChunkSet {
             new_blob_ctx.current_uncompressed_offset += aligned_size;
             new_blob_ctx.uncompressed_blob_size += aligned_size;
         }
-        new_blob_ctx.blob_id = format!("{:x}", new_blob_ctx.blob_hash.clone().finalize());
 
         // dump blob meta for v6
         Blob::dump_meta_data(build_ctx, new_blob_ctx, &mut blob_writer)?;
+        // The blob id must be the digest of the whole blob file, including blob meta.
+        new_blob_ctx.blob_id = format!("{:x}", new_blob_ctx.blob_hash.clone().finalize());
         let blob_id = new_blob_ctx.blob_id();
         blob_writer.finalize(blob_id)?;
 
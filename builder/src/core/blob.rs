@@ -5,18 +5,19 @@
 use std::borrow::Cow;
 use std::slice;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use nydus_rafs::metadata::RAFS_MAX_CHUNK_SIZE;
 use nydus_storage::device::BlobFeatures;
 use nydus_storage::meta::{toc, BlobMetaChunkArray};
 use nydus_utils::digest::{self, DigestHasher, RafsDigest};
-use nydus_utils::{compress, crypt};
+use nydus_utils::trace::trace_dir_timing;
+use nydus_utils::{compress, crypt, root_tracer, timing_tracer};
 use sha2::digest::Digest;
 
 use super::layout::BlobLayout;
 use super::node::Node;
-use crate::core::context::Artifact;
-use crate::{BlobContext, BlobManager, BuildContext, ConversionType, Feature};
+use crate::core::context::{Artifact, PADDING_BUF_SIZE};
+use crate::{BlobContext, BlobManager, BuildContext, ConversionType, Feature, PrefetchPolicy};
 
 /// Generator for RAFS data blob.
 pub(crate) struct Blob {}
@@ -32,22 +33,48 @@ impl Blob {
             ConversionType::DirectoryToRafs => {
                 let mut chunk_data_buf = vec![0u8; RAFS_MAX_CHUNK_SIZE as usize];
                 let (inodes, prefetch_entries) = BlobLayout::layout_blob_simple(&ctx.prefetch)?;
+                // Index of the next node to issue readahead for.
+                let mut readahead_idx = 0;
                 for (idx, node) in inodes.iter().enumerate() {
+                    // Keep a window of upcoming files being loaded from disk while the current
+                    // file is compressed and dumped.
+                    if ctx.readahead_files > 0 {
+                        readahead_idx = readahead_idx.max(idx + 1);
+                        let end = inodes.len().min(idx + 1 + ctx.readahead_files);
+                        while readahead_idx < end {
+                            inodes[readahead_idx].borrow().readahead();
+                            readahead_idx += 1;
+                        }
+                    }
                     let mut node = node.borrow_mut();
-                    let size = node
-                        .dump_node_data(ctx, blob_mgr, blob_writer, &mut chunk_data_buf)
-                        .context("failed to dump blob chunks")?;
+                    let target = node.target().clone();
+                    let size = trace_dir_timing("dump_blob", &target, timing_tracer!(), || {
+                        node.dump_node_data(ctx, blob_mgr, blob_writer, &mut chunk_data_buf)
+                    })
+                    .context("failed to dump blob chunks")?;
                     if idx < prefetch_entries {
                         if let Some((_, blob_ctx)) = blob_mgr.get_current_blob() {
                             blob_ctx.blob_prefetch_size += size;
                         }
+                        // Chunks may be deduplicated into other blobs, so record exact ranges
+                        // for each blob instead of a prefix of the current blob.
+                        if ctx.prefetch.policy == PrefetchPolicy::Blob {
+                            for chunk in node.chunks.iter() {
+                                blob_mgr.add_prefetch_range(
+                                    chunk.inner.blob_index(),
+                                    chunk.inner.compressed_offset(),
+                                    chunk.inner.compressed_size() as u64,
+                                );
+                            }
+                        }
                     }
                 }
                 Self::finalize_blob_data(ctx, blob_mgr, blob_writer)?;
             }
             ConversionType::TarToRafs
             | ConversionType::TargzToRafs
-            | ConversionType::EStargzToRafs => {
+            | ConversionType::EStargzToRafs
+            | ConversionType::OciRefToRafs => {
                 Self::finalize_blob_data(ctx, blob_mgr, blob_writer)?;
             }
             ConversionType::TarToTarfs
@@ -76,7 +103,7 @@ impl Blob {
                 }
                 Self::finalize_blob_data(ctx, blob_mgr, blob_writer)?;
             }
-            ConversionType::EStargzIndexToRef => {
+            ConversionType::EStargzIndexToRef | ConversionType::ZstdChunkedToRef => {
                 Self::finalize_blob_data(ctx, blob_mgr, blob_writer)?;
             }
             ConversionType::TarToStargz
@@ -109,6 +136,7 @@ impl Blob {
                         blob_ctx,
                         blob_writer,
                         batch.chunk_data_buf(),
+                        ctx.compressor,
                     )?;
                     batch.add_context(compressed_size);
                     batch.clear_chunk_data_buf();
@@ -144,6 +172,47 @@ impl Blob {
         Ok(())
     }
 
+    /// Pad the data blob with zeros, so the data following the padding and `trailer_size` bytes
+    /// ends at a multiple of `alignment`.
+    ///
+    /// The padding is described by a tar header and a ToC entry if the blob has them, so tools
+    /// won't mistake it for data.
+    pub(crate) fn dump_padding(
+        ctx: &BuildContext,
+        blob_ctx: &mut BlobContext,
+        blob_writer: &mut dyn Artifact,
+        alignment: u64,
+        trailer_size: u64,
+        name: &str,
+    ) -> Result<u64> {
+        let has_tar_header = ctx.blob_inline_meta || ctx.features.is_enabled(Feature::BlobToc);
+        let header_size = if has_tar_header { 512 } else { 0 };
+        let offset = blob_writer.pos()?;
+        let end = offset + header_size + trailer_size;
+        let size = (alignment - end % alignment) % alignment;
+
+        blob_ctx.write_padding(blob_writer, size)?;
+        if has_tar_header {
+            blob_ctx.write_tar_header(blob_writer, name, size)?;
+        }
+        if ctx.features.is_enabled(Feature::BlobToc) {
+            let padding = vec![0u8; std::cmp::min(size, PADDING_BUF_SIZE) as usize];
+            let mut hasher = RafsDigest::hasher(digest::Algorithm::Sha256);
+            let mut left = size;
+            while left > 0 {
+                let sz = std::cmp::min(left, PADDING_BUF_SIZE) as usize;
+                hasher.digest_update(&padding[..sz]);
+                left -= sz as u64;
+            }
+            let digest = hasher.digest_finalize();
+            blob_ctx
+                .entry_list
+                .add(name, compress::Algorithm::None, digest, offset, size, size)?;
+        }
+
+        Ok(size)
+    }
+
     fn get_compression_algorithm_for_meta(ctx: &BuildContext) -> compress::Algorithm {
         if ctx.conversion_type.is_to_ref() {
             compress::Algorithm::Zstd
@@ -157,6 +226,7 @@ impl Blob {
         blob_ctx: &mut BlobContext,
         blob_writer: &mut dyn Artifact,
     ) -> Result<()> {
+        blob_ctx.merge_chunk_meta_info()?;
         // Dump blob meta for v6 when it has chunks or bootstrap is to be inlined.
         if !blob_ctx.blob_meta_info_enabled || blob_ctx.uncompressed_blob_size == 0 {
             return Ok(());
@@ -199,6 +269,16 @@ impl Blob {
 
         let encrypted_ci_data =
             crypt::encrypt_with_context(&compressed_data, cipher_obj, cipher_ctx, encrypt)?;
+        if ctx.blob_meta_alignment > 0 {
+            Self::dump_padding(
+                ctx,
+                blob_ctx,
+                blob_writer,
+                ctx.blob_meta_alignment,
+                0,
+                toc::TOC_ENTRY_BLOB_META_PADDING,
+            )?;
+        }
         let compressed_offset = blob_writer.pos()?;
         let compressed_size = encrypted_ci_data.len() as u64;
         let uncompressed_size = ci_data.len() as u64;
@@ -208,12 +288,20 @@ impl Blob {
         header.set_ci_compressed_offset(compressed_offset);
         header.set_ci_compressed_size(compressed_size as u64);
         header.set_ci_uncompressed_size(uncompressed_size as u64);
-        header.set_aligned(true);
+        // The aligned feature is derived from `ctx.aligned_chunk` by `BuildContext`, make sure
+        // it hasn't been changed behind the builder.
+        if header.is_4k_aligned() != ctx.aligned_chunk {
+            bail!(
+                "aligned feature of blob meta ({}) doesn't match aligned chunk setting ({})",
+                header.is_4k_aligned(),
+                ctx.aligned_chunk
+            );
+        }
         match blob_meta_info {
             BlobMetaChunkArray::V1(_) => header.set_chunk_info_v2(false),
             BlobMetaChunkArray::V2(_) => header.set_chunk_info_v2(true),
         }
-        if ctx.features.is_enabled(Feature::BlobToc) && blob_ctx.chunk_count > 0 {
+        if ctx.features.is_enabled(Feature::BlobToc) && blob_ctx.chunk_count() > 0 {
             header.set_inlined_chunk_digest(true);
         }
 
@@ -286,7 +374,7 @@ impl Blob {
             let digest = RafsDigest::from_buf(buf, digest::Algorithm::Sha256);
             let compressed_offset = blob_writer.pos()?;
             let size = buf.len() as u64;
-            blob_writer.write_all(buf)?;
+            blob_ctx.write_data(blob_writer, buf)?;
             blob_ctx.write_tar_header(blob_writer, toc::TOC_ENTRY_BLOB_DIGEST, size)?;
             blob_ctx.entry_list.add(
                 toc::TOC_ENTRY_BLOB_DIGEST,
@@ -304,7 +392,142 @@ impl Blob {
 
 #[cfg(test)]
 mod tests {
+    use std::io::Write;
+    use std::sync::Arc;
+
     use super::*;
+    use crate::core::context::NoopArtifactWriter;
+    use crate::Features;
+
+    #[test]
+    fn test_dump_padding() {
+        let ctx = BuildContext {
+            features: Features::try_from("blob-toc").unwrap(),
+            ..Default::default()
+        };
+        let mut blob_ctx = BlobContext::new(
+            String::new(),
+            0,
+            BlobFeatures::empty(),
+            compress::Algorithm::None,
+            digest::Algorithm::Sha256,
+            crypt::Algorithm::None,
+            Arc::new(Default::default()),
+            None,
+        );
+        let mut writer = NoopArtifactWriter::default();
+        writer.write_all(&[1u8; 100]).unwrap();
+
+        let size = Blob::dump_padding(
+            &ctx,
+            &mut blob_ctx,
+            &mut writer,
+            0x1000,
+            0,
+            toc::TOC_ENTRY_BLOB_PADDING,
+        )
+        .unwrap();
+        assert_eq!(size, 0x1000 - 100 - 512);
+        assert_eq!(writer.pos().unwrap(), 0x1000);
+        let entry = blob_ctx
+            .entry_list
+            .get_entry(toc::TOC_ENTRY_BLOB_PADDING)
+            .unwrap();
+        assert_eq!(entry.compressed_offset(), 100);
+        assert_eq!(entry.compressed_size(), size);
+        let expected = RafsDigest::from_buf(&vec![0u8; size as usize], digest::Algorithm::Sha256);
+        assert_eq!(entry.uncompressed_digest(), expected);
+
+        // Padding larger than the zero buffer is written and hashed in pieces.
+        let mut blob_ctx = BlobContext::new(
+            String::new(),
+            0,
+            BlobFeatures::empty(),
+            compress::Algorithm::None,
+            digest::Algorithm::Sha256,
+            crypt::Algorithm::None,
+            Arc::new(Default::default()),
+            None,
+        );
+        let align = PADDING_BUF_SIZE * 4;
+        let size = Blob::dump_padding(
+            &ctx,
+            &mut blob_ctx,
+            &mut writer,
+            align,
+            0,
+            toc::TOC_ENTRY_BLOB_PADDING,
+        )
+        .unwrap();
+        assert_eq!(writer.pos().unwrap(), align);
+        assert!(size > PADDING_BUF_SIZE);
+        let entry = blob_ctx
+            .entry_list
+            .get_entry(toc::TOC_ENTRY_BLOB_PADDING)
+            .unwrap();
+        let expected = RafsDigest::from_buf(&vec![0u8; size as usize], digest::Algorithm::Sha256);
+        assert_eq!(entry.uncompressed_digest(), expected);
+    }
+
+    #[derive(Default)]
+    struct BufferArtifactWriter {
+        buf: Vec<u8>,
+    }
+
+    impl Write for BufferArtifactWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.buf.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Artifact for BufferArtifactWriter {
+        fn pos(&self) -> Result<u64> {
+            Ok(self.buf.len() as u64)
+        }
+
+        fn finalize(&mut self, _name: Option<String>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_dump_meta_data_blob_hash() {
+        let ctx = BuildContext {
+            features: Features::try_from("blob-toc").unwrap(),
+            compressor: compress::Algorithm::None,
+            ..Default::default()
+        };
+        let mut blob_ctx = BlobContext::new(
+            String::new(),
+            0,
+            BlobFeatures::empty(),
+            compress::Algorithm::None,
+            digest::Algorithm::Sha256,
+            crypt::Algorithm::None,
+            Arc::new(Default::default()),
+            None,
+        );
+        blob_ctx.blob_meta_info_enabled = true;
+        blob_ctx.uncompressed_blob_size = 0x1000;
+        blob_ctx.set_chunk_count(1);
+        blob_ctx.blob_chunk_digest.push([0x5au8; 32]);
+
+        let mut writer = BufferArtifactWriter::default();
+        Blob::dump_meta_data(&ctx, &mut blob_ctx, &mut writer).unwrap();
+        assert!(blob_ctx
+            .entry_list
+            .get_entry(toc::TOC_ENTRY_BLOB_DIGEST)
+            .is_some());
+
+        // The blob id is derived from `blob_hash`, so it must cover every byte of the blob.
+        let expected = sha2::Sha256::digest(&writer.buf);
+        assert_eq!(blob_ctx.blob_hash.clone().finalize(), expected);
+    }
 
     #[test]
     fn test_default_compression_algorithm_for_meta_ci() {
@@ -3,14 +3,18 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::{Context, Error, Result};
+use anyhow::{ensure, Context, Error, Result};
 use nydus_utils::digest::{self, RafsDigest};
 use std::ops::Deref;
 
 use nydus_rafs::metadata::layout::{RafsBlobTable, RAFS_V5_ROOT_INODE};
 use nydus_rafs::metadata::{RafsSuper, RafsSuperConfig, RafsSuperFlags};
 
-use crate::{ArtifactStorage, BlobManager, BootstrapContext, BootstrapManager, BuildContext, Tree};
+use super::node::Node;
+use crate::{
+    ArtifactStorage, BlobManager, BootstrapContext, BootstrapManager, BuildContext, InodeOrder,
+    PrivilegedFiles, Tree,
+};
 
 /// RAFS bootstrap/meta builder.
 pub struct Bootstrap {
@@ -48,12 +52,25 @@ impl Bootstrap {
         );
         drop(root_node);
 
-        Self::build_rafs(ctx, bootstrap_ctx, &mut self.tree)?;
-        if ctx.fs_version.is_v6() {
-            let root_offset = self.tree.node.borrow().v6_offset;
-            Self::v6_update_dirents(&self.tree, root_offset);
+        match ctx.inode_order {
+            InodeOrder::Bfs => Self::build_rafs(ctx, bootstrap_ctx, &mut self.tree)?,
+            order => {
+                ensure!(
+                    ctx.fs_version.is_v6(),
+                    "inode order {} is only supported by RAFS v6",
+                    order
+                );
+                Self::build_rafs_ordered(ctx, bootstrap_ctx, &self.tree, order)?;
+            }
         }
 
+        let mut privileged_files = PrivilegedFiles::default();
+        self.tree.walk_dfs_pre(&mut |t| {
+            privileged_files.add(&t.borrow_mut_node());
+            Ok(())
+        })?;
+        ctx.privileged_files = privileged_files;
+
         Ok(())
     }
 
@@ -120,40 +137,7 @@ impl Bootstrap {
                 child_node.inode.set_parent(parent_ino);
             }
 
-            // Handle hardlink.
-            // All hardlink nodes' ino and nlink should be the same.
-            // We need to find hardlink node index list in the layer where the node is located
-            // because the real_ino may be different among different layers,
-            let mut v6_hardlink_offset: Option<u64> = None;
-            let key = (
-                child_node.layer_idx,
-                child_node.info.src_ino,
-                child_node.info.src_dev,
-            );
-            if let Some(indexes) = bootstrap_ctx.inode_map.get_mut(&key) {
-                let nlink = indexes.len() as u32 + 1;
-                // Update nlink for previous hardlink inodes
-                for n in indexes.iter() {
-                    n.borrow_mut().inode.set_nlink(nlink);
-                }
-
-                let (first_ino, first_offset) = {
-                    let first_node = indexes[0].borrow_mut();
-                    (first_node.inode.ino(), first_node.v6_offset)
-                };
-                // set offset for rafs v6 hardlinks
-                v6_hardlink_offset = Some(first_offset);
-                child_node.inode.set_nlink(nlink);
-                child_node.inode.set_ino(first_ino);
-                indexes.push(child.node.clone());
-            } else {
-                child_node.inode.set_ino(index);
-                child_node.inode.set_nlink(1);
-                // Store inode real ino
-                bootstrap_ctx
-                    .inode_map
-                    .insert(key, vec![child.node.clone()]);
-            }
+            let v6_hardlink_offset = Self::set_inode_ino(bootstrap_ctx, child, &mut child_node);
 
             // update bootstrap_ctx.offset for rafs v6 non-dir nodes.
             if !child_node.is_dir() && ctx.fs_version.is_v6() {
@@ -177,6 +161,107 @@ impl Bootstrap {
         Ok(())
     }
 
+    /// Traverse node tree in the specified order, set inode index, ino, child_count and offset
+    /// etc according to the RAFS v6 metadata format.
+    fn build_rafs_ordered(
+        ctx: &mut BuildContext,
+        bootstrap_ctx: &mut BootstrapContext,
+        tree: &Tree,
+        order: InodeOrder,
+    ) -> Result<()> {
+        let block_size = ctx.v6_block_size();
+        let mut nodes = Vec::new();
+        Self::collect_nodes_dfs(tree, &mut nodes);
+        if order == InodeOrder::Name {
+            // The root node is always the first one because its path is a prefix of all others.
+            nodes.sort_by_cached_key(|t| t.borrow_mut_node().target().as_os_str().to_owned());
+        }
+
+        for (idx, t) in nodes.into_iter().enumerate() {
+            let mut node = t.borrow_mut_node();
+            // The root node has been handled by the caller.
+            let v6_hardlink_offset = if idx > 0 {
+                node.index = bootstrap_ctx.generate_next_ino();
+                Self::set_inode_ino(bootstrap_ctx, t, &mut node)
+            } else {
+                None
+            };
+
+            if node.is_dir() {
+                node.inode.set_child_count(t.children.len() as u32);
+                let d_size = node.v6_dirent_size(ctx, t)?;
+                node.v6_set_dir_offset(bootstrap_ctx, d_size, block_size)?;
+                let dirs = t
+                    .children
+                    .iter()
+                    .filter(|c| c.borrow_mut_node().is_dir())
+                    .count();
+                node.inode.set_nlink((2 + dirs) as u32);
+            } else {
+                node.v6_set_offset(bootstrap_ctx, v6_hardlink_offset, block_size)?;
+            }
+            if idx > 0 {
+                ctx.prefetch.insert(&t.node, node.deref());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn collect_nodes_dfs<'a>(tree: &'a Tree, nodes: &mut Vec<&'a Tree>) {
+        nodes.push(tree);
+        for child in tree.children.iter() {
+            Self::collect_nodes_dfs(child, nodes);
+        }
+    }
+
+    /// Set ino and nlink of the inode, return offset of the first inode if it's a RAFS v6
+    /// hardlink.
+    fn set_inode_ino(
+        bootstrap_ctx: &mut BootstrapContext,
+        tree: &Tree,
+        node: &mut Node,
+    ) -> Option<u64> {
+        // Handle hardlink.
+        // All hardlink nodes' ino and nlink should be the same.
+        // We need to find hardlink node index list in the layer where the node is located
+        // because the real_ino may be different among different layers,
+        // Directories can't be hardlinked, but the same source directory may be reached multiple
+        // times when following symlinks, so never share inode among directories.
+        let key = (node.layer_idx, node.info.src_ino, node.info.src_dev);
+        if let Some(indexes) = bootstrap_ctx
+            .inode_map
+            .get_mut(&key)
+            .filter(|_| !node.is_dir())
+        {
+            let nlink = indexes.len() as u32 + 1;
+            // Update nlink for previous hardlink inodes
+            for n in indexes.iter() {
+                n.borrow_mut().inode.set_nlink(nlink);
+            }
+
+            let (first_ino, first_offset) = {
+                let first_node = indexes[0].borrow_mut();
+                (first_node.inode.ino(), first_node.v6_offset)
+            };
+            node.inode.set_nlink(nlink);
+            node.inode.set_ino(first_ino);
+            indexes.push(tree.node.clone());
+            // set offset for rafs v6 hardlinks
+            Some(first_offset)
+        } else {
+            node.inode.set_ino(node.index);
+            node.inode.set_nlink(1);
+            // Store inode real ino
+            bootstrap_ctx
+                .inode_map
+                .entry(key)
+                .or_default()
+                .push(tree.node.clone());
+            None
+        }
+    }
+
     /// Load a parent RAFS bootstrap and return the `Tree` object representing the filesystem.
     pub fn load_parent_bootstrap(
         ctx: &mut BuildContext,
@@ -8,7 +8,7 @@ use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use nydus_api::ConfigV2;
 use nydus_rafs::metadata::chunk::ChunkWrapper;
 use nydus_rafs::metadata::layout::v5::RafsV5ChunkInfo;
@@ -87,7 +87,8 @@ pub struct HashChunkDict {
 
 impl ChunkDict for HashChunkDict {
     fn add_chunk(&mut self, chunk: Arc<ChunkWrapper>, digester: digest::Algorithm) {
-        if self.digester == digester {
+        // Zero-length chunk records carry no data, so they must not be deduplication targets.
+        if self.digester == digester && chunk.uncompressed_size() > 0 {
             if let Some(e) = self.m.get(chunk.id()) {
                 e.1.fetch_add(1, Ordering::AcqRel);
             } else {
@@ -99,7 +100,7 @@ impl ChunkDict for HashChunkDict {
 
     fn get_chunk(&self, digest: &RafsDigest, uncompressed_size: u32) -> Option<&Arc<ChunkWrapper>> {
         if let Some((chunk, _)) = self.m.get(digest) {
-            if chunk.uncompressed_size() == 0 || chunk.uncompressed_size() == uncompressed_size {
+            if chunk.uncompressed_size() == uncompressed_size {
                 return Some(chunk);
             }
         }
@@ -149,9 +150,97 @@ impl HashChunkDict {
         config: Arc<ConfigV2>,
         rafs_config: &RafsSuperConfig,
     ) -> Result<Arc<dyn ChunkDict>> {
-        let file_path = parse_chunk_dict_arg(arg)?;
-        HashChunkDict::from_bootstrap_file(&file_path, config, rafs_config)
-            .map(|d| Arc::new(d) as Arc<dyn ChunkDict>)
+        Self::from_commandline_args([arg], config, rafs_config)
+    }
+
+    /// Parse commandline arguments for chunk dictionaries and load chunks into one dictionary.
+    ///
+    /// Only chunk dictionaries from local filesystem are supported, see [ChunkDictSource].
+    pub fn from_commandline_args<'a>(
+        args: impl IntoIterator<Item = &'a str>,
+        config: Arc<ConfigV2>,
+        rafs_config: &RafsSuperConfig,
+    ) -> Result<Arc<dyn ChunkDict>> {
+        let mut paths = Vec::new();
+        for arg in args {
+            paths.extend(parse_chunk_dict_arg(arg)?.local_bootstrap_paths()?);
+        }
+        Self::from_bootstrap_files(&paths, config, rafs_config)
+    }
+
+    /// Load chunks from multiple RAFS filesystems into one chunk dictionary.
+    ///
+    /// Dictionaries take precedence in the order specified, that is, a chunk from an earlier
+    /// dictionary wins over the same chunk from later dictionaries.
+    pub fn from_bootstrap_files(
+        paths: &[PathBuf],
+        config: Arc<ConfigV2>,
+        rafs_config: &RafsSuperConfig,
+    ) -> Result<Arc<dyn ChunkDict>> {
+        let mut dict: Option<HashChunkDict> = None;
+        for path in paths {
+            let d = HashChunkDict::from_bootstrap_file(path, config.clone(), rafs_config)?;
+            match dict.as_mut() {
+                None => dict = Some(d),
+                Some(v) => v
+                    .merge(d)
+                    .with_context(|| format!("failed to merge chunk dictionary {:?}", path))?,
+            }
+        }
+
+        dict.map(|d| Arc::new(d) as Arc<dyn ChunkDict>)
+            .ok_or_else(|| anyhow!("no chunk dictionary specified"))
+    }
+
+    /// Merge chunks from another chunk dictionary, existing chunks take precedence.
+    ///
+    /// Data blobs from `other` are appended to the blob list unless there's already a blob with
+    /// the same id, and chunks from `other` are updated to refer to the merged blob list. Chunk
+    /// dictionaries using different digest algorithms can't be merged.
+    pub fn merge(&mut self, other: HashChunkDict) -> Result<()> {
+        ensure!(
+            self.digester == other.digester,
+            "chunk dictionary uses digest algorithm {}, expect {}",
+            other.digester,
+            self.digester
+        );
+
+        let mut blob_idx_map = Vec::with_capacity(other.blobs.len());
+        for blob in other.blobs.iter() {
+            let blob_id = blob.blob_id();
+            let idx = match self.blobs.iter().position(|b| b.blob_id() == blob_id) {
+                Some(idx) => idx as u32,
+                None => {
+                    let idx = self.blobs.len() as u32;
+                    let mut blob = blob.as_ref().clone();
+                    blob.set_blob_index(idx);
+                    self.blobs.push(Arc::new(blob));
+                    idx
+                }
+            };
+            blob_idx_map.push(idx);
+        }
+
+        for (digest, (chunk, count)) in other.m {
+            if let Some(e) = self.m.get(&digest) {
+                e.1.fetch_add(count.into_inner(), Ordering::AcqRel);
+                continue;
+            }
+            let blob_idx = match blob_idx_map.get(chunk.blob_index() as usize) {
+                Some(idx) => *idx,
+                None => continue,
+            };
+            let chunk = if blob_idx == chunk.blob_index() {
+                chunk
+            } else {
+                let mut c = chunk.as_ref().clone();
+                c.set_blob_index(blob_idx);
+                Arc::new(c)
+            };
+            self.m.insert(digest, (chunk, count));
+        }
+
+        Ok(())
     }
 
     /// Load chunks from the RAFS filesystem into the chunk dictionary.
@@ -208,19 +297,86 @@ impl HashChunkDict {
     }
 }
 
+/// Source of a chunk dictionary, specified by commandline argument.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChunkDictSource {
+    /// A RAFS metadata blob/bootstrap file.
+    Bootstrap(PathBuf),
+    /// A directory, all files in which are RAFS bootstrap files used as chunk dictionaries.
+    BootstrapDir(PathBuf),
+    /// A nydus image hosted on container registry, whose bootstrap is used as chunk dictionary.
+    Registry(String),
+}
+
+impl ChunkDictSource {
+    /// Get paths of bootstrap files from local filesystem, in the order to be loaded.
+    pub fn local_bootstrap_paths(&self) -> Result<Vec<PathBuf>> {
+        match self {
+            ChunkDictSource::Bootstrap(path) => {
+                if !path.is_file() {
+                    bail!(
+                        "chunk dict bootstrap {} is not a regular file",
+                        path.display()
+                    );
+                }
+                Ok(vec![path.clone()])
+            }
+            ChunkDictSource::BootstrapDir(dir) => {
+                let mut paths = Vec::new();
+                let entries = dir
+                    .read_dir()
+                    .with_context(|| format!("failed to read chunk dict directory {:?}", dir))?;
+                for entry in entries {
+                    let path = entry?.path();
+                    if path.is_file() {
+                        paths.push(path);
+                    }
+                }
+                if paths.is_empty() {
+                    bail!("no bootstrap file found in chunk dict directory {:?}", dir);
+                }
+                paths.sort();
+                Ok(paths)
+            }
+            ChunkDictSource::Registry(reference) => {
+                bail!(
+                    "chunk dict {} must be pulled from registry first",
+                    reference
+                )
+            }
+        }
+    }
+}
+
 /// Parse a chunk dictionary argument string.
 ///
 /// # Argument
-/// `arg` may be in inform of:
-/// - type=path: type of external source and corresponding path
+/// `arg` may be in form of:
 /// - path: type default to "bootstrap"
+/// - type=path: type of external source and corresponding path, type may be "bootstrap" or
+///   "bootstrap-dir"
+/// - scheme://reference: remote source, scheme may be "registry"
 ///
 /// for example:
 ///     bootstrap=image.boot
 ///     image.boot
 ///     ~/image/image.boot
-///     boltdb=/var/db/dict.db (not supported yet)
-pub fn parse_chunk_dict_arg(arg: &str) -> Result<PathBuf> {
+///     bootstrap-dir=/var/lib/dicts
+///     registry://registry.example.com/dict/image:tag
+pub fn parse_chunk_dict_arg(arg: &str) -> Result<ChunkDictSource> {
+    if let Some((scheme, reference)) = arg.split_once("://") {
+        debug!("parse chunk dict argument {}://{}", scheme, reference);
+        return match scheme {
+            "registry" if !reference.is_empty() => Ok(ChunkDictSource::Registry(arg.to_string())),
+            "registry" => bail!("invalid chunk dict `{}`, image reference is missing", arg),
+            _ => bail!(
+                "invalid chunk dict `{}`, unsupported scheme `{}`, expect `registry`",
+                arg,
+                scheme
+            ),
+        };
+    }
+
     let (file_type, file_path) = match arg.find('=') {
         None => ("bootstrap", arg),
         Some(idx) => (&arg[0..idx], &arg[idx + 1..]),
@@ -228,9 +384,17 @@ pub fn parse_chunk_dict_arg(arg: &str) -> Result<PathBuf> {
 
     debug!("parse chunk dict argument {}={}", file_type, file_path);
 
+    if file_path.is_empty() {
+        bail!("invalid chunk dict `{}`, path is missing", arg);
+    }
     match file_type {
-        "bootstrap" => Ok(PathBuf::from(file_path)),
-        _ => bail!("invalid chunk dict type {}", file_type),
+        "bootstrap" => Ok(ChunkDictSource::Bootstrap(PathBuf::from(file_path))),
+        "bootstrap-dir" => Ok(ChunkDictSource::BootstrapDir(PathBuf::from(file_path))),
+        _ => bail!(
+            "invalid chunk dict `{}`, unsupported type `{}`, expect `bootstrap` or `bootstrap-dir`",
+            arg,
+            file_type
+        ),
     }
 }
 
@@ -252,6 +416,21 @@ mod tests {
         assert_eq!(dict.get_real_blob_idx(5).unwrap(), 5);
     }
 
+    #[test]
+    fn test_zero_length_chunk() {
+        let mut dict = HashChunkDict::new(digest::Algorithm::Sha256);
+        let mut chunk = ChunkWrapper::new(RafsVersion::V6);
+        chunk.set_id(RafsDigest::from_buf(b"chunk", digest::Algorithm::Sha256));
+        dict.add_chunk(Arc::new(chunk.clone()), digest::Algorithm::Sha256);
+        assert!(dict.get_chunk(chunk.id(), 0).is_none());
+        assert!(dict.get_chunk(chunk.id(), 0x1000).is_none());
+
+        chunk.set_uncompressed_size(0x1000);
+        dict.add_chunk(Arc::new(chunk.clone()), digest::Algorithm::Sha256);
+        assert!(dict.get_chunk(chunk.id(), 0x1000).is_some());
+        assert!(dict.get_chunk(chunk.id(), 0x800).is_none());
+    }
+
     #[test]
     fn test_chunk_dict() {
         let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
@@ -277,4 +456,98 @@ mod tests {
         assert_eq!(dict.get_real_blob_idx(0), Some(10));
         assert_eq!(dict.get_real_blob_idx(1), None);
     }
+
+    #[test]
+    fn test_parse_chunk_dict_arg() {
+        assert_eq!(
+            parse_chunk_dict_arg("image.boot").unwrap(),
+            ChunkDictSource::Bootstrap(PathBuf::from("image.boot"))
+        );
+        assert_eq!(
+            parse_chunk_dict_arg("bootstrap=/image.boot").unwrap(),
+            ChunkDictSource::Bootstrap(PathBuf::from("/image.boot"))
+        );
+        assert_eq!(
+            parse_chunk_dict_arg("bootstrap-dir=/dicts").unwrap(),
+            ChunkDictSource::BootstrapDir(PathBuf::from("/dicts"))
+        );
+        assert_eq!(
+            parse_chunk_dict_arg("registry://example.com/dict:v1").unwrap(),
+            ChunkDictSource::Registry("registry://example.com/dict:v1".to_string())
+        );
+        assert!(parse_chunk_dict_arg("bootstrap=").is_err());
+        assert!(parse_chunk_dict_arg("boltdb=/var/db/dict.db").is_err());
+        assert!(parse_chunk_dict_arg("registry://").is_err());
+        assert!(parse_chunk_dict_arg("http://example.com/dict").is_err());
+
+        let tmp_dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let dir = ChunkDictSource::BootstrapDir(tmp_dir.as_path().to_path_buf());
+        assert!(dir.local_bootstrap_paths().is_err());
+        std::fs::write(tmp_dir.as_path().join("b.boot"), b"").unwrap();
+        std::fs::write(tmp_dir.as_path().join("a.boot"), b"").unwrap();
+        let paths = dir.local_bootstrap_paths().unwrap();
+        assert_eq!(paths.len(), 2);
+        assert!(paths[0].ends_with("a.boot"));
+        let file = ChunkDictSource::Bootstrap(tmp_dir.as_path().join("c.boot"));
+        assert!(file.local_bootstrap_paths().is_err());
+        let registry = ChunkDictSource::Registry("registry://dict".to_string());
+        assert!(registry.local_bootstrap_paths().is_err());
+    }
+
+    #[test]
+    fn test_merge_chunk_dicts() {
+        let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
+        let mut source_path = PathBuf::from(root_dir);
+        source_path.push("../tests/texture/bootstrap/rafs-v5.boot");
+        let path = source_path.to_str().unwrap();
+        let rafs_config = RafsSuperConfig {
+            version: RafsVersion::V5,
+            compressor: compress::Algorithm::Lz4Block,
+            digester: digest::Algorithm::Blake3,
+            chunk_size: 0x100000,
+            batch_size: 0,
+            explicit_uidgid: true,
+            is_tarfs_mode: false,
+        };
+        let dict = HashChunkDict::from_commandline_args(
+            [path, path],
+            Arc::new(ConfigV2::default()),
+            &rafs_config,
+        )
+        .unwrap();
+        // Blobs shared by both dictionaries are not duplicated.
+        assert_eq!(dict.get_blobs().len(), 18);
+        for (idx, blob) in dict.get_blobs().iter().enumerate() {
+            assert_eq!(blob.blob_index(), idx as u32);
+        }
+
+        let mut d1 = HashChunkDict::new(digest::Algorithm::Sha256);
+        let mut blob = BlobInfo::default();
+        blob.set_blob_index(0);
+        d1.blobs.push(Arc::new(blob));
+        let mut d2 = HashChunkDict::new(digest::Algorithm::Sha256);
+        let blob = BlobInfo::new(0, "blob2".to_string(), 0, 0, 0, 0, Default::default());
+        d2.blobs.push(Arc::new(blob));
+        let mut chunk = ChunkWrapper::new(RafsVersion::V6);
+        chunk.set_id(RafsDigest::from_buf(b"chunk", digest::Algorithm::Sha256));
+        chunk.set_uncompressed_size(0x1000);
+        d2.add_chunk(Arc::new(chunk.clone()), digest::Algorithm::Sha256);
+
+        d1.merge(d2).unwrap();
+        assert_eq!(d1.get_blobs().len(), 2);
+        assert_eq!(d1.get_blob_by_inner_idx(1).unwrap().blob_index(), 1);
+        let c = d1.get_chunk(chunk.id(), 0x1000).unwrap();
+        assert_eq!(c.blob_index(), 1);
+
+        // Dictionaries using different digest algorithms can't be merged.
+        let d3 = HashChunkDict::new(digest::Algorithm::Blake3);
+        assert!(d1.merge(d3).is_err());
+
+        assert!(HashChunkDict::from_commandline_args(
+            Vec::<&str>::new(),
+            Arc::new(ConfigV2::default()),
+            &rafs_config
+        )
+        .is_err());
+    }
 }
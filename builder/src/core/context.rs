@@ -6,28 +6,33 @@
 
 use std::any::Any;
 use std::borrow::Cow;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
+use std::ffi::OsStr;
 use std::fs::{remove_file, rename, File, OpenOptions};
 use std::io::{BufWriter, Cursor, Read, Seek, Write};
 use std::mem::size_of;
-use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::path::{Display, Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
 use std::{fmt, fs};
 
-use anyhow::{anyhow, Context, Error, Result};
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
+use nix::sys::statvfs::statvfs;
 use nydus_utils::crypt::{self, Cipher, CipherContext};
 use sha2::{Digest, Sha256};
 use tar::{EntryType, Header};
+use vmm_sys_util::tempdir::TempDir;
 use vmm_sys_util::tempfile::TempFile;
 
 use nydus_api::ConfigV2;
 use nydus_rafs::metadata::chunk::ChunkWrapper;
 use nydus_rafs::metadata::layout::v5::RafsV5BlobTable;
 use nydus_rafs::metadata::layout::v6::{
-    RafsV6BlobTable, EROFS_BLOCK_SIZE_4096, EROFS_INODE_SLOT_SIZE,
+    RafsV6Annotations, RafsV6BlobTable, RafsV6SharedXattrs, EROFS_BLOCK_SIZE_4096,
+    EROFS_INODE_SLOT_SIZE,
 };
 use nydus_rafs::metadata::layout::RafsBlobTable;
 use nydus_rafs::metadata::{Inode, RAFS_DEFAULT_CHUNK_SIZE};
@@ -41,15 +46,25 @@ use nydus_storage::meta::{
     BlobMetaChunkArray, BlobMetaChunkInfo, ZranContextGenerator,
 };
 use nydus_utils::digest::DigestData;
-use nydus_utils::{compress, digest, div_round_up, round_down, try_round_up_4k, BufReaderInfo};
+use nydus_utils::filemap::FileMapState;
+use nydus_utils::{
+    compress, digest, div_round_up, event_tracer, root_tracer, round_down, try_round_up_4k,
+    BufReaderInfo,
+};
+use serde::{Deserialize, Serialize};
 
-use super::node::ChunkSource;
+use super::node::{ChunkSource, Node};
+use super::transform::{self, FileTransform, TransformedFile};
 use crate::core::tree::TreeNode;
 use crate::{ChunkDict, Feature, Features, HashChunkDict, Prefetch, PrefetchPolicy, WhiteoutSpec};
 
 // TODO: select BufWriter capacity by performance testing.
 pub const BUF_WRITER_CAPACITY: usize = 2 << 17;
 
+const XATTR_SECURITY_CAPABILITY: &str = "security.capability";
+/// Size of the zero filled buffer to write blob padding.
+pub(crate) const PADDING_BUF_SIZE: u64 = 0x10_0000;
+
 /// Filesystem conversion type supported by RAFS builder.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum ConversionType {
@@ -59,6 +74,7 @@ pub enum ConversionType {
     EStargzToRafs,
     EStargzToRef,
     EStargzIndexToRef,
+    OciRefToRafs,
     TargzToRafs,
     TargzToStargz,
     TargzToRef,
@@ -66,6 +82,7 @@ pub enum ConversionType {
     TarToRafs,
     TarToRef,
     TarToTarfs,
+    ZstdChunkedToRef,
 }
 
 impl Default for ConversionType {
@@ -84,12 +101,14 @@ impl FromStr for ConversionType {
             "estargz-rafs" => Ok(Self::EStargzToRafs),
             "estargz-ref" => Ok(Self::EStargzToRef),
             "estargztoc-ref" => Ok(Self::EStargzIndexToRef),
+            "oci-ref" => Ok(Self::OciRefToRafs),
             "targz-rafs" => Ok(Self::TargzToRafs),
             "targz-stargz" => Ok(Self::TargzToStargz),
             "targz-ref" => Ok(Self::TargzToRef),
             "tar-rafs" => Ok(Self::TarToRafs),
             "tar-stargz" => Ok(Self::TarToStargz),
             "tar-tarfs" => Ok(Self::TarToTarfs),
+            "zstdchunked-ref" => Ok(Self::ZstdChunkedToRef),
             // kept for backward compatibility
             "directory" => Ok(Self::DirectoryToRafs),
             "stargz_index" => Ok(Self::EStargzIndexToRef),
@@ -107,6 +126,7 @@ impl fmt::Display for ConversionType {
             ConversionType::EStargzToRafs => write!(f, "estargz-rafs"),
             ConversionType::EStargzToRef => write!(f, "estargz-ref"),
             ConversionType::EStargzIndexToRef => write!(f, "estargztoc-ref"),
+            ConversionType::OciRefToRafs => write!(f, "oci-ref"),
             ConversionType::TargzToRafs => write!(f, "targz-rafs"),
             ConversionType::TargzToStargz => write!(f, "targz-ref"),
             ConversionType::TargzToRef => write!(f, "targz-ref"),
@@ -114,6 +134,7 @@ impl fmt::Display for ConversionType {
             ConversionType::TarToRef => write!(f, "tar-ref"),
             ConversionType::TarToStargz => write!(f, "tar-stargz"),
             ConversionType::TarToTarfs => write!(f, "tar-tarfs"),
+            ConversionType::ZstdChunkedToRef => write!(f, "zstdchunked-ref"),
         }
     }
 }
@@ -128,10 +149,127 @@ impl ConversionType {
                 | ConversionType::TargzToRef
                 | ConversionType::TarToRef
                 | ConversionType::TarToTarfs
+                | ConversionType::ZstdChunkedToRef
         )
     }
 }
 
+/// Order to linearize the filesystem tree into the RAFS inode table.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InodeOrder {
+    /// Directory by directory: entries of a directory are laid out together, and then
+    /// subdirectories are handled one by one.
+    Bfs,
+    /// Depth first: a subdirectory and all its descendants are laid out right after the
+    /// subdirectory itself, before its following siblings.
+    Dfs,
+    /// Sorted by full path of the entries.
+    Name,
+}
+
+impl Default for InodeOrder {
+    fn default() -> Self {
+        Self::Bfs
+    }
+}
+
+impl FromStr for InodeOrder {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bfs" => Ok(Self::Bfs),
+            "dfs" => Ok(Self::Dfs),
+            "name" => Ok(Self::Name),
+            _ => Err(anyhow!("invalid inode order")),
+        }
+    }
+}
+
+impl fmt::Display for InodeOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InodeOrder::Bfs => write!(f, "bfs"),
+            InodeOrder::Dfs => write!(f, "dfs"),
+            InodeOrder::Name => write!(f, "name"),
+        }
+    }
+}
+
+/// Identity of the filesystem containing a source file, used to group hardlinks.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HardlinkKey {
+    /// Device number of the file, after applying the device equivalence map.
+    Dev,
+    /// Filesystem id reported by statvfs, which is the same for bind mounts of a filesystem
+    /// even if they present different device numbers.
+    Fsid,
+}
+
+impl Default for HardlinkKey {
+    fn default() -> Self {
+        Self::Dev
+    }
+}
+
+impl FromStr for HardlinkKey {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "dev" => Ok(Self::Dev),
+            "fsid" => Ok(Self::Fsid),
+            _ => Err(anyhow!("invalid hardlink key")),
+        }
+    }
+}
+
+impl fmt::Display for HardlinkKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HardlinkKey::Dev => write!(f, "dev"),
+            HardlinkKey::Fsid => write!(f, "fsid"),
+        }
+    }
+}
+
+/// How to handle source entries of file types unsupported by RAFS.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UnsupportedFilePolicy {
+    /// Abort the build.
+    Error,
+    /// Skip the entry silently.
+    Skip,
+    /// Skip the entry with a warning message.
+    Warn,
+}
+
+impl Default for UnsupportedFilePolicy {
+    fn default() -> Self {
+        Self::Error
+    }
+}
+
+impl FromStr for UnsupportedFilePolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "error" => Ok(Self::Error),
+            "skip" => Ok(Self::Skip),
+            "warn" => Ok(Self::Warn),
+            _ => Err(anyhow!("invalid unsupported file policy")),
+        }
+    }
+}
+
+impl fmt::Display for UnsupportedFilePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnsupportedFilePolicy::Error => write!(f, "error"),
+            UnsupportedFilePolicy::Skip => write!(f, "skip"),
+            UnsupportedFilePolicy::Warn => write!(f, "warn"),
+        }
+    }
+}
+
 /// Filesystem based storage configuration for artifacts.
 #[derive(Debug, Clone)]
 pub enum ArtifactStorage {
@@ -244,6 +382,325 @@ impl Write for ArtifactFileWriter {
     }
 }
 
+/// Initial size of the memory mapped bootstrap file, it's doubled when more space is needed.
+const MMAP_WRITER_INIT_SIZE: u64 = 0x10_0000;
+
+/// ArtifactMmapWriter provides a writer to build bootstrap in a pre-sized, memory mapped file.
+///
+/// Seeking back and forth to populate different regions of the bootstrap is cheap because there's
+/// no buffered data to flush. The file is truncated to the size of written data when finalized.
+struct ArtifactMmapWriter {
+    writer: ArtifactWriter,
+    file: File,
+    map: FileMapState,
+    pos: u64,
+    len: u64,
+}
+
+impl ArtifactMmapWriter {
+    fn new(storage: ArtifactStorage, stage: Option<Arc<ArtifactStage>>) -> Result<Self> {
+        let writer = ArtifactWriter::new_with_stage(storage, None, stage)?;
+        let path = match (&writer.tmp_file, &writer.storage) {
+            (Some(tmp), _) => tmp.as_path().to_path_buf(),
+            (None, ArtifactStorage::SingleFile(p)) => p.clone(),
+            (None, ArtifactStorage::FileDir(p)) => {
+                bail!("no temporary bootstrap file in {}", p.display())
+            }
+        };
+        // The writer of `ArtifactWriter` is write only, but mmap needs a readable file.
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("failed to open file {}", path.display()))?;
+        let mut mmap_writer = Self {
+            writer,
+            file,
+            map: FileMapState::default(),
+            pos: 0,
+            len: 0,
+        };
+        mmap_writer
+            .remap(MMAP_WRITER_INIT_SIZE)
+            .with_context(|| format!("failed to memory map file {}", path.display()))?;
+
+        Ok(mmap_writer)
+    }
+
+    /// Check whether bootstrap can be built in a memory mapped file, FIFOs can't be mapped.
+    fn is_mappable(storage: &ArtifactStorage) -> bool {
+        match storage {
+            ArtifactStorage::FileDir(_) => true,
+            ArtifactStorage::SingleFile(p) => {
+                fs::metadata(p).map(|md| md.is_file()).unwrap_or(true)
+            }
+        }
+    }
+
+    fn remap(&mut self, size: u64) -> std::io::Result<()> {
+        // Unmap the old region before resizing the file.
+        self.map = FileMapState::default();
+        self.file.set_len(size)?;
+        let file = self.file.try_clone()?;
+        self.map = FileMapState::new(file, 0, size as usize, true)?;
+        Ok(())
+    }
+
+    /// Make sure the mapped region covers [0, end).
+    fn reserve(&mut self, end: u64) -> std::io::Result<()> {
+        let mut size = self.map.size() as u64;
+        if end > size {
+            size = size.max(MMAP_WRITER_INIT_SIZE);
+            while size < end {
+                size = size.checked_mul(2).ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("bootstrap size 0x{:x} is too big", end),
+                    )
+                })?;
+            }
+            self.remap(size)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ArtifactMmapWriter {
+    fn drop(&mut self) {
+        self.map = FileMapState::default();
+        let _ = self.file.set_len(self.len);
+    }
+}
+
+impl RafsIoWrite for ArtifactMmapWriter {
+    fn as_any(&self) -> &dyn Any {
+        &self.writer
+    }
+
+    fn finalize(&mut self, name: Option<String>) -> Result<()> {
+        self.map = FileMapState::default();
+        self.file
+            .set_len(self.len)
+            .context("failed to truncate bootstrap file")?;
+        self.writer.finalize(name)
+    }
+
+    fn as_bytes(&mut self) -> std::io::Result<Cow<[u8]>> {
+        Ok(Cow::Borrowed(self.map.get_slice(0, self.len as usize)?))
+    }
+
+    fn backing_file(&mut self, len: u64) -> std::io::Result<Option<File>> {
+        self.reserve(len)?;
+        self.len = self.len.max(len);
+        Ok(Some(self.file.try_clone()?))
+    }
+}
+
+impl Seek for ArtifactMmapWriter {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            std::io::SeekFrom::Start(off) => Some(off),
+            std::io::SeekFrom::End(off) => self.len.checked_add_signed(off),
+            std::io::SeekFrom::Current(off) => self.pos.checked_add_signed(off),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+impl Write for ArtifactMmapWriter {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        let end = self.pos + bytes.len() as u64;
+        self.reserve(end)?;
+        self.map
+            .get_slice_mut::<u8>(self.pos as usize, bytes.len())?
+            .copy_from_slice(bytes);
+        self.pos = end;
+        self.len = self.len.max(end);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A region of bootstrap with fixed offset and size, which is populated by typed writers.
+///
+/// Positions are absolute offsets into the bootstrap, so existing [RafsStore] implementations
+/// work on regions as is, but writes out of the region fail instead of silently corrupting
+/// neighboring data. If the bootstrap is a memory mapped file, the region is populated in place
+/// through its own mapping of the file, otherwise it's buffered until committed. Regions are
+/// independent of each other and may be populated in parallel before being committed.
+///
+/// [RafsStore]: nydus_rafs::metadata::RafsStore
+pub(crate) struct BootstrapRegion {
+    offset: u64,
+    size: usize,
+    pos: u64,
+    data: BootstrapRegionData,
+}
+
+enum BootstrapRegionData {
+    /// The bootstrap file memory mapped up to the end of the region.
+    Mapped(FileMapState),
+    /// Content of the region to be written into the bootstrap when committed.
+    Buffered(Vec<u8>),
+}
+
+impl BootstrapRegion {
+    /// Create a region of `size` bytes at `offset` of the bootstrap written by `w`, which must be
+    /// aligned to `alignment`.
+    pub fn new(w: &mut dyn RafsIoWrite, offset: u64, size: usize, alignment: u64) -> Result<Self> {
+        if alignment > 1 && offset % alignment != 0 {
+            bail!(
+                "bootstrap region offset 0x{:x} is not aligned to 0x{:x}",
+                offset,
+                alignment
+            );
+        }
+        let end = match offset.checked_add(size as u64) {
+            Some(end) if end <= usize::MAX as u64 => end,
+            _ => bail!(
+                "bootstrap region at 0x{:x} with size 0x{:x} is too big",
+                offset,
+                size
+            ),
+        };
+
+        let file = if size > 0 {
+            w.backing_file(end)
+                .with_context(|| format!("failed to extend bootstrap to 0x{:x}", end))?
+        } else {
+            None
+        };
+        let data = match file {
+            Some(file) => {
+                let mut map = FileMapState::new(file, 0, end as usize, true)
+                    .context("failed to memory map bootstrap region")?;
+                map.get_slice_mut::<u8>(offset as usize, size)?.fill(0);
+                BootstrapRegionData::Mapped(map)
+            }
+            None => BootstrapRegionData::Buffered(vec![0u8; size]),
+        };
+
+        Ok(Self {
+            offset,
+            size,
+            pos: offset,
+            data,
+        })
+    }
+
+    /// Get size of the region.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Write content of the region into bootstrap, leaving `w` at the end of the region.
+    pub fn commit(self, w: &mut dyn RafsIoWrite) -> Result<()> {
+        match &self.data {
+            // Content of mapped regions is already in the bootstrap.
+            BootstrapRegionData::Mapped(_) => {
+                w.seek_offset(self.end()).with_context(|| {
+                    format!("failed to seek to end of region at 0x{:x}", self.offset)
+                })?;
+            }
+            BootstrapRegionData::Buffered(data) => {
+                w.seek_offset(self.offset)
+                    .with_context(|| format!("failed to seek to region at 0x{:x}", self.offset))?;
+                w.write_all(data).with_context(|| {
+                    format!(
+                        "failed to write region at 0x{:x} with size 0x{:x}",
+                        self.offset, self.size
+                    )
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn data_mut(&mut self) -> std::io::Result<&mut [u8]> {
+        match &mut self.data {
+            BootstrapRegionData::Mapped(map) => map.get_slice_mut(self.offset as usize, self.size),
+            BootstrapRegionData::Buffered(data) => Ok(data),
+        }
+    }
+
+    fn end(&self) -> u64 {
+        self.offset + self.size as u64
+    }
+}
+
+impl RafsIoWrite for BootstrapRegion {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_bytes(&mut self) -> std::io::Result<Cow<[u8]>> {
+        Ok(Cow::Borrowed(self.data_mut()?))
+    }
+}
+
+impl Seek for BootstrapRegion {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            std::io::SeekFrom::Start(off) => Some(off),
+            std::io::SeekFrom::End(off) => self.end().checked_add_signed(off),
+            std::io::SeekFrom::Current(off) => self.pos.checked_add_signed(off),
+        };
+        match pos {
+            Some(pos) if pos >= self.offset && pos <= self.end() => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "invalid seek out of bootstrap region [0x{:x}, 0x{:x})",
+                    self.offset,
+                    self.end()
+                ),
+            )),
+        }
+    }
+}
+
+impl Write for BootstrapRegion {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        let end = self.pos + bytes.len() as u64;
+        if end > self.end() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "writing 0x{:x} bytes at 0x{:x} overflows bootstrap region [0x{:x}, 0x{:x})",
+                    bytes.len(),
+                    self.pos,
+                    self.offset,
+                    self.end()
+                ),
+            ));
+        }
+        let start = (self.pos - self.offset) as usize;
+        self.data_mut()?[start..start + bytes.len()].copy_from_slice(bytes);
+        self.pos = end;
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
 pub trait Artifact: Write {
     fn pos(&self) -> Result<u64>;
     fn finalize(&mut self, name: Option<String>) -> Result<()>;
@@ -285,6 +742,7 @@ pub struct ArtifactWriter {
     // Keep this because tmp file will be removed automatically when it is dropped.
     // But we will rename/link the tmp file before it is removed.
     tmp_file: Option<TempFile>,
+    stage: Option<Arc<ArtifactStage>>,
 }
 
 impl Write for ArtifactWriter {
@@ -302,7 +760,37 @@ impl Write for ArtifactWriter {
 impl ArtifactWriter {
     /// Create a new instance of [ArtifactWriter] from a [ArtifactStorage] configuration object.
     pub fn new(storage: ArtifactStorage) -> Result<Self> {
+        Self::new_with_tmp_dir(storage, None)
+    }
+
+    /// Create a new instance of [ArtifactWriter], with the temporary file for
+    /// [ArtifactStorage::FileDir] created in `tmp_dir` instead of the target directory.
+    ///
+    /// The temporary file is copied into the target directory on finalization if `tmp_dir` is on
+    /// another filesystem, so `tmp_dir` may be placed on a larger filesystem than the target.
+    pub fn new_with_tmp_dir(storage: ArtifactStorage, tmp_dir: Option<&Path>) -> Result<Self> {
+        Self::new_with_stage(storage, tmp_dir, None)
+    }
+
+    /// Create a new instance of [ArtifactWriter], with the artifact staged in `stage` instead of
+    /// being published on finalization.
+    ///
+    /// Regular files of [ArtifactStorage::SingleFile] are staged too, but FIFOs are written as is.
+    pub fn new_with_stage(
+        storage: ArtifactStorage,
+        tmp_dir: Option<&Path>,
+        stage: Option<Arc<ArtifactStage>>,
+    ) -> Result<Self> {
         match storage {
+            ArtifactStorage::SingleFile(ref p) if stage.is_some() && !is_fifo(p) => {
+                // Safe to unwrap because it has just been checked.
+                let stage = stage.unwrap();
+                let dir = match tmp_dir {
+                    Some(dir) => dir.to_path_buf(),
+                    None => stage.stage_dir(parent_dir(p))?,
+                };
+                Self::new_tmp_file(storage, &dir, Some(stage))
+            }
             ArtifactStorage::SingleFile(ref p) => {
                 let mut opener = &mut OpenOptions::new();
                 opener = opener.write(true).create(true);
@@ -330,28 +818,51 @@ impl ArtifactWriter {
                     reader,
                     storage,
                     tmp_file: None,
+                    stage: None,
                 })
             }
             ArtifactStorage::FileDir(ref p) => {
                 // Better we can use open(2) O_TMPFILE, but for compatibility sake, we delay this job.
                 // TODO: Blob dir existence?
-                let tmp = TempFile::new_in(p)
-                    .with_context(|| format!("failed to create temp file in {}", p.display()))?;
-                let tmp2 = tmp.as_file().try_clone()?;
-                let reader = OpenOptions::new()
-                    .read(true)
-                    .open(tmp.as_path())
-                    .with_context(|| format!("failed to open file {}", tmp.as_path().display()))?;
-                Ok(Self {
-                    pos: 0,
-                    file: BufWriter::with_capacity(BUF_WRITER_CAPACITY, tmp2),
-                    reader,
-                    storage,
-                    tmp_file: Some(tmp),
-                })
+                let dir = match (tmp_dir, stage.as_ref()) {
+                    (Some(dir), _) => dir.to_path_buf(),
+                    (None, Some(stage)) => stage.stage_dir(p)?,
+                    (None, None) => p.clone(),
+                };
+                Self::new_tmp_file(storage, &dir, stage)
             }
         }
     }
+
+    fn new_tmp_file(
+        storage: ArtifactStorage,
+        dir: &Path,
+        stage: Option<Arc<ArtifactStage>>,
+    ) -> Result<Self> {
+        let tmp = TempFile::new_in(dir)
+            .with_context(|| format!("failed to create temp file in {}", dir.display()))?;
+        let tmp2 = tmp.as_file().try_clone()?;
+        let reader = OpenOptions::new()
+            .read(true)
+            .open(tmp.as_path())
+            .with_context(|| format!("failed to open file {}", tmp.as_path().display()))?;
+        Ok(Self {
+            pos: 0,
+            file: BufWriter::with_capacity(BUF_WRITER_CAPACITY, tmp2),
+            reader,
+            storage,
+            tmp_file: Some(tmp),
+            stage,
+        })
+    }
+
+    /// Move the temporary file to `path` in directory `dir`, or stage it if there's a stage.
+    fn persist(&self, tmp_path: &Path, path: &Path, dir: &Path) -> Result<()> {
+        match self.stage.as_ref() {
+            Some(stage) => stage.stage(tmp_path, path).map(|_| ()),
+            None => persist_tmp_file(tmp_path, path, dir),
+        }
+    }
 }
 
 impl Artifact for ArtifactWriter {
@@ -369,19 +880,45 @@ impl Artifact for ArtifactWriter {
         if let Some(n) = name {
             if let ArtifactStorage::FileDir(s) = &self.storage {
                 let path = Path::new(s).join(n);
-                if !path.exists() {
-                    if let Some(tmp_file) = &self.tmp_file {
-                        rename(tmp_file.as_path(), &path).with_context(|| {
-                            format!(
-                                "failed to rename blob {:?} to {:?}",
-                                tmp_file.as_path(),
-                                path
-                            )
-                        })?;
+                if let Some(tmp_file) = &self.tmp_file {
+                    if is_content_addressed(&n) && path.exists() {
+                        // Artifacts named by sha256 digest of their content are identical if the
+                        // sizes match, so there's no need to read multi-GB files again.
+                        let size = tmp_file.as_file().metadata()?.len();
+                        let md = fs::metadata(&path)
+                            .with_context(|| format!("failed to stat blob {:?}", path))?;
+                        ensure!(
+                            md.is_file() && md.len() == size,
+                            "blob {} already exists with size 0x{:x} instead of 0x{:x}, refuse to overwrite it",
+                            path.display(),
+                            md.len(),
+                            size
+                        );
+                        // The temporary file will be removed when dropped.
+                        info!(
+                            "reuse existing blob {} with identical content",
+                            path.display()
+                        );
+                    } else {
+                        self.persist(tmp_file.as_path(), &path, s)
+                            .with_context(|| {
+                                format!(
+                                    "failed to rename blob {:?} to {:?}",
+                                    tmp_file.as_path(),
+                                    path
+                                )
+                            })?;
                     }
                 }
+            } else if let (ArtifactStorage::SingleFile(s), Some(tmp_file)) =
+                (&self.storage, &self.tmp_file)
+            {
+                self.persist(tmp_file.as_path(), s, parent_dir(s))
+                    .with_context(|| {
+                        format!("failed to rename {:?} to {:?}", tmp_file.as_path(), s)
+                    })?;
             }
-        } else if let ArtifactStorage::SingleFile(s) = &self.storage {
+        } else if let (ArtifactStorage::SingleFile(s), None) = (&self.storage, &self.tmp_file) {
             if let Ok(md) = s.metadata() {
                 if md.is_file() {
                     remove_file(s).with_context(|| format!("failed to remove blob {:?}", s))?;
@@ -393,15 +930,179 @@ impl Artifact for ArtifactWriter {
     }
 }
 
+/// Sum sizes of regular files in `dir`, hardlinks are counted once.
+///
+/// It's an estimation, so entries which can't be accessed are ignored.
+fn source_dir_size(dir: &Path) -> u64 {
+    let mut size = 0;
+    let mut inodes = HashSet::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let md = match entry.path().symlink_metadata() {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            if md.is_dir() {
+                dirs.push(entry.path());
+            } else if md.is_file() && (md.nlink() <= 1 || inodes.insert((md.dev(), md.ino()))) {
+                size += md.len();
+            }
+        }
+    }
+    size
+}
+
+/// Move the temporary file to `path` in directory `dir`.
+///
+/// Rename doesn't work across filesystems, so copy the temporary file into `dir` first, then
+/// rename it to make sure the target never contains partial content.
+fn persist_tmp_file(tmp_path: &Path, path: &Path, dir: &Path) -> Result<()> {
+    match rename(tmp_path, path) {
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            let tmp = TempFile::new_in(dir)
+                .with_context(|| format!("failed to create temp file in {}", dir.display()))?;
+            fs::copy(tmp_path, tmp.as_path())
+                .with_context(|| format!("failed to copy {:?} to {:?}", tmp_path, tmp.as_path()))?;
+            rename(tmp.as_path(), path)?;
+            Ok(())
+        }
+        r => Ok(r?),
+    }
+}
+
+/// Check whether the artifact name is a sha256 digest, which is the digest of its content.
+fn is_content_addressed(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Prefix of per-build directories to stage artifacts, they are hidden in target directories.
+const ARTIFACT_STAGE_PREFIX: &str = ".nydus-build-";
+
+/// Per-build namespace to stage artifacts before publishing them all together.
+///
+/// Artifacts are moved into a hidden per-build directory inside their target directories when
+/// finalized, and renamed to their final paths only when [ArtifactStage::publish] is called, so
+/// consumers watching target directories never observe a partially published build. Artifacts
+/// are published in the order they were staged, and bootstraps are always finalized after data
+/// blobs referenced by them. Staged artifacts are discarded if the stage is dropped without being
+/// published.
+#[derive(Default)]
+pub struct ArtifactStage {
+    dirs: Mutex<HashMap<PathBuf, TempDir>>,
+    // Pairs of (staged path, final path).
+    artifacts: Mutex<Vec<(PathBuf, PathBuf)>>,
+}
+
+impl ArtifactStage {
+    /// Create a new instance of [ArtifactStage].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the directory to stage artifacts for `dir`, which is created on first use.
+    pub fn stage_dir(&self, dir: &Path) -> Result<PathBuf> {
+        let mut dirs = self.dirs.lock().unwrap();
+        if let Some(d) = dirs.get(dir) {
+            return Ok(d.as_path().to_path_buf());
+        }
+        let d = TempDir::new_with_prefix(dir.join(ARTIFACT_STAGE_PREFIX))
+            .with_context(|| format!("failed to create staging directory in {}", dir.display()))?;
+        let staged = d.as_path().to_path_buf();
+        dirs.insert(dir.to_path_buf(), d);
+        Ok(staged)
+    }
+
+    /// Stage the temporary file `tmp_path` to be published as `path`, return the staged path.
+    pub fn stage(&self, tmp_path: &Path, path: &Path) -> Result<PathBuf> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("invalid artifact path {}", path.display()))?;
+        let dir = self.stage_dir(parent_dir(path))?;
+        let staged = dir.join(name);
+        persist_tmp_file(tmp_path, &staged, &dir)?;
+
+        let mut artifacts = self.artifacts.lock().unwrap();
+        // Artifacts with identical content may be generated more than once.
+        if !artifacts.iter().any(|(_, p)| p == path) {
+            artifacts.push((staged.clone(), path.to_path_buf()));
+        }
+        Ok(staged)
+    }
+
+    /// Get the path to access artifact `path` before publishing.
+    pub fn resolve(&self, path: &Path) -> PathBuf {
+        let artifacts = self.artifacts.lock().unwrap();
+        artifacts
+            .iter()
+            .find(|(_, p)| p == path)
+            .map(|(staged, _)| staged.clone())
+            .unwrap_or_else(|| path.to_path_buf())
+    }
+
+    /// Publish all staged artifacts, return their final paths.
+    ///
+    /// Each artifact is published by an atomic rename, but publishing a group of artifacts is not
+    /// atomic. If any rename fails, artifacts already published are moved back into the stage,
+    /// so the bootstrap, which is staged last, is never published without its data blobs. Files
+    /// replaced at final paths can't be restored, which is fine for data blobs named by digests.
+    pub fn publish(&self) -> Result<Vec<PathBuf>> {
+        let mut artifacts = self.artifacts.lock().unwrap();
+        let mut published: Vec<PathBuf> = Vec::with_capacity(artifacts.len());
+        for (staged, path) in artifacts.iter() {
+            if let Err(e) = rename(staged, path) {
+                for (staged, path) in artifacts.iter().take(published.len()).rev() {
+                    if let Err(e) = rename(path, staged) {
+                        warn!("failed to roll back published artifact {:?}, {}", path, e);
+                    }
+                }
+                return Err(e)
+                    .with_context(|| format!("failed to publish {:?} to {:?}", staged, path));
+            }
+            published.push(path.clone());
+        }
+        artifacts.clear();
+        Ok(published)
+    }
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    }
+}
+
+fn is_fifo(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|md| md.file_type().is_fifo())
+        .unwrap_or(false)
+}
+
 pub struct BlobCacheGenerator {
-    blob_data: Mutex<ArtifactFileWriter>,
+    blob_data: Option<Mutex<ArtifactFileWriter>>,
     blob_meta: Mutex<ArtifactFileWriter>,
 }
 
 impl BlobCacheGenerator {
     pub fn new(storage: ArtifactStorage) -> Result<Self> {
         Ok(BlobCacheGenerator {
-            blob_data: Mutex::new(ArtifactFileWriter(ArtifactWriter::new(storage.clone())?)),
+            blob_data: Some(Mutex::new(ArtifactFileWriter(ArtifactWriter::new(
+                storage.clone(),
+            )?))),
+            blob_meta: Mutex::new(ArtifactFileWriter(ArtifactWriter::new(storage)?)),
+        })
+    }
+
+    /// Create a generator only generating the `$id.blob.meta` file, so blob caches may be
+    /// pre-seeded with blob meta while data blobs are generated as usual.
+    pub fn new_meta_only(storage: ArtifactStorage) -> Result<Self> {
+        Ok(BlobCacheGenerator {
+            blob_data: None,
             blob_meta: Mutex::new(ArtifactFileWriter(ArtifactWriter::new(storage)?)),
         })
     }
@@ -434,7 +1135,10 @@ impl BlobCacheGenerator {
         chunk_info: &ChunkWrapper,
         aligned_d_size: u32,
     ) -> Result<()> {
-        let mut guard = self.blob_data.lock().unwrap();
+        let mut guard = match self.blob_data.as_ref() {
+            Some(blob_data) => blob_data.lock().unwrap(),
+            None => return Ok(()),
+        };
         let curr_pos = guard.seek(std::io::SeekFrom::End(0))?;
         if curr_pos < chunk_info.uncompressed_offset() + aligned_d_size as u64 {
             guard.set_len(chunk_info.uncompressed_offset() + aligned_d_size as u64)?;
@@ -448,10 +1152,10 @@ impl BlobCacheGenerator {
     }
 
     pub fn finalize(&self, name: &str) -> Result<()> {
-        let blob_data_name = format!("{}.blob.data", name);
-        let mut guard = self.blob_data.lock().unwrap();
-        guard.finalize(Some(blob_data_name))?;
-        drop(guard);
+        if let Some(blob_data) = self.blob_data.as_ref() {
+            let blob_data_name = format!("{}.blob.data", name);
+            blob_data.lock().unwrap().finalize(Some(blob_data_name))?;
+        }
 
         let blob_meta_name = format!("{}.blob.meta", name);
         let mut guard = self.blob_meta.lock().unwrap();
@@ -459,6 +1163,12 @@ impl BlobCacheGenerator {
     }
 }
 
+/// Number of shards buffering chunk meta information appended to a blob.
+const CHUNK_META_SHARDS: usize = 16;
+
+/// Chunk meta information appended to a blob, pending to be merged in order of chunk index.
+type PendingChunkMeta = (ChunkWrapper, Option<BlobChunkInfoV2Ondisk>);
+
 /// BlobContext is used to hold the blob information of a layer during build.
 pub struct BlobContext {
     /// Blob id (user specified or sha256(blob)).
@@ -468,6 +1178,8 @@ pub struct BlobContext {
     pub blob_digester: digest::Algorithm,
     pub blob_cipher: crypt::Algorithm,
     pub blob_prefetch_size: u64,
+    /// Compressed data ranges `(offset, size)` of prefetched files, for `PrefetchPolicy::Blob`.
+    pub blob_prefetch_ranges: Vec<(u64, u64)>,
     /// Whether to generate blob metadata information.
     pub blob_meta_info_enabled: bool,
     /// Data chunks stored in the data blob, for v6.
@@ -476,6 +1188,9 @@ pub struct BlobContext {
     pub blob_meta_header: BlobCompressionContextHeader,
     /// Blob chunk digest array.
     pub blob_chunk_digest: Vec<DigestData>,
+    /// Chunk meta information appended concurrently, sharded by chunk index and merged into
+    /// `blob_meta_info` and `blob_chunk_digest` by `merge_chunk_meta_info()`.
+    chunk_meta_shards: Vec<Mutex<Vec<PendingChunkMeta>>>,
 
     /// Final compressed blob file size.
     pub compressed_blob_size: u64,
@@ -487,7 +1202,7 @@ pub struct BlobContext {
     pub current_uncompressed_offset: u64,
 
     /// The number of counts in a blob by the index of blob table.
-    pub chunk_count: u32,
+    chunk_count: AtomicU32,
     /// Chunk slice size.
     pub chunk_size: u32,
     /// Whether the blob is from chunk dict.
@@ -536,10 +1251,14 @@ impl BlobContext {
             blob_digester: digester,
             blob_cipher: cipher,
             blob_prefetch_size: 0,
+            blob_prefetch_ranges: Vec::new(),
             blob_meta_info_enabled: false,
             blob_meta_info,
             blob_meta_header: BlobCompressionContextHeader::default(),
             blob_chunk_digest: Vec::new(),
+            chunk_meta_shards: (0..CHUNK_META_SHARDS)
+                .map(|_| Mutex::new(Vec::new()))
+                .collect(),
 
             compressed_blob_size: 0,
             uncompressed_blob_size: 0,
@@ -547,7 +1266,7 @@ impl BlobContext {
             current_compressed_offset: blob_offset,
             current_uncompressed_offset: 0,
 
-            chunk_count: 0,
+            chunk_count: AtomicU32::new(0),
             chunk_size: RAFS_DEFAULT_CHUNK_SIZE as u32,
             chunk_source: ChunkSource::Build,
 
@@ -701,7 +1420,8 @@ impl BlobContext {
             cipher_ctx,
         );
         blob_ctx.blob_prefetch_size = blob.prefetch_size();
-        blob_ctx.chunk_count = blob.chunk_count();
+        blob_ctx.blob_prefetch_ranges = blob.prefetch_ranges().to_vec();
+        blob_ctx.set_chunk_count(blob.chunk_count());
         blob_ctx.uncompressed_blob_size = blob.uncompressed_size();
         blob_ctx.compressed_blob_size = compressed_blob_size;
         blob_ctx.chunk_size = blob.chunk_size();
@@ -739,14 +1459,39 @@ impl BlobContext {
     // TODO: check the logic to reset prefetch size
     pub fn set_blob_prefetch_size(&mut self, ctx: &BuildContext) {
         if (self.uncompressed_blob_size > 0
-            || (ctx.conversion_type == ConversionType::EStargzIndexToRef
+            || ((ctx.conversion_type == ConversionType::EStargzIndexToRef
+                || ctx.conversion_type == ConversionType::ZstdChunkedToRef)
                 && !self.blob_id.is_empty()))
             && ctx.prefetch.policy != PrefetchPolicy::Blob
         {
             self.blob_prefetch_size = 0;
+            self.blob_prefetch_ranges.clear();
+        }
+    }
+
+    /// Record a compressed data range of the blob to prefetch.
+    pub fn add_prefetch_range(&mut self, offset: u64, size: u64) {
+        if size > 0 {
+            self.blob_prefetch_ranges.push((offset, size));
         }
     }
 
+    /// Get sorted prefetch ranges, with overlapping and adjacent ranges coalesced.
+    pub fn prefetch_ranges(&self) -> Vec<(u64, u64)> {
+        let mut ranges = self.blob_prefetch_ranges.clone();
+        ranges.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+        for (offset, size) in ranges {
+            match merged.last_mut() {
+                Some((last_offset, last_size)) if offset <= *last_offset + *last_size => {
+                    *last_size = (*last_size).max(offset + size - *last_offset);
+                }
+                _ => merged.push((offset, size)),
+            }
+        }
+        merged
+    }
+
     pub fn set_meta_info_enabled(&mut self, enable: bool) {
         self.blob_meta_info_enabled = enable;
     }
@@ -760,13 +1505,42 @@ impl BlobContext {
         self.cipher_ctx = cipher_ctx;
     }
 
+    /// Append meta information of a chunk to the blob.
+    ///
+    /// It only needs a shared reference, so chunks may be appended concurrently in any order
+    /// after allocating their indexes by `alloc_chunk_index()`. The appended information gets
+    /// visible in `blob_meta_info` and `blob_chunk_digest` after `merge_chunk_meta_info()`.
     pub fn add_chunk_meta_info(
-        &mut self,
+        &self,
         chunk: &ChunkWrapper,
         chunk_info: Option<BlobChunkInfoV2Ondisk>,
     ) -> Result<()> {
         if self.blob_meta_info_enabled {
-            assert_eq!(chunk.index() as usize, self.blob_meta_info.len());
+            let shard = chunk.index() as usize % CHUNK_META_SHARDS;
+            self.chunk_meta_shards[shard]
+                .lock()
+                .unwrap()
+                .push((chunk.clone(), chunk_info));
+        }
+
+        Ok(())
+    }
+
+    /// Merge chunk meta information appended by `add_chunk_meta_info()` in order of chunk index.
+    pub fn merge_chunk_meta_info(&mut self) -> Result<()> {
+        let mut pending = Vec::new();
+        for shard in self.chunk_meta_shards.iter_mut() {
+            pending.append(shard.get_mut().unwrap());
+        }
+        pending.sort_unstable_by_key(|(chunk, _)| chunk.index());
+
+        for (chunk, chunk_info) in pending {
+            ensure!(
+                chunk.index() as usize == self.blob_meta_info.len(),
+                "unexpected chunk index {} in blob meta, expect {}",
+                chunk.index(),
+                self.blob_meta_info.len()
+            );
             match &self.blob_meta_info {
                 BlobMetaChunkArray::V1(_) => {
                     self.blob_meta_info.add_v1(
@@ -778,6 +1552,9 @@ impl BlobContext {
                     self.blob_chunk_digest.push(chunk.id().data);
                 }
                 BlobMetaChunkArray::V2(_) => {
+                    if chunk.compressor().is_some() {
+                        self.blob_meta_header.set_chunk_compressor(true);
+                    }
                     if let Some(mut info) = chunk_info {
                         info.set_uncompressed_offset(chunk.uncompressed_offset());
                         self.blob_meta_info.add_v2_info(info);
@@ -788,10 +1565,11 @@ impl BlobContext {
                             chunk.uncompressed_offset(),
                             chunk.uncompressed_size(),
                             chunk.is_compressed(),
+                            chunk.compressor(),
                             chunk.is_encrypted(),
                             chunk.is_batch(),
                             0,
-                        );
+                        )?;
                     }
                     self.blob_chunk_digest.push(chunk.id().data);
                 }
@@ -802,18 +1580,29 @@ impl BlobContext {
     }
 
     /// Allocate a count index sequentially in a blob.
-    pub fn alloc_chunk_index(&mut self) -> Result<u32> {
-        let index = self.chunk_count;
-
+    ///
+    /// It only needs a shared reference, so chunk indexes may be allocated concurrently.
+    pub fn alloc_chunk_index(&self) -> Result<u32> {
         // Rafs v6 only supports 24 bit chunk id.
-        if index >= 0xff_ffff {
-            Err(Error::msg(
-                "the number of chunks in blob exceeds the u32 limit",
-            ))
-        } else {
-            self.chunk_count += 1;
-            Ok(index)
-        }
+        self.chunk_count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |index| {
+                if index >= 0xff_ffff {
+                    None
+                } else {
+                    Some(index + 1)
+                }
+            })
+            .map_err(|_| Error::msg("the number of chunks in blob exceeds the u32 limit"))
+    }
+
+    /// Get number of chunks allocated in the blob.
+    pub fn chunk_count(&self) -> u32 {
+        self.chunk_count.load(Ordering::Acquire)
+    }
+
+    /// Set number of chunks in the blob.
+    pub fn set_chunk_count(&mut self, count: u32) {
+        *self.chunk_count.get_mut() = count;
     }
 
     /// Get blob id if the blob has some chunks.
@@ -832,6 +1621,19 @@ impl BlobContext {
         Ok(())
     }
 
+    /// Helper to write zero padding to blob and update blob hash.
+    pub fn write_padding(&mut self, blob_writer: &mut dyn Artifact, size: u64) -> Result<()> {
+        // Padding may be up to hundreds of MB, so write it from a small buffer.
+        let padding = vec![0u8; std::cmp::min(size, PADDING_BUF_SIZE) as usize];
+        let mut left = size;
+        while left > 0 {
+            let sz = std::cmp::min(left, PADDING_BUF_SIZE) as usize;
+            self.write_data(blob_writer, &padding[..sz])?;
+            left -= sz as u64;
+        }
+        Ok(())
+    }
+
     /// Helper to write a tar header to blob and update blob hash.
     pub fn write_tar_header(
         &mut self,
@@ -885,6 +1687,8 @@ pub struct BlobManager {
     /// Used for chunk data de-duplication between layers (with `--parent-bootstrap`)
     /// or within layer (with `--inline-bootstrap`).
     pub(crate) layered_chunk_dict: HashChunkDict,
+    /// Statistics about chunk deduplication of the current build.
+    pub(crate) dedup_stats: ChunkDedupStats,
 }
 
 impl BlobManager {
@@ -895,6 +1699,7 @@ impl BlobManager {
             current_blob_index: None,
             global_chunk_dict: Arc::new(()),
             layered_chunk_dict: HashChunkDict::new(digester),
+            dedup_stats: ChunkDedupStats::default(),
         }
     }
 
@@ -1030,6 +1835,13 @@ impl BlobManager {
         self.blobs.get(idx)
     }
 
+    /// Record a compressed data range to prefetch for the blob at `blob_index`.
+    pub fn add_prefetch_range(&mut self, blob_index: u32, offset: u64, size: u64) {
+        if let Some(blob_ctx) = self.blobs.get_mut(blob_index as usize) {
+            blob_ctx.add_prefetch_range(offset, size);
+        }
+    }
+
     pub fn take_blob(&mut self, idx: usize) -> BlobContext {
         self.blobs.remove(idx)
     }
@@ -1106,7 +1918,7 @@ impl BlobManager {
         for ctx in &self.blobs {
             let blob_id = ctx.blob_id.clone();
             let blob_prefetch_size = u32::try_from(ctx.blob_prefetch_size)?;
-            let chunk_count = ctx.chunk_count;
+            let chunk_count = ctx.chunk_count();
             let decompressed_blob_size = ctx.uncompressed_blob_size;
             let compressed_blob_size = ctx.compressed_blob_size;
             let mut flags = RafsSuperFlags::empty();
@@ -1133,7 +1945,7 @@ impl BlobManager {
                     flags |= RafsSuperFlags::from(ctx.blob_compressor);
                     flags |= RafsSuperFlags::from(ctx.blob_digester);
                     flags |= RafsSuperFlags::from(ctx.blob_cipher);
-                    table.add(
+                    let blob_index = table.add(
                         blob_id,
                         0,
                         blob_prefetch_size,
@@ -1151,6 +1963,7 @@ impl BlobManager {
                         ctx.cipher_object.clone(),
                         ctx.cipher_ctx.clone(),
                     );
+                    table.set_prefetch_ranges(blob_index, ctx.prefetch_ranges())?;
                 }
             }
         }
@@ -1167,6 +1980,7 @@ pub struct BootstrapContext {
     pub(crate) inode_map: HashMap<(u16, Inode, u64), Vec<TreeNode>>,
     /// Current position to write in f_bootstrap
     pub(crate) offset: u64,
+    /// Writer for bootstrap, which is memory mapped if the target is a regular file.
     pub(crate) writer: Box<dyn RafsIoWrite>,
     /// Not fully used blocks
     pub(crate) v6_available_blocks: Vec<VecDeque<u64>>,
@@ -1177,8 +1991,22 @@ pub struct BootstrapContext {
 impl BootstrapContext {
     /// Create a new instance of [BootstrapContext].
     pub fn new(storage: Option<ArtifactStorage>, layered: bool) -> Result<Self> {
+        Self::new_with_stage(storage, layered, None)
+    }
+
+    /// Create a new instance of [BootstrapContext], with the bootstrap staged in `stage`.
+    pub fn new_with_stage(
+        storage: Option<ArtifactStorage>,
+        layered: bool,
+        stage: Option<Arc<ArtifactStage>>,
+    ) -> Result<Self> {
         let writer = if let Some(storage) = storage {
-            Box::new(ArtifactFileWriter(ArtifactWriter::new(storage)?)) as Box<dyn RafsIoWrite>
+            if ArtifactMmapWriter::is_mappable(&storage) {
+                Box::new(ArtifactMmapWriter::new(storage, stage)?) as Box<dyn RafsIoWrite>
+            } else {
+                let writer = ArtifactWriter::new_with_stage(storage, None, stage)?;
+                Box::new(ArtifactFileWriter(writer)) as Box<dyn RafsIoWrite>
+            }
         } else {
             Box::<ArtifactMemoryWriter>::default() as Box<dyn RafsIoWrite>
         };
@@ -1256,6 +2084,7 @@ impl BootstrapContext {
 pub struct BootstrapManager {
     pub(crate) f_parent_path: Option<PathBuf>,
     pub(crate) bootstrap_storage: Option<ArtifactStorage>,
+    artifact_stage: Option<Arc<ArtifactStage>>,
 }
 
 impl BootstrapManager {
@@ -1264,12 +2093,22 @@ impl BootstrapManager {
         Self {
             f_parent_path: f_parent_path.map(PathBuf::from),
             bootstrap_storage,
+            artifact_stage: None,
         }
     }
 
+    /// Stage generated bootstraps in `stage` instead of publishing them on finalization.
+    pub fn set_artifact_stage(&mut self, stage: Option<Arc<ArtifactStage>>) {
+        self.artifact_stage = stage;
+    }
+
     /// Create a new instance of [BootstrapContext]
     pub fn create_ctx(&self) -> Result<BootstrapContext> {
-        BootstrapContext::new(self.bootstrap_storage.clone(), self.f_parent_path.is_some())
+        BootstrapContext::new_with_stage(
+            self.bootstrap_storage.clone(),
+            self.f_parent_path.is_some(),
+            self.artifact_stage.clone(),
+        )
     }
 }
 
@@ -1285,6 +2124,15 @@ pub struct BuildContext {
     pub blob_offset: u64,
     /// Blob chunk compress flag.
     pub compressor: compress::Algorithm,
+    /// Compression level for lz4hc, lz4_frame and zstd, use the default level if `None`.
+    pub compress_level: Option<u32>,
+    /// Chunk compress flags for specific files, keyed by path in the image.
+    ///
+    /// Chunks of these files are compressed by the specified algorithms instead of `compressor`,
+    /// which is recorded per chunk in the chunk information. RAFS v6 only.
+    pub file_compressors: HashMap<PathBuf, compress::Algorithm>,
+    /// Chunk compress flag for hot files covered by prefetch patterns, RAFS v6 only.
+    pub hot_compressor: Option<compress::Algorithm>,
     /// Inode and chunk digest algorithm flag.
     pub digester: digest::Algorithm,
     /// Blob encryption algorithm flag.
@@ -1301,6 +2149,10 @@ pub struct BuildContext {
     pub fs_version: RafsVersion,
     /// Whether any directory/file has extended attributes.
     pub has_xattr: bool,
+    /// Shared xattr area of RAFS v6, filled when dumping inodes with big extended attributes.
+    pub v6_shared_xattrs: RafsV6SharedXattrs,
+    /// Order to lay out inodes into the inode table.
+    pub inode_order: InodeOrder,
 
     /// Format conversion type.
     pub conversion_type: ConversionType,
@@ -1308,6 +2160,35 @@ pub struct BuildContext {
     /// - Directory: `source_path` should be a directory path
     /// - StargzIndex: `source_path` should be a stargz index json file path
     pub source_path: PathBuf,
+    /// Include content of directories referenced by symlinks in the source directory.
+    pub follow_symlinks: bool,
+    /// How to identify the filesystem of source files when detecting hardlinks.
+    pub hardlink_key: HardlinkKey,
+    /// Map device numbers of source files to equivalent ones when detecting hardlinks.
+    pub hardlink_dev_map: HashMap<u64, u64>,
+    /// Skip source files which can't be read instead of aborting the build.
+    pub skip_unreadable: bool,
+    /// Don't cross mount points when walking the source directory.
+    pub one_file_system: bool,
+    /// Number of upcoming source files to issue readahead for while dumping chunk data.
+    pub readahead_files: usize,
+    /// Source files skipped because they can't be read, also updated when dumping file data.
+    pub skipped_files: Mutex<Vec<SkippedFile>>,
+    /// How to handle source entries of file types unsupported by RAFS.
+    pub unsupported_file_policy: UnsupportedFilePolicy,
+    /// Source entries skipped because of unsupported file types.
+    pub unsupported_files: Vec<SkippedFile>,
+    /// Audit of privileged files in the image, generated when building the bootstrap.
+    pub privileged_files: PrivilegedFiles,
+    /// Hook to transform content of regular files in the source directory before chunking.
+    pub file_transform: Option<Arc<dyn FileTransform>>,
+    /// Regular files whose content has been transformed.
+    pub transformed_files: Vec<TransformedFile>,
+    /// Path of transformed content and its size indexed by (dev, ino) of source files, `None` if
+    /// the file is kept as is.
+    pub(crate) transformed_data: HashMap<(u64, Inode), Option<(PathBuf, u64)>>,
+    /// Directory to hold transformed content, created on first use.
+    pub(crate) transform_dir: Option<TempDir>,
 
     /// Track file/chunk prefetch state.
     pub prefetch: Prefetch,
@@ -1319,6 +2200,16 @@ pub struct BuildContext {
     pub blob_tar_reader: Option<BufReaderInfo<File>>,
     pub blob_features: BlobFeatures,
     pub blob_inline_meta: bool,
+    /// Pad the data blob to a multiple of the size, zero means no padding.
+    pub blob_padding: u64,
+    /// Align the blob compression context table to the size, zero means no alignment.
+    pub blob_meta_alignment: u64,
+    /// Directory to create temporary data blob files, the blob directory if `None`.
+    pub tmp_dir: Option<PathBuf>,
+    /// Stage generated artifacts to publish them together after the build is validated.
+    pub artifact_stage: Option<Arc<ArtifactStage>>,
+    /// Key/value annotations embedded in the bootstrap, RAFS v6 only.
+    pub annotations: RafsV6Annotations,
 
     pub features: Features,
     pub configuration: Arc<ConfigV2>,
@@ -1360,17 +2251,31 @@ impl BuildContext {
         if conversion_type == ConversionType::TarToTarfs {
             blob_features |= BlobFeatures::TARFS;
         }
+        if aligned_chunk {
+            blob_features |= BlobFeatures::ALIGNED;
+        }
 
         let cipher = if encrypt {
             crypt::Algorithm::Aes128Xts
         } else {
             crypt::Algorithm::None
         };
+        // Unsupported entries of (e)stargz images have always been skipped with a warning.
+        let unsupported_file_policy = match conversion_type {
+            ConversionType::EStargzToRafs
+            | ConversionType::EStargzToRef
+            | ConversionType::EStargzIndexToRef
+            | ConversionType::ZstdChunkedToRef => UnsupportedFilePolicy::Warn,
+            _ => UnsupportedFilePolicy::Error,
+        };
         BuildContext {
             blob_id,
             aligned_chunk,
             blob_offset,
             compressor,
+            compress_level: None,
+            file_compressors: HashMap::new(),
+            hot_compressor: None,
             digester,
             cipher,
             explicit_uidgid,
@@ -1379,9 +2284,24 @@ impl BuildContext {
             chunk_size: RAFS_DEFAULT_CHUNK_SIZE as u32,
             batch_size: 0,
             fs_version: RafsVersion::default(),
+            inode_order: InodeOrder::default(),
 
             conversion_type,
             source_path,
+            follow_symlinks: false,
+            hardlink_key: HardlinkKey::default(),
+            hardlink_dev_map: HashMap::new(),
+            skip_unreadable: false,
+            one_file_system: false,
+            readahead_files: 0,
+            skipped_files: Mutex::new(Vec::new()),
+            unsupported_file_policy,
+            unsupported_files: Vec::new(),
+            privileged_files: PrivilegedFiles::default(),
+            file_transform: None,
+            transformed_files: Vec::new(),
+            transformed_data: HashMap::new(),
+            transform_dir: None,
 
             prefetch,
             blob_storage,
@@ -1390,7 +2310,13 @@ impl BuildContext {
             blob_tar_reader: None,
             blob_features,
             blob_inline_meta,
+            blob_padding: 0,
+            blob_meta_alignment: 0,
+            tmp_dir: None,
+            artifact_stage: None,
+            annotations: RafsV6Annotations::new(),
             has_xattr: false,
+            v6_shared_xattrs: RafsV6SharedXattrs::new(),
 
             features,
             configuration: Arc::new(ConfigV2::default()),
@@ -1401,6 +2327,16 @@ impl BuildContext {
 
     pub fn set_fs_version(&mut self, fs_version: RafsVersion) {
         self.fs_version = fs_version;
+        // RAFS v6 runtime assumes uncompressed chunks are 4K aligned, except for tarfs mode.
+        if fs_version.is_v6() && self.conversion_type != ConversionType::TarToTarfs {
+            self.set_aligned_chunk(true);
+        }
+    }
+
+    /// Set whether to align uncompressed data chunks to 4K, keeping blob features in sync.
+    pub fn set_aligned_chunk(&mut self, aligned_chunk: bool) {
+        self.aligned_chunk = aligned_chunk;
+        self.blob_features.set(BlobFeatures::ALIGNED, aligned_chunk);
     }
 
     pub fn set_chunk_size(&mut self, chunk_size: u32) {
@@ -1411,6 +2347,298 @@ impl BuildContext {
         self.batch_size = batch_size;
     }
 
+    pub fn set_compress_level(&mut self, compress_level: Option<u32>) {
+        self.compress_level = compress_level;
+    }
+
+    pub fn set_file_compressor(
+        &mut self,
+        path: PathBuf,
+        compressor: compress::Algorithm,
+    ) -> Result<()> {
+        Self::check_chunk_compressor(compressor)?;
+        self.file_compressors.insert(path, compressor);
+        Ok(())
+    }
+
+    pub fn set_hot_compressor(&mut self, compressor: Option<compress::Algorithm>) -> Result<()> {
+        if let Some(compressor) = compressor {
+            Self::check_chunk_compressor(compressor)?;
+        }
+        self.hot_compressor = compressor;
+        Ok(())
+    }
+
+    // Per-chunk compression algorithms are recorded in a 3-bit field of chunk flags, which has
+    // no room for custom algorithms.
+    fn check_chunk_compressor(compressor: compress::Algorithm) -> Result<()> {
+        if compressor.is_custom() {
+            bail!(
+                "custom compressor '{}' can't be used to compress individual files",
+                compressor
+            );
+        }
+        Ok(())
+    }
+
+    /// Get compression algorithm for data chunks of the file at `path` in the image.
+    ///
+    /// Per-chunk compression algorithms are recorded in the chunk information array v2 of
+    /// RAFS v6 blobs, otherwise all chunks are compressed by `compressor`.
+    pub fn file_compressor(&self, path: &Path) -> compress::Algorithm {
+        if self.fs_version.is_v6() && self.blob_features.contains(BlobFeatures::CHUNK_INFO_V2) {
+            if let Some(compressor) = self.file_compressors.get(path) {
+                return *compressor;
+            }
+            if let Some(compressor) = self.hot_compressor {
+                if self.prefetch.contains(path) {
+                    return compressor;
+                }
+            }
+        }
+        self.compressor
+    }
+
+    pub fn set_inode_order(&mut self, inode_order: InodeOrder) {
+        self.inode_order = inode_order;
+    }
+
+    pub fn set_follow_symlinks(&mut self, follow_symlinks: bool) {
+        self.follow_symlinks = follow_symlinks;
+    }
+
+    pub fn set_hardlink_key(&mut self, key: HardlinkKey, dev_map: HashMap<u64, u64>) {
+        self.hardlink_key = key;
+        self.hardlink_dev_map = dev_map;
+    }
+
+    pub fn set_skip_unreadable(&mut self, skip_unreadable: bool) {
+        self.skip_unreadable = skip_unreadable;
+    }
+
+    pub fn set_unsupported_file_policy(&mut self, policy: UnsupportedFilePolicy) {
+        self.unsupported_file_policy = policy;
+    }
+
+    /// Handle a source entry of unsupported file type according to the unsupported file policy.
+    ///
+    /// Return an error if the build should be aborted, otherwise the entry is recorded and the
+    /// caller should skip it.
+    pub fn add_unsupported_file(&mut self, path: &Path, reason: &str) -> Result<()> {
+        match self.unsupported_file_policy {
+            UnsupportedFilePolicy::Error => {
+                bail!("{} for {}", reason, path.display())
+            }
+            UnsupportedFilePolicy::Skip => {
+                debug!("skip unsupported file {}: {}", path.display(), reason)
+            }
+            UnsupportedFilePolicy::Warn => {
+                warn!("skip unsupported file {}: {}", path.display(), reason)
+            }
+        }
+        self.unsupported_files.push(SkippedFile {
+            path: path.display().to_string(),
+            reason: reason.to_string(),
+        });
+        let paths = self
+            .unsupported_files
+            .iter()
+            .map(|f| f.path.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+        event_tracer!("unsupported_files", "{}", paths);
+
+        Ok(())
+    }
+
+    pub fn set_one_file_system(&mut self, one_file_system: bool) {
+        self.one_file_system = one_file_system;
+    }
+
+    pub fn set_readahead_files(&mut self, readahead_files: usize) {
+        self.readahead_files = readahead_files;
+    }
+
+    pub fn set_file_transform(&mut self, file_transform: Arc<dyn FileTransform>) {
+        self.file_transform = Some(file_transform);
+    }
+
+    /// Transform content of the regular file `node` with the file transform hook, and update its
+    /// size and chunk count to match the transformed content.
+    ///
+    /// Hardlinks share the transformed content, so each source inode is transformed only once.
+    pub(crate) fn transform_file(&mut self, node: &mut Node) -> Result<()> {
+        let hook = match self.file_transform.as_ref() {
+            Some(hook) if node.is_reg() => hook.clone(),
+            _ => return Ok(()),
+        };
+        let key = (node.info.src_dev, node.info.src_ino);
+        if !self.transformed_data.contains_key(&key) {
+            if self.transform_dir.is_none() {
+                let tmp_dir = self.tmp_dir.clone().unwrap_or_else(std::env::temp_dir);
+                let dir = TempDir::new_with_prefix(tmp_dir.join("nydus-transform-")).with_context(
+                    || {
+                        format!(
+                            "failed to create temporary directory in {}",
+                            tmp_dir.display()
+                        )
+                    },
+                )?;
+                self.transform_dir = Some(dir);
+            }
+            // Only keep paths of transformed content, to avoid holding a file descriptor for
+            // each transformed file until the data blob is dumped.
+            let tmp_path = self
+                .transform_dir
+                .as_ref()
+                .unwrap()
+                .as_path()
+                .join(self.transformed_data.len().to_string());
+            let file = File::open(node.path())
+                .with_context(|| format!("failed to open file {:?}", node.path()))?;
+            let tmp_file = File::create(&tmp_path)
+                .with_context(|| format!("failed to create temporary file {:?}", tmp_path))?;
+            let mut input = transform::DigestStream::new(file);
+            let mut output = transform::DigestStream::new(BufWriter::new(tmp_file));
+            let transformed = hook
+                .transform(&mut input, node.path(), node.target(), &mut output)
+                .with_context(|| format!("failed to transform file {:?}", node.path()))?;
+            output
+                .flush()
+                .context("failed to write transformed content")?;
+            let mut data = None;
+            if transformed {
+                // Hash remaining content not consumed by the hook.
+                std::io::copy(&mut input, &mut std::io::sink())
+                    .with_context(|| format!("failed to read file {:?}", node.path()))?;
+                let (original_size, original_digest) = input.finish();
+                let (size, digest) = output.finish();
+                if digest != original_digest {
+                    debug!(
+                        "transformed file {:?}, size 0x{:x} -> 0x{:x}",
+                        node.target(),
+                        original_size,
+                        size
+                    );
+                    self.transformed_files.push(TransformedFile {
+                        path: node.target().display().to_string(),
+                        original_size,
+                        original_digest,
+                        size,
+                        digest,
+                    });
+                    data = Some((tmp_path, size));
+                }
+            }
+            if data.is_none() {
+                let _ = remove_file(&tmp_path);
+            }
+            self.transformed_data.insert(key, data);
+        }
+
+        if let Some(Some((_, size))) = self.transformed_data.get(&key) {
+            node.set_data_size(*size, self.chunk_size)?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the file holding transformed content of the regular file `node`, if transformed.
+    pub(crate) fn get_transformed_data(&self, node: &Node) -> Option<&Path> {
+        match self
+            .transformed_data
+            .get(&(node.info.src_dev, node.info.src_ino))
+        {
+            Some(Some((path, _))) => Some(path.as_path()),
+            _ => None,
+        }
+    }
+
+    /// Record a source file skipped because it can't be read.
+    pub fn add_skipped_file(&self, path: &Path, err: &Error) {
+        warn!("skip unreadable file {}: {:#}", path.display(), err);
+        self.skipped_files.lock().unwrap().push(SkippedFile {
+            path: path.display().to_string(),
+            reason: format!("{:#}", err),
+        });
+    }
+
+    pub fn set_blob_padding(&mut self, blob_padding: u64) {
+        self.blob_padding = blob_padding;
+    }
+
+    pub fn set_blob_meta_alignment(&mut self, blob_meta_alignment: u64) {
+        self.blob_meta_alignment = blob_meta_alignment;
+    }
+
+    pub fn set_tmp_dir(&mut self, tmp_dir: Option<PathBuf>) {
+        self.tmp_dir = tmp_dir;
+    }
+
+    pub fn set_artifact_stage(&mut self, stage: Option<Arc<ArtifactStage>>) {
+        self.artifact_stage = stage;
+    }
+
+    /// Add an annotation to embed in the bootstrap.
+    pub fn add_annotation(&mut self, key: &str, value: &str) -> Result<()> {
+        self.annotations
+            .insert(key, value)
+            .with_context(|| format!("failed to add annotation {}", key))
+    }
+
+    /// Estimate the worst case size of the data blob from the size of the source.
+    ///
+    /// Return `None` if the size can't be estimated, such as building from compressed tarballs
+    /// or image references.
+    pub fn estimate_blob_size(&self) -> Option<u64> {
+        let size = match self.conversion_type {
+            ConversionType::DirectoryToRafs => source_dir_size(&self.source_path),
+            ConversionType::TarToRafs => fs::metadata(&self.source_path).ok()?.len(),
+            _ => return None,
+        };
+        // Reserve space for incompressible data and the blob compression context table.
+        Some(size + size / 64)
+    }
+
+    /// Make sure filesystems holding temporary and final data blobs have enough free space for
+    /// the data blob, instead of failing with ENOSPC in the middle of the build.
+    ///
+    /// The estimation is pessimistic and walks the whole source directory, so it's only done on
+    /// request.
+    pub fn check_free_space(&self) -> Result<()> {
+        let blob_dir = match &self.blob_storage {
+            Some(ArtifactStorage::FileDir(p)) => p.as_path(),
+            _ => return Ok(()),
+        };
+        let size = match self.estimate_blob_size() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        // The temporary file is copied into the blob directory if they are on different
+        // filesystems, so both of them need space for the whole data blob.
+        let mut checked = Vec::new();
+        for dir in self.tmp_dir.as_deref().into_iter().chain([blob_dir]) {
+            let stat =
+                statvfs(dir).with_context(|| format!("failed to statvfs {}", dir.display()))?;
+            if checked.contains(&stat.filesystem_id()) {
+                continue;
+            }
+            checked.push(stat.filesystem_id());
+            let available = stat.blocks_available() as u64 * stat.fragment_size() as u64;
+            if available < size {
+                bail!(
+                    "not enough space in {}, 0x{:x} bytes available but up to 0x{:x} bytes may be needed for the data blob, use '--tmp-dir' to place temporary files on another filesystem",
+                    dir.display(),
+                    available,
+                    size
+                );
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn set_configuration(&mut self, config: Arc<ConfigV2>) {
         self.configuration = config;
     }
@@ -1427,6 +2655,9 @@ impl Default for BuildContext {
             aligned_chunk: false,
             blob_offset: 0,
             compressor: compress::Algorithm::default(),
+            compress_level: None,
+            file_compressors: HashMap::new(),
+            hot_compressor: None,
             digester: digest::Algorithm::default(),
             cipher: crypt::Algorithm::None,
             explicit_uidgid: true,
@@ -1435,9 +2666,24 @@ impl Default for BuildContext {
             chunk_size: RAFS_DEFAULT_CHUNK_SIZE as u32,
             batch_size: 0,
             fs_version: RafsVersion::default(),
+            inode_order: InodeOrder::default(),
 
             conversion_type: ConversionType::default(),
             source_path: PathBuf::new(),
+            follow_symlinks: false,
+            hardlink_key: HardlinkKey::default(),
+            hardlink_dev_map: HashMap::new(),
+            skip_unreadable: false,
+            one_file_system: false,
+            readahead_files: 0,
+            skipped_files: Mutex::new(Vec::new()),
+            unsupported_file_policy: UnsupportedFilePolicy::default(),
+            unsupported_files: Vec::new(),
+            privileged_files: PrivilegedFiles::default(),
+            file_transform: None,
+            transformed_files: Vec::new(),
+            transformed_data: HashMap::new(),
+            transform_dir: None,
 
             prefetch: Prefetch::default(),
             blob_storage: None,
@@ -1446,7 +2692,13 @@ impl Default for BuildContext {
             blob_tar_reader: None,
             blob_features: BlobFeatures::empty(),
             has_xattr: true,
+            v6_shared_xattrs: RafsV6SharedXattrs::new(),
             blob_inline_meta: false,
+            blob_padding: 0,
+            blob_meta_alignment: 0,
+            tmp_dir: None,
+            artifact_stage: None,
+            annotations: RafsV6Annotations::new(),
             features: Features::new(),
             configuration: Arc::new(ConfigV2::default()),
             blob_cache_generator: None,
@@ -1455,6 +2707,165 @@ impl Default for BuildContext {
     }
 }
 
+/// A source file skipped by the build, because it can't be read or its file type is unsupported.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SkippedFile {
+    /// Path of the file in the source.
+    pub path: String,
+    /// Why the file has been skipped.
+    pub reason: String,
+}
+
+/// Statistics about where data chunks of a build come from.
+///
+/// Sizes are uncompressed data sizes in bytes. Chunks of hardlinks are not accounted as reused.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkDedupStats {
+    /// Number of chunks newly written into the data blob.
+    pub new_chunks: u64,
+    /// Size of chunks newly written into the data blob.
+    pub new_size: u64,
+    /// Number of chunks deduplicated against chunks generated by the same build.
+    pub build_chunks: u64,
+    /// Size of chunks deduplicated against chunks generated by the same build.
+    pub build_size: u64,
+    /// Number of chunks reused from the parent bootstrap.
+    pub parent_chunks: u64,
+    /// Size of chunks reused from the parent bootstrap.
+    pub parent_size: u64,
+    /// Number of chunks reused from the chunk dictionary.
+    pub dict_chunks: u64,
+    /// Size of chunks reused from the chunk dictionary.
+    pub dict_size: u64,
+}
+
+impl ChunkDedupStats {
+    /// Account a chunk newly written into the data blob.
+    pub fn add_new(&mut self, size: u64) {
+        self.new_chunks += 1;
+        self.new_size += size;
+    }
+
+    /// Account a deduplicated chunk reused from `source`.
+    pub fn add_reused(&mut self, source: &ChunkSource, size: u64) {
+        match source {
+            ChunkSource::Build => {
+                self.build_chunks += 1;
+                self.build_size += size;
+            }
+            ChunkSource::Parent => {
+                self.parent_chunks += 1;
+                self.parent_size += size;
+            }
+            ChunkSource::Dict => {
+                self.dict_chunks += 1;
+                self.dict_size += size;
+            }
+        }
+    }
+
+    /// Merge statistics from another build.
+    pub fn merge(&mut self, other: &ChunkDedupStats) {
+        self.new_chunks += other.new_chunks;
+        self.new_size += other.new_size;
+        self.build_chunks += other.build_chunks;
+        self.build_size += other.build_size;
+        self.parent_chunks += other.parent_chunks;
+        self.parent_size += other.parent_size;
+        self.dict_chunks += other.dict_chunks;
+        self.dict_size += other.dict_size;
+    }
+}
+
+impl fmt::Display for ChunkDedupStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "new {} chunks (0x{:x} bytes), deduplicated within build {} chunks (0x{:x} bytes), from parent {} chunks (0x{:x} bytes), from chunk dict {} chunks (0x{:x} bytes)",
+            self.new_chunks,
+            self.new_size,
+            self.build_chunks,
+            self.build_size,
+            self.parent_chunks,
+            self.parent_size,
+            self.dict_chunks,
+            self.dict_size
+        )
+    }
+}
+
+/// A regular file with elevated privileges, such as setuid binaries.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrivilegedFile {
+    /// Path of the file in the image.
+    pub path: String,
+    /// Whether the setuid bit is set.
+    pub setuid: bool,
+    /// Whether the setgid bit is set.
+    pub setgid: bool,
+    /// Whether the file has file capabilities in the `security.capability` extended attribute.
+    pub capability: bool,
+}
+
+/// Audit of regular files with elevated privileges baked into the image.
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrivilegedFiles {
+    /// Number of setuid files.
+    pub setuid: u64,
+    /// Number of setgid files.
+    pub setgid: u64,
+    /// Number of files with file capabilities.
+    pub capability: u64,
+    /// Privileged files in the order of walking the filesystem tree.
+    pub files: Vec<PrivilegedFile>,
+}
+
+impl PrivilegedFiles {
+    /// Account the node if it's a regular file with elevated privileges.
+    pub fn add(&mut self, node: &Node) {
+        if !node.is_reg() {
+            return;
+        }
+        let mode = node.inode.mode();
+        let setuid = mode & libc::S_ISUID as u32 != 0;
+        let setgid = mode & libc::S_ISGID as u32 != 0;
+        let capability = node
+            .info
+            .xattrs
+            .get(OsStr::new(XATTR_SECURITY_CAPABILITY))
+            .is_some();
+        if setuid || setgid || capability {
+            self.setuid += setuid as u64;
+            self.setgid += setgid as u64;
+            self.capability += capability as u64;
+            self.files.push(PrivilegedFile {
+                path: node.target().display().to_string(),
+                setuid,
+                setgid,
+                capability,
+            });
+        }
+    }
+
+    /// Merge audit result from another build.
+    pub fn merge(&mut self, other: &PrivilegedFiles) {
+        self.setuid += other.setuid;
+        self.setgid += other.setgid;
+        self.capability += other.capability;
+        self.files.extend(other.files.iter().cloned());
+    }
+}
+
+impl fmt::Display for PrivilegedFiles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "setuid {} files, setgid {} files, with capabilities {} files",
+            self.setuid, self.setgid, self.capability
+        )
+    }
+}
+
 /// BuildOutput represents the output in this build.
 #[derive(Default, Debug, Clone)]
 pub struct BuildOutput {
@@ -1464,6 +2875,16 @@ pub struct BuildOutput {
     pub blob_size: Option<u64>,
     /// File path for the metadata blob.
     pub bootstrap_path: Option<String>,
+    /// Statistics about chunk deduplication.
+    pub dedup_stats: ChunkDedupStats,
+    /// Source files skipped because they can't be read.
+    pub skipped_files: Vec<SkippedFile>,
+    /// Source entries skipped because of unsupported file types.
+    pub unsupported_files: Vec<SkippedFile>,
+    /// Audit of privileged files in the image.
+    pub privileged_files: PrivilegedFiles,
+    /// Regular files whose content has been transformed.
+    pub transformed_files: Vec<TransformedFile>,
 }
 
 impl fmt::Display for BuildOutput {
@@ -1478,7 +2899,26 @@ impl fmt::Display for BuildOutput {
             "data blob size: 0x{:x}",
             self.blob_size.unwrap_or_default()
         )?;
-        write!(f, "data blobs: {:?}", self.blobs)?;
+        writeln!(f, "data blobs: {:?}", self.blobs)?;
+        writeln!(f, "data chunks: {}", self.dedup_stats)?;
+        write!(f, "privileged files: {}", self.privileged_files)?;
+        if !self.skipped_files.is_empty() {
+            write!(
+                f,
+                "\nskipped unreadable files: {}",
+                self.skipped_files.len()
+            )?;
+        }
+        if !self.unsupported_files.is_empty() {
+            write!(
+                f,
+                "\nskipped unsupported files: {}",
+                self.unsupported_files.len()
+            )?;
+        }
+        if !self.transformed_files.is_empty() {
+            write!(f, "\ntransformed files: {}", self.transformed_files.len())?;
+        }
         Ok(())
     }
 }
@@ -1501,15 +2941,22 @@ impl BuildOutput {
             blobs,
             blob_size,
             bootstrap_path,
+            dedup_stats: blob_mgr.dedup_stats,
+            skipped_files: Vec::new(),
+            unsupported_files: Vec::new(),
+            privileged_files: PrivilegedFiles::default(),
+            transformed_files: Vec::new(),
         })
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::os::unix::fs::MetadataExt;
     use std::sync::atomic::AtomicBool;
 
     use nydus_api::{BackendConfigV2, ConfigV2Internal, LocalFsConfig};
+    use nydus_utils::digest::RafsDigest;
 
     use super::*;
 
@@ -1572,4 +3019,475 @@ mod tests {
         assert_eq!(blob_ctx.uncompressed_blob_size, 16);
         assert!(blob_ctx.blob_meta_info_enabled);
     }
+
+    #[test]
+    fn test_build_context_v6_aligned_chunk() {
+        let mut ctx = BuildContext::default();
+        assert!(!ctx.aligned_chunk);
+        ctx.set_fs_version(RafsVersion::V6);
+        assert!(ctx.aligned_chunk);
+        assert!(ctx.blob_features.contains(BlobFeatures::ALIGNED));
+
+        let mut ctx = BuildContext {
+            conversion_type: ConversionType::TarToTarfs,
+            ..Default::default()
+        };
+        ctx.set_fs_version(RafsVersion::V6);
+        assert!(!ctx.aligned_chunk);
+        assert!(!ctx.blob_features.contains(BlobFeatures::ALIGNED));
+
+        let mut ctx = BuildContext::default();
+        ctx.set_fs_version(RafsVersion::V5);
+        assert!(!ctx.aligned_chunk);
+    }
+
+    #[test]
+    fn test_file_compressor() {
+        let mut ctx = BuildContext {
+            compressor: compress::Algorithm::Zstd,
+            ..Default::default()
+        };
+        ctx.prefetch.policy = PrefetchPolicy::Fs;
+        ctx.prefetch.add_patterns(vec![PathBuf::from("/usr/bin")]);
+        ctx.set_hot_compressor(Some(compress::Algorithm::Lz4Block))
+            .unwrap();
+        ctx.set_file_compressor(PathBuf::from("/etc/hosts"), compress::Algorithm::Lz4Hc)
+            .unwrap();
+        assert!(ctx
+            .set_file_compressor(PathBuf::from("/etc/passwd"), compress::Algorithm::Custom0)
+            .is_err());
+        assert!(ctx
+            .set_hot_compressor(Some(compress::Algorithm::Custom1))
+            .is_err());
+
+        // Per-chunk compression algorithms require RAFS v6 with chunk information array v2.
+        ctx.set_fs_version(RafsVersion::V6);
+        let hot = Path::new("/usr/bin/sh");
+        assert_eq!(ctx.file_compressor(hot), compress::Algorithm::Zstd);
+        ctx.blob_features |= BlobFeatures::CHUNK_INFO_V2;
+        assert_eq!(ctx.file_compressor(hot), compress::Algorithm::Lz4Block);
+        assert_eq!(
+            ctx.file_compressor(Path::new("/etc/hosts")),
+            compress::Algorithm::Lz4Hc
+        );
+        assert_eq!(
+            ctx.file_compressor(Path::new("/usr/lib/libc.so")),
+            compress::Algorithm::Zstd
+        );
+
+        ctx.set_hot_compressor(None).unwrap();
+        assert_eq!(ctx.file_compressor(hot), compress::Algorithm::Zstd);
+        ctx.set_hot_compressor(Some(compress::Algorithm::Lz4Block))
+            .unwrap();
+        ctx.set_fs_version(RafsVersion::V5);
+        assert_eq!(ctx.file_compressor(hot), compress::Algorithm::Zstd);
+    }
+
+    #[test]
+    fn test_hardlink_key() {
+        assert_eq!(HardlinkKey::default(), HardlinkKey::Dev);
+        assert_eq!(HardlinkKey::from_str("dev").unwrap(), HardlinkKey::Dev);
+        assert_eq!(HardlinkKey::from_str("fsid").unwrap(), HardlinkKey::Fsid);
+        assert!(HardlinkKey::from_str("ino").is_err());
+        assert_eq!(HardlinkKey::Fsid.to_string(), "fsid");
+    }
+
+    #[test]
+    fn test_unsupported_file_policy() {
+        assert_eq!(
+            UnsupportedFilePolicy::from_str("warn").unwrap(),
+            UnsupportedFilePolicy::Warn
+        );
+        assert!(UnsupportedFilePolicy::from_str("ignore").is_err());
+
+        let mut ctx = BuildContext::default();
+        assert_eq!(ctx.unsupported_file_policy, UnsupportedFilePolicy::Error);
+        ctx.add_unsupported_file(Path::new("/a"), "unsupported file type")
+            .unwrap_err();
+        assert!(ctx.unsupported_files.is_empty());
+
+        ctx.set_unsupported_file_policy(UnsupportedFilePolicy::Skip);
+        ctx.add_unsupported_file(Path::new("/a"), "unsupported file type")
+            .unwrap();
+        ctx.set_unsupported_file_policy(UnsupportedFilePolicy::Warn);
+        ctx.add_unsupported_file(Path::new("/b"), "unsupported file type")
+            .unwrap();
+        let paths: Vec<&str> = ctx
+            .unsupported_files
+            .iter()
+            .map(|f| f.path.as_str())
+            .collect();
+        assert_eq!(paths, vec!["/a", "/b"]);
+    }
+
+    #[test]
+    fn test_chunk_dedup_stats() {
+        let mut stats = ChunkDedupStats::default();
+        stats.add_new(0x1000);
+        stats.add_new(0x800);
+        stats.add_reused(&ChunkSource::Build, 0x1000);
+        stats.add_reused(&ChunkSource::Parent, 0x2000);
+        stats.add_reused(&ChunkSource::Dict, 0x3000);
+        assert_eq!(stats.new_chunks, 2);
+        assert_eq!(stats.new_size, 0x1800);
+        assert_eq!(stats.build_chunks, 1);
+        assert_eq!(stats.parent_size, 0x2000);
+        assert_eq!(stats.dict_size, 0x3000);
+
+        let mut total = stats;
+        total.merge(&stats);
+        assert_eq!(total.new_chunks, 4);
+        assert_eq!(total.dict_chunks, 2);
+        assert_eq!(total.dict_size, 0x6000);
+    }
+
+    #[test]
+    fn test_add_chunk_meta_info_concurrently() {
+        let mut ctx = BuildContext::default();
+        ctx.set_fs_version(RafsVersion::V6);
+        let mut blob_mgr = BlobManager::new(digest::Algorithm::Sha256);
+        let (_, blob_ctx) = blob_mgr.get_or_create_current_blob(&ctx).unwrap();
+        blob_ctx.blob_meta_info_enabled = true;
+        let blob_ctx = &*blob_ctx;
+
+        let mut indexes = std::thread::scope(|s| {
+            let workers = (0..4)
+                .map(|_| {
+                    s.spawn(|| {
+                        (0..100)
+                            .map(|_| {
+                                let index = blob_ctx.alloc_chunk_index().unwrap();
+                                let mut chunk = ChunkWrapper::new(RafsVersion::V6);
+                                chunk.set_index(index);
+                                chunk.set_uncompressed_offset(index as u64 * 0x1000);
+                                chunk.set_uncompressed_size(0x1000);
+                                chunk.set_compressed_offset(index as u64 * 0x1000);
+                                chunk.set_compressed_size(0x1000);
+                                blob_ctx.add_chunk_meta_info(&chunk, None).unwrap();
+                                index
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .flat_map(|w| w.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        indexes.sort_unstable();
+        assert_eq!(indexes, (0..400).collect::<Vec<u32>>());
+        assert_eq!(blob_ctx.chunk_count(), 400);
+
+        // Meta information is merged in order of chunk index.
+        let (_, blob_ctx) = blob_mgr.get_current_blob().unwrap();
+        assert_eq!(blob_ctx.blob_meta_info.len(), 0);
+        blob_ctx.merge_chunk_meta_info().unwrap();
+        assert_eq!(blob_ctx.blob_meta_info.len(), 400);
+        assert_eq!(blob_ctx.blob_chunk_digest.len(), 400);
+        let offsets: Vec<u64> = match &blob_ctx.blob_meta_info {
+            BlobMetaChunkArray::V1(v) => v.iter().map(|c| c.uncompressed_offset()).collect(),
+            BlobMetaChunkArray::V2(v) => v.iter().map(|c| c.uncompressed_offset()).collect(),
+        };
+        assert_eq!(offsets, (0..400).map(|i| i * 0x1000).collect::<Vec<_>>());
+
+        // Chunks must be appended without holes.
+        let mut chunk = ChunkWrapper::new(RafsVersion::V6);
+        chunk.set_index(401);
+        blob_ctx.add_chunk_meta_info(&chunk, None).unwrap();
+        assert!(blob_ctx.merge_chunk_meta_info().is_err());
+
+        // Rafs v6 only supports 24 bit chunk id.
+        let (_, blob_ctx) = blob_mgr.get_current_blob().unwrap();
+        blob_ctx.set_chunk_count(0xff_ffff);
+        assert!(blob_ctx.alloc_chunk_index().is_err());
+        assert_eq!(blob_ctx.chunk_count(), 0xff_ffff);
+    }
+
+    #[test]
+    fn test_privileged_files() {
+        use crate::core::node::tests::new_test_node;
+        use std::os::unix::fs::PermissionsExt;
+        use vmm_sys_util::tempdir::TempDir;
+
+        let tmp_dir = TempDir::new().unwrap();
+        let root = tmp_dir.as_path();
+        let load = |name: &str, mode: u32| {
+            let path = root.join(name);
+            if !path.exists() {
+                fs::write(&path, b"data").unwrap();
+            }
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+            new_test_node(root, name)
+        };
+
+        let mut files = PrivilegedFiles::default();
+        files.add(&load("plain", 0o755));
+        files.add(&load("suid", 0o4755));
+        files.add(&load("sgid", 0o2755));
+        let mut node = load("cap", 0o755);
+        Arc::make_mut(&mut node.info)
+            .xattrs
+            .add(XATTR_SECURITY_CAPABILITY.into(), vec![0u8; 20])
+            .unwrap();
+        files.add(&node);
+        fs::create_dir(root.join("dir")).unwrap();
+        files.add(&load("dir", 0o2755));
+
+        assert_eq!(files.setuid, 1);
+        assert_eq!(files.setgid, 1);
+        assert_eq!(files.capability, 1);
+        assert_eq!(files.files.len(), 3);
+        assert_eq!(files.files[0].path, "/suid");
+        assert!(files.files[0].setuid && !files.files[0].setgid);
+        assert_eq!(files.files[2].path, "/cap");
+        assert!(files.files[2].capability);
+
+        let mut total = files.clone();
+        total.merge(&files);
+        assert_eq!(total.setuid, 2);
+        assert_eq!(total.files.len(), 6);
+    }
+
+    #[test]
+    fn test_artifact_writer_reuse_identical_blob() {
+        let tmp_dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let dir = tmp_dir.as_path().to_path_buf();
+        let write_blob = |name: &str, data: &[u8]| {
+            let mut writer = ArtifactWriter::new(ArtifactStorage::FileDir(dir.clone())).unwrap();
+            writer.write_all(data).unwrap();
+            writer.finalize(Some(name.to_string()))
+        };
+        let name = RafsDigest::from_buf(b"data1", digest::Algorithm::Sha256).to_string();
+        let blob_path = dir.join(&name);
+        let file_count = || fs::read_dir(&dir).unwrap().count();
+
+        write_blob(&name, b"data1").unwrap();
+        assert_eq!(fs::read(&blob_path).unwrap(), b"data1");
+        let ino = fs::metadata(&blob_path).unwrap().ino();
+
+        // Identical content, the existing blob file is kept and the temporary file is dropped.
+        write_blob(&name, b"data1").unwrap();
+        assert_eq!(file_count(), 1);
+        assert_eq!(fs::metadata(&blob_path).unwrap().ino(), ino);
+
+        // The existing blob file is never overwritten with content of different size.
+        assert!(write_blob(&name, b"data12").is_err());
+        assert_eq!(file_count(), 1);
+        assert_eq!(fs::read(&blob_path).unwrap(), b"data1");
+
+        // Artifacts not named by digest are replaced.
+        write_blob("blob", b"data1").unwrap();
+        write_blob("blob", b"data2").unwrap();
+        assert_eq!(file_count(), 2);
+        assert_eq!(fs::read(dir.join("blob")).unwrap(), b"data2");
+    }
+
+    #[test]
+    fn test_artifact_writer_with_tmp_dir() {
+        let blob_dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let tmp_dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let storage = ArtifactStorage::FileDir(blob_dir.as_path().to_path_buf());
+        let mut writer =
+            ArtifactWriter::new_with_tmp_dir(storage, Some(tmp_dir.as_path())).unwrap();
+        writer.write_all(b"data").unwrap();
+        assert_eq!(fs::read_dir(tmp_dir.as_path()).unwrap().count(), 1);
+        assert_eq!(fs::read_dir(blob_dir.as_path()).unwrap().count(), 0);
+
+        writer.finalize(Some("blob".to_string())).unwrap();
+        drop(writer);
+        assert_eq!(fs::read(blob_dir.as_path().join("blob")).unwrap(), b"data");
+        assert_eq!(fs::read_dir(tmp_dir.as_path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_artifact_stage() {
+        let blob_dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let blob_path = blob_dir.as_path().join("blob");
+        let bootstrap_path = blob_dir.as_path().join("bootstrap");
+        let stage = Arc::new(ArtifactStage::new());
+
+        let storage = ArtifactStorage::FileDir(blob_dir.as_path().to_path_buf());
+        let mut writer =
+            ArtifactWriter::new_with_stage(storage, None, Some(stage.clone())).unwrap();
+        writer.write_all(b"data").unwrap();
+        writer.finalize(Some("blob".to_string())).unwrap();
+        let storage = ArtifactStorage::SingleFile(bootstrap_path.clone());
+        let mut writer =
+            ArtifactWriter::new_with_stage(storage, None, Some(stage.clone())).unwrap();
+        writer.write_all(b"meta").unwrap();
+        writer.finalize(Some(String::default())).unwrap();
+        drop(writer);
+
+        // Only the hidden staging directory is visible before publishing.
+        assert_eq!(fs::read_dir(blob_dir.as_path()).unwrap().count(), 1);
+        assert!(!blob_path.exists());
+        assert_eq!(fs::read(stage.resolve(&blob_path)).unwrap(), b"data");
+        assert_eq!(fs::read(stage.resolve(&bootstrap_path)).unwrap(), b"meta");
+
+        let published = stage.publish().unwrap();
+        assert_eq!(published, vec![blob_path.clone(), bootstrap_path.clone()]);
+        assert_eq!(stage.resolve(&blob_path), blob_path);
+        assert_eq!(fs::read(&blob_path).unwrap(), b"data");
+        assert_eq!(fs::read(&bootstrap_path).unwrap(), b"meta");
+        drop(stage);
+        assert_eq!(fs::read_dir(blob_dir.as_path()).unwrap().count(), 2);
+
+        // Staged artifacts are discarded if not published.
+        let stage = Arc::new(ArtifactStage::new());
+        let storage = ArtifactStorage::FileDir(blob_dir.as_path().to_path_buf());
+        let mut writer =
+            ArtifactWriter::new_with_stage(storage, None, Some(stage.clone())).unwrap();
+        writer.write_all(b"data2").unwrap();
+        writer.finalize(Some("blob2".to_string())).unwrap();
+        drop(writer);
+        drop(stage);
+        assert_eq!(fs::read_dir(blob_dir.as_path()).unwrap().count(), 2);
+        assert!(!blob_dir.as_path().join("blob2").exists());
+
+        // Published artifacts are rolled back if any artifact fails to be published.
+        let stage = Arc::new(ArtifactStage::new());
+        let storage = ArtifactStorage::FileDir(blob_dir.as_path().to_path_buf());
+        let mut writer =
+            ArtifactWriter::new_with_stage(storage, None, Some(stage.clone())).unwrap();
+        writer.write_all(b"data3").unwrap();
+        writer.finalize(Some("blob3".to_string())).unwrap();
+        let bootstrap_path = blob_dir.as_path().join("bootstrap3");
+        let storage = ArtifactStorage::SingleFile(bootstrap_path.clone());
+        let mut writer =
+            ArtifactWriter::new_with_stage(storage, None, Some(stage.clone())).unwrap();
+        writer.write_all(b"meta3").unwrap();
+        writer.finalize(Some(String::default())).unwrap();
+        drop(writer);
+        // A non-empty directory can't be replaced by renaming a file.
+        fs::create_dir_all(bootstrap_path.join("dir")).unwrap();
+        assert!(stage.publish().is_err());
+        let blob_path = blob_dir.as_path().join("blob3");
+        assert!(!blob_path.exists());
+        assert_eq!(fs::read(stage.resolve(&blob_path)).unwrap(), b"data3");
+    }
+
+    #[test]
+    fn test_artifact_mmap_writer() {
+        let tmp_file = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        let path = tmp_file.as_path().to_path_buf();
+        let storage = ArtifactStorage::SingleFile(path.clone());
+        assert!(ArtifactMmapWriter::is_mappable(&storage));
+        let mut writer = ArtifactMmapWriter::new(storage).unwrap();
+        assert_eq!(writer.seek_to_end().unwrap(), 0);
+
+        // Grow the mapped file by writing beyond the initial size.
+        let offset = MMAP_WRITER_INIT_SIZE * 2 + 0x10;
+        writer.seek_offset(offset).unwrap();
+        writer.write_all(b"tail").unwrap();
+        assert_eq!(writer.seek_to_end().unwrap(), offset + 4);
+        writer.seek_offset(0x1000).unwrap();
+        writer.write_all(b"head").unwrap();
+        assert_eq!(writer.seek_current(0).unwrap(), 0x1004);
+        assert_eq!(writer.seek_to_end().unwrap(), offset + 4);
+        assert!(writer
+            .seek(std::io::SeekFrom::Current(-0x100_0000))
+            .is_err());
+
+        let data = writer.as_bytes().unwrap();
+        assert_eq!(data.len() as u64, offset + 4);
+        assert_eq!(&data[0x1000..0x1004], b"head");
+        writer.finalize(Some(String::default())).unwrap();
+        drop(writer);
+
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data.len() as u64, offset + 4);
+        assert_eq!(&data[offset as usize..], b"tail");
+        assert!(data[0x1004..offset as usize].iter().all(|v| *v == 0));
+    }
+
+    fn check_bootstrap_region(writer: &mut dyn RafsIoWrite) {
+        assert!(BootstrapRegion::new(writer, 0x10, 0x100, 0x1000).is_err());
+        assert!(BootstrapRegion::new(writer, u64::MAX, 0x100, 0).is_err());
+
+        let mut region = BootstrapRegion::new(writer, 0x1000, 0x10, 0x1000).unwrap();
+        assert_eq!(region.size(), 0x10);
+        assert!(region.seek_offset(0).is_err());
+        assert!(region.seek_offset(0x1011).is_err());
+        assert_eq!(region.seek_to_end().unwrap(), 0x1010);
+        region.seek_offset(0x1008).unwrap();
+        region.write_all(b"12345678").unwrap();
+        assert!(region.write_all(b"9").is_err());
+        region.seek_offset(0x1004).unwrap();
+        assert!(region.write_all(&[0u8; 0x10]).is_err());
+        assert_eq!(&region.as_bytes().unwrap()[8..], b"12345678");
+
+        region.commit(writer).unwrap();
+        assert_eq!(writer.seek_current(0).unwrap(), 0x1010);
+        let data = writer.as_bytes().unwrap();
+        assert_eq!(data.len(), 0x1010);
+        assert_eq!(&data[0x1008..], b"12345678");
+    }
+
+    #[test]
+    fn test_bootstrap_region() {
+        let mut writer = ArtifactMemoryWriter::default();
+        check_bootstrap_region(&mut writer);
+
+        // Regions of memory mapped bootstrap are populated in place.
+        let tmp_file = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        let path = tmp_file.as_path().to_path_buf();
+        let mut writer =
+            ArtifactMmapWriter::new(ArtifactStorage::SingleFile(path.clone())).unwrap();
+        assert!(writer.backing_file(0x1000).unwrap().is_some());
+        check_bootstrap_region(&mut writer);
+        writer.finalize(Some(String::default())).unwrap();
+        drop(writer);
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data.len(), 0x1010);
+        assert_eq!(&data[0x1008..], b"12345678");
+    }
+
+    #[test]
+    fn test_check_free_space() {
+        let source = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let blob_dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        fs::write(source.as_path().join("file"), vec![0u8; 0x1000]).unwrap();
+        fs::hard_link(source.as_path().join("file"), source.as_path().join("link")).unwrap();
+        fs::create_dir(source.as_path().join("dir")).unwrap();
+        fs::write(source.as_path().join("dir/file"), vec![0u8; 0x1000]).unwrap();
+
+        let mut ctx = BuildContext {
+            conversion_type: ConversionType::DirectoryToRafs,
+            source_path: source.as_path().to_path_buf(),
+            blob_storage: Some(ArtifactStorage::FileDir(blob_dir.as_path().to_path_buf())),
+            ..Default::default()
+        };
+        assert_eq!(ctx.estimate_blob_size(), Some(0x2000 + 0x2000 / 64));
+        ctx.check_free_space().unwrap();
+        ctx.set_tmp_dir(Some(source.as_path().to_path_buf()));
+        ctx.check_free_space().unwrap();
+
+        ctx.conversion_type = ConversionType::TargzToRafs;
+        assert_eq!(ctx.estimate_blob_size(), None);
+    }
+
+    #[test]
+    fn test_blob_cache_generator_meta_only() {
+        let tmp_dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let dir = tmp_dir.as_path().to_path_buf();
+        let generator =
+            BlobCacheGenerator::new_meta_only(ArtifactStorage::FileDir(dir.clone())).unwrap();
+        let header = BlobCompressionContextHeader::default();
+        let chunk = ChunkWrapper::new(RafsVersion::V6);
+        generator.write_blob_meta(&[0x5au8; 16], &header).unwrap();
+        generator
+            .write_blob_data(&[0xa5u8; 16], &chunk, 0x1000)
+            .unwrap();
+        generator.finalize("blob").unwrap();
+
+        let meta = fs::read(dir.join("blob.blob.meta")).unwrap();
+        assert_eq!(
+            meta.len(),
+            0x1000 + size_of::<BlobCompressionContextHeader>()
+        );
+        assert_eq!(&meta[..16], &[0x5au8; 16]);
+        assert_eq!(&meta[0x1000..], header.as_bytes());
+        assert!(!dir.join("blob.blob.data").exists());
+    }
 }
@@ -11,6 +11,7 @@ pub(crate) mod layout;
 pub(crate) mod node;
 pub(crate) mod overlay;
 pub(crate) mod prefetch;
+pub(crate) mod transform;
 pub(crate) mod tree;
 pub(crate) mod v5;
 pub(crate) mod v6;
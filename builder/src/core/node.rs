@@ -13,10 +13,14 @@ use std::os::linux::fs::MetadataExt;
 #[cfg(target_os = "macos")]
 use std::os::macos::fs::MetadataExt;
 use std::os::unix::ffi::OsStrExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
 use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::{anyhow, bail, Context, Error, Result};
+#[cfg(target_os = "linux")]
+use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
 use nydus_rafs::metadata::chunk::ChunkWrapper;
 use nydus_rafs::metadata::inode::InodeWrapper;
 use nydus_rafs::metadata::layout::v6::EROFS_INODE_FLAT_PLAIN;
@@ -35,6 +39,59 @@ use super::context::Artifact;
 
 /// Filesystem root path for Unix OSs.
 const ROOT_PATH_NAME: &[u8] = &[b'/'];
+/// Maximum amount of data to read ahead for a source file, the kernel sequential readahead
+/// takes over once the builder starts consuming the file.
+#[cfg(target_os = "linux")]
+const READAHEAD_MAX_SIZE: u64 = 0x200_0000;
+
+/// Reader for data of a regular file, which fills the rest of the file with zeros once it fails
+/// to be read if unreadable files are to be skipped.
+///
+/// Files may become unreadable or be truncated after the RAFS metadata has been laid out, when
+/// their sizes and chunk counts can't be changed any more.
+struct UnreadableFileReader {
+    path: PathBuf,
+    file: Option<File>,
+    skip_unreadable: bool,
+    // Error causing the rest of the file to be filled with zeros.
+    error: Option<Error>,
+}
+
+impl UnreadableFileReader {
+    fn new(path: &Path, file: Result<File>, skip_unreadable: bool) -> Result<Self> {
+        let (file, error) = match file {
+            Ok(file) => (Some(file), None),
+            Err(e) if skip_unreadable => (None, Some(e)),
+            Err(e) => return Err(e),
+        };
+        Ok(UnreadableFileReader {
+            path: path.to_path_buf(),
+            file,
+            skip_unreadable,
+            error,
+        })
+    }
+}
+
+impl Read for UnreadableFileReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(file) = self.file.as_mut() {
+            let err = match file.read(buf) {
+                Ok(0) if !buf.is_empty() && self.skip_unreadable => {
+                    anyhow!("file {:?} is truncated", self.path)
+                }
+                Err(e) if e.kind() != std::io::ErrorKind::Interrupted && self.skip_unreadable => {
+                    Error::from(e).context(format!("failed to read file {:?}", self.path))
+                }
+                res => return res,
+            };
+            self.error = Some(err);
+            self.file = None;
+        }
+        buf.fill(0);
+        Ok(buf.len())
+    }
+}
 
 /// Source of chunk data: chunk dictionary, parent filesystem or builder.
 #[derive(Clone, Hash, PartialEq, Eq)]
@@ -163,7 +220,7 @@ pub struct Node {
     pub v6_offset: u64,
     /// V6: offset to build directory entries.
     pub v6_dirents_offset: u64,
-    /// V6: information to build directory entries.
+    /// V6: information to build directory entries, only available while dumping the directory.
     pub v6_dirents: Vec<(u64, OsString, u32)>,
 }
 
@@ -222,15 +279,59 @@ impl Node {
         blob_writer: &mut dyn Artifact,
         chunk_data_buf: &mut [u8],
     ) -> Result<u64> {
-        let mut reader = if self.is_reg() {
-            let file = File::open(self.path())
-                .with_context(|| format!("failed to open node file {:?}", self.path()))?;
-            Some(file)
+        // Empty files have no data chunk, so there's no need to open them.
+        let mut reader = if self.is_reg() && self.inode.size() > 0 {
+            let path = ctx.get_transformed_data(self).unwrap_or(self.path());
+            let file =
+                File::open(path).with_context(|| format!("failed to open node file {:?}", path));
+            #[cfg(target_os = "linux")]
+            if let Ok(file) = file.as_ref() {
+                if ctx.readahead_files > 0 {
+                    let _ = posix_fadvise(
+                        file.as_raw_fd(),
+                        0,
+                        0,
+                        PosixFadviseAdvice::POSIX_FADV_SEQUENTIAL,
+                    );
+                }
+            }
+            Some(UnreadableFileReader::new(path, file, ctx.skip_unreadable)?)
         } else {
             None
         };
 
-        self.dump_node_data_with_reader(ctx, blob_mgr, blob_writer, reader.as_mut(), chunk_data_buf)
+        let size = self.dump_node_data_with_reader(
+            ctx,
+            blob_mgr,
+            blob_writer,
+            reader.as_mut(),
+            chunk_data_buf,
+        )?;
+        // Metadata of the file has been laid out, so keep the file with zero filled content.
+        if let Some(e) = reader.and_then(|r| r.error) {
+            ctx.add_skipped_file(self.path(), &e);
+        }
+
+        Ok(size)
+    }
+
+    /// Hint the kernel to load leading data of the regular file into page cache asynchronously,
+    /// so reading the file from disk overlaps with dumping data of preceding files.
+    ///
+    /// It's best effort, errors are ignored.
+    pub fn readahead(&self) {
+        #[cfg(target_os = "linux")]
+        if self.is_reg() && self.inode.size() > 0 {
+            if let Ok(file) = File::open(self.path()) {
+                let size = std::cmp::min(self.inode.size(), READAHEAD_MAX_SIZE);
+                let _ = posix_fadvise(
+                    file.as_raw_fd(),
+                    0,
+                    size as i64,
+                    PosixFadviseAdvice::POSIX_FADV_WILLNEED,
+                );
+            }
+        }
     }
 
     /// Dump data from a reader into the data blob, and generate chunk information.
@@ -259,7 +360,8 @@ impl Node {
             } else {
                 return Err(Error::msg("inode's symblink is invalid."));
             }
-        } else if self.is_special() {
+        } else if self.is_special() || self.inode.size() == 0 {
+            // Empty regular files have no data chunk, so reading them never touches data blobs.
             if self.inode.is_v5() {
                 self.inode
                     .set_digest(RafsDigest::hasher(ctx.digester).digest_finalize());
@@ -333,6 +435,7 @@ impl Node {
                     .layered_chunk_dict
                     .add_chunk(chunk.clone(), ctx.digester);
             }
+            blob_mgr.dedup_stats.add_new(uncompressed_size as u64);
             self.chunks.push(NodeChunk {
                 source: ChunkSource::Build,
                 inner: chunk,
@@ -443,8 +546,13 @@ impl Node {
                 // Dump current batch chunk if exists, and then add into a new batch chunk.
                 if !batch.chunk_data_buf_is_empty() {
                     // Dump current batch chunk.
-                    let (_, c_size, _) =
-                        Self::write_chunk_data(ctx, blob_ctx, blob_writer, batch.chunk_data_buf())?;
+                    let (_, c_size, _) = Self::write_chunk_data(
+                        ctx,
+                        blob_ctx,
+                        blob_writer,
+                        batch.chunk_data_buf(),
+                        ctx.compressor,
+                    )?;
                     dumped_size = Some(c_size);
                     batch.add_context(c_size);
                     batch.clear_chunk_data_buf();
@@ -469,21 +577,30 @@ impl Node {
                 let mut batch = batch.lock().unwrap();
                 if !batch.chunk_data_buf_is_empty() {
                     // Dump current batch chunk.
-                    let (_, c_size, _) =
-                        Self::write_chunk_data(ctx, blob_ctx, blob_writer, batch.chunk_data_buf())?;
+                    let (_, c_size, _) = Self::write_chunk_data(
+                        ctx,
+                        blob_ctx,
+                        blob_writer,
+                        batch.chunk_data_buf(),
+                        ctx.compressor,
+                    )?;
                     dumped_size = Some(c_size);
                     batch.add_context(c_size);
                     batch.clear_chunk_data_buf();
                 }
             }
 
+            let compressor = ctx.file_compressor(self.target());
             let (pre_c_offset, c_size, is_compressed) =
-                Self::write_chunk_data(ctx, blob_ctx, blob_writer, chunk_data)
+                Self::write_chunk_data(ctx, blob_ctx, blob_writer, chunk_data, compressor)
                     .with_context(|| format!("failed to write chunk data {:?}", self.path()))?;
             dumped_size = Some(dumped_size.unwrap_or(0) + c_size);
             chunk.set_compressed_offset(pre_c_offset);
             chunk.set_compressed_size(c_size);
             chunk.set_compressed(is_compressed);
+            if is_compressed && compressor != ctx.compressor {
+                chunk.set_compressor(Some(compressor))?;
+            }
         }
 
         if let Some(blob_cache) = ctx.blob_cache_generator.as_ref() {
@@ -499,9 +616,17 @@ impl Node {
         blob_ctx: &mut BlobContext,
         blob_writer: &mut dyn Artifact,
         chunk_data: &[u8],
+        compressor: compress::Algorithm,
     ) -> Result<(u64, u32, bool)> {
-        let (compressed, is_compressed) = compress::compress(chunk_data, ctx.compressor)
-            .with_context(|| "failed to compress node file".to_string())?;
+        // The compression level is for `ctx.compressor`, other algorithms use their defaults.
+        let level = if compressor == ctx.compressor {
+            ctx.compress_level
+        } else {
+            None
+        };
+        let (compressed, is_compressed) =
+            compress::compress_with_level(chunk_data, compressor, level)
+                .with_context(|| "failed to compress node file".to_string())?;
         let encrypted = crypt::encrypt_with_context(
             &compressed,
             &blob_ctx.cipher_object,
@@ -542,7 +667,8 @@ impl Node {
         };
 
         // The chunks of hardlink should be always deduplicated.
-        if !self.is_hardlink() {
+        let is_hardlink = self.is_hardlink();
+        if !is_hardlink {
             event_tracer!("dedup_uncompressed_size", +uncompressed_size);
             event_tracer!("dedup_chunks", +1);
         }
@@ -577,6 +703,11 @@ impl Node {
         } else {
             ChunkSource::Build
         };
+        if !is_hardlink {
+            blob_mgr
+                .dedup_stats
+                .add_reused(&source, uncompressed_size as u64);
+        }
         self.chunks.push(NodeChunk {
             source,
             inner: Arc::new(chunk),
@@ -627,7 +758,7 @@ impl Node {
             v6_dirents: Vec::new(),
         };
 
-        node.build_inode(chunk_size)
+        node.build_inode(chunk_size, false)
             .context("failed to build Node from fs object")?;
         if version.is_v6() {
             node.v6_set_inode_compact();
@@ -636,8 +767,36 @@ impl Node {
         Ok(node)
     }
 
-    fn build_inode_xattr(&mut self) -> Result<()> {
-        let file_xattrs = match xattr::list(self.path()) {
+    /// Rebuild a symlink node from the directory it points to, so the directory is included
+    /// in the image instead of the symlink.
+    ///
+    /// Return false and keep the node unchanged if the symlink doesn't point to a directory.
+    pub fn follow_symlink_dir(&mut self, version: RafsVersion, chunk_size: u32) -> Result<bool> {
+        if !self.is_symlink() || !self.path().is_dir() {
+            return Ok(false);
+        }
+
+        let mut info = self.info.deref().clone();
+        info.symlink = None;
+        info.xattrs = RafsXAttrs::default();
+        self.info = Arc::new(info);
+        self.inode = InodeWrapper::new(version);
+        self.build_inode(chunk_size, true)
+            .with_context(|| format!("failed to follow symlink {}", self.path().display()))?;
+        if version.is_v6() {
+            self.v6_set_inode_compact();
+        }
+
+        Ok(true)
+    }
+
+    fn build_inode_xattr(&mut self, deref: bool) -> Result<()> {
+        let file_xattrs = if deref {
+            xattr::list_deref(self.path())
+        } else {
+            xattr::list(self.path())
+        };
+        let file_xattrs = match file_xattrs {
             Ok(x) => x,
             Err(e) => {
                 if e.raw_os_error() == Some(libc::EOPNOTSUPP) {
@@ -654,7 +813,12 @@ impl Node {
 
         let mut info = self.info.deref().clone();
         for key in file_xattrs {
-            let value = xattr::get(self.path(), &key).with_context(|| {
+            let value = if deref {
+                xattr::get_deref(self.path(), &key)
+            } else {
+                xattr::get(self.path(), &key)
+            };
+            let value = value.with_context(|| {
                 format!("failed to get xattr {:?} of {}", key, self.path().display())
             })?;
             info.xattrs.add(key, value.unwrap_or_default())?;
@@ -667,9 +831,9 @@ impl Node {
         Ok(())
     }
 
-    fn build_inode_stat(&mut self) -> Result<()> {
+    fn build_inode_stat(&mut self, deref: bool) -> Result<()> {
         let meta = self
-            .meta()
+            .meta(deref)
             .with_context(|| format!("failed to get metadata of {}", self.path().display()))?;
         let mut info = self.info.deref().clone();
 
@@ -710,7 +874,7 @@ impl Node {
         Ok(())
     }
 
-    fn build_inode(&mut self, chunk_size: u32) -> Result<()> {
+    fn build_inode(&mut self, chunk_size: u32, deref: bool) -> Result<()> {
         let size = self.name().byte_size();
         if size > u16::MAX as usize {
             bail!("file name length 0x{:x} is too big", size,);
@@ -718,9 +882,9 @@ impl Node {
         self.inode.set_name_size(size);
 
         // NOTE: Always retrieve xattr before attr so that we can know the size of xattr pairs.
-        self.build_inode_xattr()
+        self.build_inode_xattr(deref)
             .with_context(|| format!("failed to get xattr for {}", self.path().display()))?;
-        self.build_inode_stat()
+        self.build_inode_stat(deref)
             .with_context(|| format!("failed to build inode {}", self.path().display()))?;
 
         if self.is_reg() {
@@ -747,10 +911,13 @@ impl Node {
         Ok(())
     }
 
-    fn meta(&self) -> Result<impl MetadataExt> {
-        self.path()
-            .symlink_metadata()
-            .with_context(|| format!("failed to get metadata of {}", self.path().display()))
+    fn meta(&self, deref: bool) -> Result<impl MetadataExt> {
+        let meta = if deref {
+            self.path().metadata()
+        } else {
+            self.path().symlink_metadata()
+        };
+        meta.with_context(|| format!("failed to get metadata of {}", self.path().display()))
     }
 }
 
@@ -793,6 +960,16 @@ impl Node {
         }
     }
 
+    /// Update size and chunk count of the regular file, whose content is replaced by data of
+    /// `size` bytes.
+    pub fn set_data_size(&mut self, size: u64, chunk_size: u32) -> Result<()> {
+        self.inode.set_size(size);
+        self.v5_set_inode_blocks();
+        let chunk_count = self.chunk_count(chunk_size as u64)?;
+        self.inode.set_child_count(chunk_count);
+        Ok(())
+    }
+
     /// Get file type of the inode.
     pub fn file_type(&self) -> &str {
         let mut file_type = "";
@@ -894,9 +1071,11 @@ impl Node {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use std::io::BufReader;
 
+    use nydus_rafs::metadata::RAFS_DEFAULT_CHUNK_SIZE;
+    use nydus_storage::meta::BlobMetaChunkArray;
     use nydus_utils::{digest, BufReaderInfo};
     use vmm_sys_util::tempfile::TempFile;
 
@@ -904,6 +1083,20 @@ mod tests {
 
     use super::*;
 
+    /// Create a RAFS v6 node for the source file at `path` relative to the source directory.
+    pub(crate) fn new_test_node(source: &Path, path: impl AsRef<Path>) -> Node {
+        Node::from_fs_object(
+            RafsVersion::V6,
+            source.to_path_buf(),
+            source.join(path),
+            Overlay::UpperAddition,
+            RAFS_DEFAULT_CHUNK_SIZE as u32,
+            false,
+            false,
+        )
+        .unwrap()
+    }
+
     #[test]
     fn test_node_chunk() {
         let chunk_wrapper1 = ChunkWrapper::new(RafsVersion::V5);
@@ -1018,6 +1211,117 @@ mod tests {
         assert_eq!(data_size.unwrap(), 18);
     }
 
+    #[test]
+    fn test_node_dump_file_compressor() {
+        let tmp_dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let source = tmp_dir.as_path().to_path_buf();
+        fs::write(source.join("hot"), "This is a test!\n".repeat(0x200)).unwrap();
+        fs::write(source.join("cold"), "This is another test!\n".repeat(0x200)).unwrap();
+
+        let mut ctx = BuildContext::default();
+        ctx.set_fs_version(RafsVersion::V6);
+        ctx.compressor = compress::Algorithm::Zstd;
+        ctx.blob_features |= BlobFeatures::CHUNK_INFO_V2;
+        ctx.set_file_compressor(PathBuf::from("/hot"), compress::Algorithm::Lz4Block)
+            .unwrap();
+        assert_eq!(
+            ctx.file_compressor(Path::new("/hot")),
+            compress::Algorithm::Lz4Block
+        );
+        assert_eq!(
+            ctx.file_compressor(Path::new("/cold")),
+            compress::Algorithm::Zstd
+        );
+
+        let tmp_file = TempFile::new().unwrap();
+        let mut blob_writer = ArtifactWriter::new(crate::ArtifactStorage::SingleFile(
+            tmp_file.as_path().to_path_buf(),
+        ))
+        .unwrap();
+        let mut blob_mgr = BlobManager::new(digest::Algorithm::Sha256);
+        let mut chunk_data_buf = vec![0u8; RAFS_DEFAULT_CHUNK_SIZE as usize];
+        let mut dump = |name: &str| {
+            let mut node = new_test_node(&source, name);
+            node.dump_node_data(&ctx, &mut blob_mgr, &mut blob_writer, &mut chunk_data_buf)
+                .unwrap();
+            node.chunks[0].inner.clone()
+        };
+
+        let hot = dump("hot");
+        assert!(hot.is_compressed());
+        assert_eq!(hot.compressor(), Some(compress::Algorithm::Lz4Block));
+        assert!(hot.compressed_size() < hot.uncompressed_size());
+
+        let cold = dump("cold");
+        assert!(cold.is_compressed());
+        assert_eq!(cold.compressor(), None);
+
+        let (_, blob_ctx) = blob_mgr.get_current_blob().unwrap();
+        blob_ctx.merge_chunk_meta_info().unwrap();
+        assert_ne!(
+            blob_ctx.blob_meta_header.features() & BlobFeatures::CHUNK_COMPRESSOR.bits(),
+            0
+        );
+        match &blob_ctx.blob_meta_info {
+            BlobMetaChunkArray::V2(v) => {
+                assert_eq!(v.len(), 2);
+                assert_eq!(v[0].compressor(), Some(compress::Algorithm::Lz4Block));
+                assert_eq!(v[1].compressor(), None);
+            }
+            BlobMetaChunkArray::V1(_) => panic!("unexpected blob meta format"),
+        }
+    }
+
+    #[test]
+    fn test_node_dump_unreadable_file() {
+        let tmp_dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let source = tmp_dir.as_path().to_path_buf();
+        fs::write(source.join("truncated"), vec![0x5au8; 0x2000]).unwrap();
+        fs::write(source.join("removed"), vec![0xa5u8; 0x1000]).unwrap();
+        let mut truncated = new_test_node(&source, "truncated");
+        let mut removed = new_test_node(&source, "removed");
+        fs::write(source.join("truncated"), vec![0x5au8; 0x1000]).unwrap();
+        fs::remove_file(source.join("removed")).unwrap();
+
+        let tmp_file = TempFile::new().unwrap();
+        let mut blob_writer = ArtifactWriter::new(crate::ArtifactStorage::SingleFile(
+            tmp_file.as_path().to_path_buf(),
+        ))
+        .unwrap();
+        let mut blob_mgr = BlobManager::new(digest::Algorithm::Sha256);
+        let mut chunk_data_buf = vec![0u8; RAFS_DEFAULT_CHUNK_SIZE as usize];
+        let mut ctx = BuildContext::default();
+        ctx.set_fs_version(RafsVersion::V6);
+        assert!(truncated
+            .dump_node_data(&ctx, &mut blob_mgr, &mut blob_writer, &mut chunk_data_buf)
+            .is_err());
+        assert!(removed
+            .dump_node_data(&ctx, &mut blob_mgr, &mut blob_writer, &mut chunk_data_buf)
+            .is_err());
+
+        // Unreadable content is filled with zeros, and the files are recorded as skipped.
+        ctx.set_skip_unreadable(true);
+        truncated.chunks.clear();
+        truncated
+            .dump_node_data(&ctx, &mut blob_mgr, &mut blob_writer, &mut chunk_data_buf)
+            .unwrap();
+        assert_eq!(truncated.chunks.len(), 1);
+        let mut data = vec![0x5au8; 0x1000];
+        data.resize(0x2000, 0);
+        let digest = RafsDigest::from_buf(&data, ctx.digester);
+        assert_eq!(truncated.chunks[0].inner.id(), &digest);
+        removed.chunks.clear();
+        removed
+            .dump_node_data(&ctx, &mut blob_mgr, &mut blob_writer, &mut chunk_data_buf)
+            .unwrap();
+        assert_eq!(removed.chunks.len(), 1);
+
+        let skipped = ctx.skipped_files.lock().unwrap();
+        assert_eq!(skipped.len(), 2);
+        assert!(skipped[0].reason.contains("is truncated"));
+        assert!(skipped[1].reason.contains("failed to open node file"));
+    }
+
     #[test]
     fn test_node() {
         let inode = InodeWrapper::new(RafsVersion::V5);
@@ -1108,4 +1412,36 @@ mod tests {
         node.remove_xattr(OsStr::new("system.posix_acl_default.key"));
         assert!(!node.inode.has_xattr());
     }
+
+    #[test]
+    fn test_node_follow_symlink_dir() {
+        let tmp_dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let source = tmp_dir.as_path().to_path_buf();
+        fs::create_dir(source.join("dir")).unwrap();
+        fs::write(source.join("file"), b"data").unwrap();
+        std::os::unix::fs::symlink("dir", source.join("link_dir")).unwrap();
+        std::os::unix::fs::symlink("file", source.join("link_file")).unwrap();
+        std::os::unix::fs::symlink("missing", source.join("link_missing")).unwrap();
+
+        let new_node = |name: &str| new_test_node(&source, name);
+
+        let mut node = new_node("link_dir");
+        assert!(node.is_symlink());
+        assert!(node
+            .follow_symlink_dir(RafsVersion::V6, RAFS_DEFAULT_CHUNK_SIZE as u32)
+            .unwrap());
+        assert!(node.is_dir());
+        assert!(node.info.symlink.is_none());
+        assert_eq!(node.target(), &PathBuf::from("/link_dir"));
+        let dir = new_node("dir");
+        assert_eq!(node.info.src_ino, dir.info.src_ino);
+
+        for name in ["link_file", "link_missing", "dir"] {
+            let mut node = new_node(name);
+            assert!(!node
+                .follow_symlink_dir(RafsVersion::V6, RAFS_DEFAULT_CHUNK_SIZE as u32)
+                .unwrap());
+        }
+        assert!(new_node("link_file").is_symlink());
+    }
 }
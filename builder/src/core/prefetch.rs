@@ -3,7 +3,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 use anyhow::{anyhow, Context, Error, Result};
@@ -83,32 +83,38 @@ fn generate_patterns(input: Vec<String>) -> Result<IndexMap<PathBuf, Option<Tree
             continue;
         }
 
-        let mut current_path = file_trimmed.clone();
-        let mut skip = patterns.contains_key(&current_path);
-        while !skip && current_path.pop() {
-            if patterns.contains_key(&current_path) {
-                skip = true;
-                break;
-            }
-        }
-
-        if skip {
-            warn!(
-                "prefetch pattern {} is covered by previous pattern and thus omitted",
-                file
-            );
-        } else {
+        if insert_pattern(&mut patterns, file_trimmed.clone()) {
             debug!(
                 "prefetch pattern: {}, trimmed file name {:?}",
                 file, file_trimmed
             );
-            patterns.insert(file_trimmed, None);
+        } else {
+            warn!(
+                "prefetch pattern {} is covered by previous pattern and thus omitted",
+                file
+            );
         }
     }
 
     Ok(patterns)
 }
 
+/// Insert `path` into prefetch patterns, return false if it's covered by an existing pattern.
+fn insert_pattern(patterns: &mut IndexMap<PathBuf, Option<TreeNode>>, path: PathBuf) -> bool {
+    let mut current_path = path.clone();
+    if patterns.contains_key(&current_path) {
+        return false;
+    }
+    while current_path.pop() {
+        if patterns.contains_key(&current_path) {
+            return false;
+        }
+    }
+
+    patterns.insert(path, None);
+    true
+}
+
 /// Manage filesystem data prefetch configuration and state for builder.
 #[derive(Default, Clone)]
 pub struct Prefetch {
@@ -187,6 +193,14 @@ impl Prefetch {
         }
     }
 
+    /// Check whether the file or directory at `path` is covered by prefetch patterns.
+    pub fn contains(&self, path: &Path) -> bool {
+        if self.policy == PrefetchPolicy::None || self.disabled {
+            return false;
+        }
+        path.ancestors().any(|p| self.patterns.contains_key(p))
+    }
+
     /// Get node Vector of files in the prefetch list and non-prefetch list.
     /// The order of prefetch files is the same as the order of prefetch patterns.
     /// The order of non-prefetch files is the same as the order of BFS traversal of file tree.
@@ -251,6 +265,19 @@ impl Prefetch {
         }
     }
 
+    /// Append patterns to prefetch files and directories, such as those from prefetch tables of
+    /// existing RAFS filesystems. Patterns covered by existing ones are omitted.
+    pub fn add_patterns(&mut self, paths: Vec<PathBuf>) {
+        for path in paths {
+            if !insert_pattern(&mut self.patterns, path.clone()) {
+                debug!(
+                    "prefetch pattern {} is covered by previous pattern and thus omitted",
+                    path.display()
+                );
+            }
+        }
+    }
+
     /// Disable filesystem data prefetch.
     pub fn disable(&mut self) {
         self.disabled = true;
@@ -295,6 +322,44 @@ mod tests {
         assert!(!patterns.contains_key(&PathBuf::from("/k")));
     }
 
+    #[test]
+    fn test_add_patterns() {
+        let mut prefetch = Prefetch::default();
+        prefetch.add_patterns(vec![PathBuf::from("/a/b"), PathBuf::from("/f")]);
+        prefetch.add_patterns(vec![
+            PathBuf::from("/a/b/c"),
+            PathBuf::from("/f"),
+            PathBuf::from("/h"),
+        ]);
+        let patterns: Vec<&PathBuf> = prefetch.patterns.keys().collect();
+        assert_eq!(
+            patterns,
+            vec![
+                &PathBuf::from("/a/b"),
+                &PathBuf::from("/f"),
+                &PathBuf::from("/h")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prefetch_contains() {
+        let mut prefetch = Prefetch::default();
+        prefetch.add_patterns(vec![PathBuf::from("/a/b"), PathBuf::from("/f")]);
+        assert!(!prefetch.contains(Path::new("/f")));
+
+        prefetch.policy = PrefetchPolicy::Fs;
+        assert!(prefetch.contains(Path::new("/a/b")));
+        assert!(prefetch.contains(Path::new("/a/b/c/d")));
+        assert!(prefetch.contains(Path::new("/f")));
+        assert!(!prefetch.contains(Path::new("/a")));
+        assert!(!prefetch.contains(Path::new("/fg")));
+        assert!(!prefetch.contains(Path::new("/")));
+
+        prefetch.disable();
+        assert!(!prefetch.contains(Path::new("/f")));
+    }
+
     #[test]
     fn test_prefetch_policy() {
         let policy = PrefetchPolicy::from_str("fs").unwrap();
@@ -0,0 +1,269 @@
+// Copyright (C) 2024 Nydus Developers. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Hooks to transform content of regular files before they are chunked into data blobs.
+//!
+//! Transforming files during the build, such as stripping debug symbols, avoids copying the
+//! whole source tree for pre-processing. Transformed content replaces the original content in
+//! the image, so it determines file sizes, chunk digests and the data blob.
+
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{ChildStdin, Command, Stdio};
+use std::thread;
+
+use anyhow::{bail, Context, Result};
+use nydus_utils::digest::{self, DigestHasher, RafsDigest, RafsDigestHasher};
+use serde::{Deserialize, Serialize};
+
+/// Environment variable passed to transform commands, with path of the source file.
+pub const TRANSFORM_ENV_SOURCE: &str = "NYDUS_TRANSFORM_SOURCE";
+/// Environment variable passed to transform commands, with path of the file in the filesystem.
+pub const TRANSFORM_ENV_TARGET: &str = "NYDUS_TRANSFORM_TARGET";
+
+/// Trait to transform content of regular files during the build.
+pub trait FileTransform: Send + Sync {
+    /// Write transformed content of the source file at `path` into `output`.
+    ///
+    /// `input` provides original content of the source file, and `target` is the path of the file
+    /// within the RAFS filesystem. Return false if the file should be kept as is, and data written
+    /// into `output` will be discarded.
+    fn transform(
+        &self,
+        input: &mut (dyn Read + Send),
+        path: &Path,
+        target: &Path,
+        output: &mut dyn Write,
+    ) -> Result<bool>;
+}
+
+/// Transform files by an external command, executed by `sh -c` for each regular file.
+///
+/// The command reads original content from stdin and writes transformed content to stdout, and
+/// it must exit successfully. Paths of the source file and the file within the filesystem are
+/// passed by environment variables [TRANSFORM_ENV_SOURCE] and [TRANSFORM_ENV_TARGET].
+pub struct CommandTransform {
+    cmd: String,
+}
+
+impl CommandTransform {
+    pub fn new(cmd: &str) -> Self {
+        CommandTransform {
+            cmd: cmd.to_string(),
+        }
+    }
+
+    fn feed_input(input: &mut (dyn Read + Send), stdin: Option<ChildStdin>) -> io::Result<()> {
+        if let Some(mut stdin) = stdin {
+            // The command may exit without consuming all of its input.
+            if let Err(e) = io::copy(input, &mut stdin) {
+                if e.kind() != io::ErrorKind::BrokenPipe {
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FileTransform for CommandTransform {
+    fn transform(
+        &self,
+        input: &mut (dyn Read + Send),
+        path: &Path,
+        target: &Path,
+        output: &mut dyn Write,
+    ) -> Result<bool> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.cmd)
+            .env(TRANSFORM_ENV_SOURCE, path)
+            .env(TRANSFORM_ENV_TARGET, target)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to execute transform command '{}'", self.cmd))?;
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take();
+        let result = thread::scope(|s| {
+            let feeder = s.spawn(move || Self::feed_input(input, stdin));
+            let copied = match stdout {
+                Some(mut stdout) => io::copy(&mut stdout, output).map(|_| ()),
+                None => Ok(()),
+            };
+            if copied.is_err() {
+                // The command may block on reading stdin, kill it before joining the feeder.
+                let _ = child.kill();
+            }
+            let fed = feeder
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "feeder panicked")));
+            copied.context("failed to save output of transform command")?;
+            fed.with_context(|| format!("failed to feed file {:?} to transform command", path))
+        });
+        if let Err(e) = result {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(e);
+        }
+        let status = child
+            .wait()
+            .context("failed to wait for transform command")?;
+        if !status.success() {
+            bail!("transform command '{}' failed, {}", self.cmd, status);
+        }
+
+        Ok(true)
+    }
+}
+
+/// A regular file whose content has been transformed during the build.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransformedFile {
+    /// Path of the file within the filesystem.
+    pub path: String,
+    /// Size of the original content.
+    pub original_size: u64,
+    /// Sha256 digest of the original content.
+    pub original_digest: String,
+    /// Size of the transformed content.
+    pub size: u64,
+    /// Sha256 digest of the transformed content.
+    pub digest: String,
+}
+
+/// Wrapper to calculate size and sha256 digest of data read from or written into `inner`.
+///
+/// Content is hashed while streaming through the transform hook, so neither the source file nor
+/// the transformed content needs to be read again.
+pub(crate) struct DigestStream<T> {
+    inner: T,
+    hasher: RafsDigestHasher,
+    size: u64,
+}
+
+impl<T> DigestStream<T> {
+    pub fn new(inner: T) -> Self {
+        DigestStream {
+            inner,
+            hasher: RafsDigest::hasher(digest::Algorithm::Sha256),
+            size: 0,
+        }
+    }
+
+    /// Get size and sha256 digest of data passed through the stream.
+    pub fn finish(self) -> (u64, String) {
+        (
+            self.size,
+            format!("sha256:{}", self.hasher.digest_finalize()),
+        )
+    }
+}
+
+impl<T: Read> Read for DigestStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let sz = self.inner.read(buf)?;
+        self.hasher.digest_update(&buf[..sz]);
+        self.size += sz as u64;
+        Ok(sz)
+    }
+}
+
+impl<T: Write> Write for DigestStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let sz = self.inner.write(buf)?;
+        self.hasher.digest_update(&buf[..sz]);
+        self.size += sz as u64;
+        Ok(sz)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::node::tests::new_test_node;
+    use crate::BuildContext;
+    use std::fs;
+    use std::sync::Arc;
+    use vmm_sys_util::tempdir::TempDir;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_command_transform() {
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(b"hello nydus").unwrap();
+        let path = file.as_path();
+        let target = Path::new("/usr/share/hello");
+        let open = || fs::File::open(path).unwrap();
+
+        let transform = CommandTransform::new("tr a-z A-Z");
+        let mut output = Vec::new();
+        assert!(transform
+            .transform(&mut open(), path, target, &mut output)
+            .unwrap());
+        assert_eq!(output, b"HELLO NYDUS");
+
+        // Commands may ignore their input.
+        let transform = CommandTransform::new("printf %s \"$NYDUS_TRANSFORM_TARGET\"");
+        let mut output = Vec::new();
+        transform
+            .transform(&mut open(), path, target, &mut output)
+            .unwrap();
+        assert_eq!(output, b"/usr/share/hello");
+
+        let transform = CommandTransform::new("cat; exit 1");
+        let mut output = Vec::new();
+        assert!(transform
+            .transform(&mut open(), path, target, &mut output)
+            .is_err());
+
+        let mut input = DigestStream::new(open());
+        let mut output = DigestStream::new(Vec::new());
+        io::copy(&mut input, &mut output).unwrap();
+        let (size, digest) = input.finish();
+        assert_eq!(size, 11);
+        assert!(digest.starts_with("sha256:"));
+        assert_eq!(output.finish(), (size, digest));
+    }
+
+    #[test]
+    fn test_build_context_transform_file() {
+        let tmp_dir = TempDir::new().unwrap();
+        let source = tmp_dir.as_path().to_path_buf();
+        fs::write(source.join("a"), b"hello nydus").unwrap();
+        fs::hard_link(source.join("a"), source.join("b")).unwrap();
+        fs::write(source.join("c"), b"HELLO").unwrap();
+        let load = |name: &str| new_test_node(&source, name);
+
+        let mut ctx = BuildContext::default();
+        ctx.set_file_transform(Arc::new(CommandTransform::new("tr a-z A-Z; echo")));
+        let mut node = load("a");
+        ctx.transform_file(&mut node).unwrap();
+        assert_eq!(node.inode.size(), 12);
+        assert_eq!(node.inode.child_count(), 1);
+        let data = fs::read(ctx.get_transformed_data(&node).unwrap()).unwrap();
+        assert_eq!(data, b"HELLO NYDUS\n");
+
+        // Hardlinks share transformed content.
+        let mut node = load("b");
+        ctx.transform_file(&mut node).unwrap();
+        assert_eq!(node.inode.size(), 12);
+        assert!(ctx.get_transformed_data(&node).is_some());
+        assert_eq!(ctx.transformed_files.len(), 1);
+        assert_eq!(ctx.transformed_files[0].path, "/a");
+        assert_eq!(ctx.transformed_files[0].original_size, 11);
+
+        // Files with unchanged content are kept as is.
+        ctx.set_file_transform(Arc::new(CommandTransform::new("cat")));
+        let mut node = load("c");
+        ctx.transform_file(&mut node).unwrap();
+        assert_eq!(node.inode.size(), 5);
+        assert!(ctx.get_transformed_data(&node).is_none());
+        assert_eq!(ctx.transformed_files.len(), 1);
+    }
+}
@@ -174,7 +174,18 @@ impl Bootstrap {
 
         // Set super block
         let mut super_block = RafsV5SuperBlock::new();
-        let inodes_count = bootstrap_ctx.inode_map.len() as u64;
+        // Each directory has its own inode, while hardlinks share the same inode.
+        let inodes_count: u64 = bootstrap_ctx
+            .inode_map
+            .values()
+            .map(|nodes| {
+                if nodes[0].borrow().is_dir() {
+                    nodes.len() as u64
+                } else {
+                    1
+                }
+            })
+            .sum();
         super_block.set_inodes_count(inodes_count);
         super_block.set_inode_table_offset(super_block_size as u64);
         super_block.set_inode_table_entries(inode_table_entries);
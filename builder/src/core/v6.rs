@@ -12,11 +12,12 @@ use std::sync::Arc;
 
 use anyhow::{bail, ensure, Context, Result};
 use nydus_rafs::metadata::chunk::ChunkWrapper;
+use nydus_rafs::metadata::layout::v5::RafsV5ChunkInfo;
 use nydus_rafs::metadata::layout::v6::{
     align_offset, calculate_nid, new_v6_inode, RafsV6BlobTable, RafsV6Device, RafsV6Dirent,
-    RafsV6InodeChunkAddr, RafsV6InodeChunkHeader, RafsV6OndiskInode, RafsV6SuperBlock,
-    RafsV6SuperBlockExt, EROFS_BLOCK_BITS_9, EROFS_BLOCK_SIZE_4096, EROFS_BLOCK_SIZE_512,
-    EROFS_DEVTABLE_OFFSET, EROFS_INODE_CHUNK_BASED, EROFS_INODE_FLAT_INLINE,
+    RafsV6InodeChunkAddr, RafsV6InodeChunkHeader, RafsV6OndiskInode, RafsV6SharedXattrs,
+    RafsV6SuperBlock, RafsV6SuperBlockExt, EROFS_BLOCK_BITS_9, EROFS_BLOCK_SIZE_4096,
+    EROFS_BLOCK_SIZE_512, EROFS_DEVTABLE_OFFSET, EROFS_INODE_CHUNK_BASED, EROFS_INODE_FLAT_INLINE,
     EROFS_INODE_FLAT_PLAIN, EROFS_INODE_SLOT_SIZE, EROFS_SUPER_BLOCK_SIZE, EROFS_SUPER_OFFSET,
 };
 use nydus_rafs::metadata::RafsStore;
@@ -25,6 +26,7 @@ use nydus_storage::device::BlobFeatures;
 use nydus_utils::{root_tracer, round_down, round_up, timing_tracer};
 
 use super::chunk_dict::DigestWithBlobIndex;
+use super::context::BootstrapRegion;
 use super::node::Node;
 use crate::{Bootstrap, BootstrapContext, BuildContext, ConversionType, Tree};
 
@@ -42,10 +44,15 @@ impl Node {
         meta_addr: u64,
         chunk_cache: &mut BTreeMap<DigestWithBlobIndex, Arc<ChunkWrapper>>,
     ) -> Result<()> {
+        self.info
+            .xattrs
+            .validate_v6()
+            .with_context(|| format!("invalid xattrs of {}", self.path().display()))?;
         let xattr_inline_count = self.info.xattrs.count_v6();
         ensure!(
             xattr_inline_count <= u16::MAX as usize,
-            "size of extended attributes is too big"
+            "size of extended attributes of {} is too big",
+            self.path().display()
         );
         let mut inode = new_v6_inode(
             &self.inode,
@@ -324,8 +331,13 @@ impl Node {
         if !self.info.xattrs.is_empty() {
             self.info
                 .xattrs
-                .store_v6(f_bootstrap)
-                .context("failed to dump xattr to bootstrap")?;
+                .store_v6(f_bootstrap, &mut ctx.v6_shared_xattrs)
+                .with_context(|| {
+                    format!(
+                        "failed to dump xattr of {} to bootstrap",
+                        self.path().display()
+                    )
+                })?;
             ctx.has_xattr = true;
         }
         Ok(())
@@ -575,6 +587,8 @@ impl BuildContext {
 }
 
 impl Bootstrap {
+    /// Generate directory entries for directory `parent`, whose parent directory locates at
+    /// `parent_offset`.
     pub(crate) fn v6_update_dirents(parent: &Tree, parent_offset: u64) {
         let mut node = parent.borrow_mut_node();
         let node_offset = node.v6_offset;
@@ -594,7 +608,6 @@ impl Bootstrap {
                 .push((parent_offset, OsString::from(".."), libc::S_IFDIR.into()));
         }
 
-        let mut dirs: Vec<&Tree> = Vec::new();
         for child in parent.children.iter() {
             let child_node = child.borrow_mut_node();
             let entry = (
@@ -603,16 +616,58 @@ impl Bootstrap {
                 child_node.inode.mode(),
             );
             node.v6_dirents.push(entry);
-            if child_node.is_dir() {
-                dirs.push(child);
-            }
         }
         node.v6_dirents
             .sort_unstable_by(|a, b| a.1.as_os_str().cmp(b.1.as_os_str()));
+    }
 
-        for dir in dirs {
-            Self::v6_update_dirents(dir, node_offset);
+    /// Dump inodes of all descendants of `tree` to meta blob.
+    ///
+    /// Children of a directory are dumped together, then subdirectories are visited recursively
+    /// in depth-first order.
+    ///
+    /// Directory entries are generated just before dumping a directory and released once the
+    /// directory is dumped, instead of being kept for the whole filesystem. This doesn't make the
+    /// dump streaming: inode offsets are assigned to the whole node tree before dumping, so the
+    /// tree and the chunk cache still reside in memory and peak memory usage still grows with
+    /// the number of files and chunks.
+    fn v6_dump_children(
+        ctx: &mut BuildContext,
+        f_bootstrap: &mut dyn RafsIoWrite,
+        tree: &Tree,
+        tree_offset: u64,
+        orig_meta_addr: u64,
+        meta_addr: u64,
+        chunk_cache: &mut BTreeMap<DigestWithBlobIndex, Arc<ChunkWrapper>>,
+    ) -> Result<()> {
+        let mut dirs = Vec::with_capacity(32);
+        for child in tree.children.iter() {
+            // Offsets are relocated when dumping, so save the original one for `..` entries.
+            let (offset, is_dir) = {
+                let node = child.borrow_mut_node();
+                (node.v6_offset, node.is_dir())
+            };
+            if is_dir {
+                Self::v6_update_dirents(child, tree_offset);
+                dirs.push((child, offset));
+            }
+            let mut node = child.borrow_mut_node();
+            node.dump_bootstrap_v6(ctx, f_bootstrap, orig_meta_addr, meta_addr, chunk_cache)?;
+            node.v6_dirents = Vec::new();
         }
+        for (dir, offset) in dirs {
+            Self::v6_dump_children(
+                ctx,
+                f_bootstrap,
+                dir,
+                offset,
+                orig_meta_addr,
+                meta_addr,
+                chunk_cache,
+            )?;
+        }
+
+        Ok(())
     }
 
     /// Dump bootstrap and blob file, return (Vec<blob_id>, blob_size)
@@ -632,6 +687,9 @@ impl Bootstrap {
         // |   |block    |superblock+ |             |                 |         |                  |
         // |   |         |devslot     |             |                 |         |                  |
         // +---+---------+------------+-------------+----------------------------------------------+
+        //
+        // The optional shared xattr area locates between inodes and the chunk info table, and the
+        // optional blob prefetch range table and annotation region follow the chunk info table.
 
         let block_size = ctx.v6_block_size();
         let blobs = blob_table.get_all();
@@ -699,24 +757,51 @@ impl Bootstrap {
         // If HashChunkDict is used here, it will cause duplication. The chunks are removed,
         // resulting in incomplete chunk info.
         let mut chunk_cache = BTreeMap::new();
+        ctx.v6_shared_xattrs = RafsV6SharedXattrs::new();
 
-        // Dump bootstrap
+        // Dump bootstrap, the `..` entry of the root directory points to itself.
         timing_tracer!(
             {
-                self.tree.walk_bfs(true, &mut |n| {
-                    n.borrow_mut_node().dump_bootstrap_v6(
-                        ctx,
-                        bootstrap_ctx.writer.as_mut(),
-                        orig_meta_addr,
-                        meta_addr,
-                        &mut chunk_cache,
-                    )
-                })
+                Self::v6_update_dirents(&self.tree, root_node_offset);
+                let mut root = self.tree.borrow_mut_node();
+                root.dump_bootstrap_v6(
+                    ctx,
+                    bootstrap_ctx.writer.as_mut(),
+                    orig_meta_addr,
+                    meta_addr,
+                    &mut chunk_cache,
+                )?;
+                root.v6_dirents = Vec::new();
+                drop(root);
+                Self::v6_dump_children(
+                    ctx,
+                    bootstrap_ctx.writer.as_mut(),
+                    &self.tree,
+                    root_node_offset,
+                    orig_meta_addr,
+                    meta_addr,
+                    &mut chunk_cache,
+                )
             },
             "dump_bootstrap"
         )?;
         Self::v6_align_to_4k(bootstrap_ctx)?;
 
+        // Dump shared xattr area referenced by inodes.
+        let xattr_offset = if ctx.v6_shared_xattrs.is_empty() {
+            0
+        } else {
+            let offset = bootstrap_ctx
+                .writer
+                .seek_to_end()
+                .context("failed to seek to bootstrap's end for shared xattrs")?;
+            std::mem::take(&mut ctx.v6_shared_xattrs)
+                .store(bootstrap_ctx.writer.as_mut())
+                .context("failed to dump shared xattrs")?;
+            Self::v6_align_to_4k(bootstrap_ctx)?;
+            offset
+        };
+
         // `Node` offset might be updated during above inodes dumping. So `get_prefetch_table` after it.
         if prefetch_table_size > 0 {
             let prefetch_table = ctx.prefetch.get_v6_prefetch_table(meta_addr);
@@ -725,11 +810,15 @@ impl Bootstrap {
                 // Device slots are very close to extended super block.
                 ext_sb.set_prefetch_table_offset(prefetch_table_offset);
                 ext_sb.set_prefetch_table_size(prefetch_table_size);
-                bootstrap_ctx
-                    .writer
-                    .seek_offset(prefetch_table_offset as u64)
-                    .context("failed seek prefetch table offset")?;
-                pt.store(bootstrap_ctx.writer.as_mut()).unwrap();
+                let mut region = BootstrapRegion::new(
+                    bootstrap_ctx.writer.as_mut(),
+                    prefetch_table_offset,
+                    prefetch_table_size as usize,
+                    size_of::<u32>() as u64,
+                )?;
+                pt.store(&mut region)
+                    .context("failed to store prefetch table")?;
+                region.commit(bootstrap_ctx.writer.as_mut())?;
             }
         }
 
@@ -739,13 +828,19 @@ impl Bootstrap {
             .writer
             .seek_to_end()
             .context("failed to seek to bootstrap's end for chunk table")?;
-        let mut chunk_table_size: u64 = 0;
+        let mut region = BootstrapRegion::new(
+            bootstrap_ctx.writer.as_mut(),
+            chunk_table_offset,
+            chunk_cache.len() * size_of::<RafsV5ChunkInfo>(),
+            EROFS_BLOCK_SIZE_4096,
+        )?;
         for (_, chunk) in chunk_cache.iter() {
-            let chunk_size = chunk
-                .store(bootstrap_ctx.writer.as_mut())
+            chunk
+                .store(&mut region)
                 .context("failed to dump chunk table")?;
-            chunk_table_size += chunk_size as u64;
         }
+        let chunk_table_size = region.size() as u64;
+        region.commit(bootstrap_ctx.writer.as_mut())?;
         ext_sb.set_chunk_table(chunk_table_offset, chunk_table_size);
         debug!(
             "chunk_table offset {} size {}",
@@ -753,6 +848,50 @@ impl Bootstrap {
         );
         Self::v6_align_to_4k(bootstrap_ctx)?;
 
+        // Dump blob prefetch range table.
+        let range_table_size = blob_table.prefetch_range_table_size();
+        if range_table_size > 0 {
+            let range_table_offset = bootstrap_ctx
+                .writer
+                .seek_to_end()
+                .context("failed to seek to bootstrap's end for blob prefetch range table")?;
+            let mut region = BootstrapRegion::new(
+                bootstrap_ctx.writer.as_mut(),
+                range_table_offset,
+                range_table_size,
+                EROFS_BLOCK_SIZE_4096,
+            )?;
+            blob_table
+                .store_prefetch_ranges(&mut region)
+                .context("failed to dump blob prefetch range table")?;
+            region.commit(bootstrap_ctx.writer.as_mut())?;
+            let range_table_size =
+                u32::try_from(range_table_size).context("blob prefetch range table is too big")?;
+            ext_sb.set_blob_prefetch_table(range_table_offset, range_table_size);
+            Self::v6_align_to_4k(bootstrap_ctx)?;
+        }
+
+        // Dump annotations.
+        if !ctx.annotations.is_empty() {
+            let anno_size = ctx.annotations.size();
+            let anno_offset = bootstrap_ctx
+                .writer
+                .seek_to_end()
+                .context("failed to seek to bootstrap's end for annotations")?;
+            let mut region = BootstrapRegion::new(
+                bootstrap_ctx.writer.as_mut(),
+                anno_offset,
+                anno_size,
+                EROFS_BLOCK_SIZE_4096,
+            )?;
+            ctx.annotations
+                .store(&mut region)
+                .context("failed to dump annotations")?;
+            region.commit(bootstrap_ctx.writer.as_mut())?;
+            ext_sb.set_annotations(anno_offset, anno_size as u32);
+            Self::v6_align_to_4k(bootstrap_ctx)?;
+        }
+
         // Prepare device slots.
         let mut pos = bootstrap_ctx
             .writer
@@ -808,10 +947,16 @@ impl Bootstrap {
         sb.set_blocks(block_count);
         sb.set_root_nid(root_nid as u16);
         sb.set_meta_addr(meta_addr);
+        sb.set_xattr_addr(xattr_offset);
         sb.set_extra_devices(blob_table_entries as u16);
-        bootstrap_ctx.writer.seek(SeekFrom::Start(0))?;
-        sb.store(bootstrap_ctx.writer.as_mut())
-            .context("failed to store SB")?;
+        // Super block, extended super block and device slots share the region before blob table.
+        let mut region = BootstrapRegion::new(
+            bootstrap_ctx.writer.as_mut(),
+            0,
+            blob_table_offset as usize,
+            0,
+        )?;
+        sb.store(&mut region).context("failed to store SB")?;
 
         // Dump extended super block.
         if ctx.explicit_uidgid {
@@ -826,32 +971,34 @@ impl Bootstrap {
         if ctx.conversion_type == ConversionType::TarToTarfs {
             ext_sb.set_tarfs_mode();
         }
-        bootstrap_ctx
-            .writer
+        region
             .seek_offset((EROFS_SUPER_OFFSET + EROFS_SUPER_BLOCK_SIZE) as u64)
             .context("failed to seek for extended super block")?;
         ext_sb
-            .store(bootstrap_ctx.writer.as_mut())
+            .store(&mut region)
             .context("failed to store extended super block")?;
 
         // Dump device slots.
-        bootstrap_ctx
-            .writer
+        region
             .seek_offset(EROFS_DEVTABLE_OFFSET as u64)
             .context("failed to seek devtslot")?;
         for slot in devtable.iter() {
-            slot.store(bootstrap_ctx.writer.as_mut())
+            slot.store(&mut region)
                 .context("failed to store device slot")?;
         }
+        region.commit(bootstrap_ctx.writer.as_mut())?;
 
         // Dump blob table
-        bootstrap_ctx
-            .writer
-            .seek_offset(blob_table_offset as u64)
-            .context("failed seek for extended blob table offset")?;
+        let mut region = BootstrapRegion::new(
+            bootstrap_ctx.writer.as_mut(),
+            blob_table_offset,
+            blob_table_size as usize,
+            EROFS_BLOCK_SIZE_4096,
+        )?;
         blob_table
-            .store(bootstrap_ctx.writer.as_mut())
+            .store(&mut region)
             .context("failed to store extended blob table")?;
+        region.commit(bootstrap_ctx.writer.as_mut())?;
 
         Ok(())
     }
@@ -895,8 +1042,10 @@ impl Bootstrap {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{ArtifactStorage, BootstrapContext, Overlay};
+    use crate::core::node::tests::new_test_node;
+    use crate::{ArtifactStorage, BootstrapContext, InodeOrder, Overlay};
     use nydus_rafs::metadata::layout::v6::{EROFS_INODE_CHUNK_BASED, EROFS_INODE_SLOT_SIZE};
+    use nydus_rafs::metadata::layout::RafsBlobTable;
     use nydus_rafs::metadata::{RafsVersion, RAFS_DEFAULT_CHUNK_SIZE};
     use std::fs::File;
     use vmm_sys_util::{tempdir::TempDir, tempfile::TempFile};
@@ -1056,4 +1205,116 @@ mod tests {
 
         std::fs::remove_file(&pa_pyc).unwrap();
     }
+
+    #[test]
+    fn test_v6_dump_dirents_on_demand() {
+        let root_dir = TempDir::new().unwrap();
+        let sub_dir = root_dir.as_path().join("sub");
+        std::fs::create_dir(&sub_dir).unwrap();
+        std::fs::write(sub_dir.join("b"), b"").unwrap();
+        std::fs::create_dir(sub_dir.join("a")).unwrap();
+        let new_node = |name: &str| new_test_node(root_dir.as_path(), name);
+        let mut sub = Tree::new(new_node("sub"));
+        sub.insert_child(Tree::new(new_node("sub/b")));
+        sub.insert_child(Tree::new(new_node("sub/a")));
+        let mut tree = Tree::new(new_node(""));
+        tree.insert_child(sub);
+
+        let mut ctx = BuildContext {
+            fs_version: RafsVersion::V6,
+            ..Default::default()
+        };
+        let bootstrap_path = TempFile::new().unwrap();
+        let storage = ArtifactStorage::SingleFile(bootstrap_path.as_path().to_path_buf());
+        let mut bootstrap_ctx = BootstrapContext::new(Some(storage), false).unwrap();
+        let mut bootstrap = Bootstrap::new(tree).unwrap();
+        bootstrap.build(&mut ctx, &mut bootstrap_ctx).unwrap();
+        bootstrap
+            .tree
+            .walk_bfs(true, &mut |t| {
+                assert!(t.borrow_mut_node().v6_dirents.is_empty());
+                Ok(())
+            })
+            .unwrap();
+
+        let root_offset = bootstrap.tree.borrow_mut_node().v6_offset;
+        let sub = &bootstrap.tree.children[0];
+        let sub_offset = sub.borrow_mut_node().v6_offset;
+        Bootstrap::v6_update_dirents(sub, root_offset);
+        {
+            let node = sub.borrow_mut_node();
+            let names: Vec<&OsStr> = node.v6_dirents.iter().map(|d| d.1.as_os_str()).collect();
+            assert_eq!(names, vec![".", "..", "a", "b"]);
+            assert_eq!(node.v6_dirents[0].0, sub_offset);
+            assert_eq!(node.v6_dirents[1].0, root_offset);
+        }
+        sub.borrow_mut_node().v6_dirents.clear();
+
+        let blob_table = RafsBlobTable::V6(RafsV6BlobTable::new());
+        bootstrap
+            .dump(&mut ctx, &mut None, &mut bootstrap_ctx, &blob_table)
+            .unwrap();
+        bootstrap
+            .tree
+            .walk_bfs(true, &mut |t| {
+                assert!(t.borrow_mut_node().v6_dirents.is_empty());
+                Ok(())
+            })
+            .unwrap();
+        assert!(bootstrap_path.as_path().metadata().unwrap().len() > 0);
+    }
+
+    #[test]
+    fn test_v6_inode_order() {
+        let root_dir = TempDir::new().unwrap();
+        let root = root_dir.as_path();
+        std::fs::create_dir(root.join("a")).unwrap();
+        std::fs::write(root.join("a/x"), b"").unwrap();
+        std::fs::write(root.join("a-c"), b"").unwrap();
+        std::fs::write(root.join("b"), b"").unwrap();
+        let new_tree = |name: &str| Tree::new(new_test_node(root, name));
+
+        let layout = |order: InodeOrder| -> Vec<String> {
+            let mut dir = new_tree("a");
+            dir.insert_child(new_tree("a/x"));
+            let mut tree = new_tree("");
+            tree.insert_child(dir);
+            tree.insert_child(new_tree("a-c"));
+            tree.insert_child(new_tree("b"));
+
+            let mut ctx = BuildContext {
+                fs_version: RafsVersion::V6,
+                inode_order: order,
+                ..Default::default()
+            };
+            let mut bootstrap_ctx = BootstrapContext::new(None, false).unwrap();
+            let mut bootstrap = Bootstrap::new(tree).unwrap();
+            bootstrap.build(&mut ctx, &mut bootstrap_ctx).unwrap();
+
+            let mut nodes = Vec::new();
+            bootstrap
+                .tree
+                .walk_dfs_pre(&mut |t| {
+                    let node = t.borrow_mut_node();
+                    nodes.push((node.v6_offset, node.target().display().to_string()));
+                    Ok(())
+                })
+                .unwrap();
+            nodes.sort();
+            nodes.into_iter().map(|(_, path)| path).collect()
+        };
+
+        assert_eq!(
+            layout(InodeOrder::Bfs),
+            vec!["/", "/a-c", "/b", "/a", "/a/x"]
+        );
+        assert_eq!(
+            layout(InodeOrder::Dfs),
+            vec!["/", "/a", "/a/x", "/a-c", "/b"]
+        );
+        assert_eq!(
+            layout(InodeOrder::Name),
+            vec!["/", "/a", "/a-c", "/a/x", "/b"]
+        );
+    }
 }
@@ -2,10 +2,21 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::fs;
-use std::fs::DirEntry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::OsString;
+use std::fs::{self, DirEntry, File};
+use std::mem;
+use std::os::unix::fs::MetadataExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 
 use anyhow::{Context, Result};
+#[cfg(target_os = "linux")]
+use nix::sys::statvfs::fstatvfs;
+#[cfg(not(target_os = "linux"))]
+use nix::sys::statvfs::statvfs;
 use nydus_utils::{event_tracer, lazy_drop, root_tracer, timing_tracer};
 
 use crate::core::context::{Artifact, NoopArtifactWriter};
@@ -13,21 +24,32 @@ use crate::core::context::{Artifact, NoopArtifactWriter};
 use super::core::blob::Blob;
 use super::core::context::{
     ArtifactWriter, BlobManager, BootstrapContext, BootstrapManager, BuildContext, BuildOutput,
+    HardlinkKey,
 };
 use super::core::node::Node;
 use super::{build_bootstrap, dump_bootstrap, finalize_blob, Builder, Overlay, Tree, TreeNode};
 
-struct FilesystemTreeBuilder {}
+struct FilesystemTreeBuilder {
+    /// (dev, ino) of directories on the path being walked, to detect loops caused by symlinks.
+    ancestors: HashSet<(u64, u64)>,
+    /// Cache of filesystem ids indexed by device numbers.
+    fsids: HashMap<u64, u64>,
+    /// Device number of the source directory, if mount points shouldn't be crossed.
+    root_dev: Option<u64>,
+}
 
 impl FilesystemTreeBuilder {
-    fn new() -> Self {
-        Self {}
+    fn new(root_dev: Option<u64>) -> Self {
+        Self {
+            ancestors: HashSet::new(),
+            fsids: HashMap::new(),
+            root_dev,
+        }
     }
 
-    #[allow(clippy::only_used_in_recursion)]
     /// Walk directory to build node tree by DFS
     fn load_children(
-        &self,
+        &mut self,
         ctx: &mut BuildContext,
         bootstrap_ctx: &mut BootstrapContext,
         parent: &TreeNode,
@@ -38,6 +60,8 @@ impl FilesystemTreeBuilder {
         if !parent.is_dir() {
             return Ok(result);
         }
+        let parent_id = (parent.info.src_dev, parent.info.src_ino);
+        self.ancestors.insert(parent_id);
 
         let children = fs::read_dir(parent.path())
             .with_context(|| format!("failed to read dir {:?}", parent.path()))?;
@@ -46,17 +70,51 @@ impl FilesystemTreeBuilder {
         event_tracer!("load_from_directory", +children.len());
         for child in children {
             let path = child.path();
-            let mut child = Node::from_fs_object(
-                ctx.fs_version,
-                ctx.source_path.clone(),
-                path.clone(),
-                Overlay::UpperAddition,
-                ctx.chunk_size,
-                parent.info.explicit_uidgid,
-                true,
-            )
-            .with_context(|| format!("failed to create node {:?}", path))?;
+            let mut child = match Self::load_node(ctx, &path, parent.info.explicit_uidgid) {
+                Ok(node) => node,
+                Err(e) if ctx.skip_unreadable => {
+                    ctx.add_skipped_file(&path, &e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if !(child.is_reg() || child.is_dir() || child.is_symlink() || child.is_special()) {
+                let reason = format!(
+                    "unsupported file type 0o{:o}",
+                    child.inode.mode() & libc::S_IFMT as u32
+                );
+                ctx.add_unsupported_file(&path, &reason)?;
+                continue;
+            }
             child.layer_idx = layer_idx;
+            if ctx.follow_symlinks && child.is_symlink() {
+                self.follow_symlink(ctx, &mut child)?;
+            }
+            let mount_point = self.root_dev.is_some_and(|dev| dev != child.info.src_dev);
+            if mount_point {
+                // Keep mount point directories as empty directories, and skip other mount points,
+                // such as bind mounted files.
+                if !child.is_dir() {
+                    warn!("skip mount point {}", path.display());
+                    continue;
+                }
+                warn!("skip content of mount point {}", path.display());
+            }
+            // Directories can't be hardlinked, so only care about non-directories.
+            if !child.is_dir() {
+                match self.normalize_dev(ctx, &mut child) {
+                    Ok(()) => {}
+                    Err(e) if ctx.skip_unreadable => {
+                        ctx.add_skipped_file(&path, &e);
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            // Transform after normalizing device numbers, so hardlinks share transformed content.
+            if child.is_reg() && !mount_point {
+                ctx.transform_file(&mut child)?;
+            }
 
             // as per OCI spec, whiteout file should not be present within final image
             // or filesystem, only existed in layers.
@@ -68,7 +126,9 @@ impl FilesystemTreeBuilder {
             }
 
             let mut child = Tree::new(child);
-            child.children = self.load_children(ctx, bootstrap_ctx, &child.node, layer_idx)?;
+            if !mount_point {
+                child.children = self.load_children(ctx, bootstrap_ctx, &child.node, layer_idx)?;
+            }
             child
                 .borrow_mut_node()
                 .v5_set_dir_size(ctx.fs_version, &child.children);
@@ -76,9 +136,185 @@ impl FilesystemTreeBuilder {
         }
 
         result.sort_unstable_by(|a, b| a.name().cmp(b.name()));
+        self.ancestors.remove(&parent_id);
 
         Ok(result)
     }
+
+    /// Create a node for the source file at `path`.
+    ///
+    /// When unreadable files are to be skipped, also make sure content of the file can be read,
+    /// so the build won't fail later when dumping file data or walking the directory.
+    fn load_node(ctx: &BuildContext, path: &Path, explicit_uidgid: bool) -> Result<Node> {
+        let node = Node::from_fs_object(
+            ctx.fs_version,
+            ctx.source_path.clone(),
+            path.to_path_buf(),
+            Overlay::UpperAddition,
+            ctx.chunk_size,
+            explicit_uidgid,
+            true,
+        )
+        .with_context(|| format!("failed to create node {:?}", path))?;
+        if ctx.skip_unreadable {
+            if node.is_reg() {
+                File::open(path).with_context(|| format!("failed to open file {:?}", path))?;
+            } else if node.is_dir() {
+                fs::read_dir(path).with_context(|| format!("failed to read dir {:?}", path))?;
+            }
+        }
+
+        Ok(node)
+    }
+
+    /// Replace a symlink to directory with the directory it points to, unless it points to one of
+    /// its ancestor directories, which would make the walk never end.
+    ///
+    /// Symlinks are resolved as inside the image, with absolute targets relative to the source
+    /// root, so a symlink never pulls content of the build host into the image.
+    fn follow_symlink(&self, ctx: &BuildContext, node: &mut Node) -> Result<()> {
+        let resolved = match resolve_in_root(&node.info.source, node.path()) {
+            Some(p) => p,
+            None => {
+                warn!(
+                    "symlink {} points outside of the source directory, keep it as symlink",
+                    node.path().display()
+                );
+                return Ok(());
+            }
+        };
+        // Dangling symlinks and symlinks to non-directory are kept as is.
+        let meta = match fs::metadata(&resolved) {
+            Ok(meta) if meta.is_dir() => meta,
+            _ => return Ok(()),
+        };
+        // The directory is walked through the symlink itself, so it must resolve to the same
+        // directory on the build host, which is not the case for absolute targets.
+        match fs::metadata(node.path()) {
+            Ok(m) if m.dev() == meta.dev() && m.ino() == meta.ino() => {}
+            _ => {
+                warn!(
+                    "symlink {} resolves to a different directory on the build host, keep it as symlink",
+                    node.path().display()
+                );
+                return Ok(());
+            }
+        }
+        if self.ancestors.contains(&(meta.dev(), meta.ino())) {
+            warn!(
+                "symlink {} points to its ancestor directory, keep it as symlink",
+                node.path().display()
+            );
+            return Ok(());
+        }
+        node.follow_symlink_dir(ctx.fs_version, ctx.chunk_size)?;
+
+        Ok(())
+    }
+
+    /// Normalize device number of the node for hardlink detection, because bind mounts of the
+    /// same filesystem may present different device numbers for the same inode.
+    fn normalize_dev(&mut self, ctx: &BuildContext, node: &mut Node) -> Result<()> {
+        let dev = node.info.src_dev;
+        let dev = match ctx.hardlink_key {
+            HardlinkKey::Dev => match ctx.hardlink_dev_map.get(&dev) {
+                Some(v) => *v,
+                None => return Ok(()),
+            },
+            HardlinkKey::Fsid => match self.fsids.get(&dev) {
+                Some(v) => *v,
+                None => {
+                    let fsid = Self::get_fsid(node.path(), dev)?;
+                    self.fsids.insert(dev, fsid);
+                    fsid
+                }
+            },
+        };
+        Arc::make_mut(&mut node.info).src_dev = dev;
+
+        Ok(())
+    }
+
+    /// Get filesystem id of the filesystem object at `path`, without following symlinks.
+    ///
+    /// The object itself is queried because it may be a mount point on a different filesystem
+    /// than its parent directory, such as a bind mounted file. Some filesystems report a zero
+    /// filesystem id, fall back to the device number `dev` of the object for them, to avoid
+    /// grouping inodes from different filesystems together.
+    fn get_fsid(path: &Path, dev: u64) -> Result<u64> {
+        #[cfg(target_os = "linux")]
+        let stat = {
+            let file = File::options()
+                .read(true)
+                .custom_flags(libc::O_PATH | libc::O_NOFOLLOW)
+                .open(path)
+                .with_context(|| format!("failed to open {}", path.display()))?;
+            fstatvfs(&file)
+        };
+        #[cfg(not(target_os = "linux"))]
+        let stat = {
+            // Symlinks can't be mount points, query the parent directory instead of the target.
+            let meta = fs::symlink_metadata(path)
+                .with_context(|| format!("failed to stat {}", path.display()))?;
+            match path.parent() {
+                Some(parent) if meta.file_type().is_symlink() => statvfs(parent),
+                _ => statvfs(path),
+            }
+        };
+        let fsid = stat
+            .with_context(|| format!("failed to statvfs {}", path.display()))?
+            .filesystem_id() as u64;
+
+        Ok(if fsid == 0 { dev } else { fsid })
+    }
+}
+
+/// Maximum number of symlinks followed when resolving a path, as `MAXSYMLINKS` of Linux.
+const MAX_SYMLINK_HOPS: u32 = 40;
+
+/// Resolve `path` under the source root directory `root` as it would be resolved inside the
+/// image, that is absolute symlink targets are relative to `root`.
+///
+/// Return None if the path escapes `root`, contains a dangling symlink or too many symlinks.
+fn resolve_in_root(root: &Path, path: &Path) -> Option<PathBuf> {
+    let mut pending = path
+        .strip_prefix(root)
+        .ok()?
+        .components()
+        .map(|c| c.as_os_str().to_os_string())
+        .collect::<VecDeque<OsString>>();
+    let mut resolved = root.to_path_buf();
+    let mut hops = 0;
+
+    while let Some(name) = pending.pop_front() {
+        match Path::new(&name).components().next() {
+            Some(Component::RootDir) => resolved = root.to_path_buf(),
+            Some(Component::CurDir) | None => {}
+            Some(Component::ParentDir) => {
+                if resolved == root {
+                    return None;
+                }
+                resolved.pop();
+            }
+            _ => {
+                let candidate = resolved.join(&name);
+                if fs::symlink_metadata(&candidate).ok()?.is_symlink() {
+                    hops += 1;
+                    if hops > MAX_SYMLINK_HOPS {
+                        return None;
+                    }
+                    let target = fs::read_link(&candidate).ok()?;
+                    for c in target.components().rev() {
+                        pending.push_front(c.as_os_str().to_os_string());
+                    }
+                } else {
+                    resolved = candidate;
+                }
+            }
+        }
+    }
+
+    Some(resolved)
 }
 
 #[derive(Default)]
@@ -105,8 +341,9 @@ impl DirectoryBuilder {
             ctx.explicit_uidgid,
             true,
         )?;
+        let root_dev = ctx.one_file_system.then_some(node.info.src_dev);
         let mut tree = Tree::new(node);
-        let tree_builder = FilesystemTreeBuilder::new();
+        let mut tree_builder = FilesystemTreeBuilder::new(root_dev);
 
         tree.children = timing_tracer!(
             { tree_builder.load_children(ctx, bootstrap_ctx, &tree.node, layer_idx) },
@@ -129,7 +366,11 @@ impl Builder for DirectoryBuilder {
         let mut bootstrap_ctx = bootstrap_mgr.create_ctx()?;
         let layer_idx = u16::from(bootstrap_ctx.layered);
         let mut blob_writer: Box<dyn Artifact> = if let Some(blob_stor) = ctx.blob_storage.clone() {
-            Box::new(ArtifactWriter::new(blob_stor)?)
+            Box::new(ArtifactWriter::new_with_stage(
+                blob_stor,
+                ctx.tmp_dir.as_deref(),
+                ctx.artifact_stage.clone(),
+            )?)
         } else {
             Box::<NoopArtifactWriter>::default()
         };
@@ -192,6 +433,46 @@ impl Builder for DirectoryBuilder {
 
         lazy_drop(bootstrap_ctx);
 
-        BuildOutput::new(blob_mgr, &bootstrap_mgr.bootstrap_storage)
+        let mut output = BuildOutput::new(blob_mgr, &bootstrap_mgr.bootstrap_storage)?;
+        output.skipped_files = mem::take(ctx.skipped_files.get_mut().unwrap());
+        output.unsupported_files = mem::take(&mut ctx.unsupported_files);
+        output.privileged_files = mem::take(&mut ctx.privileged_files);
+        output.transformed_files = mem::take(&mut ctx.transformed_files);
+        ctx.transformed_data.clear();
+        ctx.transform_dir = None;
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_resolve_in_root() {
+        let tmp_dir = TempDir::new().unwrap();
+        let source = tmp_dir.as_path().join("source");
+        fs::create_dir_all(source.join("usr/lib")).unwrap();
+        symlink("/usr/lib", source.join("lib")).unwrap();
+        symlink("usr/../usr/lib", source.join("lib_rel")).unwrap();
+        symlink("/lib", source.join("lib_chain")).unwrap();
+        symlink("..", source.join("parent")).unwrap();
+        symlink("/../..", source.join("escape")).unwrap();
+        symlink("missing", source.join("dangling")).unwrap();
+        symlink("loop", source.join("loop")).unwrap();
+
+        let lib = source.join("usr/lib");
+        for name in ["lib", "lib_rel", "lib_chain", "usr/lib"] {
+            assert_eq!(
+                resolve_in_root(&source, &source.join(name)),
+                Some(lib.clone())
+            );
+        }
+        for name in ["parent", "escape", "dangling", "loop"] {
+            assert_eq!(resolve_in_root(&source, &source.join(name)), None);
+        }
+        assert_eq!(resolve_in_root(&source, tmp_dir.as_path()), None);
     }
 }
@@ -9,6 +9,7 @@ extern crate log;
 
 use crate::core::context::Artifact;
 use std::ffi::OsString;
+use std::mem::size_of;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 
@@ -21,28 +22,38 @@ use nydus_utils::digest::{DigestHasher, RafsDigest};
 use nydus_utils::{compress, digest, root_tracer, timing_tracer};
 use sha2::Digest;
 
+use self::core::blob::Blob;
 use self::core::node::{Node, NodeInfo};
 
+pub use self::blob_meta_generator::BlobMetaGenerator;
 pub use self::chunkdict_generator::ChunkdictBlobInfo;
 pub use self::chunkdict_generator::ChunkdictChunkInfo;
 pub use self::chunkdict_generator::Generator;
 pub use self::compact::BlobCompactor;
 pub use self::core::bootstrap::Bootstrap;
-pub use self::core::chunk_dict::{parse_chunk_dict_arg, ChunkDict, HashChunkDict};
+pub use self::core::chunk_dict::{parse_chunk_dict_arg, ChunkDict, ChunkDictSource, HashChunkDict};
 pub use self::core::context::{
-    ArtifactStorage, ArtifactWriter, BlobCacheGenerator, BlobContext, BlobManager,
-    BootstrapContext, BootstrapManager, BuildContext, BuildOutput, ConversionType,
+    ArtifactStage, ArtifactStorage, ArtifactWriter, BlobCacheGenerator, BlobContext, BlobManager,
+    BootstrapContext, BootstrapManager, BuildContext, BuildOutput, ChunkDedupStats, ConversionType,
+    HardlinkKey, InodeOrder, PrivilegedFile, PrivilegedFiles, SkippedFile, UnsupportedFilePolicy,
 };
 pub use self::core::feature::{Feature, Features};
 pub use self::core::node::{ChunkSource, NodeChunk};
-pub use self::core::overlay::{Overlay, WhiteoutSpec};
+pub use self::core::overlay::{
+    Overlay, WhiteoutSpec, OCISPEC_WHITEOUT_OPAQUE, OCISPEC_WHITEOUT_PREFIX,
+    OVERLAYFS_WHITEOUT_OPAQUE,
+};
 pub use self::core::prefetch::{Prefetch, PrefetchPolicy};
+pub use self::core::transform::{
+    CommandTransform, FileTransform, TransformedFile, TRANSFORM_ENV_SOURCE, TRANSFORM_ENV_TARGET,
+};
 pub use self::core::tree::{MetadataTreeBuilder, Tree, TreeNode};
 pub use self::directory::DirectoryBuilder;
-pub use self::merge::Merger;
+pub use self::merge::{Merger, PathFilter};
 pub use self::stargz::StargzBuilder;
 pub use self::tarball::TarballBuilder;
 
+mod blob_meta_generator;
 mod chunkdict_generator;
 mod compact;
 mod core;
@@ -192,6 +203,25 @@ fn finalize_blob(
         let is_tarfs = ctx.conversion_type == ConversionType::TarToTarfs;
 
         if !is_tarfs {
+            if ctx.blob_padding > 0 {
+                // The ToC must stay at the tail of the blob, so reserve space for it and the
+                // ToC entry describing the padding.
+                let trailer_size = if ctx.features.is_enabled(Feature::BlobToc) {
+                    blob_ctx.entry_list.as_bytes().len() as u64
+                        + size_of::<toc::TocEntry>() as u64
+                        + 512
+                } else {
+                    0
+                };
+                Blob::dump_padding(
+                    ctx,
+                    blob_ctx,
+                    blob_writer,
+                    ctx.blob_padding,
+                    trailer_size,
+                    toc::TOC_ENTRY_BLOB_PADDING,
+                )?;
+            }
             dump_toc(ctx, blob_ctx, blob_writer)?;
         }
         if !ctx.conversion_type.is_to_ref() {
@@ -5,20 +5,53 @@
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
-use std::path::PathBuf;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::{anyhow, bail, ensure, Context, Result};
 use hex::FromHex;
 use nydus_api::ConfigV2;
 use nydus_rafs::metadata::{RafsSuper, RafsVersion};
+use nydus_rafs::RafsIoReader;
 use nydus_storage::device::{BlobFeatures, BlobInfo};
 use nydus_utils::crypt;
 
 use super::{
     ArtifactStorage, BlobContext, BlobManager, Bootstrap, BootstrapContext, BuildContext,
-    BuildOutput, ChunkSource, ConversionType, Overlay, Tree,
+    BuildOutput, ChunkSource, ConversionType, Overlay, PrefetchPolicy, Tree,
 };
+use crate::core::overlay::WhiteoutType;
+
+/// Path filter to drop changes of upper source layers when merging them.
+///
+/// The lowest `keep_layers` source layers are kept as is, and changes of the upper layers under
+/// `excludes` are dropped, so files from kept layers survive there. For example, keep the base OS
+/// layer and drop modifications to `/etc` from application layers. Layers and their data blobs are
+/// still merged as usual, the filter only decides which files are taken from each layer.
+#[derive(Clone, Debug, Default)]
+pub struct PathFilter {
+    /// Number of lowest source layers to keep as is.
+    pub keep_layers: usize,
+    /// Absolute paths in which changes of filtered layers are dropped.
+    pub excludes: Vec<PathBuf>,
+}
+
+impl PathFilter {
+    fn is_filtered(&self, layer_idx: usize) -> bool {
+        layer_idx >= self.keep_layers && !self.excludes.is_empty()
+    }
+
+    fn is_excluded(&self, path: &Path) -> bool {
+        self.excludes.iter().any(|e| path.starts_with(e))
+    }
+
+    /// Check whether removing `path` also removes files in excluded paths.
+    fn is_ancestor_of_excluded(&self, path: &Path) -> bool {
+        self.excludes.iter().any(|e| e.starts_with(path))
+    }
+}
 
 /// Struct to generate the merged RAFS bootstrap for an image from per layer RAFS bootstraps.
 ///
@@ -65,11 +98,90 @@ impl Merger {
         })
     }
 
+    /// Get paths of files and directories in the prefetch table of a RAFS filesystem, `tree` is
+    /// the filesystem tree loaded from the same bootstrap.
+    fn get_prefetch_paths(
+        rs: &RafsSuper,
+        reader: &mut RafsIoReader,
+        tree: &Tree,
+        bootstrap_path: &Path,
+    ) -> Result<Vec<PathBuf>> {
+        let inos = rs
+            .get_prefetched_inos(reader)
+            .with_context(|| format!("failed to load prefetch table of {:?}", bootstrap_path))?;
+        if inos.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut ino_paths = HashMap::new();
+        tree.walk_bfs(true, &mut |n| {
+            let node = n.borrow_mut_node();
+            ino_paths
+                .entry(node.info.src_ino)
+                .or_insert_with(|| node.target().clone());
+            Ok(())
+        })?;
+
+        let mut paths = Vec::with_capacity(inos.len());
+        for ino in inos {
+            match ino_paths.get(&(ino as u64)) {
+                Some(path) => paths.push(path.clone()),
+                None => warn!(
+                    "prefetch table of {:?} references nonexistent inode {}, ignored",
+                    bootstrap_path, ino
+                ),
+            }
+        }
+
+        Ok(paths)
+    }
+
+    /// Drop nodes of a filtered layer under excluded paths, including whiteouts removing them or
+    /// their ancestor directories.
+    fn filter_layer(
+        ctx: &BuildContext,
+        tree: &mut Tree,
+        path: &Path,
+        filter: &PathFilter,
+    ) -> Result<()> {
+        tree.children.retain(|child| {
+            let node = child.borrow_mut_node();
+            let (name, removal) = match node.whiteout_type(ctx.whiteout_spec) {
+                Some(t @ WhiteoutType::OciRemoval) => (node.origin_name(t), true),
+                Some(WhiteoutType::OverlayFsRemoval) => {
+                    (Some(OsStr::from_bytes(child.name())), true)
+                }
+                _ => (Some(OsStr::from_bytes(child.name())), false),
+            };
+            match name {
+                Some(name) => {
+                    let target = path.join(name);
+                    !filter.is_excluded(&target)
+                        && !(removal && filter.is_ancestor_of_excluded(&target))
+                }
+                None => true,
+            }
+        });
+        for child in tree.children.iter_mut() {
+            let child_path = path.join(OsStr::from_bytes(child.name()));
+            if child.borrow_mut_node().is_dir() {
+                Self::filter_layer(ctx, child, &child_path, filter)?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Overlay multiple RAFS filesystems into a merged RAFS filesystem.
     ///
+    /// Prefetch tables of the parent bootstrap and source bootstraps are merged, with paths
+    /// removed by upper layers omitted.
+    ///
     /// # Arguments
     /// - sources: contains one or more per layer bootstraps in order of lower to higher.
-    /// - chunk_dict: contain the chunk dictionary used to build per layer boostrap, or None.
+    /// - chunk_dicts: contain the chunk dictionaries used to build per layer boostrap, may be empty.
+    /// - filter: path filter to drop changes of upper source layers, source layers are merged as
+    ///   is by default.
     #[allow(clippy::too_many_arguments)]
     pub fn merge(
         ctx: &mut BuildContext,
@@ -81,12 +193,22 @@ impl Merger {
         blob_toc_digests: Option<Vec<String>>,
         blob_toc_sizes: Option<Vec<u64>>,
         target: ArtifactStorage,
-        chunk_dict: Option<PathBuf>,
+        chunk_dicts: Vec<PathBuf>,
         config_v2: Arc<ConfigV2>,
+        filter: &PathFilter,
     ) -> Result<BuildOutput> {
         if sources.is_empty() {
             bail!("source bootstrap list is empty , at least one bootstrap is required");
         }
+        ensure!(
+            filter.keep_layers <= sources.len(),
+            "number of layers to keep {} exceeds number of sources {}",
+            filter.keep_layers,
+            sources.len(),
+        );
+        if let Some(path) = filter.excludes.iter().find(|p| !p.is_absolute()) {
+            bail!("excluded path {:?} isn't absolute", path);
+        }
         if let Some(digests) = blob_digests.as_ref() {
             ensure!(
                 digests.len() == sources.len(),
@@ -132,10 +254,11 @@ impl Merger {
         let mut blob_mgr = BlobManager::new(ctx.digester);
         let mut blob_idx_map = HashMap::new();
         let mut parent_layers = 0;
+        let mut prefetch_paths = Vec::new();
 
         // Load parent bootstrap
         if let Some(parent_bootstrap_path) = &parent_bootstrap_path {
-            let (rs, _) =
+            let (rs, mut reader) =
                 RafsSuper::load_from_file(parent_bootstrap_path, config_v2.clone(), false)
                     .context(format!("load parent bootstrap {:?}", parent_bootstrap_path))?;
             let blobs = rs.superblock.get_blob_infos();
@@ -145,16 +268,23 @@ impl Merger {
                 blob_mgr.add_blob(blob_ctx);
             }
             parent_layers = blobs.len();
-            tree = Some(Tree::from_bootstrap(&rs, &mut ())?);
+            let parent = Tree::from_bootstrap(&rs, &mut ())?;
+            prefetch_paths.extend(Self::get_prefetch_paths(
+                &rs,
+                &mut reader,
+                &parent,
+                Path::new(parent_bootstrap_path),
+            )?);
+            tree = Some(parent);
         }
 
         // Get the blobs come from chunk dictionary.
         let mut chunk_dict_blobs = HashSet::new();
         let mut config = None;
-        if let Some(chunk_dict_path) = &chunk_dict {
+        for chunk_dict_path in chunk_dicts.iter() {
             let (rs, _) = RafsSuper::load_from_file(chunk_dict_path, config_v2.clone(), false)
                 .context(format!("load chunk dict bootstrap {:?}", chunk_dict_path))?;
-            config = Some(rs.meta.get_config());
+            config.get_or_insert_with(|| rs.meta.get_config());
             for blob in rs.superblock.get_blob_infos() {
                 chunk_dict_blobs.insert(blob.blob_id().to_string());
             }
@@ -164,8 +294,9 @@ impl Merger {
         let mut chunk_size = None;
 
         for (layer_idx, bootstrap_path) in sources.iter().enumerate() {
-            let (rs, _) = RafsSuper::load_from_file(bootstrap_path, config_v2.clone(), false)
-                .context(format!("load bootstrap {:?}", bootstrap_path))?;
+            let (rs, mut reader) =
+                RafsSuper::load_from_file(bootstrap_path, config_v2.clone(), false)
+                    .context(format!("load bootstrap {:?}", bootstrap_path))?;
             config
                 .get_or_insert_with(|| rs.meta.get_config())
                 .check_compatibility(&rs.meta)?;
@@ -255,7 +386,7 @@ impl Merger {
                 }
             }
 
-            let upper = Tree::from_bootstrap(&rs, &mut ())?;
+            let mut upper = Tree::from_bootstrap(&rs, &mut ())?;
             upper.walk_bfs(true, &mut |n| {
                 let mut node = n.borrow_mut_node();
                 for chunk in &mut node.chunks {
@@ -280,6 +411,15 @@ impl Merger {
                 node.overlay = Overlay::UpperAddition;
                 Ok(())
             })?;
+            if filter.is_filtered(layer_idx) {
+                Self::filter_layer(ctx, &mut upper, Path::new("/"), filter)?;
+            }
+            prefetch_paths.extend(Self::get_prefetch_paths(
+                &rs,
+                &mut reader,
+                &upper,
+                bootstrap_path,
+            )?);
 
             if let Some(tree) = &mut tree {
                 tree.merge_overaly(ctx, upper)?;
@@ -304,7 +444,19 @@ impl Merger {
             ctx.chunk_size = chunk_size;
         }
 
-        let mut bootstrap_ctx = BootstrapContext::new(Some(target.clone()), false)?;
+        // Patterns for paths removed by upper layers just match nothing.
+        if !prefetch_paths.is_empty() {
+            if ctx.prefetch.policy == PrefetchPolicy::None {
+                ctx.prefetch.policy = PrefetchPolicy::Fs;
+            }
+            ctx.prefetch.add_patterns(prefetch_paths);
+        }
+
+        let mut bootstrap_ctx = BootstrapContext::new_with_stage(
+            Some(target.clone()),
+            false,
+            ctx.artifact_stage.clone(),
+        )?;
         let mut bootstrap = Bootstrap::new(tree)?;
         bootstrap.build(ctx, &mut bootstrap_ctx)?;
         let blob_table = blob_mgr.to_blob_table(ctx)?;
@@ -318,10 +470,14 @@ impl Merger {
 
 #[cfg(test)]
 mod tests {
+    use std::fs;
+
     use nydus_utils::digest;
+    use vmm_sys_util::tempdir::TempDir;
     use vmm_sys_util::tempfile::TempFile;
 
     use super::*;
+    use crate::core::node::tests::new_test_node;
 
     #[test]
     fn test_merger_get_string_from_list() {
@@ -404,12 +560,105 @@ mod tests {
             blob_toc_digests,
             Some(vec![64u64, 128]),
             target,
-            None,
+            Vec::new(),
             Arc::new(ConfigV2::new("config_v2")),
+            &PathFilter::default(),
         );
         assert!(build_output.is_ok());
         let build_output = build_output.unwrap();
         println!("BuildOutput: {}", build_output);
         assert_eq!(build_output.blob_size, Some(16));
     }
+
+    #[test]
+    fn test_merger_filter_paths() {
+        let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
+        let source = PathBuf::from(root_dir).join("../tests/texture/bootstrap/rafs-v6-2.2.boot");
+        let merge = |keep_layers: usize| {
+            let mut ctx = BuildContext::default();
+            ctx.configuration.internal.set_blob_accessible(true);
+            let tmp_file = TempFile::new().unwrap();
+            let target = ArtifactStorage::SingleFile(tmp_file.as_path().to_path_buf());
+            let filter = PathFilter {
+                keep_layers,
+                excludes: vec![PathBuf::from("/lib.rs")],
+            };
+            Merger::merge(
+                &mut ctx,
+                None,
+                vec![source.clone(), source.clone()],
+                None,
+                None,
+                None,
+                None,
+                None,
+                target,
+                Vec::new(),
+                ctx.configuration.clone(),
+                &filter,
+            )
+            .unwrap();
+            let (rs, _) =
+                RafsSuper::load_from_file(tmp_file.as_path(), ctx.configuration.clone(), false)
+                    .unwrap();
+            let tree = Tree::from_bootstrap(&rs, &mut ()).unwrap();
+            tree.get_node(Path::new("/lib.rs")).is_some()
+        };
+
+        // Files of the kept layer survive in excluded paths.
+        assert!(merge(1));
+        // Excluded paths are dropped from all layers if no layer is kept.
+        assert!(!merge(0));
+
+        let mut ctx = BuildContext::default();
+        let tmp_file = TempFile::new().unwrap();
+        let filter = PathFilter {
+            keep_layers: 2,
+            excludes: Vec::new(),
+        };
+        assert!(Merger::merge(
+            &mut ctx,
+            None,
+            vec![source],
+            None,
+            None,
+            None,
+            None,
+            None,
+            ArtifactStorage::SingleFile(tmp_file.as_path().to_path_buf()),
+            Vec::new(),
+            Arc::new(ConfigV2::default()),
+            &filter,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_merger_filter_layer_whiteouts() {
+        let tmp_dir = TempDir::new().unwrap();
+        let source = tmp_dir.as_path().to_path_buf();
+        fs::create_dir(source.join("etc")).unwrap();
+        for name in [".wh.etc", ".wh.usr", "etc/.wh.passwd", "etc/.wh.group"] {
+            fs::write(source.join(name), b"").unwrap();
+        }
+        let new_tree = |name: &str| Tree::new(new_test_node(&source, name));
+        let mut tree = new_tree("");
+        let mut etc = new_tree("etc");
+        etc.children = vec![new_tree("etc/.wh.group"), new_tree("etc/.wh.passwd")];
+        tree.children = vec![new_tree(".wh.etc"), new_tree(".wh.usr"), etc];
+
+        let filter = PathFilter {
+            keep_layers: 0,
+            excludes: vec![PathBuf::from("/etc/passwd")],
+        };
+        let ctx = BuildContext::default();
+        Merger::filter_layer(&ctx, &mut tree, Path::new("/"), &filter).unwrap();
+
+        // Whiteouts removing excluded paths or their ancestors are dropped.
+        let names = |tree: &Tree| -> Vec<Vec<u8>> {
+            tree.children.iter().map(|c| c.name().to_vec()).collect()
+        };
+        assert_eq!(names(&tree), vec![b".wh.usr".to_vec(), b"etc".to_vec()]);
+        assert_eq!(names(&tree.children[1]), vec![b".wh.group".to_vec()]);
+    }
 }
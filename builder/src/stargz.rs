@@ -2,14 +2,17 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-//! Generate a RAFS filesystem bootstrap from an stargz layer, reusing the stargz layer as data blob.
+//! Generate a RAFS filesystem bootstrap from an stargz or zstd:chunked layer, reusing the layer
+//! as data blob.
 
 use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
 use std::io::{Seek, SeekFrom};
+use std::mem;
 use std::ops::Deref;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
@@ -33,13 +36,22 @@ use crate::core::context::{Artifact, NoopArtifactWriter};
 
 use super::core::blob::Blob;
 use super::core::context::{
-    ArtifactWriter, BlobManager, BootstrapManager, BuildContext, BuildOutput,
+    ArtifactWriter, BlobManager, BootstrapManager, BuildContext, BuildOutput, ConversionType,
 };
 use super::core::node::{ChunkSource, Node, NodeChunk, NodeInfo};
 use super::{
     build_bootstrap, dump_bootstrap, finalize_blob, Bootstrap, Builder, TarBuilder, Tree, TreeNode,
 };
 
+/// Size of the zstd:chunked footer, stored in a zstd skippable frame at the end of the layer.
+const ZSTD_CHUNKED_FOOTER_SIZE: u64 = 64;
+/// Magic number at the end of the zstd:chunked footer.
+const ZSTD_CHUNKED_FRAME_MAGIC: &[u8; 8] = b"GNUlInUx";
+/// zstd:chunked manifest compatible with the CRFS/stargz TOC.
+const ZSTD_CHUNKED_MANIFEST_TYPE_CRFS: u64 = 1;
+/// Upper limit of the uncompressed zstd:chunked manifest size.
+const ZSTD_CHUNKED_MAX_MANIFEST_SIZE: u64 = 0x1000_0000;
+
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 struct TocEntry {
     /// This REQUIRED property contains the name of the tar entry.
@@ -175,6 +187,18 @@ struct TocEntry {
     /// from offset.
     #[serde(default, rename = "innerOffset")]
     pub inner_offset: u64,
+
+    /// This OPTIONAL property contains the end offset of the compressed data of the "reg" or
+    /// "chunk" entry in the blob, only used by zstd:chunked.
+    #[serde(default, rename = "endOffset")]
+    pub end_offset: u64,
+
+    /// This OPTIONAL property contains the type of the "reg" or "chunk" entry payload, only used
+    /// by zstd:chunked.
+    ///
+    /// Empty means the payload is stored in the blob, "zeros" means a hole without data.
+    #[serde(default, rename = "chunkType")]
+    pub chunk_type: String,
 }
 
 impl TocEntry {
@@ -306,11 +330,15 @@ impl TocEntry {
     }
 
     pub fn block_id(&self) -> Result<RafsDigest> {
-        if self.chunk_digest.len() != 71 || !self.chunk_digest.starts_with("sha256:") {
-            bail!("stargz: invalid chunk digest {}", self.chunk_digest);
+        Self::parse_digest(&self.chunk_digest)
+    }
+
+    fn parse_digest(digest: &str) -> Result<RafsDigest> {
+        if digest.len() != 71 || !digest.starts_with("sha256:") {
+            bail!("stargz: invalid chunk digest {}", digest);
         }
-        match hex::decode(&self.chunk_digest[7..]) {
-            Err(_e) => bail!("stargz: invalid chunk digest {}", self.chunk_digest),
+        match hex::decode(&digest[7..]) {
+            Err(_e) => bail!("stargz: invalid chunk digest {}", digest),
             Ok(v) => {
                 let mut data = DigestData::default();
                 data.copy_from_slice(&v[..32]);
@@ -320,6 +348,57 @@ impl TocEntry {
     }
 
     fn normalize(&mut self) -> Result<()> {
+        self.normalize_path()?;
+
+        if (self.is_reg() || self.is_chunk())
+            && (self.digest.is_empty() || self.chunk_digest.is_empty())
+        {
+            bail!("stargz: missing digest or chunk digest");
+        }
+
+        if self.is_chunk() && self.chunk_offset == 0 {
+            bail!("stargz: chunk offset is zero");
+        }
+
+        Ok(())
+    }
+
+    /// Normalize entries of zstd:chunked manifest, where digests are optional because they can
+    /// be calculated from the layer.
+    fn normalize_zstd_chunked(&mut self) -> Result<()> {
+        if let Ok(name) = self.name.strip_prefix("./") {
+            self.name = name.to_path_buf();
+        }
+        if self.is_hardlink() {
+            if let Ok(name) = self.link_name.strip_prefix("./") {
+                self.link_name = name.to_path_buf();
+            }
+        }
+        self.normalize_path()?;
+
+        if self.is_chunk() && self.chunk_offset == 0 {
+            bail!("zstd:chunked: chunk offset is zero");
+        }
+        if !self.chunk_type.is_empty() && self.chunk_type != "data" {
+            bail!(
+                "zstd:chunked: unsupported chunk type {} for {}",
+                self.chunk_type,
+                self.name.display()
+            );
+        }
+        if (self.is_reg() && self.size > 0 || self.is_chunk()) && self.end_offset <= self.offset {
+            bail!(
+                "zstd:chunked: invalid compressed data range 0x{:x}-0x{:x} for {}",
+                self.offset,
+                self.end_offset,
+                self.name.display()
+            );
+        }
+
+        Ok(())
+    }
+
+    fn normalize_path(&mut self) -> Result<()> {
         if self.name.is_empty() {
             bail!("stargz: invalid TocEntry with empty name");
         }
@@ -336,16 +415,6 @@ impl TocEntry {
             self.link_name = PathBuf::from("/").join(&self.link_name);
         }
 
-        if (self.is_reg() || self.is_chunk())
-            && (self.digest.is_empty() || self.chunk_digest.is_empty())
-        {
-            bail!("stargz: missing digest or chunk digest");
-        }
-
-        if self.is_chunk() && self.chunk_offset == 0 {
-            bail!("stargz: chunk offset is zero");
-        }
-
         Ok(())
     }
 }
@@ -386,15 +455,96 @@ impl TocIndex {
 
         Ok(toc_index)
     }
+
+    /// Load the manifest from a zstd:chunked layer.
+    ///
+    /// The layer ends with a skippable frame containing a 64-byte footer, which records
+    /// offset and sizes of the zstd compressed manifest followed by the zstd:chunked magic.
+    fn load_zstd_chunked(path: &Path) -> Result<TocIndex> {
+        let file = File::open(path)
+            .with_context(|| format!("zstd:chunked: failed to open layer file {:?}", path))?;
+        let file_size = file.metadata()?.len();
+        if file_size < ZSTD_CHUNKED_FOOTER_SIZE {
+            bail!("zstd:chunked: layer file {:?} is too small", path);
+        }
+        let mut footer = [0u8; ZSTD_CHUNKED_FOOTER_SIZE as usize];
+        file.read_exact_at(&mut footer, file_size - ZSTD_CHUNKED_FOOTER_SIZE)
+            .context("zstd:chunked: failed to read footer")?;
+        if &footer[56..64] != ZSTD_CHUNKED_FRAME_MAGIC {
+            bail!("zstd:chunked: invalid footer magic in {:?}", path);
+        }
+        let field = |idx: usize| {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&footer[idx * 8..idx * 8 + 8]);
+            u64::from_le_bytes(buf)
+        };
+        let (offset, compressed_size, uncompressed_size, manifest_type) =
+            (field(0), field(1), field(2), field(3));
+        if manifest_type != ZSTD_CHUNKED_MANIFEST_TYPE_CRFS {
+            bail!("zstd:chunked: unsupported manifest type {}", manifest_type);
+        }
+        if offset
+            .checked_add(compressed_size)
+            .map(|end| end > file_size - ZSTD_CHUNKED_FOOTER_SIZE)
+            .unwrap_or(true)
+            || uncompressed_size > ZSTD_CHUNKED_MAX_MANIFEST_SIZE
+        {
+            bail!(
+                "zstd:chunked: invalid manifest offset 0x{:x}/size 0x{:x}/0x{:x}",
+                offset,
+                compressed_size,
+                uncompressed_size
+            );
+        }
+
+        let mut compressed = vec![0u8; compressed_size as usize];
+        file.read_exact_at(&mut compressed, offset)
+            .context("zstd:chunked: failed to read manifest")?;
+        let mut manifest = vec![0u8; uncompressed_size as usize];
+        let size = compress::decompress(&compressed, &mut manifest, compress::Algorithm::Zstd)
+            .context("zstd:chunked: failed to decompress manifest")?;
+        if size != manifest.len() {
+            bail!("zstd:chunked: manifest size doesn't match");
+        }
+        let mut toc_index: TocIndex = serde_json::from_slice(&manifest).with_context(|| {
+            format!("zstd:chunked: failed to deserialize manifest of {:?}", path)
+        })?;
+        if toc_index.version != 1 {
+            bail!(
+                "zstd:chunked: unsupported manifest version {}",
+                toc_index.version
+            );
+        }
+
+        for entry in toc_index.entries.iter_mut() {
+            entry.normalize_zstd_chunked()?;
+        }
+
+        Ok(toc_index)
+    }
 }
 
-/// Build RAFS filesystems from eStargz images.
+/// Compressed data frames of a regular file in a zstd:chunked layer.
+struct ZstdChunkedFile {
+    path: PathBuf,
+    size: u64,
+    digest: String,
+    frames: Vec<TocEntry>,
+}
+
+/// Build RAFS filesystems from eStargz TOCs or zstd:chunked layers.
+///
+/// For zstd:chunked layers, each RAFS chunk references one or more consecutive zstd frames of a
+/// file in the original layer, so frame boundaries of files bigger than the chunk size must be
+/// aligned to the chunk size.
 pub struct StargzBuilder {
     blob_size: u64,
     builder: TarBuilder,
     file_chunk_map: HashMap<PathBuf, (u64, Vec<NodeChunk>)>,
     hardlink_map: HashMap<PathBuf, TreeNode>,
     uncompressed_offset: u64,
+    zstd_chunked: bool,
+    zstd_files: Vec<ZstdChunkedFile>,
 }
 
 impl StargzBuilder {
@@ -406,11 +556,17 @@ impl StargzBuilder {
             file_chunk_map: HashMap::new(),
             hardlink_map: HashMap::new(),
             uncompressed_offset: 0,
+            zstd_chunked: ctx.conversion_type == ConversionType::ZstdChunkedToRef,
+            zstd_files: Vec::new(),
         }
     }
 
     fn build_tree(&mut self, ctx: &mut BuildContext, layer_idx: u16) -> Result<Tree> {
-        let toc_index = TocIndex::load(&ctx.source_path, 0)?;
+        let toc_index = if self.zstd_chunked {
+            TocIndex::load_zstd_chunked(&ctx.source_path)?
+        } else {
+            TocIndex::load(&ctx.source_path, 0)?
+        };
         if toc_index.version != 1 {
             bail!("stargz: TOC version {} is unsupported", toc_index.version);
         } else if toc_index.entries.is_empty() {
@@ -428,72 +584,243 @@ impl StargzBuilder {
 
             // TODO: support chardev/blockdev/fifo
             if !entry.is_supported() {
-                warn!(
-                    "stargz: unsupported {} with type {}",
-                    path.display(),
-                    entry.toc_type
-                );
+                let reason = format!("stargz: unsupported entry type {}", entry.toc_type);
+                ctx.add_unsupported_file(path, &reason)?;
                 continue;
             } else if self.builder.is_stargz_special_files(path) {
                 // skip estargz special files.
                 continue;
             }
 
-            // Build RAFS chunk info from eStargz regular file or chunk data record.
-            let uncompress_size = Self::get_content_size(ctx, entry, &mut last_reg_entry)?;
-            if (entry.is_reg() || entry.is_chunk()) && uncompress_size != 0 {
-                let block_id = entry
-                    .block_id()
-                    .context("stargz: failed to get chunk digest")?;
-                // blob_index, index and compressed_size will be fixed later
+            if self.zstd_chunked {
+                if entry.is_reg() || entry.is_chunk() {
+                    self.add_zstd_frame(entry)?;
+                }
+            } else {
+                self.add_stargz_chunk(ctx, entry, &mut last_reg_entry)?;
+            }
+
+            if !entry.is_chunk() && !self.builder.is_stargz_special_files(path) {
+                self.parse_entry(&mut tree, entry, path)?;
+            }
+        }
+
+        if self.zstd_chunked {
+            self.build_zstd_chunks(ctx)?;
+        }
+        for (size, ref mut chunks) in self.file_chunk_map.values_mut() {
+            Self::sort_and_validate_chunks(chunks, *size)?;
+        }
+
+        Ok(tree)
+    }
+
+    /// Build RAFS chunk info from eStargz regular file or chunk data record.
+    fn add_stargz_chunk<'a>(
+        &mut self,
+        ctx: &mut BuildContext,
+        entry: &'a TocEntry,
+        last_reg_entry: &mut Option<&'a TocEntry>,
+    ) -> Result<()> {
+        let path = entry.path();
+        let uncompress_size = Self::get_content_size(ctx, entry, last_reg_entry)?;
+        if (entry.is_reg() || entry.is_chunk()) && uncompress_size != 0 {
+            let block_id = entry
+                .block_id()
+                .context("stargz: failed to get chunk digest")?;
+            // blob_index, index and compressed_size will be fixed later
+            let chunk_info = ChunkWrapper::V6(RafsV5ChunkInfo {
+                block_id,
+                blob_index: 0,
+                flags: BlobChunkFlags::COMPRESSED,
+                compressed_size: 0,
+                uncompressed_size: uncompress_size as u32,
+                compressed_offset: entry.offset as u64,
+                uncompressed_offset: self.uncompressed_offset,
+                file_offset: entry.chunk_offset as u64,
+                index: 0,
+                reserved: 0,
+            });
+            let chunk = NodeChunk {
+                source: ChunkSource::Build,
+                inner: Arc::new(chunk_info),
+            };
+
+            if let Some((size, chunks)) = self.file_chunk_map.get_mut(path) {
+                chunks.push(chunk);
+                if entry.is_reg() {
+                    *size = entry.size;
+                }
+            } else if entry.is_reg() {
+                self.file_chunk_map
+                    .insert(path.to_path_buf(), (entry.size, vec![chunk]));
+            } else {
+                bail!("stargz: file chunk lacks of corresponding head regular file entry");
+            }
+
+            let aligned_chunk_size = if ctx.aligned_chunk {
+                // Safe to unwrap because `chunk_size` is much less than u32::MAX.
+                try_round_up_4k(uncompress_size).unwrap()
+            } else {
+                uncompress_size
+            };
+            self.uncompressed_offset += aligned_chunk_size;
+        }
+
+        Ok(())
+    }
+
+    /// Record compressed data frame of zstd:chunked regular file or chunk entry.
+    fn add_zstd_frame(&mut self, entry: &TocEntry) -> Result<()> {
+        if entry.is_reg() {
+            self.zstd_files.push(ZstdChunkedFile {
+                path: entry.path().to_path_buf(),
+                size: entry.size,
+                digest: entry.digest.clone(),
+                frames: Vec::new(),
+            });
+            if entry.size == 0 {
+                return Ok(());
+            }
+        }
+
+        match self.zstd_files.last_mut() {
+            Some(file) if file.path == entry.path() && file.size > 0 => {
+                file.frames.push(entry.clone());
+                Ok(())
+            }
+            _ => bail!("zstd:chunked: file chunk lacks of corresponding head regular file entry"),
+        }
+    }
+
+    /// Merge consecutive zstd frames of regular files into RAFS chunks.
+    ///
+    /// A RAFS chunk covers `chunk_size` bytes of file content except for the last chunk, and
+    /// zstd decompresses concatenated frames as a whole, so consecutive frames are merged until
+    /// reaching the chunk size.
+    fn build_zstd_chunks(&mut self, ctx: &BuildContext) -> Result<()> {
+        let chunk_size = ctx.chunk_size as u64;
+        let mut source = None;
+
+        for file in std::mem::take(&mut self.zstd_files) {
+            let frames = &file.frames;
+            let mut chunks = Vec::new();
+            let mut idx = 0;
+            while idx < frames.len() {
+                let start = idx;
+                let file_offset = frames[idx].chunk_offset;
+                let mut pos = file_offset;
+                loop {
+                    let frame = &frames[idx];
+                    let next = frames
+                        .get(idx + 1)
+                        .map(|f| f.chunk_offset)
+                        .unwrap_or(file.size);
+                    if frame.chunk_offset != pos || next <= pos {
+                        bail!(
+                            "zstd:chunked: unexpected holes between data chunks of {}",
+                            file.path.display()
+                        );
+                    }
+                    if idx > start && frame.offset != frames[idx - 1].end_offset {
+                        bail!(
+                            "zstd:chunked: compressed data of {} is not continuous",
+                            file.path.display()
+                        );
+                    }
+                    pos = next;
+                    idx += 1;
+                    if pos - file_offset >= chunk_size || idx == frames.len() {
+                        break;
+                    }
+                }
+                if pos - file_offset > chunk_size {
+                    bail!(
+                        "zstd:chunked: data chunks of {} are not aligned to chunk size 0x{:x}, try a bigger chunk size",
+                        file.path.display(),
+                        chunk_size
+                    );
+                }
+
+                let uncompressed_size = pos - file_offset;
+                let compressed_offset = frames[start].offset;
+                let compressed_size = frames[idx - 1].end_offset - compressed_offset;
+                if compressed_size > RAFS_MAX_CHUNK_SIZE {
+                    bail!("zstd:chunked: compressed size is too big");
+                }
+                let block_id = if idx - start == 1 && !frames[start].chunk_digest.is_empty() {
+                    frames[start].block_id()?
+                } else if file_offset == 0 && pos == file.size && !file.digest.is_empty() {
+                    TocEntry::parse_digest(&file.digest)?
+                } else {
+                    if source.is_none() {
+                        source = Some(File::open(&ctx.source_path).with_context(|| {
+                            format!("zstd:chunked: failed to open {:?}", ctx.source_path)
+                        })?);
+                    }
+                    Self::compute_zstd_chunk_digest(
+                        source.as_ref().unwrap(),
+                        compressed_offset,
+                        compressed_size,
+                        uncompressed_size,
+                    )
+                    .with_context(|| {
+                        format!(
+                            "zstd:chunked: failed to compute chunk digest of {}",
+                            file.path.display()
+                        )
+                    })?
+                };
+
+                // blob_index and index will be fixed later
                 let chunk_info = ChunkWrapper::V6(RafsV5ChunkInfo {
                     block_id,
                     blob_index: 0,
                     flags: BlobChunkFlags::COMPRESSED,
-                    compressed_size: 0,
-                    uncompressed_size: uncompress_size as u32,
-                    compressed_offset: entry.offset as u64,
+                    compressed_size: compressed_size as u32,
+                    uncompressed_size: uncompressed_size as u32,
+                    compressed_offset,
                     uncompressed_offset: self.uncompressed_offset,
-                    file_offset: entry.chunk_offset as u64,
+                    file_offset,
                     index: 0,
                     reserved: 0,
                 });
-                let chunk = NodeChunk {
+                chunks.push(NodeChunk {
                     source: ChunkSource::Build,
                     inner: Arc::new(chunk_info),
-                };
-
-                if let Some((size, chunks)) = self.file_chunk_map.get_mut(path) {
-                    chunks.push(chunk);
-                    if entry.is_reg() {
-                        *size = entry.size;
-                    }
-                } else if entry.is_reg() {
-                    self.file_chunk_map
-                        .insert(path.to_path_buf(), (entry.size, vec![chunk]));
-                } else {
-                    bail!("stargz: file chunk lacks of corresponding head regular file entry");
-                }
+                });
 
-                let aligned_chunk_size = if ctx.aligned_chunk {
+                self.uncompressed_offset += if ctx.aligned_chunk {
                     // Safe to unwrap because `chunk_size` is much less than u32::MAX.
-                    try_round_up_4k(uncompress_size).unwrap()
+                    try_round_up_4k(uncompressed_size).unwrap()
                 } else {
-                    uncompress_size
+                    uncompressed_size
                 };
-                self.uncompressed_offset += aligned_chunk_size;
-            }
-
-            if !entry.is_chunk() && !self.builder.is_stargz_special_files(path) {
-                self.parse_entry(&mut tree, entry, path)?;
             }
+            self.file_chunk_map.insert(file.path, (file.size, chunks));
         }
 
-        for (size, ref mut chunks) in self.file_chunk_map.values_mut() {
-            Self::sort_and_validate_chunks(chunks, *size)?;
-        }
+        Ok(())
+    }
 
-        Ok(tree)
+    fn compute_zstd_chunk_digest(
+        file: &File,
+        offset: u64,
+        compressed_size: u64,
+        uncompressed_size: u64,
+    ) -> Result<RafsDigest> {
+        let mut compressed = vec![0u8; compressed_size as usize];
+        file.read_exact_at(&mut compressed, offset)?;
+        let mut data = vec![0u8; uncompressed_size as usize];
+        let size = compress::decompress(&compressed, &mut data, compress::Algorithm::Zstd)?;
+        if size != data.len() {
+            bail!(
+                "decompressed size 0x{:x} doesn't match chunk size 0x{:x}",
+                size,
+                data.len()
+            );
+        }
+        Ok(RafsDigest::from_buf(&data, digest::Algorithm::Sha256))
     }
 
     /// Get content size of a regular file or file chunk entry.
@@ -757,18 +1084,26 @@ impl StargzBuilder {
             };
             if curr >= next {
                 bail!("stargz: compressed offset is out of order");
-            } else if next - curr > RAFS_MAX_CHUNK_SIZE {
-                bail!("stargz: compressed size is too big");
             }
 
             let mut chunk = blob_chunks[idx].inner.deref().clone();
-            let uncomp_size = chunk.uncompressed_size() as usize;
-            let max_size = (next - curr) as usize;
-            let max_gzip_size = compute_compressed_gzip_size(uncomp_size, max_size);
+            if self.zstd_chunked {
+                // Compressed size has been recorded by the zstd:chunked manifest.
+                if curr + chunk.compressed_size() as u64 > next {
+                    bail!("zstd:chunked: compressed data of chunks overlaps");
+                }
+            } else {
+                if next - curr > RAFS_MAX_CHUNK_SIZE {
+                    bail!("stargz: compressed size is too big");
+                }
+                let uncomp_size = chunk.uncompressed_size() as usize;
+                let max_size = (next - curr) as usize;
+                let max_gzip_size = compute_compressed_gzip_size(uncomp_size, max_size);
+                chunk.set_compressed_size(max_gzip_size as u32);
+            }
             let chunk_index = blob_ctx.alloc_chunk_index()?;
             chunk.set_index(chunk_index);
             chunk.set_blob_index(blob_index);
-            chunk.set_compressed_size(max_gzip_size as u32);
             blob_ctx.add_chunk_meta_info(&chunk, None)?;
             compressed_blob_size = std::cmp::max(
                 compressed_blob_size,
@@ -833,13 +1168,30 @@ impl Builder for StargzBuilder {
                 "stargz: unsupported filesystem version {:?}",
                 ctx.fs_version
             );
-        } else if ctx.compressor != compress::Algorithm::GZip {
+        } else if self.zstd_chunked && ctx.compressor != compress::Algorithm::Zstd {
+            bail!(
+                "zstd:chunked: invalid compression algorithm {:?}",
+                ctx.compressor
+            );
+        } else if !self.zstd_chunked && ctx.compressor != compress::Algorithm::GZip {
             bail!("stargz: invalid compression algorithm {:?}", ctx.compressor);
         } else if ctx.digester != digest::Algorithm::Sha256 {
             bail!("stargz: invalid digest algorithm {:?}", ctx.digester);
         }
+        if self.zstd_chunked && ctx.blob_id.is_empty() {
+            // Use `sha256(layer)` as blob id, as the original layer is used as data blob.
+            let mut file = File::open(&ctx.source_path)
+                .with_context(|| format!("zstd:chunked: failed to open {:?}", ctx.source_path))?;
+            let digest = RafsDigest::from_reader(&mut file, digest::Algorithm::Sha256)
+                .context("zstd:chunked: failed to compute digest of layer")?;
+            ctx.blob_id = digest.to_string();
+        }
         let mut blob_writer: Box<dyn Artifact> = if let Some(blob_stor) = ctx.blob_storage.clone() {
-            Box::new(ArtifactWriter::new(blob_stor)?)
+            Box::new(ArtifactWriter::new_with_stage(
+                blob_stor,
+                ctx.tmp_dir.as_deref(),
+                ctx.artifact_stage.clone(),
+            )?)
         } else {
             Box::<NoopArtifactWriter>::default()
         };
@@ -904,7 +1256,10 @@ impl Builder for StargzBuilder {
 
         lazy_drop(bootstrap_ctx);
 
-        BuildOutput::new(blob_mgr, &bootstrap_mgr.bootstrap_storage)
+        let mut output = BuildOutput::new(blob_mgr, &bootstrap_mgr.bootstrap_storage)?;
+        output.unsupported_files = mem::take(&mut ctx.unsupported_files);
+        output.privileged_files = mem::take(&mut ctx.privileged_files);
+        Ok(output)
     }
 }
 
@@ -961,6 +1316,149 @@ mod tests {
         )
     }
 
+    /// Generate a zstd:chunked layer with regular files split into zstd frames of given sizes.
+    fn generate_zstd_chunked_layer(path: &Path, files: &[(&str, &[usize])]) {
+        let mut layer = Vec::new();
+        let mut entries = Vec::new();
+        for (name, frames) in files {
+            let size: usize = frames.iter().sum();
+            let data = (0..size).map(|v| (v % 251) as u8).collect::<Vec<_>>();
+            let digest = RafsDigest::from_buf(&data, digest::Algorithm::Sha256);
+            if frames.is_empty() {
+                entries.push(serde_json::json!({"type": "reg", "name": format!("./{}", name)}));
+            }
+            let mut chunk_offset = 0;
+            for (idx, frame_size) in frames.iter().enumerate() {
+                let frame = &data[chunk_offset..chunk_offset + frame_size];
+                let (compressed, _) = compress::compress(frame, compress::Algorithm::Zstd).unwrap();
+                let offset = layer.len();
+                layer.extend_from_slice(&compressed);
+                let mut entry = serde_json::json!({
+                    "type": if idx == 0 { "reg" } else { "chunk" },
+                    "name": format!("./{}", name),
+                    "offset": offset,
+                    "endOffset": layer.len(),
+                    "chunkOffset": chunk_offset,
+                    "chunkSize": frame_size,
+                });
+                if idx == 0 {
+                    entry["size"] = size.into();
+                    entry["digest"] = format!("sha256:{}", digest).into();
+                }
+                entries.push(entry);
+                chunk_offset += frame_size;
+            }
+        }
+
+        let manifest = serde_json::json!({"version": 1, "entries": entries}).to_string();
+        let (compressed, _) =
+            compress::compress(manifest.as_bytes(), compress::Algorithm::Zstd).unwrap();
+        layer.extend_from_slice(&[0x50, 0x2a, 0x4d, 0x18]);
+        layer.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        let manifest_offset = layer.len() as u64;
+        layer.extend_from_slice(&compressed);
+
+        layer.extend_from_slice(&[0x50, 0x2a, 0x4d, 0x18]);
+        layer.extend_from_slice(&(ZSTD_CHUNKED_FOOTER_SIZE as u32).to_le_bytes());
+        for v in [
+            manifest_offset,
+            compressed.len() as u64,
+            manifest.len() as u64,
+            ZSTD_CHUNKED_MANIFEST_TYPE_CRFS,
+            0,
+            0,
+            0,
+        ] {
+            layer.extend_from_slice(&v.to_le_bytes());
+        }
+        layer.extend_from_slice(ZSTD_CHUNKED_FRAME_MAGIC);
+        std::fs::write(path, layer).unwrap();
+    }
+
+    fn build_zstd_chunked(
+        source_path: PathBuf,
+        tmp_dir: &Path,
+    ) -> (Result<BuildOutput>, StargzBuilder) {
+        let blob_size = std::fs::metadata(&source_path).unwrap().len();
+        let mut ctx = BuildContext::new(
+            "".to_string(),
+            false,
+            0,
+            compress::Algorithm::Zstd,
+            digest::Algorithm::Sha256,
+            true,
+            WhiteoutSpec::Oci,
+            ConversionType::ZstdChunkedToRef,
+            source_path,
+            Prefetch::default(),
+            Some(ArtifactStorage::FileDir(tmp_dir.to_path_buf())),
+            false,
+            Features::new(),
+            false,
+        );
+        ctx.fs_version = RafsVersion::V6;
+        ctx.chunk_size = 0x1000;
+        let mut bootstrap_mgr =
+            BootstrapManager::new(Some(ArtifactStorage::FileDir(tmp_dir.to_path_buf())), None);
+        let mut blob_mgr = BlobManager::new(digest::Algorithm::Sha256);
+        let mut builder = StargzBuilder::new(blob_size, &ctx);
+        let output = builder.build(&mut ctx, &mut bootstrap_mgr, &mut blob_mgr);
+        (output, builder)
+    }
+
+    #[test]
+    fn test_build_zstd_chunked() {
+        let tmp_dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let source_path = tmp_dir.as_path().join("layer");
+        generate_zstd_chunked_layer(
+            &source_path,
+            &[
+                ("a", &[0x800]),
+                ("b", &[0x1000, 0x800]),
+                ("c", &[0x800, 0x800]),
+                ("empty", &[]),
+            ],
+        );
+        let layer_digest = RafsDigest::from_buf(
+            &std::fs::read(&source_path).unwrap(),
+            digest::Algorithm::Sha256,
+        );
+
+        let (output, builder) = build_zstd_chunked(source_path, tmp_dir.as_path());
+        let output = output.unwrap();
+        assert_eq!(output.blobs, vec![layer_digest.to_string()]);
+
+        let chunks = &builder.file_chunk_map[Path::new("/a")].1;
+        assert_eq!(chunks.len(), 1);
+        let chunks = &builder.file_chunk_map[Path::new("/b")].1;
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1].inner.file_offset(), 0x1000);
+        assert_eq!(chunks[1].inner.uncompressed_size(), 0x800);
+        // Two frames of file `c` are merged into one chunk.
+        let chunks = &builder.file_chunk_map[Path::new("/c")].1;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].inner.uncompressed_size(), 0x1000);
+        let data = (0..0x1000).map(|v| (v % 251) as u8).collect::<Vec<_>>();
+        assert_eq!(
+            chunks[0].inner.id(),
+            &RafsDigest::from_buf(&data, digest::Algorithm::Sha256)
+        );
+        assert!(builder
+            .file_chunk_map
+            .get(Path::new("/empty"))
+            .map(|(_, chunks)| chunks.is_empty())
+            .unwrap_or(true));
+    }
+
+    #[test]
+    fn test_build_zstd_chunked_unaligned() {
+        let tmp_dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let source_path = tmp_dir.as_path().join("layer");
+        generate_zstd_chunked_layer(&source_path, &[("a", &[0x800, 0x1000])]);
+        let (output, _) = build_zstd_chunked(source_path, tmp_dir.as_path());
+        assert!(output.is_err());
+    }
+
     #[test]
     fn test_toc_entry() {
         let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
@@ -985,6 +1483,8 @@ mod tests {
             chunk_size: 0,
             chunk_digest: "sha256:".to_owned(),
             inner_offset: 0,
+            end_offset: 0,
+            chunk_type: String::new(),
         };
         entry.chunk_digest.extend(vec!['a'; 64].iter());
 
@@ -18,6 +18,7 @@
 use std::ffi::{OsStr, OsString};
 use std::fs::{File, OpenOptions};
 use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::mem;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
@@ -37,6 +38,7 @@ use nydus_utils::compact::makedev;
 use nydus_utils::compress::zlib_random::{ZranReader, ZRAN_READER_BUF_SIZE};
 use nydus_utils::compress::ZlibDecoder;
 use nydus_utils::digest::RafsDigest;
+use nydus_utils::trace::trace_dir_timing;
 use nydus_utils::{div_round_up, lazy_drop, root_tracer, timing_tracer, BufReaderInfo, ByteSize};
 
 use crate::core::context::{Artifact, NoopArtifactWriter};
@@ -126,10 +128,10 @@ impl<'a> TarballTreeBuilder<'a> {
         }
     }
 
-    fn build_tree(&mut self) -> Result<Tree> {
+    fn build_tree(&mut self, source_path: &Path) -> Result<Tree> {
         let file = OpenOptions::new()
             .read(true)
-            .open(self.ctx.source_path.clone())
+            .open(source_path)
             .context("tarball: can not open source file for conversion")?;
         let mut is_file = match file.metadata() {
             Ok(md) => md.file_type().is_file(),
@@ -156,7 +158,8 @@ impl<'a> TarballTreeBuilder<'a> {
             },
             ConversionType::EStargzToRafs
             | ConversionType::TargzToRafs
-            | ConversionType::TarToRafs => match Self::detect_compression_algo(file)? {
+            | ConversionType::TarToRafs
+            | ConversionType::OciRefToRafs => match Self::detect_compression_algo(file)? {
                 (CompressionType::Gzip, buf_reader) => {
                     if is_file {
                         let mut file = buf_reader.into_inner();
@@ -247,26 +250,8 @@ impl<'a> TarballTreeBuilder<'a> {
     ) -> Result<()> {
         let header = entry.header();
         let entry_type = header.entry_type();
-        if entry_type.is_gnu_longname() {
-            return Err(anyhow!("tarball: unsupported gnu_longname from tar header"));
-        } else if entry_type.is_gnu_longlink() {
-            return Err(anyhow!("tarball: unsupported gnu_longlink from tar header"));
-        } else if entry_type.is_pax_local_extensions() {
-            return Err(anyhow!(
-                "tarball: unsupported pax_local_extensions from tar header"
-            ));
-        } else if entry_type.is_pax_global_extensions() {
-            return Err(anyhow!(
-                "tarball: unsupported pax_global_extensions from tar header"
-            ));
-        } else if entry_type.is_contiguous() {
-            return Err(anyhow!(
-                "tarball: unsupported contiguous entry type from tar header"
-            ));
-        } else if entry_type.is_gnu_sparse() {
-            return Err(anyhow!(
-                "tarball: unsupported gnu sparse file extension from tar header"
-            ));
+        if let Some(reason) = Self::unsupported_entry_type(entry_type) {
+            return self.ctx.add_unsupported_file(path, reason);
         }
 
         let mut file_size = entry.size();
@@ -461,13 +446,16 @@ impl<'a> TarballTreeBuilder<'a> {
             node.chunks = n.chunks.clone();
             node.set_xattr(n.info.xattrs.clone());
         } else {
-            node.dump_node_data_with_reader(
-                self.ctx,
-                self.blob_mgr,
-                self.blob_writer,
-                Some(entry),
-                &mut self.buf,
-            )?;
+            let target = node.target().clone();
+            trace_dir_timing("dump_blob", &target, timing_tracer!(), || {
+                node.dump_node_data_with_reader(
+                    self.ctx,
+                    self.blob_mgr,
+                    self.blob_writer,
+                    Some(entry),
+                    &mut self.buf,
+                )
+            })?;
         }
 
         // Update inode.i_blocks for RAFS v5.
@@ -500,6 +488,31 @@ impl<'a> TarballTreeBuilder<'a> {
         Ok((uid as u32, gid as u32))
     }
 
+    fn unsupported_entry_type(entry_type: EntryType) -> Option<&'static str> {
+        match entry_type {
+            EntryType::Regular
+            | EntryType::Link
+            | EntryType::Directory
+            | EntryType::Symlink
+            | EntryType::Block
+            | EntryType::Char
+            | EntryType::Fifo => None,
+            EntryType::GNULongName => Some("tarball: unsupported gnu_longname from tar header"),
+            EntryType::GNULongLink => Some("tarball: unsupported gnu_longlink from tar header"),
+            EntryType::XHeader => Some("tarball: unsupported pax_local_extensions from tar header"),
+            EntryType::XGlobalHeader => {
+                Some("tarball: unsupported pax_global_extensions from tar header")
+            }
+            EntryType::Continuous => {
+                Some("tarball: unsupported contiguous entry type from tar header")
+            }
+            EntryType::GNUSparse => {
+                Some("tarball: unsupported gnu sparse file extension from tar header")
+            }
+            _ => Some("tarball: unsupported tar entry type"),
+        }
+    }
+
     fn get_mode(header: &Header) -> Result<u32> {
         let mode = header
             .mode()
@@ -562,6 +575,7 @@ impl<'a> TarballTreeBuilder<'a> {
 /// Builder to create RAFS filesystems from tarballs.
 pub struct TarballBuilder {
     ty: ConversionType,
+    layers: Vec<PathBuf>,
 }
 
 impl TarballBuilder {
@@ -569,7 +583,51 @@ impl TarballBuilder {
     pub fn new(conversion_type: ConversionType) -> Self {
         Self {
             ty: conversion_type,
+            layers: Vec::new(),
+        }
+    }
+
+    /// Create a new instance of [TarballBuilder] to build a RAFS filesystem from a stack of
+    /// image layer tarballs, ordered from the lowest layer to the uppermost layer.
+    pub fn new_with_layers(conversion_type: ConversionType, layers: Vec<PathBuf>) -> Self {
+        Self {
+            ty: conversion_type,
+            layers,
+        }
+    }
+
+    /// Build a tree for each layer tarball into the same data blob, and merge them into one
+    /// tree with overlay whiteout rules applied.
+    fn build_layered_tree(
+        &self,
+        ctx: &mut BuildContext,
+        blob_mgr: &mut BlobManager,
+        blob_writer: &mut dyn Artifact,
+        layer_idx: u16,
+    ) -> Result<Tree> {
+        let mut merged: Option<Tree> = None;
+        for (idx, layer) in self.layers.iter().enumerate() {
+            let idx = u16::try_from(idx)
+                .ok()
+                .and_then(|v| v.checked_add(layer_idx))
+                .ok_or_else(|| anyhow!("tarball: too many image layers"))?;
+            let tree = {
+                let mut tree_builder =
+                    TarballTreeBuilder::new(self.ty, ctx, blob_mgr, blob_writer, idx);
+                tree_builder.build_tree(layer).with_context(|| {
+                    format!(
+                        "tarball: failed to build tree from layer {}",
+                        layer.display()
+                    )
+                })?
+            };
+            match merged.as_mut() {
+                None => merged = Some(tree),
+                Some(lower) => lower.merge_overaly(ctx, tree)?,
+            }
         }
+
+        merged.ok_or_else(|| anyhow!("tarball: no image layer to build from"))
     }
 }
 
@@ -588,9 +646,14 @@ impl Builder for TarballBuilder {
             | ConversionType::TargzToRafs
             | ConversionType::TargzToRef
             | ConversionType::TarToRafs
-            | ConversionType::TarToTarfs => {
+            | ConversionType::TarToTarfs
+            | ConversionType::OciRefToRafs => {
                 if let Some(blob_stor) = ctx.blob_storage.clone() {
-                    Box::new(ArtifactWriter::new(blob_stor)?)
+                    Box::new(ArtifactWriter::new_with_stage(
+                        blob_stor,
+                        ctx.tmp_dir.as_deref(),
+                        ctx.artifact_stage.clone(),
+                    )?)
                 } else {
                     Box::<NoopArtifactWriter>::default()
                 }
@@ -603,9 +666,17 @@ impl Builder for TarballBuilder {
             }
         };
 
-        let mut tree_builder =
-            TarballTreeBuilder::new(self.ty, ctx, blob_mgr, blob_writer.as_mut(), layer_idx);
-        let tree = timing_tracer!({ tree_builder.build_tree() }, "build_tree")?;
+        let tree = if self.ty == ConversionType::OciRefToRafs {
+            timing_tracer!(
+                { self.build_layered_tree(ctx, blob_mgr, blob_writer.as_mut(), layer_idx) },
+                "build_tree"
+            )?
+        } else {
+            let source_path = ctx.source_path.clone();
+            let mut tree_builder =
+                TarballTreeBuilder::new(self.ty, ctx, blob_mgr, blob_writer.as_mut(), layer_idx);
+            timing_tracer!({ tree_builder.build_tree(&source_path) }, "build_tree")?
+        };
 
         // Build bootstrap
         let mut bootstrap = timing_tracer!(
@@ -659,7 +730,10 @@ impl Builder for TarballBuilder {
 
         lazy_drop(bootstrap_ctx);
 
-        BuildOutput::new(blob_mgr, &bootstrap_mgr.bootstrap_storage)
+        let mut output = BuildOutput::new(blob_mgr, &bootstrap_mgr.bootstrap_storage)?;
+        output.unsupported_files = mem::take(&mut ctx.unsupported_files);
+        output.privileged_files = mem::take(&mut ctx.privileged_files);
+        Ok(output)
     }
 }
 
@@ -732,4 +806,62 @@ mod tests {
             .build(&mut ctx, &mut bootstrap_mgr, &mut blob_mgr)
             .unwrap();
     }
+
+    fn create_layer(path: &Path, files: &[&str]) {
+        let mut builder = tar::Builder::new(File::create(path).unwrap());
+        for name in files {
+            let mut header = Header::new_gnu();
+            header.set_size(4);
+            header.set_mode(0o644);
+            header.set_entry_type(EntryType::Regular);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, name, "test".as_bytes())
+                .unwrap();
+        }
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn test_build_layered_tree() {
+        let tmp_dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let lower = tmp_dir.as_path().join("lower.tar");
+        let upper = tmp_dir.as_path().join("upper.tar");
+        create_layer(&lower, &["a", "b"]);
+        create_layer(&upper, &[".wh.a", "c"]);
+
+        let mut ctx = BuildContext::new(
+            "test".to_string(),
+            true,
+            0,
+            compress::Algorithm::None,
+            digest::Algorithm::Sha256,
+            true,
+            WhiteoutSpec::Oci,
+            ConversionType::OciRefToRafs,
+            PathBuf::new(),
+            Prefetch::default(),
+            None,
+            false,
+            Features::new(),
+            false,
+        );
+        ctx.set_fs_version(RafsVersion::V6);
+        let mut blob_mgr = BlobManager::new(digest::Algorithm::Sha256);
+        let mut blob_writer = NoopArtifactWriter::default();
+        let builder =
+            TarballBuilder::new_with_layers(ConversionType::OciRefToRafs, vec![lower, upper]);
+        let tree = builder
+            .build_layered_tree(&mut ctx, &mut blob_mgr, &mut blob_writer, 0)
+            .unwrap();
+        assert!(tree.get_child_idx(b"a").is_none());
+        assert!(tree.get_child_idx(b".wh.a").is_none());
+        assert!(tree.get_child_idx(b"b").is_some());
+        assert!(tree.get_child_idx(b"c").is_some());
+
+        let builder = TarballBuilder::new_with_layers(ConversionType::OciRefToRafs, vec![]);
+        assert!(builder
+            .build_layered_tree(&mut ctx, &mut blob_mgr, &mut blob_writer, 0)
+            .is_err());
+    }
 }
@@ -363,14 +363,19 @@ impl Rafs {
             let mut prefetches = Vec::new();
 
             for blob in &blob_infos {
-                let sz = blob.prefetch_size();
-                if sz > 0 {
+                // Prefer exact ranges of prefetched files over the legacy prefix of the blob.
+                let ranges = if blob.prefetch_ranges().is_empty() {
+                    vec![(0, blob.prefetch_size())]
+                } else {
+                    blob.prefetch_ranges().to_vec()
+                };
+                for (start, sz) in ranges {
                     let mut offset = 0;
                     while offset < sz {
                         let len = cmp::min(sz - offset, RAFS_DEFAULT_CHUNK_SIZE);
                         prefetches.push(BlobPrefetchRequest {
                             blob_id: blob.blob_id().to_owned(),
-                            offset,
+                            offset: start + offset,
                             len,
                         });
                         offset += len;
@@ -165,6 +165,14 @@ pub trait RafsIoWrite: Write + Seek + 'static {
     fn as_bytes(&mut self) -> std::io::Result<Cow<[u8]>> {
         unimplemented!()
     }
+
+    /// Get the regular file holding written data, with written data extended to `len` bytes,
+    /// so ranges of the file may be memory mapped and populated in place.
+    ///
+    /// Return None if written data isn't held in a regular file.
+    fn backing_file(&mut self, _len: u64) -> std::io::Result<Option<File>> {
+        Ok(None)
+    }
 }
 
 impl RafsIoWrite for File {
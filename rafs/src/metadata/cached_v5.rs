@@ -23,6 +23,7 @@ use fuse_backend_rs::abi::fuse_abi;
 use fuse_backend_rs::api::filesystem::Entry;
 use nydus_storage::device::v5::BlobV5ChunkInfo;
 use nydus_storage::device::{BlobChunkFlags, BlobChunkInfo, BlobDevice, BlobInfo};
+use nydus_utils::compress;
 use nydus_utils::digest::RafsDigest;
 use nydus_utils::ByteSize;
 
@@ -761,6 +762,10 @@ impl BlobChunkInfo for CachedChunkInfoV5 {
         false
     }
 
+    fn compressor(&self) -> Option<compress::Algorithm> {
+        self.flags.compressor()
+    }
+
     fn as_any(&self) -> &dyn Any {
         self
     }
@@ -11,6 +11,7 @@ use anyhow::{Context, Result};
 use nydus_storage::device::v5::BlobV5ChunkInfo;
 use nydus_storage::device::{BlobChunkFlags, BlobChunkInfo};
 use nydus_storage::meta::BlobMetaChunk;
+use nydus_utils::compress;
 use nydus_utils::digest::RafsDigest;
 
 use crate::metadata::cached_v5::CachedChunkInfoV5;
@@ -248,6 +249,26 @@ impl ChunkWrapper {
         }
     }
 
+    /// Get compression algorithm of the chunk, `None` means the blob compression algorithm.
+    pub fn compressor(&self) -> Option<compress::Algorithm> {
+        match self {
+            ChunkWrapper::V5(c) => c.flags.compressor(),
+            ChunkWrapper::V6(c) => c.flags.compressor(),
+            ChunkWrapper::Ref(c) => c.compressor(),
+        }
+    }
+
+    /// Set compression algorithm of the chunk, `None` means the blob compression algorithm.
+    pub fn set_compressor(&mut self, compressor: Option<compress::Algorithm>) -> Result<()> {
+        self.ensure_owned();
+        match self {
+            ChunkWrapper::V5(c) => c.flags.set_compressor(compressor)?,
+            ChunkWrapper::V6(c) => c.flags.set_compressor(compressor)?,
+            ChunkWrapper::Ref(_c) => panic!("unexpected"),
+        }
+        Ok(())
+    }
+
     /// Check whether the chunk is encrypted or not.
     pub fn is_encrypted(&self) -> bool {
         match self {
@@ -450,6 +471,15 @@ mod tests {
         assert!(wrapper.is_compressed());
         wrapper.set_batch(true);
         assert!(wrapper.is_batch());
+        assert_eq!(wrapper.compressor(), None);
+        wrapper
+            .set_compressor(Some(compress::Algorithm::Lz4Block))
+            .unwrap();
+        assert_eq!(wrapper.compressor(), Some(compress::Algorithm::Lz4Block));
+        assert!(wrapper
+            .set_compressor(Some(compress::Algorithm::Custom0))
+            .is_err());
+        assert!(wrapper.is_compressed());
         wrapper
             .set_chunk_info(2048, 2048, 2048, 2048, 2048, 2048, 2048, true, true)
             .unwrap();
@@ -30,6 +30,7 @@ use arc_swap::{ArcSwap, Guard};
 use nydus_storage::device::v5::BlobV5ChunkInfo;
 use nydus_storage::device::{BlobChunkFlags, BlobChunkInfo, BlobDevice, BlobInfo, BlobIoVec};
 use nydus_storage::utils::readahead;
+use nydus_utils::compress;
 use nydus_utils::digest::RafsDigest;
 use nydus_utils::filemap::{clone_file, FileMapState};
 
@@ -854,6 +855,10 @@ impl BlobChunkInfo for DirectChunkInfoV5 {
         false
     }
 
+    fn compressor(&self) -> Option<compress::Algorithm> {
+        self.chunk(self.state().deref()).flags.compressor()
+    }
+
     fn as_any(&self) -> &dyn Any {
         self
     }
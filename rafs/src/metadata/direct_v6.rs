@@ -34,7 +34,7 @@ use nydus_storage::device::{
 };
 use nydus_storage::utils::readahead;
 use nydus_utils::filemap::{clone_file, FileMapState};
-use nydus_utils::{digest::RafsDigest, div_round_up, round_up};
+use nydus_utils::{compress, digest::RafsDigest, div_round_up, round_up};
 
 use crate::metadata::layout::v5::RafsV5ChunkInfo;
 use crate::metadata::layout::v6::{
@@ -206,7 +206,14 @@ impl DirectSuperBlockV6 {
         let mut blob_table = RafsV6BlobTable::new();
         let meta = &old_state.meta;
         r.seek(SeekFrom::Start(meta.blob_table_offset))?;
-        blob_table.load(r, meta.blob_table_size, meta.chunk_size, meta.flags)?;
+        blob_table.load(
+            r,
+            meta.blob_table_size,
+            meta.chunk_size,
+            meta.flags,
+            meta.blob_prefetch_table_offset,
+            meta.blob_prefetch_table_size,
+        )?;
         let blob_extra_infos = rafsv6_load_blob_extra_info(meta, r)?;
 
         let file_map = FileMapState::new(file, 0, len as usize, false)?;
@@ -594,8 +601,8 @@ impl OndiskInodeWrapper {
         }
     }
 
+    // Size of the xattr ibody header, the shared xattr id array and inline xattrs.
     fn xattr_size(inode: &dyn RafsV6OndiskInode) -> usize {
-        // Rafs v6 only supports EROFS inline xattr.
         if inode.xattr_inline_count() > 0 {
             (inode.xattr_inline_count() as usize - 1) * size_of::<RafsV6XattrEntry>()
                 + size_of::<RafsV6XattrIbodyHeader>()
@@ -604,6 +611,87 @@ impl OndiskInodeWrapper {
         }
     }
 
+    // Visit extended attributes of the inode, shared ones first, until `cb` returns true.
+    fn walk_xattrs(&self, cb: &mut dyn FnMut(OsString, &[u8]) -> bool) -> Result<()> {
+        let state = self.state();
+        let inode = self.disk_inode(&state);
+        let total = inode.xattr_inline_count();
+        if total == 0 {
+            return Ok(());
+        }
+
+        let mut offset = self.offset + Self::inode_size(inode);
+        let header: &RafsV6XattrIbodyHeader = state.map.get_ref(offset)?;
+        let shared_size = header.shared_count() as usize * size_of::<u32>();
+        let mut remaining = (total - 1) as usize * size_of::<RafsV6XattrEntry>();
+        if shared_size > remaining {
+            return Err(einval!(format!(
+                "v6: invalid shared xattr count {}",
+                header.shared_count()
+            )));
+        }
+        offset += size_of::<RafsV6XattrIbodyHeader>();
+
+        if shared_size > 0 {
+            if state.meta.xattr_blkaddr == 0 {
+                return Err(einval!("v6: no shared xattr area for shared xattrs"));
+            }
+            let base = state.meta.xattr_blkaddr as usize * state.block_size() as usize;
+            let ids: &[u32] = state
+                .map
+                .get_slice(offset, shared_size / size_of::<u32>())?;
+            for id in ids {
+                let addr = base + u32::from_le(*id) as usize * size_of::<RafsV6XattrEntry>();
+                let (xa_name, value, _) = Self::parse_xattr_entry(&state, addr, usize::MAX)?;
+                if cb(xa_name, value) {
+                    return Ok(());
+                }
+            }
+            offset += shared_size;
+            remaining -= shared_size;
+        }
+
+        while remaining > 0 {
+            let (xa_name, value, s) = Self::parse_xattr_entry(&state, offset, remaining)?;
+            if cb(xa_name, value) || s >= remaining {
+                break;
+            }
+            remaining -= s;
+            offset += s;
+        }
+
+        Ok(())
+    }
+
+    // Parse a xattr entry at `offset`, return name, value and aligned size of the entry.
+    fn parse_xattr_entry<'a>(
+        state: &'a DirectMappingState,
+        offset: usize,
+        remaining: usize,
+    ) -> Result<(OsString, &'a [u8], usize)> {
+        let e: &RafsV6XattrEntry = state.map.get_ref(offset)?;
+        if e.name_len() as usize + e.value_size() as usize > remaining {
+            return Err(einval!(format!(
+                "v6: invalid xattr name size {}",
+                e.name_len()
+            )));
+        }
+        let mut xa_name = recover_namespace(e.name_index())?;
+        let suffix: &[u8] = state.map.get_slice(
+            offset + size_of::<RafsV6XattrEntry>(),
+            e.name_len() as usize,
+        )?;
+        xa_name.push(OsStr::from_bytes(suffix));
+        let value: &[u8] = state.map.get_slice(
+            offset + size_of::<RafsV6XattrEntry>() + e.name_len() as usize,
+            e.value_size() as usize,
+        )?;
+        let s = e.name_len() + e.value_size() + size_of::<RafsV6XattrEntry>() as u32;
+        let s = round_up(s as u64, size_of::<RafsV6XattrEntry>() as u64) as usize;
+
+        Ok((xa_name, value, s))
+    }
+
     // Get sum of inode and xattr size aligned to RafsV6InodeChunkAddr.
     fn inode_xattr_size(inode: &dyn RafsV6OndiskInode) -> usize {
         let sz = Self::inode_size(inode) as u64 + Self::xattr_size(inode) as u64;
@@ -994,87 +1082,25 @@ impl RafsInode for OndiskInodeWrapper {
     }
 
     fn get_xattr(&self, name: &OsStr) -> Result<Option<XattrValue>> {
-        let state = self.state();
-        let inode = self.disk_inode(&state);
-        let total = inode.xattr_inline_count();
-        if total == 0 {
-            return Ok(None);
-        }
-
-        let mut offset =
-            self.offset + Self::inode_size(inode) + size_of::<RafsV6XattrIbodyHeader>();
-        let mut remaining = (total - 1) as usize * size_of::<RafsV6XattrEntry>();
-        while remaining > 0 {
-            let e: &RafsV6XattrEntry = state.map.get_ref(offset)?;
-            if e.name_len() as usize + e.value_size() as usize > remaining {
-                return Err(einval!(format!(
-                    "v6: invalid xattr name size {}",
-                    e.name_len()
-                )));
-            }
-            let mut xa_name = recover_namespace(e.name_index())?;
-            let suffix: &[u8] = state.map.get_slice(
-                offset + size_of::<RafsV6XattrEntry>(),
-                e.name_len() as usize,
-            )?;
-            xa_name.push(OsStr::from_bytes(suffix));
+        let mut value = None;
+        self.walk_xattrs(&mut |xa_name, data| {
             if xa_name == name {
-                let data: &[u8] = state.map.get_slice(
-                    offset + size_of::<RafsV6XattrEntry>() + e.name_len() as usize,
-                    e.value_size() as usize,
-                )?;
-                return Ok(Some(data.to_vec()));
-            }
-
-            let mut s = e.name_len() + e.value_size() + size_of::<RafsV6XattrEntry>() as u32;
-            s = round_up(s as u64, size_of::<RafsV6XattrEntry>() as u64) as u32;
-            if s as usize >= remaining {
-                break;
+                value = Some(data.to_vec());
+                true
+            } else {
+                false
             }
-            remaining -= s as usize;
-            offset += s as usize;
-        }
+        })?;
 
-        Ok(None)
+        Ok(value)
     }
 
     fn get_xattrs(&self) -> Result<Vec<XattrName>> {
-        let state = self.state();
-        let inode = self.disk_inode(&state);
         let mut xattrs = Vec::new();
-        let total = inode.xattr_inline_count();
-        if total == 0 {
-            return Ok(xattrs);
-        }
-
-        let mut offset =
-            self.offset + Self::inode_size(inode) + size_of::<RafsV6XattrIbodyHeader>();
-        let mut remaining = (total - 1) as usize * size_of::<RafsV6XattrEntry>();
-        while remaining > 0 {
-            let e: &RafsV6XattrEntry = state.map.get_ref(offset)?;
-            if e.name_len() as usize + e.value_size() as usize > remaining {
-                return Err(einval!(format!(
-                    "v6: invalid xattr name size {}",
-                    e.name_len()
-                )));
-            }
-            let name: &[u8] = state.map.get_slice(
-                offset + size_of::<RafsV6XattrEntry>(),
-                e.name_len() as usize,
-            )?;
-            let ns = recover_namespace(e.name_index())?;
-            let mut xa = ns.into_vec();
-            xa.extend_from_slice(name);
-            xattrs.push(xa);
-
-            let mut s = e.name_len() + e.value_size() + size_of::<RafsV6XattrEntry>() as u32;
-            s = round_up(s as u64, size_of::<RafsV6XattrEntry>() as u64) as u32;
-            if s as usize >= remaining {
-                break;
-            }
-            offset += s as usize;
-            remaining -= s as usize;
-        }
+        self.walk_xattrs(&mut |xa_name, _data| {
+            xattrs.push(xa_name.into_vec());
+            false
+        })?;
 
         Ok(xattrs)
     }
@@ -1461,6 +1487,11 @@ impl BlobChunkInfo for DirectChunkInfoV6 {
             .contains(BlobChunkFlags::ENCYPTED)
     }
 
+    fn compressor(&self) -> Option<compress::Algorithm> {
+        let state = self.state();
+        self.v5_chunk(&state).flags.compressor()
+    }
+
     fn as_any(&self) -> &dyn Any {
         self
     }
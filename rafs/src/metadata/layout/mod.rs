@@ -221,10 +221,17 @@ pub const RAFS_XATTR_PREFIXES: [&str; 5] = [
     "system.posix_acl_default",
 ];
 
+/// Max length of extended attribute names, same as `XATTR_NAME_MAX` of Linux.
+pub const RAFS_XATTR_NAME_MAX: usize = 255;
+/// Max size of extended attribute values, same as `XATTR_SIZE_MAX` of Linux.
+///
+/// RAFS v6 has a smaller limit `v6::RAFSV6_XATTR_VALUE_SIZE_MAX`.
+pub const RAFS_XATTR_VALUE_MAX: usize = 0x10000;
+
 /// Rafs inode extended attributes.
 ///
 /// An extended attribute is a (String, String) pair associated with a inode.
-#[derive(Clone, Default)]
+#[derive(Clone, Default, PartialEq, Eq)]
 pub struct RafsXAttrs {
     pairs: HashMap<OsString, XattrValue>,
 }
@@ -263,8 +270,21 @@ impl RafsXAttrs {
     /// Add or update an extended attribute.
     pub fn add(&mut self, name: OsString, value: XattrValue) -> Result<()> {
         let buf = name.as_bytes();
-        if buf.len() > 255 || value.len() > 0x10000 {
-            return Err(einval!("xattr key/value is too big"));
+        if buf.len() > RAFS_XATTR_NAME_MAX {
+            return Err(einval!(format!(
+                "xattr name {:?} is too long, {} bytes, max {} bytes",
+                name,
+                buf.len(),
+                RAFS_XATTR_NAME_MAX
+            )));
+        }
+        if value.len() > RAFS_XATTR_VALUE_MAX {
+            return Err(einval!(format!(
+                "value of xattr {:?} is too big, {} bytes, max {} bytes",
+                name,
+                value.len(),
+                RAFS_XATTR_VALUE_MAX
+            )));
         }
         for p in RAFS_XATTR_PREFIXES {
             if buf.len() >= p.as_bytes().len() && &buf[..p.as_bytes().len()] == p.as_bytes() {
@@ -45,6 +45,7 @@ use std::sync::Arc;
 
 use nydus_utils::digest::{self, DigestHasher, RafsDigest};
 use nydus_utils::{compress, ByteSize};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
 use vm_memory::VolatileMemory;
 // With Rafs v5, the storage manager needs to access file system metadata to decompress the
 // compressed blob file. To avoid circular dependency, the following Rafs v5 metadata structures
@@ -156,8 +157,10 @@ impl RafsV5SuperBlock {
         {
             // Stargz has a special chunk size of 4MB.
             return Err(einval!("invalid block size"));
-        } else if RafsSuperFlags::from_bits(self.flags()).is_none() {
-            return Err(einval!("invalid super block flags"));
+        }
+        match RafsSuperFlags::from_bits(self.flags()) {
+            None => return Err(einval!("invalid super block flags")),
+            Some(flags) => flags.validate_algorithms()?,
         }
 
         let meta_range = MetaRange::new(
@@ -224,10 +227,7 @@ impl RafsV5SuperBlock {
     pub fn set_compressor(&mut self, compressor: compress::Algorithm) {
         let c: RafsSuperFlags = compressor.into();
 
-        self.s_flags &= !RafsSuperFlags::COMPRESSION_NONE.bits();
-        self.s_flags &= !RafsSuperFlags::COMPRESSION_LZ4.bits();
-        self.s_flags &= !RafsSuperFlags::COMPRESSION_GZIP.bits();
-        self.s_flags &= !RafsSuperFlags::COMPRESSION_ZSTD.bits();
+        self.s_flags &= !RafsSuperFlags::compression_flags().bits();
         self.s_flags |= c.bits();
     }
 
@@ -235,8 +235,7 @@ impl RafsV5SuperBlock {
     pub fn set_digester(&mut self, digester: digest::Algorithm) {
         let c: RafsSuperFlags = digester.into();
 
-        self.s_flags &= !RafsSuperFlags::HASH_BLAKE3.bits();
-        self.s_flags &= !RafsSuperFlags::HASH_SHA256.bits();
+        self.s_flags &= !RafsSuperFlags::hash_flags().bits();
         self.s_flags |= c.bits();
     }
 
@@ -745,6 +744,18 @@ impl Debug for RafsV5ExtBlobEntry {
     }
 }
 
+// Serialize the entry for external tools, reserved bytes are skipped.
+impl Serialize for RafsV5ExtBlobEntry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("RafsV5ExtBlobEntry", 4)?;
+        state.serialize_field("chunk_count", &self.chunk_count)?;
+        state.serialize_field("features", &self.features)?;
+        state.serialize_field("uncompressed_size", &self.uncompressed_size)?;
+        state.serialize_field("compressed_size", &self.compressed_size)?;
+        state.end()
+    }
+}
+
 impl Default for RafsV5ExtBlobEntry {
     fn default() -> Self {
         RafsV5ExtBlobEntry {
@@ -1596,6 +1607,17 @@ pub mod tests {
                 [0u8; RAFSV5_EXT_BLOB_RESERVED_SIZE]
             );
         }
+
+        let value = serde_json::to_value(table.get(1).unwrap().as_ref()).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "chunk_count": 3,
+                "features": 0,
+                "uncompressed_size": 100,
+                "compressed_size": 100,
+            })
+        );
     }
 
     #[derive(Default, Copy, Clone)]
@@ -3,7 +3,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::{TryFrom, TryInto};
 use std::ffi::{OsStr, OsString};
 use std::fmt::Debug;
@@ -24,7 +24,7 @@ use nydus_utils::{compress, digest, round_up, ByteSize};
 
 use crate::metadata::inode::InodeWrapper;
 use crate::metadata::layout::v5::RafsV5ChunkInfo;
-use crate::metadata::layout::{MetaRange, RafsXAttrs};
+use crate::metadata::layout::{MetaRange, RafsXAttrs, XattrValue};
 use crate::metadata::{Inode, RafsBlobExtraInfo, RafsStore, RafsSuperFlags, RafsSuperMeta};
 use crate::{impl_bootstrap_converter, impl_pub_getter_setter, RafsIoReader, RafsIoWrite};
 
@@ -214,10 +214,12 @@ impl RafsV6SuperBlock {
             return Err(einval!("invalid union field in Rafsv6 superblock"));
         }
 
-        if self.s_xattr_blkaddr != 0 {
-            return Err(einval!(
-                "unsupported shared extended attribute namespace in Rafsv6 superblock"
-            ));
+        let xattr_addr = u32::from_le(self.s_xattr_blkaddr) as u64 * block_size;
+        if xattr_addr >= meta_size {
+            return Err(einval!(format!(
+                "invalid Rafs v6 shared xattr block address 0x{:x}, meta file size 0x{:x}",
+                xattr_addr, meta_size
+            )));
         }
 
         // There's a bug in old RAFS v6 images, which has set s_blocks to a fixed value 4096.
@@ -317,6 +319,23 @@ impl RafsV6SuperBlock {
         }
     }
 
+    /// Get shared xattr block address, zero if there's no shared xattr area.
+    pub fn xattr_addr(&self) -> u32 {
+        u32::from_le(self.s_xattr_blkaddr)
+    }
+
+    /// Set EROFS shared xattr block address.
+    pub fn set_xattr_addr(&mut self, xattr_addr: u64) {
+        let block_size = if self.s_blkszbits == EROFS_BLOCK_BITS_9 {
+            EROFS_BLOCK_SIZE_512
+        } else {
+            EROFS_BLOCK_SIZE_4096
+        };
+        assert_eq!(xattr_addr & (block_size - 1), 0);
+        assert!((xattr_addr / block_size) <= u32::MAX as u64);
+        self.s_xattr_blkaddr = u32::to_le((xattr_addr / block_size) as u32);
+    }
+
     /// Get device table offset.
     pub fn device_table_offset(&self) -> u64 {
         u16::from_le(self.s_devt_slotoff) as u64 * size_of::<RafsV6Device>() as u64
@@ -398,8 +417,23 @@ pub struct RafsV6SuperBlockExt {
     s_prefetch_table_offset: u64,
     s_prefetch_table_size: u32,
     s_padding: u32,
+    /// offset of the embedded signature region, zero if the bootstrap is unsigned
+    s_signature_offset: u64,
+    /// size of the embedded signature region
+    s_signature_size: u32,
+    s_padding2: u32,
+    /// offset of blob prefetch range table
+    s_blob_prefetch_table_offset: u64,
+    /// size of blob prefetch range table
+    s_blob_prefetch_table_size: u32,
+    s_padding3: u32,
+    /// offset of the annotation region
+    s_annotations_offset: u64,
+    /// size of the annotation region
+    s_annotations_size: u32,
+    s_padding4: u32,
     /// Reserved
-    s_reserved: [u8; 200],
+    s_reserved: [u8; 152],
 }
 
 impl_bootstrap_converter!(RafsV6SuperBlockExt);
@@ -423,10 +457,7 @@ impl RafsV6SuperBlockExt {
     /// Validate the Rafs v6 super block.
     pub fn validate(&self, meta_size: u64, meta: &RafsSuperMeta) -> Result<()> {
         let mut flags = self.flags();
-        flags &= RafsSuperFlags::COMPRESSION_NONE.bits()
-            | RafsSuperFlags::COMPRESSION_LZ4.bits()
-            | RafsSuperFlags::COMPRESSION_GZIP.bits()
-            | RafsSuperFlags::COMPRESSION_ZSTD.bits();
+        flags &= RafsSuperFlags::compression_flags().bits();
         if flags.count_ones() != 1 {
             return Err(einval!(format!(
                 "invalid flags {:#x} related to compression algorithm in Rafs v6 extended superblock",
@@ -435,13 +466,14 @@ impl RafsV6SuperBlockExt {
         }
 
         let mut flags = self.flags();
-        flags &= RafsSuperFlags::HASH_BLAKE3.bits() | RafsSuperFlags::HASH_SHA256.bits();
+        flags &= RafsSuperFlags::hash_flags().bits();
         if flags.count_ones() != 1 {
             return Err(einval!(format!(
                 "invalid flags {:#x} related to digest algorithm in Rafs v6 extended superblock",
                 flags
             )));
         }
+        RafsSuperFlags::from_bits_truncate(self.flags()).validate_algorithms()?;
 
         let chunk_size = u32::from_le(self.s_chunk_size) as u64;
         if !chunk_size.is_power_of_two()
@@ -493,6 +525,66 @@ impl RafsV6SuperBlockExt {
             chunk_info_tbl_range = Some(chunk_range);
         }
 
+        if self.blob_prefetch_table_size() > 0 {
+            let tbl_offset = self.blob_prefetch_table_offset();
+            let tbl_size = self.blob_prefetch_table_size() as u64;
+            if tbl_offset < EROFS_BLOCK_SIZE_4096
+                || tbl_offset % EROFS_BLOCK_SIZE_4096 != 0
+                || tbl_offset < devslot_end
+                || tbl_size % size_of::<RafsV6BlobPrefetchRange>() as u64 != 0
+                || tbl_offset.checked_add(tbl_size).is_none()
+                || tbl_offset + tbl_size > meta_size
+            {
+                return Err(einval!(format!(
+                    "invalid blob prefetch range table offset 0x{:x}/size 0x{:x} in Rafs v6 extended superblock",
+                    tbl_offset, tbl_size
+                )));
+            }
+            let range_tbl_range = MetaRange::new(tbl_offset, tbl_size, true)?;
+            if blob_range.intersect_with(&range_tbl_range) {
+                return Err(einval!(format!(
+                    "blob table intersects with blob prefetch range table in Rafs v6 extended superblock",
+                )));
+            }
+            if let Some(chunk_range) = chunk_info_tbl_range.as_ref() {
+                if chunk_range.intersect_with(&range_tbl_range) {
+                    return Err(einval!(format!(
+                    "chunk information table intersects with blob prefetch range table in Rafs v6 extended superblock",
+                )));
+                }
+            }
+        }
+
+        if self.annotations_size() > 0 {
+            let anno_offset = self.annotations_offset();
+            let anno_size = self.annotations_size() as u64;
+            if anno_offset < EROFS_BLOCK_SIZE_4096
+                || anno_offset % EROFS_BLOCK_SIZE_4096 != 0
+                || anno_offset < devslot_end
+                || anno_size > RAFS_V6_ANNOTATIONS_SIZE_MAX as u64
+                || anno_offset.checked_add(anno_size).is_none()
+                || anno_offset + anno_size > meta_size
+            {
+                return Err(einval!(format!(
+                    "invalid annotation region offset 0x{:x}/size 0x{:x} in Rafs v6 extended superblock",
+                    anno_offset, anno_size
+                )));
+            }
+            let anno_range = MetaRange::new(anno_offset, anno_size, true)?;
+            if blob_range.intersect_with(&anno_range) {
+                return Err(einval!(format!(
+                    "blob table intersects with annotation region in Rafs v6 extended superblock",
+                )));
+            }
+            if let Some(chunk_range) = chunk_info_tbl_range.as_ref() {
+                if chunk_range.intersect_with(&anno_range) {
+                    return Err(einval!(format!(
+                    "chunk information table intersects with annotation region in Rafs v6 extended superblock",
+                )));
+                }
+            }
+        }
+
         // Legacy RAFS may have zero prefetch table offset but non-zero prefetch table size for
         // empty filesystems.
         if self.prefetch_table_size() > 0 && self.prefetch_table_offset() != 0 {
@@ -524,6 +616,28 @@ impl RafsV6SuperBlockExt {
             }
         }
 
+        if self.has_signature() {
+            let sig_offset = self.signature_offset();
+            let sig_size = self.signature_size() as u64;
+            if sig_offset < EROFS_BLOCK_SIZE_4096
+                || sig_offset % EROFS_BLOCK_SIZE_4096 != 0
+                || sig_size < size_of::<RafsV6SignatureHeader>() as u64
+                || sig_offset.checked_add(sig_size).is_none()
+                || sig_offset + sig_size > meta_size
+            {
+                return Err(einval!(format!(
+                    "invalid signature offset 0x{:x}/size 0x{:x} in Rafs v6 extended superblock",
+                    sig_offset, sig_size
+                )));
+            }
+            let sig_range = MetaRange::new(sig_offset, sig_size, false)?;
+            if blob_range.intersect_with(&sig_range) {
+                return Err(einval!(format!(
+                    "blob table intersects with signature region in Rafs v6 extended superblock",
+                )));
+            }
+        }
+
         Ok(())
     }
 
@@ -531,10 +645,7 @@ impl RafsV6SuperBlockExt {
     pub fn set_compressor(&mut self, compressor: compress::Algorithm) {
         let c: RafsSuperFlags = compressor.into();
 
-        self.s_flags &= !RafsSuperFlags::COMPRESSION_NONE.bits();
-        self.s_flags &= !RafsSuperFlags::COMPRESSION_LZ4.bits();
-        self.s_flags &= !RafsSuperFlags::COMPRESSION_GZIP.bits();
-        self.s_flags &= !RafsSuperFlags::COMPRESSION_ZSTD.bits();
+        self.s_flags &= !RafsSuperFlags::compression_flags().bits();
         self.s_flags |= c.bits();
     }
 
@@ -563,8 +674,7 @@ impl RafsV6SuperBlockExt {
     pub fn set_digester(&mut self, digester: digest::Algorithm) {
         let c: RafsSuperFlags = digester.into();
 
-        self.s_flags &= !RafsSuperFlags::HASH_BLAKE3.bits();
-        self.s_flags &= !RafsSuperFlags::HASH_SHA256.bits();
+        self.s_flags &= !RafsSuperFlags::hash_flags().bits();
         self.s_flags |= c.bits();
     }
 
@@ -574,6 +684,18 @@ impl RafsV6SuperBlockExt {
         self.set_chunk_table_size(size);
     }
 
+    /// Set offset and size of blob prefetch range table.
+    pub fn set_blob_prefetch_table(&mut self, offset: u64, size: u32) {
+        self.set_blob_prefetch_table_offset(offset);
+        self.set_blob_prefetch_table_size(size);
+    }
+
+    /// Set offset and size of the annotation region.
+    pub fn set_annotations(&mut self, offset: u64, size: u32) {
+        self.set_annotations_offset(offset);
+        self.set_annotations_size(size);
+    }
+
     /// Set encryption algorithm to encrypt chunks of the Rafs filesystem.
     pub fn set_cipher(&mut self, cipher: crypt::Algorithm) {
         let c: RafsSuperFlags = cipher.into();
@@ -616,6 +738,42 @@ impl RafsV6SuperBlockExt {
         s_prefetch_table_offset,
         u64
     );
+    impl_pub_getter_setter!(
+        signature_offset,
+        set_signature_offset,
+        s_signature_offset,
+        u64
+    );
+    impl_pub_getter_setter!(signature_size, set_signature_size, s_signature_size, u32);
+    impl_pub_getter_setter!(
+        blob_prefetch_table_offset,
+        set_blob_prefetch_table_offset,
+        s_blob_prefetch_table_offset,
+        u64
+    );
+    impl_pub_getter_setter!(
+        blob_prefetch_table_size,
+        set_blob_prefetch_table_size,
+        s_blob_prefetch_table_size,
+        u32
+    );
+    impl_pub_getter_setter!(
+        annotations_offset,
+        set_annotations_offset,
+        s_annotations_offset,
+        u64
+    );
+    impl_pub_getter_setter!(
+        annotations_size,
+        set_annotations_size,
+        s_annotations_size,
+        u32
+    );
+
+    /// Check whether a signature has been embedded into the bootstrap.
+    pub fn has_signature(&self) -> bool {
+        self.signature_size() > 0
+    }
 }
 
 impl RafsStore for RafsV6SuperBlockExt {
@@ -639,9 +797,90 @@ impl Default for RafsV6SuperBlockExt {
             s_prefetch_table_offset: 0,
             s_prefetch_table_size: 0,
             s_padding: u32::to_le(0),
-            s_reserved: [0u8; 200],
+            s_signature_offset: 0,
+            s_signature_size: 0,
+            s_padding2: u32::to_le(0),
+            s_blob_prefetch_table_offset: 0,
+            s_blob_prefetch_table_size: 0,
+            s_padding3: u32::to_le(0),
+            s_annotations_offset: 0,
+            s_annotations_size: 0,
+            s_padding4: u32::to_le(0),
+            s_reserved: [0u8; 152],
+        }
+    }
+}
+
+/// Offset of the signature slot (`s_signature_offset`, `s_signature_size` and padding) in the
+/// bootstrap, which must be zeroed when computing the digest of signed bootstrap content.
+pub const RAFS_V6_SIGNATURE_SLOT_OFFSET: u64 =
+    (EROFS_SUPER_OFFSET + EROFS_SUPER_BLOCK_SIZE) as u64 + 56;
+/// Size of the signature slot in the extended superblock.
+pub const RAFS_V6_SIGNATURE_SLOT_SIZE: usize = 16;
+/// Magic number of the embedded signature region: "RSIG".
+pub const RAFS_V6_SIGNATURE_MAGIC: u32 = 0x5253_4947;
+/// Signature is generated over the SHA256 digest of the bootstrap content.
+pub const RAFS_V6_SIGNATURE_DIGEST_SHA256: u32 = 1;
+
+/// Header of the signature region embedded at the tail of a RAFS v6 bootstrap, followed by
+/// the signer identity and the signature data.
+///
+/// The signature covers bootstrap content in range `[0, s_signature_offset)`, with the
+/// signature slot in the extended superblock cleared.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct RafsV6SignatureHeader {
+    s_magic: u32,
+    s_digester: u32,
+    s_signer_size: u32,
+    s_data_size: u32,
+    s_reserved: [u8; 16],
+}
+
+impl_bootstrap_converter!(RafsV6SignatureHeader);
+
+impl RafsV6SignatureHeader {
+    /// Create a new signature header for `signer` and `data` sizes.
+    pub fn new(signer_size: u32, data_size: u32) -> Self {
+        debug_assert!(size_of::<Self>() == 32);
+        Self {
+            s_magic: u32::to_le(RAFS_V6_SIGNATURE_MAGIC),
+            s_digester: u32::to_le(RAFS_V6_SIGNATURE_DIGEST_SHA256),
+            s_signer_size: u32::to_le(signer_size),
+            s_data_size: u32::to_le(data_size),
+            s_reserved: [0u8; 16],
+        }
+    }
+
+    /// Validate the signature header against size of the signature region.
+    pub fn validate(&self, region_size: u64) -> Result<()> {
+        if self.magic() != RAFS_V6_SIGNATURE_MAGIC {
+            return Err(einval!(format!(
+                "invalid magic 0x{:x} of signature region",
+                self.magic()
+            )));
+        }
+        if self.digester() != RAFS_V6_SIGNATURE_DIGEST_SHA256 {
+            return Err(einval!(format!(
+                "unsupported digest algorithm {} of signature region",
+                self.digester()
+            )));
         }
+        let size = size_of::<Self>() as u64 + self.signer_size() as u64 + self.data_size() as u64;
+        if self.data_size() == 0 || size > region_size {
+            return Err(einval!(format!(
+                "invalid signer size 0x{:x}/signature size 0x{:x} of signature region",
+                self.signer_size(),
+                self.data_size()
+            )));
+        }
+        Ok(())
     }
+
+    impl_pub_getter_setter!(magic, set_magic, s_magic, u32);
+    impl_pub_getter_setter!(digester, set_digester, s_digester, u32);
+    impl_pub_getter_setter!(signer_size, set_signer_size, s_signer_size, u32);
+    impl_pub_getter_setter!(data_size, set_data_size, s_data_size, u32);
 }
 
 /// Type of EROFS inodes.
@@ -1443,8 +1682,11 @@ struct RafsV6Blob {
     cipher_iv: [u8; 8],
     // Crypt algorithm for chunks in the blob.
     cipher_algo: u32,
+    // Number of entries in the blob prefetch range table belonging to the blob. Entries of all
+    // blobs are stored in the order of blob index.
+    prefetch_range_count: u32,
 
-    reserved2: [u8; 36],
+    reserved2: [u8; 32],
 }
 
 impl Default for RafsV6Blob {
@@ -1470,8 +1712,9 @@ impl Default for RafsV6Blob {
             blob_toc_size: 0u32,
             cipher_iv: [0u8; 8],
             cipher_algo: (crypt::Algorithm::None as u32).to_le(),
+            prefetch_range_count: 0u32,
 
-            reserved2: [0u8; 36],
+            reserved2: [0u8; 32],
         }
     }
 }
@@ -1586,6 +1829,9 @@ impl RafsV6Blob {
             }
         };
 
+        let prefetch_range_count = u32::try_from(blob_info.prefetch_ranges().len())
+            .map_err(|_| einval!("too many prefetch ranges in blob info"))?;
+
         Ok(RafsV6Blob {
             blob_id,
             blob_index: blob_info.blob_index().to_le(),
@@ -1607,8 +1853,9 @@ impl RafsV6Blob {
             blob_toc_size: blob_info.blob_toc_size(),
             cipher_iv,
             cipher_algo: (blob_info.cipher() as u32).to_le(),
+            prefetch_range_count: prefetch_range_count.to_le(),
 
-            reserved2: [0u8; 36],
+            reserved2: [0u8; 32],
         })
     }
 
@@ -1663,6 +1910,16 @@ impl RafsV6Blob {
             return false;
         }
 
+        // Each prefetch range covers at least one chunk of the blob.
+        let prefetch_range_count = u32::from_le(self.prefetch_range_count);
+        if prefetch_range_count > chunk_count {
+            error!(
+                "RafsV6Blob: idx {} invalid prefetch_range_count {:x}, chunk_count {:x}",
+                blob_index, prefetch_range_count, chunk_count
+            );
+            return false;
+        }
+
         if compress::Algorithm::try_from(u32::from_le(self.compression_algo)).is_err()
             || compress::Algorithm::try_from(u32::from_le(self.ci_compressor)).is_err()
             || digest::Algorithm::try_from(u32::from_le(self.digest_algo)).is_err()
@@ -1769,6 +2026,28 @@ impl RafsV6Blob {
     }
 }
 
+/// Compressed data range of a blob to prefetch, entry of the blob prefetch range table.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct RafsV6BlobPrefetchRange {
+    // Offset into the compressed blob.
+    offset: u64,
+    // Size of compressed data to prefetch.
+    size: u64,
+}
+
+impl_bootstrap_converter!(RafsV6BlobPrefetchRange);
+
+impl RafsV6BlobPrefetchRange {
+    fn new(offset: u64, size: u64) -> Self {
+        debug_assert!(size_of::<RafsV6BlobPrefetchRange>() == 16);
+        RafsV6BlobPrefetchRange {
+            offset: offset.to_le(),
+            size: size.to_le(),
+        }
+    }
+}
+
 /// Rafs v6 blob description table.
 #[derive(Clone, Debug, Default)]
 pub struct RafsV6BlobTable {
@@ -1789,6 +2068,15 @@ impl RafsV6BlobTable {
         self.entries.len() * size_of::<RafsV6Blob>()
     }
 
+    /// Get size of the blob prefetch range table.
+    pub fn prefetch_range_table_size(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|e| e.prefetch_ranges().len())
+            .sum::<usize>()
+            * size_of::<RafsV6BlobPrefetchRange>()
+    }
+
     /// Get base information for a blob.
     #[inline]
     pub fn get(&self, blob_index: u32) -> Result<Arc<BlobInfo>> {
@@ -1860,13 +2148,25 @@ impl RafsV6BlobTable {
         blob_index
     }
 
-    /// Load blob information table from a reader.
+    /// Set compressed data ranges to prefetch for a blob.
+    pub fn set_prefetch_ranges(&mut self, blob_index: u32, ranges: Vec<(u64, u64)>) -> Result<()> {
+        let entry = self
+            .entries
+            .get_mut(blob_index as usize)
+            .ok_or_else(|| enoent!("blob not found"))?;
+        Arc::make_mut(entry).set_prefetch_ranges(ranges);
+        Ok(())
+    }
+
+    /// Load blob information table and blob prefetch range table from a reader.
     pub fn load(
         &mut self,
         r: &mut RafsIoReader,
         blob_table_size: u32,
         chunk_size: u32,
         flags: RafsSuperFlags,
+        prefetch_table_offset: u64,
+        prefetch_table_size: u32,
     ) -> Result<()> {
         if blob_table_size == 0 {
             return Ok(());
@@ -1876,18 +2176,59 @@ impl RafsV6BlobTable {
             return Err(einval!(msg));
         }
 
+        let mut blobs = Vec::new();
         for idx in 0..(blob_table_size as usize / size_of::<RafsV6Blob>()) {
             let mut blob = RafsV6Blob::default();
             r.read_exact(blob.as_mut())?;
             if !blob.validate(idx as u32, chunk_size, flags) {
                 return Err(einval!("invalid Rafs v6 blob entry"));
             }
-            let blob_info = blob.to_blob_info()?;
+            blobs.push(blob);
+        }
+
+        let range_count = blobs
+            .iter()
+            .map(|b| u32::from_le(b.prefetch_range_count) as u64)
+            .sum::<u64>();
+        if range_count * size_of::<RafsV6BlobPrefetchRange>() as u64 != prefetch_table_size as u64 {
+            return Err(einval!(format!(
+                "blob prefetch range table size 0x{:x} doesn't match {} ranges in Rafs v6 blob table",
+                prefetch_table_size, range_count
+            )));
+        }
+        if range_count > 0 {
+            r.seek_to_offset(prefetch_table_offset)?;
+        }
+
+        for blob in blobs {
+            let mut blob_info = blob.to_blob_info()?;
+            let count = u32::from_le(blob.prefetch_range_count) as usize;
+            if count > 0 {
+                let mut ranges = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let mut range = RafsV6BlobPrefetchRange::default();
+                    r.read_exact(range.as_mut())?;
+                    ranges.push((u64::from_le(range.offset), u64::from_le(range.size)));
+                }
+                blob_info.set_prefetch_ranges(ranges);
+            }
             self.entries.push(Arc::new(blob_info));
         }
 
         Ok(())
     }
+
+    /// Store the blob prefetch range table, with ranges of all blobs in the order of blob index.
+    pub fn store_prefetch_ranges(&self, w: &mut dyn RafsIoWrite) -> Result<usize> {
+        for blob_info in self.entries.iter() {
+            for (offset, size) in blob_info.prefetch_ranges() {
+                let range = RafsV6BlobPrefetchRange::new(*offset, *size);
+                w.write_all(range.as_ref())?;
+            }
+        }
+
+        Ok(self.prefetch_range_table_size())
+    }
 }
 
 impl RafsStore for RafsV6BlobTable {
@@ -1907,6 +2248,141 @@ impl RafsStore for RafsV6BlobTable {
     }
 }
 
+/// Max size of the annotation region.
+pub const RAFS_V6_ANNOTATIONS_SIZE_MAX: u32 = 0x10_0000;
+/// Max size of annotation keys.
+pub const RAFS_V6_ANNOTATION_KEY_SIZE_MAX: usize = 255;
+
+/// Key/value annotations embedded in RAFS v6 bootstrap, to record provenance of the image.
+///
+/// The annotation region is a sequence of entries sorted by key, each entry has a 1-byte key size
+/// and a 4-byte value size in little endian, followed by the key and the value in UTF-8.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RafsV6Annotations {
+    entries: BTreeMap<String, String>,
+}
+
+impl RafsV6Annotations {
+    /// Create a new instance of `RafsV6Annotations`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an annotation, the old value is replaced if the key exists.
+    pub fn insert(&mut self, key: &str, value: &str) -> Result<()> {
+        if key.is_empty()
+            || key.len() > RAFS_V6_ANNOTATION_KEY_SIZE_MAX
+            || key.contains(|c: char| c == '=' || c.is_whitespace() || c.is_control())
+        {
+            return Err(einval!(format!("invalid annotation key {:?}", key)));
+        }
+        let old_size = self
+            .entries
+            .get(key)
+            .map(|v| Self::entry_size(key, v))
+            .unwrap_or_default();
+        if self.size() - old_size + Self::entry_size(key, value)
+            > RAFS_V6_ANNOTATIONS_SIZE_MAX as usize
+        {
+            return Err(einval!(format!(
+                "annotations exceed the max size 0x{:x}",
+                RAFS_V6_ANNOTATIONS_SIZE_MAX
+            )));
+        }
+        self.entries.insert(key.to_string(), value.to_string());
+
+        Ok(())
+    }
+
+    /// Get value of the annotation `key`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(|v| v.as_str())
+    }
+
+    /// Get all annotations sorted by key.
+    pub fn entries(&self) -> &BTreeMap<String, String> {
+        &self.entries
+    }
+
+    /// Check whether there's no annotation.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Get size of the annotation region.
+    pub fn size(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|(k, v)| Self::entry_size(k, v))
+            .sum()
+    }
+
+    fn entry_size(key: &str, value: &str) -> usize {
+        1 + size_of::<u32>() + key.len() + value.len()
+    }
+
+    /// Load annotations from the region at `offset` with `size` bytes.
+    pub fn load(r: &mut RafsIoReader, offset: u64, size: u32) -> Result<Self> {
+        let mut annotations = Self::new();
+        if size == 0 {
+            return Ok(annotations);
+        }
+        if size > RAFS_V6_ANNOTATIONS_SIZE_MAX {
+            return Err(einval!(format!(
+                "invalid annotation region size 0x{:x}",
+                size
+            )));
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        r.seek_to_offset(offset)?;
+        r.read_exact(&mut buf)?;
+        let mut data = buf.as_slice();
+        let mut last_key: Option<String> = None;
+        while !data.is_empty() {
+            if data.len() < 1 + size_of::<u32>() {
+                return Err(einval!("truncated annotation entry header"));
+            }
+            let key_size = data[0] as usize;
+            let value_size = u32::from_le_bytes(data[1..5].try_into().unwrap()) as usize;
+            data = &data[1 + size_of::<u32>()..];
+            if key_size == 0 || data.len() < key_size || data.len() - key_size < value_size {
+                return Err(einval!("truncated annotation entry"));
+            }
+            let key = std::str::from_utf8(&data[..key_size])
+                .map_err(|_| einval!("annotation key is not valid UTF-8"))?;
+            let value = std::str::from_utf8(&data[key_size..key_size + value_size])
+                .map_err(|_| einval!(format!("value of annotation {} is not valid UTF-8", key)))?;
+            if last_key.as_deref().map(|k| k >= key).unwrap_or(false) {
+                return Err(einval!(format!(
+                    "annotation {} is duplicated or out of order",
+                    key
+                )));
+            }
+            annotations.insert(key, value)?;
+            last_key = Some(key.to_string());
+            data = &data[key_size + value_size..];
+        }
+
+        Ok(annotations)
+    }
+}
+
+impl RafsStore for RafsV6Annotations {
+    fn store(&self, w: &mut dyn RafsIoWrite) -> Result<usize> {
+        for (key, value) in self.entries.iter() {
+            let value_size = u32::try_from(value.len())
+                .map_err(|_| einval!(format!("value of annotation {} is too big", key)))?;
+            w.write_all(&[key.len() as u8])?;
+            w.write_all(&value_size.to_le_bytes())?;
+            w.write_all(key.as_bytes())?;
+            w.write_all(value.as_bytes())?;
+        }
+
+        Ok(self.size())
+    }
+}
+
 // RafsV6 xattr
 const EROFS_XATTR_INDEX_USER: u8 = 1;
 const EROFS_XATTR_INDEX_POSIX_ACL_ACCESS: u8 = 2;
@@ -1915,6 +2391,14 @@ const EROFS_XATTR_INDEX_TRUSTED: u8 = 4;
 // const EROFS_XATTR_INDEX_LUSTRE: u8 = 5;
 const EROFS_XATTR_INDEX_SECURITY: u8 = 6;
 
+/// Max size of xattr value in RAFS v6, limited by `RafsV6XattrEntry::e_value_size`.
+pub const RAFSV6_XATTR_VALUE_SIZE_MAX: usize = u16::MAX as usize;
+/// Xattr pairs with on-disk entry bigger than this are stored in the shared xattr area.
+pub const RAFSV6_XATTR_INLINE_ENTRY_MAX: usize = 512;
+/// Max size of inline xattrs of an inode, limited by `i_xattr_icount`.
+pub const RAFSV6_XATTR_INLINE_AREA_MAX: usize =
+    size_of::<RafsV6XattrIbodyHeader>() + (u16::MAX as usize - 1) * size_of::<RafsV6XattrEntry>();
+
 const XATTR_USER_PREFIX: &str = "user.";
 const XATTR_SECURITY_PREFIX: &str = "security.";
 const XATTR_TRUSTED_PREFIX: &str = "trusted.";
@@ -1991,6 +2475,16 @@ impl RafsV6XattrIbodyHeader {
         RafsV6XattrIbodyHeader::default()
     }
 
+    /// Get number of entries in the shared xattr id array.
+    pub fn shared_count(&self) -> u8 {
+        self.h_shared_count
+    }
+
+    /// Set number of entries in the shared xattr id array.
+    pub fn set_shared_count(&mut self, count: u8) {
+        self.h_shared_count = count;
+    }
+
     /// Load a `RafsV6XattrIbodyHeader` from a reader.
     pub fn load(&mut self, r: &mut RafsIoReader) -> Result<()> {
         r.read_exact(self.as_mut())
@@ -2067,53 +2561,133 @@ impl RafsXAttrs {
         if self.is_empty() {
             0
         } else {
+            let (shared, inline) = self.split_v6();
             let mut size: usize = size_of::<RafsV6XattrIbodyHeader>();
-            for (key, value) in self.pairs.iter() {
-                // Safe to unwrap() because RafsXAttrs.add()/adds() has validated the prefix.
-                let (_, prefix_len) = Self::match_prefix(key).expect("xattr is not valid");
-
-                size += size_of::<RafsV6XattrEntry>();
-                size += key.byte_size() - prefix_len + value.len();
-                size = round_up(size as u64, size_of::<RafsV6XattrEntry>() as u64) as usize;
+            size += shared.len() * size_of::<u32>();
+            for (key, value) in inline {
+                size += Self::entry_size_v6(key, value);
             }
             size
         }
     }
 
+    /// Check whether all xattr pairs can be represented by the RAFS v6 on-disk format.
+    pub fn validate_v6(&self) -> Result<()> {
+        for (key, value) in self.pairs.iter() {
+            let (_, prefix_len) = Self::match_prefix(key)?;
+            if key.byte_size() - prefix_len > u8::MAX as usize {
+                return Err(einval!(format!(
+                    "xattr name {:?} is too long, suffix after the namespace prefix is {} bytes, max {} bytes",
+                    key,
+                    key.byte_size() - prefix_len,
+                    u8::MAX
+                )));
+            }
+            if value.len() > RAFSV6_XATTR_VALUE_SIZE_MAX {
+                return Err(einval!(format!(
+                    "value of xattr {:?} is too big, {} bytes, max {} bytes",
+                    key,
+                    value.len(),
+                    RAFSV6_XATTR_VALUE_SIZE_MAX
+                )));
+            }
+        }
+        if self.aligned_size_v6() > RAFSV6_XATTR_INLINE_AREA_MAX {
+            return Err(einval!(format!(
+                "inline xattrs are too big, {} bytes, max {} bytes",
+                self.aligned_size_v6(),
+                RAFSV6_XATTR_INLINE_AREA_MAX
+            )));
+        }
+
+        Ok(())
+    }
+
     /// Write Xattr to rafsv6 ondisk inode.
-    pub fn store_v6(&self, w: &mut dyn RafsIoWrite) -> Result<usize> {
-        let header = RafsV6XattrIbodyHeader::new();
-        w.write_all(header.as_ref())?;
+    ///
+    /// Big xattr pairs are stored into `shared_xattrs`, and referenced by the shared xattr id
+    /// array following the ibody header.
+    pub fn store_v6(
+        &self,
+        w: &mut dyn RafsIoWrite,
+        shared_xattrs: &mut RafsV6SharedXattrs,
+    ) -> Result<usize> {
+        self.validate_v6()?;
 
-        if !self.pairs.is_empty() {
-            for (key, value) in self.pairs.iter() {
-                let (index, prefix_len) = Self::match_prefix(key)
-                    .map_err(|_| einval!(format!("invalid xattr key {:?}", key)))?;
-                if key.len() < prefix_len {
-                    return Err(einval!(format!("invalid xattr key {:?}", key)));
-                }
-                if value.len() > u16::MAX as usize {
-                    return Err(einval!("xattr value size is too big"));
-                }
+        let (shared, inline) = self.split_v6();
+        let mut header = RafsV6XattrIbodyHeader::new();
+        header.set_shared_count(shared.len() as u8);
+        w.write_all(header.as_ref())?;
+        for (key, value) in shared {
+            let id = shared_xattrs.add(key, value)?;
+            w.write_all(&id.to_le_bytes())?;
+        }
+        for (key, value) in inline {
+            let mut buf = Vec::with_capacity(Self::entry_size_v6(key, value));
+            Self::encode_entry_v6(key, value, &mut buf)?;
+            w.write_all(&buf)?;
+        }
 
-                let mut entry = RafsV6XattrEntry::new();
-                entry.set_name_len((key.byte_size() - prefix_len) as u8);
-                entry.set_name_index(index);
-                entry.set_value_size(value.len() as u16);
+        Ok(0)
+    }
 
-                w.write_all(entry.as_ref())?;
-                w.write_all(&key.as_bytes()[prefix_len..])?;
-                w.write_all(value.as_ref())?;
+    // Split xattr pairs into shared ones and inline ones, both sorted by name.
+    #[allow(clippy::type_complexity)]
+    fn split_v6(&self) -> (Vec<(&OsString, &XattrValue)>, Vec<(&OsString, &XattrValue)>) {
+        let mut pairs: Vec<(&OsString, &XattrValue)> = self.pairs.iter().collect();
+        pairs.sort_by(|a, b| a.0.cmp(b.0));
 
-                let size =
-                    size_of::<RafsV6XattrEntry>() + key.byte_size() - prefix_len + value.len();
-                let padding =
-                    round_up(size as u64, size_of::<RafsV6XattrEntry>() as u64) as usize - size;
-                w.write_padding(padding)?;
+        let mut shared = Vec::new();
+        let mut inline = Vec::new();
+        for (key, value) in pairs {
+            if Self::entry_size_v6(key, value) > RAFSV6_XATTR_INLINE_ENTRY_MAX
+                && shared.len() < u8::MAX as usize
+            {
+                shared.push((key, value));
+            } else {
+                inline.push((key, value));
             }
         }
 
-        Ok(0)
+        (shared, inline)
+    }
+
+    // Get aligned size of the on-disk entry for a xattr pair.
+    fn entry_size_v6(key: &OsStr, value: &[u8]) -> usize {
+        // Safe to unwrap() because RafsXAttrs.add()/adds() has validated the prefix.
+        let (_, prefix_len) = Self::match_prefix(key).expect("xattr is not valid");
+        let size = size_of::<RafsV6XattrEntry>() + key.byte_size() - prefix_len + value.len();
+        round_up(size as u64, size_of::<RafsV6XattrEntry>() as u64) as usize
+    }
+
+    // Encode a xattr pair into an on-disk entry, including padding.
+    fn encode_entry_v6(key: &OsStr, value: &[u8], buf: &mut Vec<u8>) -> Result<()> {
+        let (index, prefix_len) =
+            Self::match_prefix(key).map_err(|_| einval!(format!("invalid xattr key {:?}", key)))?;
+        if key.len() < prefix_len {
+            return Err(einval!(format!("invalid xattr key {:?}", key)));
+        }
+        if value.len() > RAFSV6_XATTR_VALUE_SIZE_MAX {
+            return Err(einval!(format!(
+                "value of xattr {:?} is too big, {} bytes, max {} bytes",
+                key,
+                value.len(),
+                RAFSV6_XATTR_VALUE_SIZE_MAX
+            )));
+        }
+
+        let mut entry = RafsV6XattrEntry::new();
+        entry.set_name_len((key.byte_size() - prefix_len) as u8);
+        entry.set_name_index(index);
+        entry.set_value_size(value.len() as u16);
+
+        let start = buf.len();
+        buf.extend_from_slice(entry.as_ref());
+        buf.extend_from_slice(&key.as_bytes()[prefix_len..]);
+        buf.extend_from_slice(value);
+        buf.resize(start + Self::entry_size_v6(key, value), 0);
+
+        Ok(())
     }
 
     fn match_prefix(key: &OsStr) -> Result<(u8, usize)> {
@@ -2129,6 +2703,60 @@ impl RafsXAttrs {
     }
 }
 
+/// Shared xattr area of RAFS v6 filesystems.
+///
+/// Xattr pairs too big to be stored inline are stored once into the shared xattr area, which
+/// starts at `s_xattr_blkaddr` of the superblock. Inodes reference them by shared xattr ids,
+/// which are offsets in the area in unit of 4 bytes.
+#[derive(Default)]
+pub struct RafsV6SharedXattrs {
+    ids: HashMap<(OsString, XattrValue), u32>,
+    data: Vec<u8>,
+}
+
+impl RafsV6SharedXattrs {
+    /// Create a new instance of `RafsV6SharedXattrs`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a xattr pair into the shared area and return its shared xattr id.
+    ///
+    /// Identical xattr pairs are stored only once.
+    pub fn add(&mut self, key: &OsStr, value: &[u8]) -> Result<u32> {
+        let k = (key.to_os_string(), value.to_vec());
+        if let Some(id) = self.ids.get(&k) {
+            return Ok(*id);
+        }
+
+        let offset = self.data.len() / size_of::<RafsV6XattrEntry>();
+        if offset > u32::MAX as usize {
+            return Err(einval!("too many shared xattrs"));
+        }
+        RafsXAttrs::encode_entry_v6(key, value, &mut self.data)?;
+        self.ids.insert(k, offset as u32);
+
+        Ok(offset as u32)
+    }
+
+    /// Check whether the shared xattr area is empty.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Get size of the shared xattr area.
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl RafsStore for RafsV6SharedXattrs {
+    fn store(&self, w: &mut dyn RafsIoWrite) -> Result<usize> {
+        w.write_all(&self.data)?;
+        Ok(self.data.len())
+    }
+}
+
 #[derive(Clone, Default, Debug)]
 pub struct RafsV6PrefetchTable {
     /// List of inode numbers for prefetch.
@@ -2436,7 +3064,9 @@ mod tests {
             )
             .unwrap();
         xattrs.add(OsString::from("user.nydus"), vec![1u8]).unwrap();
-        xattrs.store_v6(&mut writer).unwrap();
+        let mut shared_xattrs = RafsV6SharedXattrs::new();
+        xattrs.store_v6(&mut writer, &mut shared_xattrs).unwrap();
+        assert!(shared_xattrs.is_empty());
         writer.flush().unwrap();
 
         let mut header = RafsV6XattrIbodyHeader::new();
@@ -2501,6 +3131,50 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_rafs_xattr_shared_v6() {
+        let mut xattrs = RafsXAttrs::new();
+        xattrs
+            .add(OsString::from("security.ima"), vec![0x5au8; 1024])
+            .unwrap();
+        xattrs.add(OsString::from("user.a"), vec![1u8]).unwrap();
+        xattrs.validate_v6().unwrap();
+        // Header, one shared xattr id and the inline "user.a" entry.
+        assert_eq!(xattrs.aligned_size_v6(), 12 + 4 + 8);
+        assert_eq!(xattrs.count_v6(), 4);
+
+        let mut writer = BufWriter::new(TempFile::new().unwrap().into_file());
+        let mut shared_xattrs = RafsV6SharedXattrs::new();
+        xattrs.store_v6(&mut writer, &mut shared_xattrs).unwrap();
+        // "security." is stored as name index, only "ima" is stored in the entry.
+        assert_eq!(shared_xattrs.size(), 4 + 3 + 1024 + 1);
+
+        // Identical xattr pairs are shared.
+        let mut xattrs2 = RafsXAttrs::new();
+        xattrs2
+            .add(OsString::from("security.ima"), vec![0x5au8; 1024])
+            .unwrap();
+        xattrs2.store_v6(&mut writer, &mut shared_xattrs).unwrap();
+        assert_eq!(shared_xattrs.size(), 4 + 3 + 1024 + 1);
+        let id = shared_xattrs
+            .add(OsStr::new("security.ima"), &[0xa5u8; 1024])
+            .unwrap();
+        assert_eq!(id as usize * 4, 4 + 3 + 1024 + 1);
+
+        let mut xattrs3 = RafsXAttrs::new();
+        xattrs3
+            .add(OsString::from("user.big"), vec![0u8; 0x10000])
+            .unwrap();
+        let err = xattrs3.validate_v6().unwrap_err();
+        assert!(err.to_string().contains("too big"));
+        xattrs3
+            .store_v6(&mut writer, &mut shared_xattrs)
+            .unwrap_err();
+        xattrs3
+            .add(OsString::from("user.big"), vec![0u8; 0x10001])
+            .unwrap_err();
+    }
+
     #[test]
     fn test_invalid_blob_idx_from_chunk_addr() {
         let mut addr = RafsV6InodeChunkAddr::new();
@@ -2568,6 +3242,32 @@ mod tests {
             ext.s_flags & RafsSuperFlags::ENCRYPTION_ASE_128_XTS.bits(),
             0
         );
+
+        assert!(!ext.has_signature());
+        ext.set_signature_offset(0x2000);
+        ext.set_signature_size(0x100);
+        assert!(ext.has_signature());
+        let buf = ext.as_ref();
+        let slot = RAFS_V6_SIGNATURE_SLOT_OFFSET as usize
+            - (EROFS_SUPER_OFFSET + EROFS_SUPER_BLOCK_SIZE) as usize;
+        assert_eq!(&buf[slot..slot + 8], &0x2000u64.to_le_bytes());
+        assert_eq!(&buf[slot + 8..slot + 12], &0x100u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_rafs_v6_signature_header() {
+        let header = RafsV6SignatureHeader::new(8, 256);
+        assert_eq!(header.magic(), RAFS_V6_SIGNATURE_MAGIC);
+        assert_eq!(header.signer_size(), 8);
+        assert_eq!(header.data_size(), 256);
+        assert!(header.validate(32 + 8 + 256).is_ok());
+        assert!(header.validate(32 + 8 + 255).is_err());
+
+        let mut header = RafsV6SignatureHeader::new(0, 256);
+        header.set_magic(0);
+        assert!(header.validate(4096).is_err());
+        let header = RafsV6SignatureHeader::new(8, 0);
+        assert!(header.validate(4096).is_err());
     }
 
     #[test]
@@ -2783,6 +3483,119 @@ mod tests {
         writer.flush().unwrap();
     }
 
+    #[test]
+    fn test_rafs_v6_blob_prefetch_ranges() {
+        let mut table = RafsV6BlobTable::new();
+        let mut header = BlobCompressionContextHeader::default();
+        header.set_aligned(true);
+        header.set_ci_uncompressed_size(2 * size_of::<BlobChunkInfoV1Ondisk>() as u64);
+        let blob_index = table.add(
+            "a".repeat(BLOB_SHA256_LEN),
+            0,
+            0,
+            0x1000,
+            2,
+            0x2000,
+            0x1800,
+            RafsSuperFlags { bits: 0 },
+            [0; 32],
+            [0; 32],
+            0,
+            0,
+            false,
+            header,
+            Arc::new(crypt::Algorithm::None.new_cipher().unwrap()),
+            None,
+        );
+        assert_eq!(table.prefetch_range_table_size(), 0);
+        let ranges = vec![(0, 0x800), (0x1000, 0x800)];
+        table
+            .set_prefetch_ranges(blob_index, ranges.clone())
+            .unwrap();
+        assert!(table.set_prefetch_ranges(1, Vec::new()).is_err());
+        assert_eq!(
+            table.prefetch_range_table_size(),
+            2 * size_of::<RafsV6BlobPrefetchRange>()
+        );
+
+        let (mut reader, mut writer) = get_streams();
+        table.store(&mut writer).unwrap();
+        let range_table_size = table.store_prefetch_ranges(&mut writer).unwrap();
+        writer.flush().unwrap();
+
+        let mut loaded = RafsV6BlobTable::new();
+        loaded
+            .load(
+                &mut reader,
+                table.size() as u32,
+                0x1000,
+                RafsSuperFlags { bits: 0 },
+                table.size() as u64,
+                range_table_size as u32,
+            )
+            .unwrap();
+        assert_eq!(loaded.get(0).unwrap().prefetch_ranges(), ranges.as_slice());
+
+        // The range table size must match range counts in blob entries.
+        reader.seek_to_offset(0).unwrap();
+        let mut loaded = RafsV6BlobTable::new();
+        assert!(loaded
+            .load(
+                &mut reader,
+                table.size() as u32,
+                0x1000,
+                RafsSuperFlags { bits: 0 },
+                table.size() as u64,
+                size_of::<RafsV6BlobPrefetchRange>() as u32,
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_rafs_v6_annotations() {
+        let mut annotations = RafsV6Annotations::new();
+        assert!(annotations.insert("", "v").is_err());
+        assert!(annotations.insert("a=b", "v").is_err());
+        assert!(annotations.insert("a b", "v").is_err());
+        assert!(annotations
+            .insert(&"k".repeat(RAFS_V6_ANNOTATION_KEY_SIZE_MAX + 1), "v")
+            .is_err());
+        assert!(annotations
+            .insert("big", &"v".repeat(RAFS_V6_ANNOTATIONS_SIZE_MAX as usize))
+            .is_err());
+        assert!(annotations.is_empty());
+
+        annotations.insert("org.example.label", "value").unwrap();
+        annotations.insert("io.nydus.builder", "v2.2").unwrap();
+        annotations.insert("empty", "").unwrap();
+        assert_eq!(annotations.get("io.nydus.builder"), Some("v2.2"));
+        assert_eq!(
+            annotations.size(),
+            3 * 5 + "org.example.label".len() + 5 + "io.nydus.builder".len() + 4 + 5
+        );
+
+        let (mut reader, mut writer) = get_streams();
+        let size = annotations.store(&mut writer).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(size, annotations.size());
+        let loaded = RafsV6Annotations::load(&mut reader, 0, size as u32).unwrap();
+        assert_eq!(loaded, annotations);
+        assert!(RafsV6Annotations::load(&mut reader, 0, size as u32 - 1).is_err());
+        assert!(RafsV6Annotations::load(&mut reader, 0, 0)
+            .unwrap()
+            .is_empty());
+
+        // Keys must be sorted and unique.
+        let (mut reader, mut writer) = get_streams();
+        for key in ["b", "a"] {
+            writer.write_all(&[1]).unwrap();
+            writer.write_all(&0u32.to_le_bytes()).unwrap();
+            writer.write_all(key.as_bytes()).unwrap();
+        }
+        writer.flush().unwrap();
+        assert!(RafsV6Annotations::load(&mut reader, 0, 12).is_err());
+    }
+
     #[test]
     fn test_rafs_v6_xattr_entry() {
         let ent = RafsV6XattrEntry::new();
@@ -249,6 +249,10 @@ impl BlobChunkInfo for V5IoChunk {
         false
     }
 
+    fn compressor(&self) -> Option<compress::Algorithm> {
+        self.flags.compressor()
+    }
+
     fn as_any(&self) -> &dyn Any {
         self
     }
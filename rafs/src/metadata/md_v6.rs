@@ -33,6 +33,7 @@ impl RafsSuper {
         self.meta.magic = sb.magic();
         self.meta.meta_blkaddr = sb.meta_addr();
         self.meta.root_nid = sb.root_nid();
+        self.meta.xattr_blkaddr = sb.xattr_addr();
         self.meta.blob_device_table_count = sb.extra_devices() as u32;
         self.meta.blob_device_table_offset = sb.device_table_offset();
 
@@ -44,6 +45,10 @@ impl RafsSuper {
         self.meta.blob_table_size = ext_sb.blob_table_size();
         self.meta.chunk_table_offset = ext_sb.chunk_table_offset();
         self.meta.chunk_table_size = ext_sb.chunk_table_size();
+        self.meta.blob_prefetch_table_offset = ext_sb.blob_prefetch_table_offset();
+        self.meta.blob_prefetch_table_size = ext_sb.blob_prefetch_table_size();
+        self.meta.annotations_offset = ext_sb.annotations_offset();
+        self.meta.annotations_size = ext_sb.annotations_size();
         self.meta.inodes_count = sb.inodes_count();
 
         self.meta.flags = RafsSuperFlags::from_bits(ext_sb.flags())
@@ -32,7 +32,7 @@ use nydus_utils::{compress, crypt};
 use serde::Serialize;
 
 use self::layout::v5::RafsV5PrefetchTable;
-use self::layout::v6::RafsV6PrefetchTable;
+use self::layout::v6::{RafsV6Annotations, RafsV6PrefetchTable};
 use self::layout::{XattrName, XattrValue, RAFS_SUPER_VERSION_V5, RAFS_SUPER_VERSION_V6};
 use self::noop::NoopSuperBlock;
 use crate::fs::{RAFS_DEFAULT_ATTR_TIMEOUT, RAFS_DEFAULT_ENTRY_TIMEOUT};
@@ -69,7 +69,7 @@ pub const DOTDOT: &str = "..";
 pub type Inode = u64;
 pub type ArcRafsInodeExt = Arc<dyn RafsInodeExt>;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct RafsBlobExtraInfo {
     /// Mapped block address from RAFS v6 devslot table.
     ///
@@ -301,6 +301,20 @@ bitflags! {
         const INLINED_CHUNK_DIGEST = 0x0000_0100;
         /// RAFS works in Tarfs mode, which directly uses tar streams as data blobs.
         const TARTFS_MODE = 0x0000_0200;
+        /// Data chunks are compressed with lz4hc, in lz4 block format.
+        const COMPRESSION_LZ4HC = 0x0000_0400;
+        /// Data chunks are compressed in lz4 frame format.
+        const COMPRESSION_LZ4_FRAME = 0x0000_0800;
+        /// Data chunks are compressed with custom compression algorithms registered at runtime.
+        const COMPRESSION_CUSTOM_0 = 0x0001_0000;
+        const COMPRESSION_CUSTOM_1 = 0x0002_0000;
+        const COMPRESSION_CUSTOM_2 = 0x0004_0000;
+        const COMPRESSION_CUSTOM_3 = 0x0008_0000;
+        /// Use custom digest algorithms registered at runtime to calculate digest.
+        const HASH_CUSTOM_0 = 0x0010_0000;
+        const HASH_CUSTOM_1 = 0x0020_0000;
+        const HASH_CUSTOM_2 = 0x0040_0000;
+        const HASH_CUSTOM_3 = 0x0080_0000;
         /// Data chunks are not encrypted.
         const ENCRYPTION_NONE = 0x0100_0000;
         /// Data chunks are encrypted with AES-128-XTS.
@@ -316,6 +330,51 @@ bitflags! {
     }
 }
 
+impl RafsSuperFlags {
+    /// Get flags recording the compression algorithm of data chunks.
+    pub fn compression_flags() -> Self {
+        RafsSuperFlags::COMPRESSION_NONE
+            | RafsSuperFlags::COMPRESSION_LZ4
+            | RafsSuperFlags::COMPRESSION_GZIP
+            | RafsSuperFlags::COMPRESSION_ZSTD
+            | RafsSuperFlags::COMPRESSION_LZ4HC
+            | RafsSuperFlags::COMPRESSION_LZ4_FRAME
+            | RafsSuperFlags::COMPRESSION_CUSTOM_0
+            | RafsSuperFlags::COMPRESSION_CUSTOM_1
+            | RafsSuperFlags::COMPRESSION_CUSTOM_2
+            | RafsSuperFlags::COMPRESSION_CUSTOM_3
+    }
+
+    /// Get flags recording the digest algorithm of data chunks.
+    pub fn hash_flags() -> Self {
+        RafsSuperFlags::HASH_BLAKE3
+            | RafsSuperFlags::HASH_SHA256
+            | RafsSuperFlags::HASH_CUSTOM_0
+            | RafsSuperFlags::HASH_CUSTOM_1
+            | RafsSuperFlags::HASH_CUSTOM_2
+            | RafsSuperFlags::HASH_CUSTOM_3
+    }
+
+    /// Check that custom compression and digest algorithms in use have been registered.
+    pub fn validate_algorithms(&self) -> Result<()> {
+        let compressor = compress::Algorithm::from(*self);
+        if compressor.is_custom() && compress::Algorithm::try_from(compressor as u32).is_err() {
+            return Err(einval!(format!(
+                "compression algorithm {:?} isn't registered",
+                compressor
+            )));
+        }
+        let digester = digest::Algorithm::from(*self);
+        if digester.is_custom() && digest::Algorithm::try_from(digester as u32).is_err() {
+            return Err(einval!(format!(
+                "digest algorithm {:?} isn't registered",
+                digester
+            )));
+        }
+        Ok(())
+    }
+}
+
 impl Default for RafsSuperFlags {
     fn default() -> Self {
         RafsSuperFlags::empty()
@@ -334,6 +393,10 @@ impl From<RafsSuperFlags> for digest::Algorithm {
         match flags {
             x if x.contains(RafsSuperFlags::HASH_BLAKE3) => digest::Algorithm::Blake3,
             x if x.contains(RafsSuperFlags::HASH_SHA256) => digest::Algorithm::Sha256,
+            x if x.contains(RafsSuperFlags::HASH_CUSTOM_0) => digest::Algorithm::Custom0,
+            x if x.contains(RafsSuperFlags::HASH_CUSTOM_1) => digest::Algorithm::Custom1,
+            x if x.contains(RafsSuperFlags::HASH_CUSTOM_2) => digest::Algorithm::Custom2,
+            x if x.contains(RafsSuperFlags::HASH_CUSTOM_3) => digest::Algorithm::Custom3,
             _ => digest::Algorithm::Blake3,
         }
     }
@@ -344,6 +407,10 @@ impl From<digest::Algorithm> for RafsSuperFlags {
         match d {
             digest::Algorithm::Blake3 => RafsSuperFlags::HASH_BLAKE3,
             digest::Algorithm::Sha256 => RafsSuperFlags::HASH_SHA256,
+            digest::Algorithm::Custom0 => RafsSuperFlags::HASH_CUSTOM_0,
+            digest::Algorithm::Custom1 => RafsSuperFlags::HASH_CUSTOM_1,
+            digest::Algorithm::Custom2 => RafsSuperFlags::HASH_CUSTOM_2,
+            digest::Algorithm::Custom3 => RafsSuperFlags::HASH_CUSTOM_3,
         }
     }
 }
@@ -355,6 +422,12 @@ impl From<RafsSuperFlags> for compress::Algorithm {
             x if x.contains(RafsSuperFlags::COMPRESSION_LZ4) => compress::Algorithm::Lz4Block,
             x if x.contains(RafsSuperFlags::COMPRESSION_GZIP) => compress::Algorithm::GZip,
             x if x.contains(RafsSuperFlags::COMPRESSION_ZSTD) => compress::Algorithm::Zstd,
+            x if x.contains(RafsSuperFlags::COMPRESSION_LZ4HC) => compress::Algorithm::Lz4Hc,
+            x if x.contains(RafsSuperFlags::COMPRESSION_LZ4_FRAME) => compress::Algorithm::Lz4Frame,
+            x if x.contains(RafsSuperFlags::COMPRESSION_CUSTOM_0) => compress::Algorithm::Custom0,
+            x if x.contains(RafsSuperFlags::COMPRESSION_CUSTOM_1) => compress::Algorithm::Custom1,
+            x if x.contains(RafsSuperFlags::COMPRESSION_CUSTOM_2) => compress::Algorithm::Custom2,
+            x if x.contains(RafsSuperFlags::COMPRESSION_CUSTOM_3) => compress::Algorithm::Custom3,
             _ => compress::Algorithm::Lz4Block,
         }
     }
@@ -367,6 +440,12 @@ impl From<compress::Algorithm> for RafsSuperFlags {
             compress::Algorithm::Lz4Block => RafsSuperFlags::COMPRESSION_LZ4,
             compress::Algorithm::GZip => RafsSuperFlags::COMPRESSION_GZIP,
             compress::Algorithm::Zstd => RafsSuperFlags::COMPRESSION_ZSTD,
+            compress::Algorithm::Lz4Hc => RafsSuperFlags::COMPRESSION_LZ4HC,
+            compress::Algorithm::Lz4Frame => RafsSuperFlags::COMPRESSION_LZ4_FRAME,
+            compress::Algorithm::Custom0 => RafsSuperFlags::COMPRESSION_CUSTOM_0,
+            compress::Algorithm::Custom1 => RafsSuperFlags::COMPRESSION_CUSTOM_1,
+            compress::Algorithm::Custom2 => RafsSuperFlags::COMPRESSION_CUSTOM_2,
+            compress::Algorithm::Custom3 => RafsSuperFlags::COMPRESSION_CUSTOM_3,
         }
     }
 }
@@ -514,10 +593,20 @@ pub struct RafsSuperMeta {
     pub meta_blkaddr: u32,
     /// Root nid for RAFS v6.
     pub root_nid: u16,
+    /// Shared xattr block address for RAFS v6, zero if there's no shared xattr area.
+    pub xattr_blkaddr: u32,
     /// Offset of the chunk table for RAFS v6.
     pub chunk_table_offset: u64,
     /// Size  of the chunk table for RAFS v6.
     pub chunk_table_size: u64,
+    /// Offset of the blob prefetch range table for RAFS v6.
+    pub blob_prefetch_table_offset: u64,
+    /// Size of the blob prefetch range table for RAFS v6.
+    pub blob_prefetch_table_size: u32,
+    /// Offset of the annotation region for RAFS v6.
+    pub annotations_offset: u64,
+    /// Size of the annotation region for RAFS v6.
+    pub annotations_size: u32,
 }
 
 impl RafsSuperMeta {
@@ -617,9 +706,14 @@ impl Default for RafsSuperMeta {
             entry_timeout: Duration::from_secs(RAFS_DEFAULT_ENTRY_TIMEOUT),
             meta_blkaddr: 0,
             root_nid: 0,
+            xattr_blkaddr: 0,
             is_chunk_dict: false,
             chunk_table_offset: 0,
             chunk_table_size: 0,
+            blob_prefetch_table_offset: 0,
+            blob_prefetch_table_size: 0,
+            annotations_offset: 0,
+            annotations_size: 0,
         }
     }
 }
@@ -1079,6 +1173,19 @@ impl RafsSuper {
         }
     }
 
+    /// Get key/value annotations embedded in the bootstrap, always empty for RAFS v5.
+    pub fn get_annotations(&self, bootstrap: &mut RafsIoReader) -> Result<RafsV6Annotations> {
+        if self.meta.is_v5() {
+            Ok(RafsV6Annotations::new())
+        } else {
+            RafsV6Annotations::load(
+                bootstrap,
+                self.meta.annotations_offset,
+                self.meta.annotations_size,
+            )
+        }
+    }
+
     /// Walk through the file tree rooted at ino, calling cb for each file or directory
     /// in the tree by DFS order, including ino, please ensure ino is a directory.
     pub fn walk_directory<P: AsRef<Path>>(
@@ -1161,6 +1268,18 @@ mod tests {
             compress::Algorithm::from(RafsSuperFlags::empty()),
             compress::Algorithm::Lz4Block
         );
+        assert_eq!(
+            compress::Algorithm::from(RafsSuperFlags::COMPRESSION_LZ4HC),
+            compress::Algorithm::Lz4Hc
+        );
+        assert_eq!(
+            compress::Algorithm::from(RafsSuperFlags::COMPRESSION_LZ4_FRAME),
+            compress::Algorithm::Lz4Frame
+        );
+        assert_eq!(
+            RafsSuperFlags::from(compress::Algorithm::Lz4Frame),
+            RafsSuperFlags::COMPRESSION_LZ4_FRAME
+        );
     }
 
     #[test]
@@ -1183,6 +1302,27 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_rafs_custom_algorithms() {
+        let flags = RafsSuperFlags::from(compress::Algorithm::Custom1)
+            | RafsSuperFlags::from(digest::Algorithm::Custom2);
+        assert_eq!(
+            flags,
+            RafsSuperFlags::COMPRESSION_CUSTOM_1 | RafsSuperFlags::HASH_CUSTOM_2
+        );
+        assert_eq!(
+            compress::Algorithm::from(flags),
+            compress::Algorithm::Custom1
+        );
+        assert_eq!(digest::Algorithm::from(flags), digest::Algorithm::Custom2);
+        assert!(RafsSuperFlags::compression_flags().contains(RafsSuperFlags::COMPRESSION_CUSTOM_1));
+        assert!(RafsSuperFlags::hash_flags().contains(RafsSuperFlags::HASH_CUSTOM_2));
+        // Custom algorithms must be registered before use.
+        assert!(flags.validate_algorithms().is_err());
+        let flags = RafsSuperFlags::COMPRESSION_ZSTD | RafsSuperFlags::HASH_SHA256;
+        assert!(flags.validate_algorithms().is_ok());
+    }
+
     #[test]
     fn test_rafs_crypt_from() {
         assert_eq!(
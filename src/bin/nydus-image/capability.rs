@@ -0,0 +1,154 @@
+// Copyright (C) 2024 Nydus Developers. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Summary of features an image depends on at runtime.
+//!
+//! Feature bits are scattered among superblock flags, the blob table and blob meta, the summary
+//! collects them in one place so operators can tell whether an image needs a newer nydusd.
+
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use nydus_rafs::metadata::{RafsSuper, RafsSuperFlags, RafsVersion};
+use nydus_storage::device::BlobFeatures;
+use nydus_storage::meta::format_blob_features;
+use nydus_utils::crypt;
+use serde::Serialize;
+
+/// Image capabilities collected from RAFS metadata.
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct ImageCapabilities {
+    /// RAFS filesystem version (5 or 6).
+    pub fs_version: String,
+    /// Superblock feature flags.
+    pub flags: String,
+    /// Size of data chunks.
+    pub chunk_size: u32,
+    /// Size of batch chunks, zero if batch chunks are disabled.
+    pub batch_size: u32,
+    /// Compression algorithms used by the filesystem and data blobs.
+    pub compressors: BTreeSet<String>,
+    /// Digest algorithms used by the filesystem and data blobs.
+    pub digesters: BTreeSet<String>,
+    /// Encryption algorithms used by data blobs.
+    pub ciphers: BTreeSet<String>,
+    /// Inodes have explicit uid/gid, otherwise nydusd euid/egid are used for all inodes.
+    pub explicit_uid_gid: bool,
+    /// Inodes may have extended attributes.
+    pub xattr: bool,
+    /// The filesystem has a prefetch table.
+    pub prefetch: bool,
+    /// The filesystem works in TARFS mode.
+    pub tarfs: bool,
+    /// Chunk digests are inlined in data blobs.
+    pub inlined_chunk_digest: bool,
+    /// Union of features of all data blobs.
+    pub blob_features: BTreeSet<String>,
+}
+
+impl ImageCapabilities {
+    pub fn new(rs: &RafsSuper) -> Self {
+        let meta = &rs.meta;
+        let mut caps = ImageCapabilities {
+            fs_version: RafsVersion::try_from(meta.version)
+                .map(|v| v.to_string())
+                .unwrap_or_else(|_| format!("0x{:x}", meta.version)),
+            flags: meta.flags.to_string(),
+            chunk_size: meta.chunk_size,
+            batch_size: meta.batch_size,
+            explicit_uid_gid: meta.explicit_uidgid(),
+            xattr: meta.has_xattr(),
+            prefetch: meta.prefetch_table_entries > 0,
+            tarfs: meta.flags.contains(RafsSuperFlags::TARTFS_MODE),
+            inlined_chunk_digest: meta.has_inlined_chunk_digest(),
+            ..Default::default()
+        };
+        caps.compressors.insert(meta.get_compressor().to_string());
+        caps.digesters.insert(meta.get_digester().to_string());
+
+        let mut features = BlobFeatures::empty();
+        for blob in rs.superblock.get_blob_infos() {
+            caps.compressors.insert(blob.compressor().to_string());
+            caps.digesters.insert(blob.digester().to_string());
+            if blob.cipher() != crypt::Algorithm::None {
+                caps.ciphers.insert(blob.cipher().to_string());
+            }
+            features |= blob.features();
+        }
+        caps.blob_features = format_blob_features(features)
+            .split_whitespace()
+            .map(|f| f.to_string())
+            .collect();
+
+        caps
+    }
+}
+
+impl Display for ImageCapabilities {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let join = |set: &BTreeSet<String>| set.iter().cloned().collect::<Vec<_>>().join(" ");
+        write!(
+            f,
+            r#"
+    Version:                {version}
+    Flags:                  {flags}
+    Chunk Size:             0x{chunk_size:x}
+    Batch Size:             0x{batch_size:x}
+    Compressors:            {compressors}
+    Digesters:              {digesters}
+    Ciphers:                {ciphers}
+    Explicit UID/GID:       {explicit_uid_gid}
+    Extended Attributes:    {xattr}
+    Prefetch Table:         {prefetch}
+    TARFS Mode:             {tarfs}
+    Inlined Chunk Digest:   {inlined_chunk_digest}
+    Blob Features:          {blob_features}
+    "#,
+            version = self.fs_version,
+            flags = self.flags,
+            chunk_size = self.chunk_size,
+            batch_size = self.batch_size,
+            compressors = join(&self.compressors),
+            digesters = join(&self.digesters),
+            ciphers = join(&self.ciphers),
+            explicit_uid_gid = self.explicit_uid_gid,
+            xattr = self.xattr,
+            prefetch = self.prefetch,
+            tarfs = self.tarfs,
+            inlined_chunk_digest = self.inlined_chunk_digest,
+            blob_features = join(&self.blob_features),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nydus_api::ConfigV2;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_image_capabilities() {
+        let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
+        let config = Arc::new(ConfigV2::default());
+
+        let path = PathBuf::from(root_dir).join("tests/texture/bootstrap/rafs-v6-2.2.boot");
+        let (rs, _) = RafsSuper::load_from_file(&path, config.clone(), false).unwrap();
+        let caps = ImageCapabilities::new(&rs);
+        assert_eq!(caps.fs_version, "6");
+        assert!(caps.compressors.contains("Zstd"));
+        assert!(caps.digesters.contains("Blake3"));
+        assert!(caps.explicit_uid_gid);
+        assert!(caps.inlined_chunk_digest);
+        assert!(caps.ciphers.is_empty());
+        assert!(!caps.tarfs);
+
+        let path = PathBuf::from(root_dir).join("tests/texture/bootstrap/rafs-v5.boot");
+        let (rs, _) = RafsSuper::load_from_file(&path, config, false).unwrap();
+        let caps = ImageCapabilities::new(&rs);
+        assert_eq!(caps.fs_version, "5");
+        assert!(!caps.inlined_chunk_digest);
+    }
+}
@@ -0,0 +1,378 @@
+// Copyright (C) 2024 Nydus Developers. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Recommend chunking parameters by analyzing a source directory.
+//!
+//! Regular files of the source directory are sampled and split into chunks of candidate sizes, to
+//! predict the number of chunks, the chunk deduplication rate and the compressed blob size of
+//! each combination of chunk size, chunking mode and compressor.
+
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use nydus_utils::compress;
+use nydus_utils::digest::{self, RafsDigest};
+use serde::Serialize;
+
+/// Candidate chunk sizes, all of them divide `READ_BLOCK_SIZE`.
+const CHUNK_SIZES: [u32; 4] = [0x1_0000, 0x4_0000, 0x10_0000, 0x40_0000];
+/// Candidate compressors, with names accepted by `--compressor`.
+const COMPRESSORS: [(&str, compress::Algorithm); 3] = [
+    ("zstd", compress::Algorithm::Zstd),
+    ("lz4_block", compress::Algorithm::Lz4Block),
+    ("none", compress::Algorithm::None),
+];
+/// Batch size for the batch chunking mode, chunks smaller than it are compressed together.
+const BATCH_SIZE: u32 = 0x1_0000;
+const READ_BLOCK_SIZE: usize = 0x40_0000;
+/// Approximate metadata size of a unique chunk: chunk table entry, chunk info in blob meta and
+/// inlined chunk digest.
+const UNIQUE_CHUNK_META_SIZE: u64 = 80;
+/// Approximate metadata size of a chunk reference in an inode.
+const CHUNK_REF_META_SIZE: u64 = 8;
+/// Options with predicted image size within the ratio of the smallest one are considered
+/// equivalent, and the one with fewer chunks is preferred to reduce metadata and requests.
+const SIZE_TOLERANCE: f64 = 0.01;
+/// Upper bounds of file size distribution buckets.
+const FILE_SIZE_BUCKETS: [u64; 4] = [0x1000, 0x1_0000, 0x10_0000, 0x100_0000];
+
+/// Number and total size of files in a size range.
+#[derive(Default, Serialize)]
+pub struct FileSizeBucket {
+    /// Exclusive upper bound of file size, `None` for the last bucket.
+    pub max_size: Option<u64>,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Predictions for a combination of chunking parameters.
+#[derive(Clone, Serialize)]
+pub struct ChunkingOption {
+    pub chunk_size: u32,
+    /// Batch size to merge small chunks, zero means fixed size chunking.
+    pub batch_size: u32,
+    pub compressor: String,
+    /// Predicted number of chunks referenced by files.
+    pub chunks: u64,
+    /// Predicted number of chunks after deduplication.
+    pub unique_chunks: u64,
+    /// Ratio of data eliminated by chunk deduplication.
+    pub dedup_rate: f64,
+    /// Predicted size of the data blob.
+    pub blob_size: u64,
+    /// Predicted size of chunk related metadata.
+    pub meta_size: u64,
+}
+
+/// Result of chunking analysis.
+#[derive(Serialize)]
+pub struct ChunkingReport {
+    pub files: u64,
+    pub bytes: u64,
+    pub sampled_files: u64,
+    pub sampled_bytes: u64,
+    pub file_sizes: Vec<FileSizeBucket>,
+    pub options: Vec<ChunkingOption>,
+    /// The recommended option, `None` if there's no data to analyze.
+    pub recommended: Option<ChunkingOption>,
+}
+
+impl ChunkingReport {
+    pub fn dump_json(&self, path: &Path) -> Result<()> {
+        let w = OpenOptions::new()
+            .truncate(true)
+            .create(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Output file {:?} can't be opened", path))?;
+
+        serde_json::to_writer_pretty(w, self).context("Write output file failed")?;
+
+        Ok(())
+    }
+}
+
+/// Statistics of sampled data split by a candidate chunk size.
+struct ChunkSizeStat {
+    chunk_size: u32,
+    chunks: u64,
+    bytes: u64,
+    digests: HashSet<RafsDigest>,
+    unique_bytes: u64,
+    // Compressed size of unique chunks by each compressor, in fixed and batch chunking mode.
+    fixed_sizes: [u64; COMPRESSORS.len()],
+    batch_sizes: [u64; COMPRESSORS.len()],
+    batch_buf: Vec<u8>,
+}
+
+impl ChunkSizeStat {
+    fn new(chunk_size: u32) -> Self {
+        ChunkSizeStat {
+            chunk_size,
+            chunks: 0,
+            bytes: 0,
+            digests: HashSet::new(),
+            unique_bytes: 0,
+            fixed_sizes: [0; COMPRESSORS.len()],
+            batch_sizes: [0; COMPRESSORS.len()],
+            batch_buf: Vec::with_capacity(BATCH_SIZE as usize * 2),
+        }
+    }
+
+    fn add_chunk(&mut self, data: &[u8]) -> Result<()> {
+        self.chunks += 1;
+        self.bytes += data.len() as u64;
+        let digest = RafsDigest::from_buf(data, digest::Algorithm::Blake3);
+        if !self.digests.insert(digest) {
+            return Ok(());
+        }
+        self.unique_bytes += data.len() as u64;
+
+        let batched = data.len() < BATCH_SIZE as usize;
+        for (idx, (_, algo)) in COMPRESSORS.iter().enumerate() {
+            let size = compressed_size(data, *algo)?;
+            self.fixed_sizes[idx] += size;
+            if !batched {
+                self.batch_sizes[idx] += size;
+            }
+        }
+        if batched {
+            self.batch_buf.extend_from_slice(data);
+            if self.batch_buf.len() >= BATCH_SIZE as usize {
+                self.flush_batch()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush_batch(&mut self) -> Result<()> {
+        if !self.batch_buf.is_empty() {
+            for (idx, (_, algo)) in COMPRESSORS.iter().enumerate() {
+                self.batch_sizes[idx] += compressed_size(&self.batch_buf, *algo)?;
+            }
+            self.batch_buf.clear();
+        }
+        Ok(())
+    }
+
+    fn options(&self, scale: f64) -> Vec<ChunkingOption> {
+        let chunks = (self.chunks as f64 * scale) as u64;
+        let unique_chunks = (self.digests.len() as f64 * scale) as u64;
+        let dedup_rate = if self.bytes == 0 {
+            0.0
+        } else {
+            1.0 - self.unique_bytes as f64 / self.bytes as f64
+        };
+        let meta_size = chunks * CHUNK_REF_META_SIZE + unique_chunks * UNIQUE_CHUNK_META_SIZE;
+
+        let mut options = Vec::with_capacity(COMPRESSORS.len() * 2);
+        for (batch_size, sizes) in [(0, &self.fixed_sizes), (BATCH_SIZE, &self.batch_sizes)] {
+            for (idx, (name, _)) in COMPRESSORS.iter().enumerate() {
+                options.push(ChunkingOption {
+                    chunk_size: self.chunk_size,
+                    batch_size,
+                    compressor: name.to_string(),
+                    chunks,
+                    unique_chunks,
+                    dedup_rate,
+                    blob_size: (sizes[idx] as f64 * scale) as u64,
+                    meta_size,
+                });
+            }
+        }
+        options
+    }
+}
+
+fn compressed_size(data: &[u8], algo: compress::Algorithm) -> Result<u64> {
+    if algo == compress::Algorithm::None {
+        return Ok(data.len() as u64);
+    }
+    let (buf, _) = compress::compress(data, algo).context("failed to compress chunk")?;
+    // Chunks are stored uncompressed if compression doesn't reduce the size.
+    Ok(std::cmp::min(buf.len(), data.len()) as u64)
+}
+
+/// Deterministically sample a file by its path, so repeated runs give the same result.
+fn is_sampled(path: &Path, ratio: f64) -> bool {
+    // FNV-1a hash of the path.
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for b in path.as_os_str().as_bytes() {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100_0000_01b3);
+    }
+    (hash % 10000) as f64 / 10000.0 < ratio
+}
+
+/// Walk the source directory to collect regular files and their sizes.
+fn collect_files(source: &Path) -> Result<Vec<(PathBuf, u64)>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![source.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries =
+            fs::read_dir(&dir).with_context(|| format!("failed to read directory {:?}", dir))?;
+        for entry in entries {
+            let path = entry?.path();
+            let md = path
+                .symlink_metadata()
+                .with_context(|| format!("failed to stat {:?}", path))?;
+            if md.is_dir() {
+                dirs.push(path);
+            } else if md.is_file() {
+                files.push((path, md.len()));
+            }
+        }
+    }
+    files.sort_unstable();
+    Ok(files)
+}
+
+/// Analyze the source directory to recommend chunking parameters.
+///
+/// Up to about `sample_size` bytes of files are read, predictions are scaled to the whole source.
+pub fn analyze(source: &Path, sample_size: u64) -> Result<ChunkingReport> {
+    let files = collect_files(source)?;
+    let total: u64 = files.iter().map(|(_, size)| size).sum();
+    let ratio = if total == 0 {
+        1.0
+    } else {
+        sample_size as f64 / total as f64
+    };
+
+    let mut file_sizes: Vec<FileSizeBucket> = FILE_SIZE_BUCKETS
+        .iter()
+        .map(|v| FileSizeBucket {
+            max_size: Some(*v),
+            ..Default::default()
+        })
+        .collect();
+    file_sizes.push(FileSizeBucket::default());
+    let mut stats: Vec<ChunkSizeStat> =
+        CHUNK_SIZES.iter().map(|v| ChunkSizeStat::new(*v)).collect();
+    let mut sampled_files = 0;
+    let mut sampled_bytes = 0;
+    let mut buf = vec![0u8; READ_BLOCK_SIZE];
+
+    for (path, size) in files.iter() {
+        let idx = FILE_SIZE_BUCKETS
+            .iter()
+            .position(|v| size < v)
+            .unwrap_or(FILE_SIZE_BUCKETS.len());
+        file_sizes[idx].files += 1;
+        file_sizes[idx].bytes += size;
+        if *size == 0 || (ratio < 1.0 && !is_sampled(path, ratio)) {
+            continue;
+        }
+
+        let mut file = File::open(path).with_context(|| format!("failed to open {:?}", path))?;
+        loop {
+            let len = read_block(&mut file, &mut buf)
+                .with_context(|| format!("failed to read {:?}", path))?;
+            if len == 0 {
+                break;
+            }
+            for stat in stats.iter_mut() {
+                for chunk in buf[..len].chunks(stat.chunk_size as usize) {
+                    stat.add_chunk(chunk)?;
+                }
+            }
+            sampled_bytes += len as u64;
+        }
+        sampled_files += 1;
+    }
+
+    let scale = if sampled_bytes == 0 {
+        0.0
+    } else {
+        total as f64 / sampled_bytes as f64
+    };
+    let mut options = Vec::new();
+    for stat in stats.iter_mut() {
+        stat.flush_batch()?;
+        options.extend(stat.options(scale));
+    }
+
+    Ok(ChunkingReport {
+        files: files.len() as u64,
+        bytes: total,
+        sampled_files,
+        sampled_bytes,
+        file_sizes,
+        recommended: recommend(&options),
+        options,
+    })
+}
+
+/// Fill `buf` from `file` unless EOF is reached, so chunk boundaries are kept.
+fn read_block(file: &mut File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+fn recommend(options: &[ChunkingOption]) -> Option<ChunkingOption> {
+    let image_size = |o: &ChunkingOption| o.blob_size + o.meta_size;
+    let min_size = options.iter().map(image_size).min()?;
+    if options.iter().all(|o| o.chunks == 0) {
+        return None;
+    }
+    let limit = min_size as f64 * (1.0 + SIZE_TOLERANCE);
+    options
+        .iter()
+        .filter(|o| image_size(o) as f64 <= limit)
+        .min_by_key(|o| (o.chunks, image_size(o)))
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_analyze_chunking() {
+        let tmpdir = TempDir::new().unwrap();
+        let dir = tmpdir.as_path();
+        let data: Vec<u8> = (0..0x20_0000u32).map(|v| (v % 251) as u8).collect();
+        fs::write(dir.join("file1"), &data).unwrap();
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/file2"), &data).unwrap();
+        fs::write(dir.join("sub/small"), b"small file").unwrap();
+        fs::write(dir.join("empty"), b"").unwrap();
+
+        let report = analyze(dir, u64::MAX).unwrap();
+        assert_eq!(report.files, 4);
+        assert_eq!(report.bytes, 0x40_0000 + 10);
+        assert_eq!(report.sampled_files, 3);
+        assert_eq!(report.sampled_bytes, report.bytes);
+        assert_eq!(report.file_sizes[0].files, 2);
+        assert_eq!(report.file_sizes[3].files, 2);
+        assert_eq!(
+            report.options.len(),
+            CHUNK_SIZES.len() * COMPRESSORS.len() * 2
+        );
+        for o in report.options.iter() {
+            assert!(o.dedup_rate >= 0.49);
+            if o.compressor == "none" {
+                assert_eq!(o.blob_size, 0x20_0000 + 10);
+            }
+        }
+        let recommended = report.recommended.unwrap();
+        assert_ne!(recommended.compressor, "none");
+
+        let tmpdir = TempDir::new().unwrap();
+        let report = analyze(tmpdir.as_path(), u64::MAX).unwrap();
+        assert!(report.recommended.is_none());
+    }
+}
@@ -0,0 +1,368 @@
+// Copyright (C) 2024 Nydus Developers. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Verify data chunks of a RAFS filesystem against chunk digests recorded in its metadata.
+//!
+//! Verifying all data of a large image from a remote backend may take hours, so progress may be
+//! saved into a checkpoint file periodically to resume verification later instead of restarting
+//! from the first chunk, and reading from backends may be throttled to limit bandwidth usage.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use nydus_api::ConfigV2;
+use nydus_rafs::metadata::chunk::ChunkWrapper;
+use nydus_storage::backend::BlobReader;
+use nydus_storage::device::{BlobFeatures, BlobInfo};
+use nydus_storage::factory::BlobFactory;
+use nydus_storage::utils::alloc_buf;
+use nydus_utils::compress;
+use nydus_utils::digest::RafsDigest;
+use serde::{Deserialize, Serialize};
+
+/// Interval to save verification progress into the checkpoint file.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A unique data chunk to verify, with one of the files referring to it for error messages.
+pub(crate) struct DataChunk {
+    pub chunk: Arc<ChunkWrapper>,
+    pub path: PathBuf,
+}
+
+/// Progress of data verification, which may be saved to resume verification later.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Checkpoint {
+    /// Digest of the RAFS metadata being verified.
+    pub bootstrap_digest: String,
+    /// Number of chunks handled, in order of blob index and compressed offset.
+    pub chunks: u64,
+    /// Bytes of data read from storage backends.
+    pub bytes: u64,
+    /// Number of batch, encrypted or ZRAN chunks skipped, which can't be verified separately.
+    pub skipped: u64,
+    /// Issues found in handled chunks.
+    pub issues: Vec<String>,
+}
+
+impl Checkpoint {
+    fn new(bootstrap_digest: &str) -> Self {
+        Checkpoint {
+            bootstrap_digest: bootstrap_digest.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Load progress of verifying the RAFS filesystem from `path`, or start over if it doesn't
+    /// exist.
+    fn load(path: &Path, bootstrap_digest: &str) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::new(bootstrap_digest));
+        }
+        let file = File::open(path)
+            .with_context(|| format!("failed to open checkpoint file {}", path.display()))?;
+        let checkpoint: Checkpoint = serde_json::from_reader(file)
+            .with_context(|| format!("failed to parse checkpoint file {}", path.display()))?;
+        if checkpoint.bootstrap_digest != bootstrap_digest {
+            bail!(
+                "checkpoint file {} is saved for RAFS metadata {}, but checking {}",
+                path.display(),
+                checkpoint.bootstrap_digest,
+                bootstrap_digest
+            );
+        }
+        Ok(checkpoint)
+    }
+
+    /// Save progress to `path`, through a temporary file so an interrupted save never corrupts
+    /// the previous checkpoint.
+    fn save(&self, path: &Path) -> Result<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let file = File::create(&tmp_path)
+            .with_context(|| format!("failed to create checkpoint file {:?}", tmp_path))?;
+        serde_json::to_writer(&file, self).context("failed to serialize checkpoint")?;
+        file.sync_all()
+            .with_context(|| format!("failed to sync checkpoint file {:?}", tmp_path))?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("failed to save checkpoint file {}", path.display()))
+    }
+}
+
+/// Limit average bandwidth by sleeping when data is read faster than the rate limit.
+struct Throttle {
+    rate: u64,
+    start: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    fn new(rate: u64) -> Self {
+        assert!(rate > 0);
+        Throttle {
+            rate,
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Account for `size` bytes read, and sleep until the average rate drops to the limit.
+    fn consume(&mut self, size: u64) {
+        self.bytes += size;
+        let expected = Duration::from_secs_f64(self.bytes as f64 / self.rate as f64);
+        let elapsed = self.start.elapsed();
+        if expected > elapsed {
+            thread::sleep(expected - elapsed);
+        }
+    }
+}
+
+enum ChunkState {
+    Valid(u64),
+    Invalid(String),
+    Skipped,
+}
+
+/// Verify data chunks by reading them from storage backends.
+pub(crate) struct DataChecker {
+    config: Arc<ConfigV2>,
+    blobs: Vec<Arc<BlobInfo>>,
+    readers: HashMap<u32, Arc<dyn BlobReader>>,
+    checkpoint: Option<PathBuf>,
+    throttle: Option<Throttle>,
+    time_limit: Option<Duration>,
+}
+
+impl DataChecker {
+    pub fn new(config: Arc<ConfigV2>, blobs: Vec<Arc<BlobInfo>>) -> Self {
+        DataChecker {
+            config,
+            blobs,
+            readers: HashMap::new(),
+            checkpoint: None,
+            throttle: None,
+            time_limit: None,
+        }
+    }
+
+    /// Save progress into `path` periodically, and resume from it if it exists.
+    pub fn set_checkpoint(&mut self, path: PathBuf) {
+        self.checkpoint = Some(path);
+    }
+
+    /// Limit bandwidth of reading data from storage backends, in bytes per second.
+    pub fn set_bandwidth(&mut self, rate: u64) {
+        self.throttle = Some(Throttle::new(rate));
+    }
+
+    /// Pause verification after `limit`, to be resumed from the checkpoint later.
+    pub fn set_time_limit(&mut self, limit: Duration) {
+        self.time_limit = Some(limit);
+    }
+
+    /// Verify `chunks`, sorted by blob index and compressed offset, and return the progress.
+    ///
+    /// Verification pauses when the time limit is reached, and progress is saved into the
+    /// checkpoint file, which is removed once all chunks have been handled. Chunks with corrupted
+    /// data are reported as issues, but failing to read data aborts verification so the chunk
+    /// will be retried when resumed.
+    pub fn check(&mut self, bootstrap_digest: &str, chunks: &[DataChunk]) -> Result<Checkpoint> {
+        let mut progress = match self.checkpoint.as_ref() {
+            Some(path) => Checkpoint::load(path, bootstrap_digest)?,
+            None => Checkpoint::new(bootstrap_digest),
+        };
+        ensure!(
+            progress.chunks <= chunks.len() as u64,
+            "checkpoint has {} chunks handled, but the filesystem has only {} chunks",
+            progress.chunks,
+            chunks.len()
+        );
+        if progress.chunks > 0 {
+            info!(
+                "resume data verification from chunk {}/{}",
+                progress.chunks,
+                chunks.len()
+            );
+        }
+
+        let start = Instant::now();
+        let mut saved = Instant::now();
+        for c in chunks[progress.chunks as usize..].iter() {
+            if matches!(self.time_limit, Some(limit) if start.elapsed() >= limit) {
+                break;
+            }
+            match self.check_chunk(&c.chunk) {
+                Ok(ChunkState::Valid(size)) => progress.bytes += size,
+                Ok(ChunkState::Invalid(msg)) => progress
+                    .issues
+                    .push(format!("{}, affected file: {:?}", msg, c.path)),
+                Ok(ChunkState::Skipped) => progress.skipped += 1,
+                Err(e) => {
+                    if let Some(path) = self.checkpoint.as_ref() {
+                        progress.save(path)?;
+                    }
+                    return Err(e);
+                }
+            }
+            progress.chunks += 1;
+            if let Some(path) = self.checkpoint.as_ref() {
+                if saved.elapsed() >= CHECKPOINT_INTERVAL {
+                    progress.save(path)?;
+                    saved = Instant::now();
+                }
+            }
+        }
+
+        if let Some(path) = self.checkpoint.as_ref() {
+            if progress.chunks < chunks.len() as u64 {
+                progress.save(path)?;
+            } else if path.exists() {
+                fs::remove_file(path).with_context(|| {
+                    format!("failed to remove checkpoint file {}", path.display())
+                })?;
+            }
+        }
+
+        Ok(progress)
+    }
+
+    fn check_chunk(&mut self, chunk: &ChunkWrapper) -> Result<ChunkState> {
+        if chunk.is_batch() || chunk.is_encrypted() {
+            return Ok(ChunkState::Skipped);
+        }
+        let blob = self
+            .blobs
+            .iter()
+            .find(|b| b.blob_index() == chunk.blob_index())
+            .ok_or_else(|| anyhow!("can not find blob by index: {}", chunk.blob_index()))?;
+        if blob.has_feature(BlobFeatures::ZRAN) {
+            return Ok(ChunkState::Skipped);
+        }
+        let blob_id = blob.blob_id();
+        let reader = match self.readers.entry(chunk.blob_index()) {
+            Entry::Occupied(e) => e.get().clone(),
+            Entry::Vacant(e) => {
+                let backend = BlobFactory::new_backend(self.config.get_backend_config()?, &blob_id)
+                    .with_context(|| format!("failed to create backend for blob {}", blob_id))?;
+                let reader = backend
+                    .get_reader(&blob_id)
+                    .map_err(|e| anyhow!("failed to get reader for blob {}, {:?}", blob_id, e))?;
+                e.insert(reader).clone()
+            }
+        };
+
+        let mut buf = alloc_buf(chunk.compressed_size() as usize);
+        let size = reader
+            .read_all(&mut buf, chunk.compressed_offset())
+            .map_err(|e| {
+                anyhow!(
+                    "failed to read chunk at offset 0x{:x} from blob {}, {:?}",
+                    chunk.compressed_offset(),
+                    blob_id,
+                    e
+                )
+            })?;
+        if let Some(throttle) = self.throttle.as_mut() {
+            throttle.consume(size as u64);
+        }
+        let desc = format!(
+            "chunk {} at offset 0x{:x} of blob {}",
+            chunk.id(),
+            chunk.compressed_offset(),
+            blob_id
+        );
+        if size != buf.len() {
+            return Ok(ChunkState::Invalid(format!(
+                "{} is truncated, read 0x{:x} of 0x{:x} bytes",
+                desc,
+                size,
+                buf.len()
+            )));
+        }
+
+        let data = if chunk.is_compressed() {
+            let compressor = chunk.compressor().unwrap_or_else(|| blob.compressor());
+            let mut data = alloc_buf(chunk.uncompressed_size() as usize);
+            match compress::decompress(&buf, &mut data, compressor) {
+                Ok(size) if size == data.len() => data,
+                Ok(size) => {
+                    return Ok(ChunkState::Invalid(format!(
+                        "{} has uncompressed size 0x{:x}, expect 0x{:x}",
+                        desc,
+                        size,
+                        data.len()
+                    )))
+                }
+                Err(e) => {
+                    return Ok(ChunkState::Invalid(format!(
+                        "{} can't be decompressed, {}",
+                        desc, e
+                    )))
+                }
+            }
+        } else {
+            buf
+        };
+        let digest = RafsDigest::from_buf(&data, blob.digester());
+        if &digest != chunk.id() {
+            return Ok(ChunkState::Invalid(format!(
+                "{} has mismatched data digest {}",
+                desc, digest
+            )));
+        }
+
+        Ok(ChunkState::Valid(size as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_checkpoint() {
+        let tmpdir = TempDir::new().unwrap();
+        let path = tmpdir.as_path().join("checkpoint");
+
+        let checkpoint = Checkpoint::load(&path, "digest1").unwrap();
+        assert_eq!(checkpoint, Checkpoint::new("digest1"));
+
+        let checkpoint = Checkpoint {
+            bootstrap_digest: "digest1".to_string(),
+            chunks: 10,
+            bytes: 0x10000,
+            skipped: 1,
+            issues: vec!["chunk is corrupted".to_string()],
+        };
+        checkpoint.save(&path).unwrap();
+        assert_eq!(Checkpoint::load(&path, "digest1").unwrap(), checkpoint);
+        assert!(Checkpoint::load(&path, "digest2").is_err());
+
+        let mut checker = DataChecker::new(Arc::new(ConfigV2::default()), Vec::new());
+        checker.set_checkpoint(path.clone());
+        assert!(checker.check("digest1", &[]).is_err());
+        assert!(checker.check("digest2", &[]).is_err());
+
+        Checkpoint::new("digest1").save(&path).unwrap();
+        let progress = checker.check("digest1", &[]).unwrap();
+        assert_eq!(progress.chunks, 0);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_throttle() {
+        let mut throttle = Throttle::new(0x10000);
+        let start = Instant::now();
+        throttle.consume(0x1000);
+        throttle.consume(0x3000);
+        assert!(start.elapsed() >= Duration::from_millis(250));
+    }
+}
@@ -297,6 +297,82 @@ impl Deduplicate<SqliteDatabase> {
     }
 }
 
+/// Collect chunks referenced at least `min_refcount` times by the images, together with blobs
+/// hosting them, to export a chunk dictionary from.
+///
+/// Chunks are identified by digest, and each reference from a file counts, while hardlinks to the
+/// same file count once.
+pub fn collect_shared_chunks(
+    bootstrap_paths: &[PathBuf],
+    config: Arc<ConfigV2>,
+    min_refcount: usize,
+) -> Result<(Vec<ChunkdictChunkInfo>, Vec<ChunkdictBlobInfo>)> {
+    let mut chunks: BTreeMap<String, (usize, ChunkdictChunkInfo)> = BTreeMap::new();
+    let mut blobs: BTreeMap<String, ChunkdictBlobInfo> = BTreeMap::new();
+
+    for bootstrap_path in bootstrap_paths {
+        let (sb, _) = RafsSuper::load_from_file(bootstrap_path, config.clone(), false)
+            .with_context(|| format!("Failed to load bootstrap {:?}", bootstrap_path))?;
+        let blob_infos = sb.superblock.get_blob_infos();
+        let image_reference = bootstrap_path.display().to_string();
+        let mut hardlinks = HashSet::new();
+        let process_chunk = &mut |t: &Tree| -> Result<()> {
+            let node = t.borrow_mut_node();
+            if node.is_hardlink() && !hardlinks.insert(node.inode.ino()) {
+                return Ok(());
+            }
+            for chunk in &node.chunks {
+                let blob = &blob_infos[chunk.inner.blob_index() as usize];
+                let (refcount, _) =
+                    chunks
+                        .entry(chunk.inner.id().to_string())
+                        .or_insert_with(|| {
+                            let info = ChunkdictChunkInfo {
+                                image_reference: image_reference.clone(),
+                                version: String::new(),
+                                chunk_blob_id: blob.blob_id(),
+                                chunk_digest: chunk.inner.id().to_string(),
+                                chunk_compressed_size: chunk.inner.compressed_size(),
+                                chunk_uncompressed_size: chunk.inner.uncompressed_size(),
+                                chunk_compressed_offset: chunk.inner.compressed_offset(),
+                                chunk_uncompressed_offset: chunk.inner.uncompressed_offset(),
+                            };
+                            (0, info)
+                        });
+                *refcount += 1;
+                blobs
+                    .entry(blob.blob_id())
+                    .or_insert_with(|| ChunkdictBlobInfo {
+                        blob_id: blob.blob_id(),
+                        blob_compressed_size: blob.compressed_size(),
+                        blob_uncompressed_size: blob.uncompressed_size(),
+                        blob_compressor: blob.compressor().to_string(),
+                        blob_meta_ci_compressed_size: blob.meta_ci_compressed_size(),
+                        blob_meta_ci_uncompressed_size: blob.meta_ci_uncompressed_size(),
+                        blob_meta_ci_offset: blob.meta_ci_offset(),
+                    });
+            }
+            Ok(())
+        };
+        let tree = Tree::from_bootstrap(&sb, &mut ())
+            .context("Failed to load bootstrap for deduplication.")?;
+        tree.walk_dfs_pre(process_chunk)?;
+    }
+
+    let chunks: Vec<ChunkdictChunkInfo> = chunks
+        .into_values()
+        .filter(|(refcount, _)| *refcount >= min_refcount)
+        .map(|(_, chunk)| chunk)
+        .collect();
+    let blob_ids: HashSet<&str> = chunks.iter().map(|c| c.chunk_blob_id.as_str()).collect();
+    let blobs = blobs
+        .into_values()
+        .filter(|blob| blob_ids.contains(blob.blob_id.as_str()))
+        .collect();
+
+    Ok((chunks, blobs))
+}
+
 pub struct Algorithm<D: Database + Send + Sync> {
     algorithm_name: String,
     db: D,
@@ -1339,6 +1415,32 @@ mod tests {
     use super::*;
     use rusqlite::Result;
 
+    #[test]
+    fn test_collect_shared_chunks() {
+        let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
+        let bootstrap = PathBuf::from(root_dir).join("tests/texture/bootstrap/rafs-v6-2.2.boot");
+        let config = Arc::new(ConfigV2::default());
+
+        let (all_chunks, all_blobs) =
+            collect_shared_chunks(&[bootstrap.clone()], config.clone(), 1).unwrap();
+        assert!(!all_chunks.is_empty());
+        assert!(!all_blobs.is_empty());
+        for chunk in all_chunks.iter() {
+            assert!(all_blobs.iter().any(|b| b.blob_id == chunk.chunk_blob_id));
+        }
+
+        // Every chunk is referenced at least twice by two copies of the same image.
+        let (chunks, blobs) =
+            collect_shared_chunks(&[bootstrap.clone(), bootstrap.clone()], config.clone(), 2)
+                .unwrap();
+        assert_eq!(chunks.len(), all_chunks.len());
+        assert_eq!(blobs.len(), all_blobs.len());
+
+        let (chunks, blobs) = collect_shared_chunks(&[bootstrap], config, usize::MAX).unwrap();
+        assert!(chunks.is_empty());
+        assert!(blobs.is_empty());
+    }
+
     #[test]
     fn test_partial_cmp() -> Result<(), Box<dyn std::error::Error>> {
         let custom_string1 = CustomString("nydus_1.2.3".to_string());
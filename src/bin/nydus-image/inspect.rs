@@ -3,24 +3,33 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::{
-    collections::BTreeMap,
+    collections::{hash_map::Entry, BTreeMap, HashMap},
     ffi::OsString,
-    fs::Permissions,
-    io::{Error, ErrorKind, Write},
+    fs::{OpenOptions, Permissions},
+    io::{self, Error, ErrorKind, Write},
     ops::DerefMut,
+    os::unix::fs::OpenOptionsExt,
     os::unix::prelude::PermissionsExt,
     path::{Path, PathBuf},
     sync::{Arc, Mutex},
 };
 
+use anyhow::Context;
 use nydus_api::ConfigV2;
 use nydus_rafs::metadata::{RafsInode, RafsInodeExt, RafsInodeWalkAction, RafsSuper};
 use nydus_rafs::RafsIoReader;
-use nydus_storage::device::BlobChunkInfo;
+use nydus_storage::device::{BlobChunkInfo, BlobFeatures};
+use nydus_storage::factory::BlobFactory;
+use nydus_utils::compress;
 use serde_json::Value;
 
+use crate::capability::ImageCapabilities;
+use crate::unpack::ChunkReader;
+
 pub(crate) struct RafsInspector {
     request_mode: bool,
+    // Configuration to access data blobs
+    config: Arc<ConfigV2>,
     // Rafs Meta Data
     rafs_meta: RafsSuper,
     // Bootstrap
@@ -40,11 +49,12 @@ impl RafsInspector {
         request_mode: bool,
         config: Arc<ConfigV2>,
     ) -> Result<Self, anyhow::Error> {
-        let (rafs_meta, f) = RafsSuper::load_from_file(bootstrap_path, config, false)?;
+        let (rafs_meta, f) = RafsSuper::load_from_file(bootstrap_path, config.clone(), false)?;
         let root_ino = rafs_meta.superblock.root_ino();
 
         Ok(RafsInspector {
             request_mode,
+            config,
             rafs_meta,
             bootstrap: Arc::new(Mutex::new(f)),
             cur_dir_ino: root_ino,
@@ -113,6 +123,36 @@ impl RafsInspector {
         Ok(o)
     }
 
+    // Implement command "features"
+    // Print features the image depends on at runtime
+    fn cmd_features(&self) -> Result<Option<Value>, anyhow::Error> {
+        let caps = ImageCapabilities::new(&self.rafs_meta);
+        if self.request_mode {
+            Ok(Some(serde_json::to_value(caps)?))
+        } else {
+            println!("{}", caps);
+            Ok(None)
+        }
+    }
+
+    // Implement command "annotations"
+    // Print key/value annotations embedded in the bootstrap
+    fn cmd_annotations(&self) -> Result<Option<Value>, anyhow::Error> {
+        let mut guard = self.bootstrap.lock().unwrap();
+        let annotations = self
+            .rafs_meta
+            .get_annotations(guard.deref_mut())
+            .context("failed to load annotations")?;
+        if self.request_mode {
+            Ok(Some(serde_json::to_value(annotations.entries())?))
+        } else {
+            for (key, value) in annotations.entries() {
+                println!("{}={}", key, value);
+            }
+            Ok(None)
+        }
+    }
+
     // Implement command "ls"
     // Walk_children_inodes with handler defined
     fn cmd_list_dir(&mut self) -> Result<Option<Value>, anyhow::Error> {
@@ -287,6 +327,7 @@ impl RafsInspector {
                 let v = json!({"blob_id": blob_info.blob_id(),
                                     "readahead_offset": blob_info.prefetch_offset(),
                                     "readahead_size": blob_info.prefetch_size(),
+                                    "prefetch_ranges": blob_info.prefetch_ranges(),
                                     "decompressed_size": blob_info.uncompressed_size(),
                                     "compressed_size": blob_info.compressed_size(),});
                 value.as_array_mut().unwrap().push(v);
@@ -312,6 +353,7 @@ Chunk Size:             0x{chunk_size:x}
 Chunk Count:            {chunk_count}
 Prefetch Table Offset:  {prefetch_tbl_offset}
 Prefetch Table Size:    {prefetch_tbl_size}
+Prefetch Ranges:        {prefetch_ranges}
 Meta Compressor:        {meta_compressor}
 Meta Offset:            {meta_offset}
 Meta Compressed Size:   {meta_comp_size}
@@ -335,6 +377,12 @@ RAFS Blob Size:         {rafs_size}
                     cipher = blob_info.cipher(),
                     prefetch_tbl_offset = blob_info.prefetch_offset(),
                     prefetch_tbl_size = blob_info.prefetch_size(),
+                    prefetch_ranges = blob_info
+                        .prefetch_ranges()
+                        .iter()
+                        .map(|(offset, size)| format!("0x{:x}/0x{:x}", offset, size))
+                        .collect::<Vec<_>>()
+                        .join(" "),
                     meta_compressor = blob_info.meta_ci_compressor(),
                     meta_offset = blob_info.meta_ci_offset(),
                     meta_comp_size = blob_info.meta_ci_compressed_size(),
@@ -492,6 +540,187 @@ Blob ID: {}
         Ok(None)
     }
 
+    // Implement command "chunks"
+    fn cmd_list_chunks(&self, file_path: &str) -> Result<Option<Value>, anyhow::Error> {
+        let (path, inode) = self.get_regular_file(file_path)?;
+
+        let mut value = json!([]);
+        if !self.request_mode {
+            println!(
+                "File: {}, Size: {}, Chunks: {}",
+                path.display(),
+                inode.size(),
+                inode.get_chunk_count()
+            );
+        }
+        for idx in 0..inode.get_chunk_count() {
+            let c = inode.get_chunk_info(idx)?;
+            let blob_id = self.get_blob_id_by_index(c.blob_index())?;
+            let file_offset = idx as u64 * self.rafs_meta.meta.chunk_size as u64;
+            let compressor = match c.compressor() {
+                _ if !c.is_compressed() => compress::Algorithm::None,
+                Some(v) => v,
+                None => self.get_blob_compressor_by_index(c.blob_index())?,
+            };
+            if self.request_mode {
+                let v = json!({"index": idx,
+                                "file_offset": file_offset,
+                                "blob_index": c.blob_index(),
+                                "blob_id": blob_id,
+                                "chunk_index": c.id(),
+                                "compressed_offset": c.compressed_offset(),
+                                "compressed_size": c.compressed_size(),
+                                "uncompressed_offset": c.uncompressed_offset(),
+                                "uncompressed_size": c.uncompressed_size(),
+                                "digest": c.chunk_id().to_string(),
+                                "compressed": c.is_compressed(),
+                                "compressor": compressor.to_string(),
+                                "encrypted": c.is_encrypted(),
+                                "batch": c.is_batch(),});
+                value.as_array_mut().unwrap().push(v);
+            } else {
+                println!(
+                    r#"
+Chunk:                  {idx}
+File Offset:            0x{file_offset:x}
+Blob Index:             {blob_index}
+Blob ID:                {blob_id}
+Chunk Index:            {chunk_index}
+Compressed Offset:      0x{compressed_offset:x}
+Compressed Size:        0x{compressed_size:x}
+Uncompressed Offset:    0x{uncompressed_offset:x}
+Uncompressed Size:      0x{uncompressed_size:x}
+Digest:                 {digest}
+Compressed:             {compressed}
+Compressor:             {compressor}
+Encrypted:              {encrypted}
+Batch:                  {batch}"#,
+                    idx = idx,
+                    file_offset = file_offset,
+                    blob_index = c.blob_index(),
+                    blob_id = blob_id,
+                    chunk_index = c.id(),
+                    compressed_offset = c.compressed_offset(),
+                    compressed_size = c.compressed_size(),
+                    uncompressed_offset = c.uncompressed_offset(),
+                    uncompressed_size = c.uncompressed_size(),
+                    digest = c.chunk_id(),
+                    compressed = c.is_compressed(),
+                    compressor = compressor,
+                    encrypted = c.is_encrypted(),
+                    batch = c.is_batch(),
+                );
+            }
+        }
+
+        if self.request_mode {
+            return Ok(Some(value));
+        }
+
+        Ok(None)
+    }
+
+    // Implement command "extract"
+    fn cmd_extract_file(
+        &self,
+        file_path: &str,
+        local_path: &str,
+    ) -> Result<Option<Value>, anyhow::Error> {
+        let (path, inode) = self.get_regular_file(file_path)?;
+        let backend_config = self.config.get_backend_config()?;
+        let blob_infos = self.rafs_meta.superblock.get_blob_infos();
+        let mut readers = HashMap::new();
+        let mut compressors = HashMap::new();
+        let mut chunks = Vec::with_capacity(inode.get_chunk_count() as usize);
+        for idx in 0..inode.get_chunk_count() {
+            let c = inode.get_chunk_info(idx)?;
+            if c.is_batch() || c.is_encrypted() {
+                bail!(
+                    "extracting batched or encrypted chunks of {} isn't supported",
+                    path.display()
+                );
+            }
+            if let Entry::Vacant(e) = readers.entry(c.blob_index()) {
+                let blob = blob_infos
+                    .iter()
+                    .find(|b| b.blob_index() == c.blob_index())
+                    .ok_or_else(|| anyhow!("can not find blob by index: {}", c.blob_index()))?;
+                if blob.has_feature(BlobFeatures::ZRAN) {
+                    bail!(
+                        "extracting {} from ZRAN blob {} isn't supported",
+                        path.display(),
+                        blob.blob_id()
+                    );
+                }
+                let blob_id = blob.blob_id();
+                let backend = BlobFactory::new_backend(backend_config, &blob_id)
+                    .with_context(|| format!("failed to create backend for blob {}", blob_id))?;
+                let reader = backend
+                    .get_reader(&blob_id)
+                    .map_err(|e| anyhow!("failed to get reader for blob {}, {:?}", blob_id, e))?;
+                e.insert(reader);
+                compressors.insert(c.blob_index(), blob.compressor());
+            }
+            chunks.push(c);
+        }
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(inode.get_attr().mode & 0o777)
+            .open(local_path)
+            .with_context(|| format!("failed to create file {}", local_path))?;
+        let mut reader = ChunkReader::new(compressors, readers, chunks);
+        let size = io::copy(&mut reader, &mut file)
+            .with_context(|| format!("failed to extract {} to {}", path.display(), local_path))?;
+        if size != inode.size() {
+            bail!(
+                "size of extracted file {} 0x{:x} doesn't match file size 0x{:x}",
+                local_path,
+                size,
+                inode.size()
+            );
+        }
+
+        if self.request_mode {
+            return Ok(Some(
+                json!({"path": path, "local_path": local_path, "size": size}),
+            ));
+        }
+        println!(
+            "Extracted {} to {}, size 0x{:x}",
+            path.display(),
+            local_path,
+            size
+        );
+
+        Ok(None)
+    }
+
+    // Find the regular file by absolute path or path relative to the current directory.
+    fn get_regular_file(
+        &self,
+        file_path: &str,
+    ) -> Result<(PathBuf, Arc<dyn RafsInodeExt>), anyhow::Error> {
+        let path = if file_path.starts_with('/') {
+            PathBuf::from(file_path)
+        } else {
+            self.rafs_meta
+                .path_from_ino(self.cur_dir_ino)?
+                .join(file_path)
+        };
+        let ino = self
+            .rafs_meta
+            .ino_from_path(&path)
+            .with_context(|| format!("failed to find file {}", path.display()))?;
+        let inode = self.rafs_meta.get_extended_inode(ino, false)?;
+        if !inode.is_reg() {
+            bail!("{} is not a regular file", path.display());
+        }
+        Ok((path, inode))
+    }
+
     #[allow(clippy::type_complexity)]
     /// Walkthrough the file tree rooted at ino, calling cb for each file or directory
     /// in the tree by DFS order, including ino, please ensure ino is a directory.
@@ -662,6 +891,19 @@ Blocks:             {blocks}"#,
         }
         Err(anyhow!("can not find blob by index: {}", blob_index))
     }
+
+    fn get_blob_compressor_by_index(
+        &self,
+        blob_index: u32,
+    ) -> Result<compress::Algorithm, anyhow::Error> {
+        let blob_infos = self.rafs_meta.superblock.get_blob_infos();
+        for b in blob_infos.iter() {
+            if b.blob_index() == blob_index {
+                return Ok(b.compressor());
+            }
+        }
+        Err(anyhow!("can not find blob by index: {}", blob_index))
+    }
 }
 
 #[derive(Debug)]
@@ -698,11 +940,22 @@ impl Executor {
             }
             ("exit", _) | ("q", _) => return Err(ExecuteError::Exit),
             ("stats", None) => inspector.cmd_stats(),
+            ("features", None) => inspector.cmd_features(),
+            ("annotations", None) => inspector.cmd_annotations(),
             ("ls", None) => inspector.cmd_list_dir(),
             ("cd", Some(dir)) => inspector.cmd_change_dir(dir),
             ("stat", Some(file_name)) => inspector.cmd_stat_file(file_name),
             ("blobs", None) => inspector.cmd_list_blobs(),
             ("prefetch", None) => inspector.cmd_list_prefetch(),
+            ("chunks", Some(file_path)) => inspector.cmd_list_chunks(file_path),
+            ("extract", Some(file_path)) => match raw.next() {
+                Some(local_path) => inspector.cmd_extract_file(file_path, local_path),
+                None => {
+                    println!("Local file path to save the extracted file is missing.");
+                    Self::usage();
+                    return Err(ExecuteError::ArgumentParse);
+                }
+            },
             ("chunk", Some(argument)) => {
                 let offset: u64 = argument.parse().unwrap();
                 inspector.cmd_show_chunk(offset)
@@ -731,12 +984,17 @@ impl Executor {
         println!(
             r#"
     stats:              Display RAFS filesystesm metadata
+    features:           Display features the image depends on at runtime
+    annotations:        Display key/value annotations embedded in RAFS filesystem metadata
     ls:                 Show files in current directory
     cd DIR:             Change current directory
     stat FILE_NAME:     Show particular information of RAFS file
     blobs:              Show blob table
     prefetch:           Show prefetch table
     chunk OFFSET:       List basic info of a single chunk together with a list of files that share it
+    chunks FILE_PATH:   Show blob, offsets, sizes, digest and flags of each chunk of a file
+    extract FILE_PATH LOCAL_PATH:
+                        Read data of a file from data blobs and save it to a local file
     icheck INODE:       Show path of the inode and basic information
     exit:               Exit
         "#
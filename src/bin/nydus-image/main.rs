@@ -13,33 +13,46 @@ extern crate log;
 extern crate serde_json;
 #[macro_use]
 extern crate lazy_static;
+use crate::capability::ImageCapabilities;
+use crate::data_checker::DataChecker;
 use crate::deduplicate::{
-    check_bootstrap_versions_consistency, update_ctx_from_parent_bootstrap, Deduplicate,
-    SqliteDatabase,
+    check_bootstrap_versions_consistency, collect_shared_chunks, update_ctx_from_parent_bootstrap,
+    Deduplicate, SqliteDatabase,
 };
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fs::{self, metadata, DirEntry, File, OpenOptions};
+use std::io::Read;
 use std::os::unix::fs::FileTypeExt;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{bail, Context, Result};
 use clap::parser::ValueSource;
 use clap::{Arg, ArgAction, ArgMatches, Command as App};
 use nix::unistd::{getegid, geteuid};
 use nydus::{get_build_time_info, setup_logging};
-use nydus_api::{BuildTimeInfo, ConfigV2, LocalFsConfig};
+use nydus_api::{
+    BackendConfigV2, BuildTimeInfo, ConfigV2, ConfigV2Internal, LocalFsConfig, ProxyConfig,
+    RegistryConfig,
+};
 use nydus_builder::{
-    parse_chunk_dict_arg, ArtifactStorage, BlobCacheGenerator, BlobCompactor, BlobManager,
-    BootstrapManager, BuildContext, BuildOutput, Builder, ChunkdictBlobInfo, ChunkdictChunkInfo,
-    ConversionType, DirectoryBuilder, Feature, Features, Generator, HashChunkDict, Merger,
-    Prefetch, PrefetchPolicy, StargzBuilder, TarballBuilder, WhiteoutSpec,
+    parse_chunk_dict_arg, ArtifactStage, ArtifactStorage, BlobCacheGenerator, BlobCompactor,
+    BlobManager, BlobMetaGenerator, BootstrapManager, BuildContext, BuildOutput, Builder,
+    ChunkDedupStats, ChunkDictSource, ChunkdictBlobInfo, ChunkdictChunkInfo, CommandTransform,
+    ConversionType, DirectoryBuilder, Feature, Features, Generator, HardlinkKey, HashChunkDict,
+    InodeOrder, Merger, PathFilter, Prefetch, PrefetchPolicy, PrivilegedFiles, SkippedFile,
+    StargzBuilder, TarballBuilder, TransformedFile, UnsupportedFilePolicy, WhiteoutSpec,
+};
+use nydus_rafs::metadata::{
+    MergeError, RafsBlobExtraInfo, RafsSuper, RafsSuperConfig, RafsVersion,
 };
-use nydus_rafs::metadata::{MergeError, RafsSuper, RafsSuperConfig, RafsVersion};
 use nydus_storage::backend::localfs::LocalFs;
 use nydus_storage::backend::BlobBackend;
-use nydus_storage::device::BlobFeatures;
+use nydus_storage::device::{BlobFeatures, BlobInfo};
 use nydus_storage::factory::BlobFactory;
+use nydus_storage::meta::toc::{TocEntryList, TOC_ENTRY_BOOTSTRAP};
 use nydus_storage::meta::{format_blob_features, BatchContextGenerator};
 use nydus_storage::{RAFS_DEFAULT_CHUNK_SIZE, RAFS_MAX_CHUNK_SIZE};
 use nydus_utils::trace::{EventTracerClass, TimingTracerClass, TraceClass};
@@ -48,7 +61,8 @@ use nydus_utils::{
 };
 use serde::{Deserialize, Serialize};
 
-use crate::unpack::{OCIUnpacker, Unpacker};
+use crate::selftest::SelfTester;
+use crate::unpack::{OCILayerUnpacker, OCIUnpacker, Unpacker};
 use crate::validator::Validator;
 
 #[cfg(target_os = "linux")]
@@ -56,13 +70,28 @@ use nydus_service::ServiceArgs;
 #[cfg(target_os = "linux")]
 use std::str::FromStr;
 
+mod capability;
+mod chunk_advisor;
+mod data_checker;
 mod deduplicate;
 mod inspect;
+mod oci;
+mod selftest;
+mod signature;
 mod stat;
 mod unpack;
 mod validator;
 
 const BLOB_ID_MAXIMUM_LENGTH: usize = 255;
+const BLOB_ALIGNMENT_MINIMUM_SIZE: u64 = 0x1000;
+const BLOB_ALIGNMENT_MAXIMUM_SIZE: u64 = 0x1000_0000;
+const REGISTRY_SCHEME: &str = "registry://";
+const STDIN_PATH: &str = "-";
+// Prefix of annotations generated by the builder, which can't be specified by users.
+const ANNOTATION_PREFIX: &str = "io.nydus.";
+const ANNOTATION_BUILDER_VERSION: &str = "io.nydus.builder.version";
+const ANNOTATION_BUILD_TIME: &str = "io.nydus.build.time";
+const ANNOTATION_SOURCE_DIGEST: &str = "io.nydus.source.digest";
 
 #[derive(Serialize, Deserialize, Default)]
 pub struct OutputSerializer {
@@ -80,6 +109,40 @@ pub struct OutputSerializer {
     fs_version: String,
     /// Chunk compression algorithm.
     compressor: String,
+    /// Per layer RAFS meta data file paths, ordered from the lowest layer to the uppermost one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    layer_bootstraps: Vec<String>,
+    /// Statistics about where data chunks come from, only available for `create`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chunk_dedup: Option<ChunkDedupStats>,
+    /// Audit of setuid, setgid and capability files in the image, only available for `create`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    privileged_files: Option<PrivilegedFiles>,
+    /// Source files skipped because they can't be read, only available for `create`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    skipped_files: Vec<SkippedFile>,
+    /// Source entries skipped because of unsupported file types, only available for `create`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    unsupported_files: Vec<SkippedFile>,
+    /// Source files transformed by the transform command, only available for `create`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    transformed_files: Vec<TransformedFile>,
+    /// Parameters of data blobs in blob table, only available for `check`.
+    #[serde(default, skip_deserializing, skip_serializing_if = "Vec::is_empty")]
+    blob_infos: Vec<BlobInfoOutput>,
+    /// Features the image depends on at runtime, only available for `check`.
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    capabilities: Option<ImageCapabilities>,
+}
+
+/// Blob parameters for external tools, such as snapshotter configuration generators.
+#[derive(Serialize)]
+struct BlobInfoOutput {
+    #[serde(flatten)]
+    info: Arc<BlobInfo>,
+    /// Extra information from RAFS v6 device table.
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    extra: Option<RafsBlobExtraInfo>,
 }
 
 impl OutputSerializer {
@@ -89,6 +152,46 @@ impl OutputSerializer {
         build_info: &BuildTimeInfo,
         compressor: compress::Algorithm,
         fs_version: RafsVersion,
+    ) -> Result<()> {
+        Self::dump_output(
+            matches,
+            build_output,
+            Vec::new(),
+            false,
+            build_info,
+            compressor,
+            fs_version,
+        )
+    }
+
+    /// Dump output of the `create` subcommand, with chunk deduplication statistics.
+    fn dump_build(
+        matches: &ArgMatches,
+        build_output: BuildOutput,
+        layer_bootstraps: Vec<String>,
+        build_info: &BuildTimeInfo,
+        compressor: compress::Algorithm,
+        fs_version: RafsVersion,
+    ) -> Result<()> {
+        Self::dump_output(
+            matches,
+            build_output,
+            layer_bootstraps,
+            true,
+            build_info,
+            compressor,
+            fs_version,
+        )
+    }
+
+    fn dump_output(
+        matches: &ArgMatches,
+        build_output: BuildOutput,
+        layer_bootstraps: Vec<String>,
+        is_build: bool,
+        build_info: &BuildTimeInfo,
+        compressor: compress::Algorithm,
+        fs_version: RafsVersion,
     ) -> Result<()> {
         let output_json: Option<PathBuf> = matches
             .get_one::<String>("output-json")
@@ -110,6 +213,14 @@ impl OutputSerializer {
                 trace,
                 fs_version: fs_version.to_string(),
                 compressor: compressor.to_string(),
+                layer_bootstraps,
+                chunk_dedup: is_build.then_some(build_output.dedup_stats),
+                privileged_files: is_build.then_some(build_output.privileged_files),
+                skipped_files: build_output.skipped_files,
+                unsupported_files: build_output.unsupported_files,
+                transformed_files: build_output.transformed_files,
+                blob_infos: Vec::new(),
+                capabilities: None,
             };
 
             serde_json::to_writer_pretty(w, &output)
@@ -123,6 +234,8 @@ impl OutputSerializer {
         matches: &ArgMatches,
         build_info: &BuildTimeInfo,
         blob_ids: Vec<String>,
+        blob_infos: Vec<BlobInfoOutput>,
+        capabilities: ImageCapabilities,
         bootstrap: &Path,
         compressor: compress::Algorithm,
         fs_version: RafsVersion,
@@ -147,6 +260,14 @@ impl OutputSerializer {
                 trace,
                 fs_version: fs_version.to_string(),
                 compressor: compressor.to_string(),
+                layer_bootstraps: Vec::new(),
+                chunk_dedup: None,
+                privileged_files: None,
+                skipped_files: Vec::new(),
+                unsupported_files: Vec::new(),
+                transformed_files: Vec::new(),
+                blob_infos,
+                capabilities: Some(capabilities),
             };
 
             serde_json::to_writer(w, &output).context("failed to write result to output file")?;
@@ -159,7 +280,9 @@ impl OutputSerializer {
 fn prepare_cmd_args(bti_string: &'static str) -> App {
     let arg_chunk_dict = Arg::new("chunk-dict")
         .long("chunk-dict")
-        .help("File path of chunk dictionary for data deduplication");
+        .help("Chunk dictionary for data deduplication, in form of [bootstrap=]<path>, bootstrap-dir=<dir> or registry://<image>, may be repeated or comma-separated, earlier ones take precedence")
+        .action(ArgAction::Append)
+        .value_delimiter(',');
     let arg_prefetch_policy = Arg::new("prefetch-policy")
         .long("prefetch-policy")
         .help("Set data prefetch policy")
@@ -175,6 +298,26 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
         .short('C')
         .help("Configuration file for storage backend, cache and RAFS FUSE filesystem.")
         .required(false);
+    let arg_proxy_url = Arg::new("proxy-url")
+        .long("proxy-url")
+        .help("Read data from remote storage backends and pull images from registries through the P2P proxy, such as Dragonfly dfdaemon, at the URL")
+        .required(false);
+    let arg_proxy_ping_url = Arg::new("proxy-ping-url")
+        .long("proxy-ping-url")
+        .help("Health checking endpoint of the P2P proxy, requests go to the origin server while the proxy is unhealthy")
+        .requires("proxy-url")
+        .required(false);
+    let arg_proxy_no_fallback = Arg::new("proxy-no-fallback")
+        .long("proxy-no-fallback")
+        .help("Don't fall back to the origin server if the P2P proxy fails")
+        .action(ArgAction::SetTrue)
+        .requires("proxy-url")
+        .required(false);
+    let arg_bootstrap_cache_dir = Arg::new("bootstrap-cache-dir")
+        .long("bootstrap-cache-dir")
+        .help("Directory to cache parent bootstraps pulled from registry, named by digest of the bootstrap layer [default: $XDG_CACHE_HOME/nydus-image/bootstraps or ~/.cache/nydus-image/bootstraps]")
+        .value_parser(clap::value_parser!(PathBuf))
+        .required(false);
 
     let app = App::new("")
         .version(bti_string)
@@ -204,9 +347,9 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
                 .about("Create RAFS filesystems from directories, tar files or OCI images")
                 .arg(
                     Arg::new("SOURCE")
-                        .help("source from which to build the RAFS filesystem")
+                        .help("source from which to build the RAFS filesystem, multiple layers may be given in order of lower to upper to build per layer RAFS filesystems and merge them")
                         .required(true)
-                        .num_args(1),
+                        .num_args(1..),
                 )
                 .arg(
                     Arg::new("type")
@@ -221,11 +364,13 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
                             "estargz-rafs",
                             "estargz-ref",
                             "estargztoc-ref",
+                            "oci-ref",
                             "tar-rafs",
                             "tar-tarfs",
                             "targz-rafs",
                             "targz-ref",
                             "stargz_index",
+                            "zstdchunked-ref",
                         ])
                 )
                 .arg(
@@ -267,7 +412,7 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
                 .arg(
                     Arg::new("blob-data-size")
                         .long("blob-data-size")
-                        .help("Set data blob size for 'estargztoc-ref' conversion"),
+                        .help("Set data blob size for 'estargztoc-ref' and 'zstdchunked-ref' conversion"),
                 )
                 .arg(
                     Arg::new("blob-offset")
@@ -289,13 +434,78 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
                         .required(false)
                         .default_value("0"),
                 )
+                .arg(
+                    Arg::new("blob-padding")
+                        .long("blob-padding")
+                        .help("Pad the data blob to a multiple of the size, must be power of two and between 0x1000-0x10000000:")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("blob-meta-alignment")
+                        .long("blob-meta-alignment")
+                        .help("Align the appended chunk information region to the size, must be power of two and between 0x1000-0x10000000:")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("tmp-dir")
+                        .long("tmp-dir")
+                        .help("Directory to create temporary data blob files, instead of the directory specified by '--blob-dir'")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .requires("blob-dir")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("check-free-space")
+                        .long("check-free-space")
+                        .help("Abort early if there may be not enough free space for the data blob, estimated from size of the source directory or tar file")
+                        .action(ArgAction::SetTrue)
+                        .requires("blob-dir")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("annotation")
+                        .long("annotation")
+                        .help("Annotation to embed in the RAFS v6 metadata in form of KEY=VALUE, may be repeated")
+                        .value_name("KEY=VALUE")
+                        .action(ArgAction::Append)
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("provenance")
+                        .long("provenance")
+                        .help("Embed provenance annotations into the RAFS v6 metadata: builder version, build time and sha256 digests of tarball sources")
+                        .action(ArgAction::SetTrue)
+                        .required(false),
+                )
                 .arg(
                     Arg::new("compressor")
                         .long("compressor")
                         .help("Algorithm to compress data chunks:")
                         .required(false)
                         .default_value("zstd")
-                        .value_parser(["none", "lz4_block", "zstd"]),
+                        .value_parser(["none", "lz4_block", "lz4hc", "lz4_frame", "zstd"]),
+                )
+                .arg(
+                    Arg::new("compress-level")
+                        .long("compress-level")
+                        .help("Compression level for 'lz4hc', 'lz4_frame' and 'zstd' compressors:")
+                        .value_parser(clap::value_parser!(u32))
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("hot-compressor")
+                        .long("hot-compressor")
+                        .help("Algorithm to compress data chunks of hot files, which are files in the prefetch list or the access trace, RAFS v6 only:")
+                        .required(false)
+                        .conflicts_with("blob-cache-dir")
+                        .value_parser(["none", "lz4_block", "lz4hc", "lz4_frame", "zstd"]),
+                )
+                .arg(
+                    Arg::new("access-trace")
+                        .long("access-trace")
+                        .help("File listing paths of files accessed at runtime, one path per line, to compress with '--hot-compressor'")
+                        .requires("hot-compressor")
+                        .required(false),
                 )
                 .arg(
                     Arg::new("digester")
@@ -323,16 +533,20 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
                 .arg(
                     arg_chunk_dict.clone(),
                 )
+                .arg(arg_proxy_url.clone())
+                .arg(arg_proxy_ping_url.clone())
+                .arg(arg_proxy_no_fallback.clone())
                 .arg(
                     Arg::new("parent-bootstrap")
                         .long("parent-bootstrap")
-                        .help("File path of the parent/referenced RAFS metadata blob (optional)")
+                        .help("File path of the parent/referenced RAFS metadata blob, or registry://<image> to pull it from registry (optional)")
                         .required(false),
                 )
+                .arg(arg_bootstrap_cache_dir.clone())
                 .arg(
                     Arg::new("aligned-chunk")
                         .long("aligned-chunk")
-                        .help("Align uncompressed data chunks to 4K, only for RAFS V5")
+                        .help("Align uncompressed data chunks to 4K, always enabled for RAFS V6 except for 'tar-tarfs'")
                         .action(ArgAction::SetTrue)
                 )
                 .arg(
@@ -342,6 +556,15 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
                         .action(ArgAction::SetTrue)
                         .required(false),
                 )
+                .arg(
+                    Arg::new("check-repeatable")
+                        .long("check-repeatable")
+                        .help("Build the RAFS filesystem twice and make sure the generated RAFS metadata and data blob are bit-identical")
+                        .action(ArgAction::SetTrue)
+                        .requires("repeatable")
+                        .conflicts_with_all(["blob-cache-dir", "blob-meta-dir"])
+                        .required(false),
+                )
                 .arg(
                     Arg::new("disable-check")
                         .long("disable-check")
@@ -357,6 +580,74 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
                         .default_value("oci")
                         .value_parser(["oci", "overlayfs", "none"])
                 )
+                .arg(
+                    Arg::new("follow-symlinks")
+                        .long("follow-symlinks")
+                        .help("Include content of directories referenced by symlinks in the source directory, instead of the symlinks")
+                        .action(ArgAction::SetTrue)
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("skip-unreadable")
+                        .long("skip-unreadable")
+                        .help("Skip source files which can't be read, such as permission denied or removed during build, instead of aborting the build, skipped files are recorded in the output json file")
+                        .action(ArgAction::SetTrue)
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("unsupported-file-policy")
+                        .long("unsupported-file-policy")
+                        .help("How to handle source entries of unsupported file types, skipped entries are recorded in the output json file [default: 'warn' for (e)stargz and zstd:chunked, 'error' for others]")
+                        .value_parser(["error", "skip", "warn"])
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("one-file-system")
+                        .long("one-file-system")
+                        .help("Don't cross mount points when walking the source directory, mount point directories are kept as empty directories")
+                        .action(ArgAction::SetTrue)
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("readahead-files")
+                        .long("readahead-files")
+                        .help("Number of upcoming source files to read ahead while dumping chunk data, overlapping disk IO with compression:")
+                        .value_parser(clap::value_parser!(u32))
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("transform-cmd")
+                        .long("transform-cmd")
+                        .help("Shell command to transform content of each regular file before chunking, reading original content from stdin and writing transformed content to stdout")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("hardlink-key")
+                        .long("hardlink-key")
+                        .help("Identity of the filesystem to group hardlinks by, 'fsid' works across bind mounts presenting different device numbers:")
+                        .default_value("dev")
+                        .value_parser(["dev", "fsid"]),
+                )
+                .arg(
+                    Arg::new("hardlink-dev-map")
+                        .long("hardlink-dev-map")
+                        .help("Treat device numbers as equivalent when grouping hardlinks, in format 'DEV=DEV[,DEV=DEV]'")
+                        .conflicts_with("hardlink-key"),
+                )
+                .arg(
+                    Arg::new("inode-order")
+                        .long("inode-order")
+                        .help("Order to lay out inodes into the inode table, 'dfs' and 'name' are for RAFS v6 only:")
+                        .default_value("bfs")
+                        .value_parser(["bfs", "dfs", "name"])
+                )
+                .arg(
+                    Arg::new("timing-dir-depth")
+                        .long("timing-dir-depth")
+                        .help("Break down time consumed by dumping file data by directories at the depth in the output json file, zero disables the breakdown:")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("0"),
+                )
                 .arg(
                     arg_prefetch_policy.clone(),
                 )
@@ -371,6 +662,14 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
                         .action(ArgAction::SetTrue)
                         .required(false)
                 )
+                .arg(
+                    Arg::new("blob-meta-dir")
+                        .long("blob-meta-dir")
+                        .help("Directory path to generate blob meta cache files ($id.blob.meta) for data blobs, to pre-seed blob caches")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .conflicts_with("blob-cache-dir")
+                        .required(false)
+                )
                 .arg(
                     Arg::new("blob-cache-dir")
                         .long("blob-cache-dir")
@@ -427,6 +726,36 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
                                 .required(false),
                         )
                     )
+                .subcommand(
+                    App::new("export")
+                        .about("export chunk dictionary with chunks shared by existing images")
+                        .arg(
+                            Arg::new("bootstrap")
+                                .long("bootstrap")
+                                .short('B')
+                                .help("RAFS metadata of images to scan, may be repeated or comma-separated")
+                                .action(ArgAction::Append)
+                                .value_delimiter(',')
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("output")
+                                .long("output")
+                                .short('O')
+                                .help("Output path of the chunk dictionary bootstrap")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("min-refcount")
+                                .long("min-refcount")
+                                .help("Only export chunks referenced at least the number of times by files of the images")
+                                .value_parser(clap::value_parser!(u32).range(1..))
+                                .default_value("2")
+                                .required(false),
+                        )
+                        .arg(arg_output_json.clone())
+                        .arg(arg_config.clone())
+                    )
                 );
 
     let app = app.subcommand(
@@ -435,9 +764,10 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
             .arg(
                 Arg::new("parent-bootstrap")
                     .long("parent-bootstrap")
-                    .help("File path of the parent/referenced RAFS metadata blob (optional)")
+                    .help("File path of the parent/referenced RAFS metadata blob, or registry://<image> to pull it from registry (optional)")
                     .required(false),
             )
+            .arg(arg_bootstrap_cache_dir)
             .arg(
                 Arg::new("bootstrap")
                     .long("bootstrap")
@@ -451,6 +781,9 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
                     .help("Directory path to save generated RAFS metadata and data blobs"),
             )
             .arg(arg_chunk_dict.clone())
+            .arg(arg_proxy_url.clone())
+            .arg(arg_proxy_ping_url.clone())
+            .arg(arg_proxy_no_fallback.clone())
             .arg(arg_prefetch_policy)
             .arg(arg_output_json.clone())
             .arg(
@@ -483,6 +816,22 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
                     .required(false)
                     .help("RAFS blob toc size list separated by comma"),
             )
+            .arg(
+                Arg::new("filter-keep-layers")
+                    .long("filter-keep-layers")
+                    .help("Number of lowest source layers to keep as is, changes of the other layers under '--filter-exclude' are dropped")
+                    .value_parser(clap::value_parser!(usize))
+                    .requires("filter-exclude")
+                    .required(false),
+            )
+            .arg(
+                Arg::new("filter-exclude")
+                    .long("filter-exclude")
+                    .help("Drop changes of filtered layers under the absolute path, so files from kept layers survive, may be repeated")
+                    .action(ArgAction::Append)
+                    .value_parser(clap::value_parser!(PathBuf))
+                    .required(false),
+            )
             .arg(arg_config.clone())
             .arg(
                 Arg::new("SOURCE")
@@ -497,8 +846,8 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
             .about("Validate RAFS filesystem metadata")
             .arg(
                 Arg::new("BOOTSTRAP")
-                    .help("File path of RAFS metadata")
-                    .required_unless_present("bootstrap"),
+                    .help("File path of RAFS metadata, '-' to read from stdin")
+                    .required_unless_present_any(["bootstrap", "blob"]),
             )
             .arg(
                 Arg::new("bootstrap")
@@ -508,6 +857,14 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
                     .conflicts_with("BOOTSTRAP")
                     .required(false),
             )
+            .arg(
+                Arg::new("blob")
+                    .long("blob")
+                    .short('b')
+                    .help("File path of RAFS data blob with inlined RAFS metadata, instead of a separate bootstrap")
+                    .conflicts_with_all(["BOOTSTRAP", "bootstrap", "blob-dir", "config"])
+                    .required(false),
+            )
             .arg(
                 Arg::new("blob-dir")
                     .long("blob-dir")
@@ -518,6 +875,9 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
                     ),
             )
             .arg(arg_config.clone())
+            .arg(arg_proxy_url.clone())
+            .arg(arg_proxy_ping_url.clone())
+            .arg(arg_proxy_no_fallback.clone())
             .arg(
                 Arg::new("verbose")
                     .long("verbose")
@@ -526,7 +886,234 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
                     .action(ArgAction::SetTrue)
                     .required(false),
             )
-            .arg(arg_output_json.clone()),
+            .arg(
+                Arg::new("public-key")
+                    .long("public-key")
+                    .help("PEM encoded public key to verify the signature embedded in the RAFS v6 metadata")
+                    .required(false),
+            )
+            .arg(
+                Arg::new("equivalent-to")
+                    .long("equivalent-to")
+                    .help("Check that the RAFS filesystem is logically equivalent to another one, such as RAFS v5 and v6 filesystems built from the same source")
+                    .required(false),
+            )
+            .arg(
+                Arg::new("duplicate-chunks")
+                    .long("duplicate-chunks")
+                    .help("Check chunks with identical digests but inconsistent sizes or flags across data blobs, which indicates digest collisions or builder bugs")
+                    .action(ArgAction::SetTrue)
+                    .required(false),
+            )
+            .arg(
+                Arg::new("symlinks")
+                    .long("symlinks")
+                    .help("Resolve symlinks within the filesystem and check for dangling or out-of-root targets")
+                    .action(ArgAction::SetTrue)
+                    .required(false),
+            )
+            .arg(
+                Arg::new("chunk-dict")
+                    .long("chunk-dict")
+                    .help("Chunk dictionary used to build the filesystem, check that deduplicated chunks resolve to matching chunks in it, in form of [bootstrap=]<path>, bootstrap-dir=<dir> or registry://<image>")
+                    .action(ArgAction::Append)
+                    .value_delimiter(',')
+                    .required(false),
+            )
+            .arg(
+                Arg::new("data")
+                    .long("data")
+                    .help("Read all data chunks from storage backends and verify them against chunk digests")
+                    .action(ArgAction::SetTrue)
+                    .required(false),
+            )
+            .arg(
+                Arg::new("data-checkpoint")
+                    .long("data-checkpoint")
+                    .help("File to save data verification progress into periodically, and to resume verification from if it exists")
+                    .value_parser(clap::value_parser!(PathBuf))
+                    .requires("data")
+                    .required(false),
+            )
+            .arg(
+                Arg::new("data-bandwidth")
+                    .long("data-bandwidth")
+                    .help("Limit bandwidth of reading data chunks from storage backends, in bytes per second")
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .requires("data")
+                    .required(false),
+            )
+            .arg(
+                Arg::new("data-time-limit")
+                    .long("data-time-limit")
+                    .help("Pause data verification after the specified seconds, to be resumed from the checkpoint file later")
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .requires("data-checkpoint")
+                    .required(false),
+            )
+            .arg(arg_output_json.clone()),
+    );
+
+    let app = app.subcommand(
+        App::new("analyze")
+            .about(
+                "Analyze a source directory to recommend chunk size, chunking mode and compressor",
+            )
+            .arg(
+                Arg::new("SOURCE")
+                    .help("Source directory to build RAFS filesystem from")
+                    .value_parser(clap::value_parser!(PathBuf))
+                    .required(true),
+            )
+            .arg(
+                Arg::new("sample-size")
+                    .long("sample-size")
+                    .help("Approximate number of bytes of source files to read for analysis")
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .default_value("268435456"),
+            )
+            .arg(arg_output_json.clone()),
+    );
+
+    let app = app.subcommand(
+        App::new("selftest")
+            .about("Exercise the read path of a RAFS filesystem in-process without mounting it")
+            .arg(
+                Arg::new("BOOTSTRAP")
+                    .help("File path of RAFS metadata")
+                    .required_unless_present("bootstrap"),
+            )
+            .arg(
+                Arg::new("bootstrap")
+                    .short('B')
+                    .long("bootstrap")
+                    .help("File path of RAFS meta blob/bootstrap")
+                    .conflicts_with("BOOTSTRAP")
+                    .required(false),
+            )
+            .arg(
+                Arg::new("blob-dir")
+                    .long("blob-dir")
+                    .short('D')
+                    .conflicts_with("config")
+                    .help(
+                        "Directory for localfs storage backend, hosting data blobs and cache files",
+                    ),
+            )
+            .arg(arg_config.clone())
+            .arg(arg_proxy_url.clone())
+            .arg(arg_proxy_ping_url.clone())
+            .arg(arg_proxy_no_fallback.clone())
+            .arg(
+                Arg::new("samples")
+                    .long("samples")
+                    .help("Number of random inodes to resolve, random chunk ranges are read from regular files among them")
+                    .value_parser(clap::value_parser!(u32).range(1..))
+                    .default_value("100"),
+            )
+            .arg(
+                Arg::new("seed")
+                    .long("seed")
+                    .help("Seed of the random generator, to reproduce a previous run")
+                    .value_parser(clap::value_parser!(u64))
+                    .required(false),
+            )
+            .arg(arg_output_json.clone()),
+    );
+
+    let app = app.subcommand(
+        App::new("sign")
+            .about("Embed a signature of RAFS v6 filesystem metadata into the metadata itself")
+            .arg(
+                Arg::new("BOOTSTRAP")
+                    .help("File path of RAFS metadata")
+                    .required_unless_present("bootstrap"),
+            )
+            .arg(
+                Arg::new("bootstrap")
+                    .short('B')
+                    .long("bootstrap")
+                    .help("[Deprecated] File path of RAFS meta blob/bootstrap")
+                    .conflicts_with("BOOTSTRAP")
+                    .required(false),
+            )
+            .arg(
+                Arg::new("private-key")
+                    .long("private-key")
+                    .help("PEM encoded RSA or EC private key to sign the RAFS metadata")
+                    .required(true),
+            )
+            .arg(
+                Arg::new("signer")
+                    .long("signer")
+                    .help("Identity of the signer to embed together with the signature")
+                    .default_value(""),
+            ),
+    );
+
+    let app = app.subcommand(
+        App::new("regen-meta")
+            .about("Reconstruct blob meta of data blobs from RAFS filesystem metadata")
+            .arg(
+                Arg::new("BOOTSTRAP")
+                    .help("File path of RAFS metadata")
+                    .required_unless_present("bootstrap"),
+            )
+            .arg(
+                Arg::new("bootstrap")
+                    .short('B')
+                    .long("bootstrap")
+                    .help("File path of RAFS meta blob/bootstrap")
+                    .conflicts_with("BOOTSTRAP")
+                    .required(false),
+            )
+            .arg(
+                Arg::new("blob-id")
+                    .long("blob-id")
+                    .short('b')
+                    .help("Id of data blob to reconstruct blob meta for, all data blobs by default")
+                    .action(ArgAction::Append)
+                    .required(false),
+            )
+            .arg(
+                Arg::new("blob-cache-dir")
+                    .long("blob-cache-dir")
+                    .help("Directory path to generate the $id.blob.meta files")
+                    .required(true),
+            ),
+    );
+
+    let app = app.subcommand(
+        App::new("push")
+            .about("Push RAFS filesystem metadata and data blobs to a registry as a nydus image")
+            .arg(
+                Arg::new("BOOTSTRAP")
+                    .help("File path of RAFS metadata")
+                    .required_unless_present("bootstrap"),
+            )
+            .arg(
+                Arg::new("bootstrap")
+                    .short('B')
+                    .long("bootstrap")
+                    .help("[Deprecated] File path of RAFS meta blob/bootstrap")
+                    .conflicts_with("BOOTSTRAP")
+                    .required(false),
+            )
+            .arg(
+                Arg::new("blob-dir")
+                    .long("blob-dir")
+                    .short('D')
+                    .help("Directory hosting data blobs named by blob id, blobs not found in the directory are assumed to exist in the registry")
+                    .required(false),
+            )
+            .arg(
+                Arg::new("target")
+                    .long("target")
+                    .short('t')
+                    .help("Image reference to push to, like registry://host/repo:tag")
+                    .required(true),
+            )
+            .arg(arg_config.clone()),
     );
 
     #[cfg(target_os = "linux")]
@@ -592,8 +1179,8 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
             .about("Inspect RAFS filesystem metadata in interactive or request mode")
             .arg(
                 Arg::new("BOOTSTRAP")
-                    .help("File path of RAFS metadata")
-                    .required_unless_present("bootstrap"),
+                    .help("File path of RAFS metadata, '-' to read from stdin")
+                    .required_unless_present_any(["bootstrap", "blob"]),
             )
             .arg(
                 Arg::new("bootstrap")
@@ -603,6 +1190,14 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
                     .conflicts_with("BOOTSTRAP")
                     .required(false),
             )
+            .arg(
+                Arg::new("blob")
+                    .long("blob")
+                    .short('b')
+                    .help("File path of RAFS data blob with inlined RAFS metadata, instead of a separate bootstrap")
+                    .conflicts_with_all(["BOOTSTRAP", "bootstrap", "blob-dir", "config"])
+                    .required(false),
+            )
             .arg(
                 Arg::new("blob-dir")
                     .long("blob-dir")
@@ -613,6 +1208,9 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
                     ),
             )
             .arg(arg_config.clone())
+            .arg(arg_proxy_url.clone())
+            .arg(arg_proxy_ping_url.clone())
+            .arg(arg_proxy_no_fallback.clone())
             .arg(
                 Arg::new("request")
                     .long("request")
@@ -629,7 +1227,7 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
                     Arg::new("bootstrap")
                         .long("bootstrap")
                         .short('B')
-                        .help("Generate statistics information for the RAFS filesystem")
+                        .help("Generate statistics information for the RAFS filesystem, '-' to read from stdin")
                         .required(false),
                 )
                 .arg(
@@ -646,7 +1244,18 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
                         .help("Generate statistics information for the RAFS filesystem after applying chunk deduplication")
                         .required(false),
                 )
+                .arg(
+                    Arg::new("access-list")
+                        .long("access-list")
+                        .help("Estimate data to download by lazy-loading runtimes for accessing files listed in the file, one path per line")
+                        .requires("bootstrap")
+                        .conflicts_with_all(["blob-dir", "target"])
+                        .required(false),
+                )
                 .arg(arg_config.clone())
+                .arg(arg_proxy_url.clone())
+                .arg(arg_proxy_ping_url.clone())
+                .arg(arg_proxy_no_fallback.clone())
                 .arg(
                     Arg::new("digester")
                         .long("digester")
@@ -737,7 +1346,15 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
             .arg(
                 Arg::new("BOOTSTRAP")
                     .help("File path of RAFS metadata")
-                    .required_unless_present("bootstrap"),
+                    .required_unless_present_any(["bootstrap", "layer-bootstraps"]),
+            )
+            .arg(
+                Arg::new("layer-bootstraps")
+                    .long("layer-bootstraps")
+                    .help("File paths of RAFS metadata of all image layers, ordered from the lowest layer to the uppermost layer, to generate a gzip compressed tar file for each layer")
+                    .num_args(1..)
+                    .conflicts_with_all(["BOOTSTRAP", "bootstrap"])
+                    .required(false),
             )
             .arg(
                 Arg::new("backend-type")
@@ -794,9 +1411,16 @@ fn prepare_cmd_args(bti_string: &'static str) -> App {
             .arg(
                 Arg::new("output")
                     .long("output")
-                    .help("Path for output tar file")
+                    .help("Path for output tar file, or output directory for '--layer-bootstraps'")
                     .required(true),
             )
+            .arg(
+                Arg::new("whiteout-spec")
+                    .long("whiteout-spec")
+                    .help("Convert whiteouts into the specification consumed by the next snapshotter, 'none' keeps them as is:")
+                    .default_value("none")
+                    .value_parser(["oci", "overlayfs", "none"]),
+            )
             .group(
                 clap::ArgGroup::new("backend")
                     .args(&["backend-type", "blob", "blob-dir"])
@@ -846,6 +1470,10 @@ fn main() -> Result<()> {
                 matches.subcommand_matches("generate").unwrap(),
                 &build_info,
             ),
+            Some("export") => Command::chunkdict_export(
+                matches.subcommand_matches("export").unwrap(),
+                &build_info,
+            ),
             _ => {
                 println!("{}", usage);
                 Ok(())
@@ -862,6 +1490,16 @@ fn main() -> Result<()> {
         result
     } else if let Some(matches) = cmd.subcommand_matches("check") {
         Command::check(matches, &build_info)
+    } else if let Some(matches) = cmd.subcommand_matches("analyze") {
+        Command::analyze(matches)
+    } else if let Some(matches) = cmd.subcommand_matches("selftest") {
+        Command::selftest(matches)
+    } else if let Some(matches) = cmd.subcommand_matches("sign") {
+        Command::sign(matches)
+    } else if let Some(matches) = cmd.subcommand_matches("regen-meta") {
+        Command::regen_meta(matches)
+    } else if let Some(matches) = cmd.subcommand_matches("push") {
+        Command::push(matches)
     } else if let Some(matches) = cmd.subcommand_matches("inspect") {
         Command::inspect(matches)
     } else if let Some(matches) = cmd.subcommand_matches("stat") {
@@ -890,23 +1528,381 @@ struct Command {}
 
 impl Command {
     fn create(matches: &ArgMatches, build_info: &BuildTimeInfo) -> Result<()> {
+        let sources: Vec<PathBuf> = matches
+            .get_many::<String>("SOURCE")
+            .map(|paths| paths.map(PathBuf::from).collect())
+            .unwrap();
+        let config = Self::get_configuration(matches)?;
+        let pull_config = Self::get_pull_configuration(matches, &config);
+        let (_chunk_dict_dirs, chunk_dict_paths) =
+            Self::get_chunk_dict_paths(matches, &pull_config)?;
+        let (_parent_dir, parent_path) = Self::pull_parent_bootstrap(matches, &pull_config)?;
+        let prefetch = Self::get_prefetch(matches)?;
+        if let Some(tracer) = timing_tracer!() {
+            tracer.set_dir_depth(*matches.get_one::<usize>("timing-dir-depth").unwrap());
+        }
+
+        let check_repeatable = matches.get_flag("check-repeatable");
+
+        if sources.len() > 1 {
+            if check_repeatable {
+                bail!("`--check-repeatable` doesn't support building from multiple sources");
+            }
+            return Self::create_layers(
+                matches,
+                build_info,
+                sources,
+                parent_path,
+                prefetch,
+                &chunk_dict_paths,
+            );
+        }
+
+        let (build_output, compressor, version) = Self::build_layer(
+            matches,
+            sources[0].clone(),
+            parent_path.clone(),
+            prefetch.clone(),
+            &chunk_dict_paths,
+            None,
+            None,
+            None,
+        )?;
+        info!("successfully built RAFS filesystem: \n{}", build_output);
+        if check_repeatable {
+            Self::check_repeatable(
+                matches,
+                &sources[0],
+                parent_path,
+                prefetch,
+                &chunk_dict_paths,
+                &build_output,
+            )?;
+        }
+        OutputSerializer::dump_build(
+            matches,
+            build_output,
+            Vec::new(),
+            build_info,
+            compressor,
+            version,
+        )
+    }
+
+    /// Add annotations specified by `--annotation`, and annotations to record provenance of the
+    /// build if `--provenance` is given, which are only supported by RAFS v6.
+    fn set_annotations(
+        ctx: &mut BuildContext,
+        matches: &ArgMatches,
+        version: RafsVersion,
+        source_digests: &[String],
+    ) -> Result<()> {
+        let labels = matches
+            .try_get_many::<String>("annotation")
+            .ok()
+            .flatten()
+            .map(|v| v.collect::<Vec<_>>())
+            .unwrap_or_default();
+        let provenance = matches.get_flag("provenance");
+        if !version.is_v6() {
+            if !labels.is_empty() {
+                bail!("'--annotation' is only supported by RAFS v6");
+            }
+            if provenance {
+                bail!("'--provenance' is only supported by RAFS v6");
+            }
+            return Ok(());
+        }
+
+        if provenance {
+            ctx.add_annotation(ANNOTATION_BUILDER_VERSION, &BTI.package_ver)?;
+            // Build time breaks repeatable builds.
+            if !matches.get_flag("repeatable") {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .context("invalid system time")?;
+                ctx.add_annotation(ANNOTATION_BUILD_TIME, &now.as_secs().to_string())?;
+            }
+            if !source_digests.is_empty() {
+                ctx.add_annotation(ANNOTATION_SOURCE_DIGEST, &source_digests.join(" "))?;
+            }
+        }
+        for label in labels {
+            let (key, value) = label
+                .split_once('=')
+                .ok_or_else(|| anyhow!("invalid annotation '{}', should be KEY=VALUE", label))?;
+            if key.starts_with(ANNOTATION_PREFIX) {
+                bail!(
+                    "annotation key '{}' is reserved, keys with prefix '{}' are generated by the builder",
+                    key,
+                    ANNOTATION_PREFIX
+                );
+            }
+            ctx.add_annotation(key, value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Build the RAFS filesystem again into a temporary directory, and make sure the generated
+    /// bootstrap and data blob are bit-identical to those generated by the first build.
+    fn check_repeatable(
+        matches: &ArgMatches,
+        source_path: &Path,
+        parent_path: Option<String>,
+        prefetch: Prefetch,
+        chunk_dict_paths: &[PathBuf],
+        output: &BuildOutput,
+    ) -> Result<()> {
+        let dir = oci::WorkDir::new()?;
+        let bootstrap_path = dir.path().join("bootstrap");
+        let (rebuilt, _, _) = Self::build_layer(
+            matches,
+            source_path.to_path_buf(),
+            parent_path,
+            prefetch,
+            chunk_dict_paths,
+            Some(ArtifactStorage::SingleFile(bootstrap_path.clone())),
+            Some(ArtifactStorage::FileDir(dir.path().to_path_buf())),
+            None,
+        )
+        .context("failed to rebuild RAFS filesystem to check repeatability")?;
+
+        if output.blobs != rebuilt.blobs || output.blob_size != rebuilt.blob_size {
+            bail!(
+                "build isn't repeatable, blobs {:?} with data blob size {:?} are generated by the first build, but blobs {:?} with data blob size {:?} by the second build",
+                output.blobs,
+                output.blob_size,
+                rebuilt.blobs,
+                rebuilt.blob_size
+            );
+        }
+        if let Some(path) = output.bootstrap_path.as_ref() {
+            let expected = oci::file_digest(Path::new(path))?;
+            let actual = oci::file_digest(&bootstrap_path)?;
+            if expected != actual {
+                bail!(
+                    "build isn't repeatable, RAFS metadata digest {} of the first build differs from {} of the second build",
+                    expected,
+                    actual
+                );
+            }
+        }
+        if let Some(blob_id) = rebuilt
+            .blobs
+            .iter()
+            .find(|id| dir.path().join(id).is_file())
+        {
+            let conversion_type: ConversionType =
+                matches.get_one::<String>("type").unwrap().parse()?;
+            let blob_path = match Self::get_blob_storage(matches, conversion_type)? {
+                Some(ArtifactStorage::FileDir(d)) => d.join(blob_id),
+                Some(ArtifactStorage::SingleFile(p)) => p,
+                None => bail!("no data blob generated by the first build"),
+            };
+            let expected = oci::file_digest(&blob_path)?;
+            let actual = oci::file_digest(&dir.path().join(blob_id))?;
+            if expected != actual {
+                bail!(
+                    "build isn't repeatable, data blob digest {} of the first build differs from {} of the second build",
+                    expected,
+                    actual
+                );
+            }
+        }
+        info!("RAFS filesystem is repeatable, the second build generates identical artifacts");
+
+        Ok(())
+    }
+
+    /// Build per layer RAFS filesystems from a stack of layers, ordered from the lowest layer to
+    /// the uppermost one, and then merge them into an image level RAFS filesystem.
+    ///
+    /// Data blobs and per layer bootstraps are stored into `--blob-dir`, and the merged bootstrap
+    /// is stored as `--bootstrap` if specified.
+    fn create_layers(
+        matches: &ArgMatches,
+        build_info: &BuildTimeInfo,
+        sources: Vec<PathBuf>,
+        parent_path: Option<String>,
+        prefetch: Prefetch,
+        chunk_dict_paths: &[PathBuf],
+    ) -> Result<()> {
+        let conversion_type: ConversionType = matches.get_one::<String>("type").unwrap().parse()?;
+        if !matches!(
+            conversion_type,
+            ConversionType::DirectoryToRafs
+                | ConversionType::EStargzToRafs
+                | ConversionType::TargzToRafs
+                | ConversionType::TarToRafs
+        ) {
+            bail!(
+                "conversion type '{}' doesn't support building from multiple sources",
+                conversion_type
+            );
+        }
+        let blob_dir = match matches.get_one::<String>("blob-dir") {
+            Some(dir) => PathBuf::from(dir),
+            None => bail!("'--blob-dir' is required to build from multiple sources"),
+        };
+        for arg in ["blob", "blob-id"] {
+            if matches.get_one::<String>(arg).is_some() {
+                bail!("building from multiple sources conflicts with '--{}'", arg);
+            }
+        }
+        if matches.get_flag("blob-inline-meta") {
+            bail!("building from multiple sources conflicts with '--blob-inline-meta'");
+        }
+
+        // Stage all generated artifacts, and publish them only if the merged bootstrap is valid.
+        let stage = Arc::new(ArtifactStage::new());
+        let mut layer_bootstraps = Vec::with_capacity(sources.len());
+        let mut dedup_stats = ChunkDedupStats::default();
+        let mut skipped_files = Vec::new();
+        let mut unsupported_files = Vec::new();
+        let mut transformed_files = Vec::new();
+        let mut privileged_files = PrivilegedFiles::default();
+        for (idx, source) in sources.into_iter().enumerate() {
+            let (output, _, _) = Self::build_layer(
+                matches,
+                source.clone(),
+                None,
+                prefetch.clone(),
+                chunk_dict_paths,
+                Some(ArtifactStorage::FileDir(blob_dir.clone())),
+                None,
+                Some(stage.clone()),
+            )
+            .with_context(|| format!("failed to build layer {} from {:?}", idx, source))?;
+            info!(
+                "successfully built RAFS filesystem for layer {}: \n{}",
+                idx, output
+            );
+            dedup_stats.merge(&output.dedup_stats);
+            skipped_files.extend(output.skipped_files);
+            unsupported_files.extend(output.unsupported_files);
+            transformed_files.extend(output.transformed_files);
+            privileged_files.merge(&output.privileged_files);
+            let path = output
+                .bootstrap_path
+                .ok_or_else(|| anyhow!("no bootstrap generated for layer {}", idx))?;
+            layer_bootstraps.push(path);
+        }
+
+        let config = Self::get_configuration(matches)?;
+        // Data blobs have just been generated, so blob ids in per layer bootstraps are valid.
+        config.internal.set_blob_accessible(true);
+        let mut ctx = BuildContext {
+            prefetch,
+            ..Default::default()
+        };
+        ctx.configuration = config.clone();
+        ctx.set_artifact_stage(Some(stage.clone()));
+        let mut source_digests = Vec::new();
+        for path in layer_bootstraps.iter() {
+            let path = stage.resolve(Path::new(path));
+            let (rs, mut reader) = RafsSuper::load_from_file(&path, config.clone(), false)
+                .with_context(|| format!("failed to load bootstrap {:?}", path))?;
+            let annotations = rs
+                .get_annotations(&mut reader)
+                .with_context(|| format!("failed to load annotations from {:?}", path))?;
+            if let Some(digest) = annotations.get(ANNOTATION_SOURCE_DIGEST) {
+                source_digests.push(digest.to_string());
+            }
+        }
+        let version = Self::get_fs_version(matches)?;
+        Self::set_annotations(&mut ctx, matches, version, &source_digests)?;
+        let mut output = Merger::merge(
+            &mut ctx,
+            parent_path,
+            layer_bootstraps
+                .iter()
+                .map(|p| stage.resolve(Path::new(p)))
+                .collect(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            Self::get_bootstrap_storage(matches)?,
+            chunk_dict_paths.to_vec(),
+            config,
+            &PathFilter::default(),
+        )
+        .context("failed to merge per layer bootstraps")?;
+
+        let bootstrap_path = output
+            .bootstrap_path
+            .as_ref()
+            .map(|p| stage.resolve(Path::new(p)))
+            .ok_or_else(|| anyhow!("no bootstrap generated by merging per layer bootstraps"))?;
+        // Data blobs are accessed by final paths, which are not published yet. Use a private
+        // copy of the internal configuration, which is shared by clones of the configuration.
+        let mut validate_config = ctx.configuration.as_ref().clone();
+        validate_config.internal = ConfigV2Internal::default();
+        validate_config.internal.set_blob_accessible(false);
+        Validator::new(&bootstrap_path, Arc::new(validate_config))
+            .and_then(|mut validator| validator.check(false))
+            .with_context(|| format!("failed to validate merged bootstrap {:?}", bootstrap_path))?;
+        let published = stage.publish()?;
+        info!("published {} artifacts", published.len());
+
+        output.dedup_stats = dedup_stats;
+        output.skipped_files = skipped_files;
+        output.unsupported_files = unsupported_files;
+        output.transformed_files = transformed_files;
+        output.privileged_files = privileged_files;
+        info!("successfully merged RAFS filesystem: \n{}", output);
+        OutputSerializer::dump_build(
+            matches,
+            output,
+            layer_bootstraps,
+            build_info,
+            ctx.compressor,
+            ctx.fs_version,
+        )
+    }
+
+    /// Build a RAFS filesystem from `source_path`.
+    ///
+    /// The bootstrap and data blob are stored into `bootstrap_storage` and `blob_storage` if
+    /// specified, otherwise the storages are decided by commandline arguments. Generated artifacts
+    /// are staged in `stage` if specified, instead of being published immediately.
+    #[allow(clippy::too_many_arguments)]
+    fn build_layer(
+        matches: &ArgMatches,
+        source_path: PathBuf,
+        parent_path: Option<String>,
+        prefetch: Prefetch,
+        chunk_dict_paths: &[PathBuf],
+        bootstrap_storage: Option<ArtifactStorage>,
+        blob_storage: Option<ArtifactStorage>,
+        stage: Option<Arc<ArtifactStage>>,
+    ) -> Result<(BuildOutput, compress::Algorithm, RafsVersion)> {
         let blob_id = Self::get_blob_id(matches)?;
         let blob_offset = Self::get_blob_offset(matches)?;
-        let parent_path = Self::get_parent_bootstrap(matches)?;
-        let prefetch = Self::get_prefetch(matches)?;
-        let source_path = PathBuf::from(matches.get_one::<String>("SOURCE").unwrap());
         let conversion_type: ConversionType = matches.get_one::<String>("type").unwrap().parse()?;
         let blob_inline_meta = matches.get_flag("blob-inline-meta");
         let repeatable = matches.get_flag("repeatable");
         let version = Self::get_fs_version(matches)?;
         let chunk_size = Self::get_chunk_size(matches, conversion_type)?;
         let batch_size = Self::get_batch_size(matches, version, conversion_type, chunk_size)?;
-        let blob_cache_storage = Self::get_blob_cache_storage(matches, conversion_type)?;
+        let blob_padding = Self::get_alignment_size(matches, "blob-padding")?;
+        let blob_meta_alignment = Self::get_alignment_size(matches, "blob-meta-alignment")?;
+        let blob_cache_storage =
+            Self::get_blob_cache_storage(matches, "blob-cache-dir", conversion_type)?;
+        let blob_meta_storage =
+            Self::get_blob_cache_storage(matches, "blob-meta-dir", conversion_type)?;
+        if blob_meta_storage.is_some() && !version.is_v6() {
+            bail!("'--blob-meta-dir' is only supported by RAFS v6");
+        }
         // blob-cache-dir and blob-dir/blob are a set of mutually exclusive functions,
         // the former is used to generate blob cache, nydusd is directly started through blob cache,
         // the latter is to generate nydus blob, as nydusd backend to start
         let blob_storage = if blob_cache_storage.is_some() {
             None
+        } else if blob_storage.is_some() {
+            blob_storage
         } else {
             Self::get_blob_storage(matches, conversion_type)?
         };
@@ -922,6 +1918,17 @@ impl Command {
             .map(|s| s.as_str())
             .unwrap_or_default()
             .parse()?;
+        let inode_order: InodeOrder = matches
+            .get_one::<String>("inode-order")
+            .map(|s| s.as_str())
+            .unwrap_or_default()
+            .parse()?;
+        if inode_order != InodeOrder::Bfs && !version.is_v6() {
+            bail!(
+                "'--inode-order {}' is only supported by RAFS v6",
+                inode_order
+            );
+        }
         let mut compressor = matches
             .get_one::<String>("compressor")
             .map(|s| s.as_str())
@@ -932,7 +1939,7 @@ impl Command {
             .map(|s| s.as_str())
             .unwrap_or_default()
             .parse()?;
-        let blob_data_size = Self::get_blob_size(matches, conversion_type)?;
+        let blob_data_size = Self::get_blob_size(matches, conversion_type, &source_path)?;
         let features = Features::try_from(
             matches
                 .get_one::<String>("features")
@@ -955,6 +1962,11 @@ impl Command {
                     bail!("both --blob and --blob-dir or --blob-cache-dir are missing");
                 }
             }
+            ConversionType::OciRefToRafs => {
+                if blob_storage.is_none() && blob_cache_storage.is_none() {
+                    bail!("both --blob and --blob-dir or --blob-cache-dir are missing");
+                }
+            }
             ConversionType::TarToRef
             | ConversionType::TargzToRef
             | ConversionType::EStargzToRef => {
@@ -1038,7 +2050,7 @@ impl Command {
                         conversion_type
                     );
                 }
-                if matches.get_one::<String>("chunk-dict").is_some() {
+                if matches.get_many::<String>("chunk-dict").is_some() {
                     bail!(
                         "conversion type '{}' conflicts with '--chunk-dict'",
                         conversion_type
@@ -1117,6 +2129,45 @@ impl Command {
                     )
                 }
             }
+            ConversionType::ZstdChunkedToRef => {
+                Self::ensure_file(&source_path)?;
+                if matches.value_source("compressor") != Some(ValueSource::DefaultValue)
+                    && compressor != compress::Algorithm::Zstd
+                {
+                    info!(
+                        "only Zstd is supported for conversion type {}, use Zstd instead of {}",
+                        conversion_type, compressor
+                    );
+                }
+                if matches.value_source("digester") != Some(ValueSource::DefaultValue)
+                    && digester != digest::Algorithm::Sha256
+                {
+                    info!(
+                        "only SHA256 is supported for conversion type {}, use SHA256 instead of {}",
+                        conversion_type, digester
+                    );
+                }
+                compressor = compress::Algorithm::Zstd;
+                digester = digest::Algorithm::Sha256;
+                if blob_storage.is_some() || blob_cache_storage.is_some() {
+                    bail!(
+                        "conversion type '{}' conflicts with '--blob' and '--blob-cache-dir'",
+                        conversion_type
+                    );
+                }
+                if version != RafsVersion::V6 {
+                    bail!(
+                        "'--fs-version 5' conflicts with conversion type '{}', only V6 is supported",
+                        conversion_type
+                    );
+                }
+                if encrypt {
+                    bail!(
+                        "conversion type '{}' conflicts with '--encrypt'",
+                        conversion_type
+                    )
+                }
+            }
             ConversionType::DirectoryToStargz
             | ConversionType::TargzToStargz
             | ConversionType::TarToStargz => {
@@ -1131,11 +2182,109 @@ impl Command {
             bail!("`--features blob-toc` can't be used with `--version 5` ");
         }
 
+        let follow_symlinks = matches.get_flag("follow-symlinks");
+        if follow_symlinks && conversion_type != ConversionType::DirectoryToRafs {
+            bail!(
+                "conversion type '{}' conflicts with '--follow-symlinks'",
+                conversion_type
+            );
+        }
+        let skip_unreadable = matches.get_flag("skip-unreadable");
+        if skip_unreadable && conversion_type != ConversionType::DirectoryToRafs {
+            bail!(
+                "conversion type '{}' conflicts with '--skip-unreadable'",
+                conversion_type
+            );
+        }
+        let unsupported_file_policy = matches
+            .get_one::<String>("unsupported-file-policy")
+            .map(|s| s.parse::<UnsupportedFilePolicy>())
+            .transpose()?;
+        let one_file_system = matches.get_flag("one-file-system");
+        if one_file_system && conversion_type != ConversionType::DirectoryToRafs {
+            bail!(
+                "conversion type '{}' conflicts with '--one-file-system'",
+                conversion_type
+            );
+        }
+        let readahead_files = matches.get_one::<u32>("readahead-files").copied();
+        if readahead_files.is_some() && conversion_type != ConversionType::DirectoryToRafs {
+            bail!(
+                "conversion type '{}' conflicts with '--readahead-files'",
+                conversion_type
+            );
+        }
+        let transform_cmd = matches.get_one::<String>("transform-cmd");
+        if transform_cmd.is_some() && conversion_type != ConversionType::DirectoryToRafs {
+            bail!(
+                "conversion type '{}' conflicts with '--transform-cmd'",
+                conversion_type
+            );
+        }
+        let hardlink_key: HardlinkKey = matches
+            .get_one::<String>("hardlink-key")
+            .map(|s| s.as_str())
+            .unwrap_or_default()
+            .parse()?;
+        let hardlink_dev_map = Self::get_hardlink_dev_map(matches)?;
+        if (hardlink_key != HardlinkKey::Dev || !hardlink_dev_map.is_empty())
+            && conversion_type != ConversionType::DirectoryToRafs
+        {
+            bail!(
+                "conversion type '{}' conflicts with '--hardlink-key' and '--hardlink-dev-map'",
+                conversion_type
+            );
+        }
+
+        if blob_padding > 0 || blob_meta_alignment > 0 {
+            if conversion_type == ConversionType::TarToTarfs {
+                bail!(
+                    "conversion type '{}' conflicts with '--blob-padding' and '--blob-meta-alignment'",
+                    conversion_type
+                );
+            }
+            // The RAFS metadata must be the last entry of blobs with inlined metadata but
+            // without ToC, so there's no room for padding.
+            if blob_padding > 0 && blob_inline_meta && !features.is_enabled(Feature::BlobToc) {
+                bail!("`--blob-padding` with `--blob-inline-meta` requires `--features blob-toc`");
+            }
+        }
+
         if blob_cache_storage.is_some() {
             // In blob cache mode, we don't need to do any compression for the original data
             compressor = compress::Algorithm::None;
         }
 
+        let compress_level = matches.get_one::<u32>("compress-level").copied();
+        if compress_level.is_some() && !compressor.has_level() {
+            bail!(
+                "'--compress-level' is not supported by compressor '{}'",
+                compressor
+            );
+        }
+
+        let hot_compressor: Option<compress::Algorithm> = matches
+            .get_one::<String>("hot-compressor")
+            .map(|s| s.parse())
+            .transpose()?;
+        if hot_compressor.is_some() {
+            if !version.is_v6() {
+                bail!("'--hot-compressor' is only supported by RAFS v6");
+            }
+            match conversion_type {
+                ConversionType::DirectoryToRafs
+                | ConversionType::TarToRafs
+                | ConversionType::TargzToRafs
+                | ConversionType::EStargzToRafs
+                | ConversionType::OciRefToRafs => {}
+                _ => bail!(
+                    "conversion type '{}' conflicts with '--hot-compressor'",
+                    conversion_type
+                ),
+            }
+        }
+        let access_trace = Self::get_access_trace(matches)?;
+
         let mut build_ctx = BuildContext::new(
             blob_id,
             aligned_chunk,
@@ -1153,12 +2302,55 @@ impl Command {
             encrypt,
         );
         build_ctx.set_fs_version(version);
+        // Hashing the whole tarball is expensive, so only do it when asked to.
+        let source_digests =
+            if version.is_v6() && matches.get_flag("provenance") && source_path.is_file() {
+                vec![format!("sha256:{}", oci::file_digest(&source_path)?)]
+            } else {
+                Vec::new()
+            };
+        Self::set_annotations(&mut build_ctx, matches, version, &source_digests)?;
         build_ctx.set_chunk_size(chunk_size);
         build_ctx.set_batch_size(batch_size);
+        build_ctx.set_compress_level(compress_level);
+        if let Some(hot_compressor) = hot_compressor {
+            // Per-chunk compression algorithms are recorded in chunk information array v2.
+            build_ctx.blob_features.insert(BlobFeatures::CHUNK_INFO_V2);
+            build_ctx.set_hot_compressor(Some(hot_compressor))?;
+            for path in access_trace {
+                build_ctx.set_file_compressor(path, hot_compressor)?;
+            }
+        }
+        build_ctx.set_inode_order(inode_order);
+        build_ctx.set_follow_symlinks(follow_symlinks);
+        build_ctx.set_skip_unreadable(skip_unreadable);
+        if let Some(policy) = unsupported_file_policy {
+            build_ctx.set_unsupported_file_policy(policy);
+        }
+        build_ctx.set_one_file_system(one_file_system);
+        build_ctx.set_readahead_files(readahead_files.unwrap_or_default() as usize);
+        if let Some(cmd) = transform_cmd {
+            build_ctx.set_file_transform(Arc::new(CommandTransform::new(cmd)));
+        }
+        build_ctx.set_hardlink_key(hardlink_key, hardlink_dev_map);
+        build_ctx.set_blob_padding(blob_padding);
+        build_ctx.set_blob_meta_alignment(blob_meta_alignment);
+        let tmp_dir = matches.get_one::<PathBuf>("tmp-dir").cloned();
+        if let Some(dir) = tmp_dir.as_ref() {
+            if !dir.is_dir() {
+                bail!("temporary directory {} doesn't exist", dir.display());
+            }
+        }
+        build_ctx.set_tmp_dir(tmp_dir);
+        build_ctx.set_artifact_stage(stage.clone());
+        if matches.get_flag("check-free-space") {
+            build_ctx.check_free_space()?;
+        }
 
-        let blob_cache_generator = match blob_cache_storage {
-            Some(storage) => Some(BlobCacheGenerator::new(storage)?),
-            None => None,
+        let blob_cache_generator = match (blob_cache_storage, blob_meta_storage) {
+            (Some(storage), _) => Some(BlobCacheGenerator::new(storage)?),
+            (None, Some(storage)) => Some(BlobCacheGenerator::new_meta_only(storage)?),
+            (None, None) => None,
         };
         build_ctx.blob_cache_generator = blob_cache_generator;
 
@@ -1170,7 +2362,7 @@ impl Command {
         build_ctx.set_configuration(config.clone());
 
         let mut blob_mgr = BlobManager::new(digester);
-        if let Some(chunk_dict_arg) = matches.get_one::<String>("chunk-dict") {
+        if !chunk_dict_paths.is_empty() {
             let config = RafsSuperConfig {
                 version,
                 compressor,
@@ -1184,7 +2376,7 @@ impl Command {
             // The separate chunk dict bootstrap doesn't support blob accessible.
             rafs_config.internal.set_blob_accessible(false);
             blob_mgr.set_chunk_dict(timing_tracer!(
-                { HashChunkDict::from_commandline_arg(chunk_dict_arg, rafs_config, &config,) },
+                { HashChunkDict::from_bootstrap_files(chunk_dict_paths, rafs_config, &config) },
                 "import_chunk_dict"
             )?);
         }
@@ -1192,9 +2384,13 @@ impl Command {
         let mut bootstrap_mgr = if blob_inline_meta {
             BootstrapManager::new(None, parent_path)
         } else {
-            let bootstrap_path = Self::get_bootstrap_storage(matches)?;
+            let bootstrap_path = match bootstrap_storage {
+                Some(storage) => storage,
+                None => Self::get_bootstrap_storage(matches)?,
+            };
             BootstrapManager::new(Some(bootstrap_path), parent_path)
         };
+        bootstrap_mgr.set_artifact_stage(stage);
 
         // Legality has been checked and filtered by `get_batch_size()`.
         if build_ctx.batch_size > 0 {
@@ -1204,6 +2400,8 @@ impl Command {
             build_ctx.blob_features.insert(BlobFeatures::CHUNK_INFO_V2);
         }
 
+        // Keep pulled image layers until the build is done.
+        let mut layer_dir = None;
         let mut builder: Box<dyn Builder> = match conversion_type {
             ConversionType::DirectoryToRafs => {
                 if encrypt {
@@ -1212,7 +2410,7 @@ impl Command {
                 }
                 Box::new(DirectoryBuilder::new())
             }
-            ConversionType::EStargzIndexToRef => {
+            ConversionType::EStargzIndexToRef | ConversionType::ZstdChunkedToRef => {
                 Box::new(StargzBuilder::new(blob_data_size, &build_ctx))
             }
             ConversionType::EStargzToRafs
@@ -1224,6 +2422,19 @@ impl Command {
                 }
                 Box::new(TarballBuilder::new(conversion_type))
             }
+            ConversionType::OciRefToRafs => {
+                if encrypt {
+                    build_ctx.blob_features.insert(BlobFeatures::CHUNK_INFO_V2);
+                    build_ctx.blob_features.insert(BlobFeatures::ENCRYPTED);
+                }
+                let pull_config = Self::get_pull_configuration(matches, &build_ctx.configuration);
+                let (dir, layers) = timing_tracer!(
+                    { Self::pull_image_layers(&build_ctx, &pull_config) },
+                    "pull_image_layers"
+                )?;
+                layer_dir = Some(dir);
+                Box::new(TarballBuilder::new_with_layers(conversion_type, layers))
+            }
             ConversionType::EStargzToRef
             | ConversionType::TargzToRef
             | ConversionType::TarToRef => {
@@ -1255,13 +2466,13 @@ impl Command {
         )?;
 
         lazy_drop(build_ctx);
+        drop(layer_dir);
 
         // Some operations like listing xattr pairs of certain namespace need the process
         // to be privileged. Therefore, trace what euid and egid are.
         event_tracer!("euid", "{}", geteuid());
         event_tracer!("egid", "{}", getegid());
-        info!("successfully built RAFS filesystem: \n{}", build_output);
-        OutputSerializer::dump(matches, build_output, build_info, compressor, version)
+        Ok((build_output, compressor, version))
     }
 
     fn chunkdict_generate(matches: &ArgMatches, build_info: &BuildTimeInfo) -> Result<()> {
@@ -1411,6 +2622,65 @@ impl Command {
         Ok(())
     }
 
+    fn chunkdict_export(matches: &ArgMatches, build_info: &BuildTimeInfo) -> Result<()> {
+        let source_bootstrap_paths: Vec<PathBuf> = matches
+            .get_many::<String>("bootstrap")
+            .map(|paths| paths.map(PathBuf::from).collect())
+            .unwrap();
+        let output_path = PathBuf::from(matches.get_one::<String>("output").unwrap());
+        let min_refcount = *matches.get_one::<u32>("min-refcount").unwrap() as usize;
+
+        let mut build_ctx = BuildContext::default();
+        check_bootstrap_versions_consistency(&mut build_ctx, &source_bootstrap_paths)?;
+        update_ctx_from_parent_bootstrap(&mut build_ctx, &source_bootstrap_paths[0])?;
+
+        let config = Self::get_configuration(matches)?;
+        let (chunkdict_chunks, chunkdict_blobs) =
+            collect_shared_chunks(&source_bootstrap_paths, config.clone(), min_refcount)?;
+        if chunkdict_chunks.is_empty() {
+            bail!(
+                "no chunk is referenced at least {} times by the images",
+                min_refcount
+            );
+        }
+        info!(
+            "exporting {} chunks from {} blobs into chunk dictionary",
+            chunkdict_chunks.len(),
+            chunkdict_blobs.len()
+        );
+
+        let storage = ArtifactStorage::SingleFile(output_path.clone());
+        config
+            .internal
+            .set_blob_accessible(matches.get_one::<String>("config").is_some());
+        build_ctx.configuration = config;
+        build_ctx.blob_storage = Some(storage.clone());
+        build_ctx
+            .blob_features
+            .insert(BlobFeatures::IS_CHUNKDICT_GENERATED);
+        build_ctx.is_chunkdict_generated = true;
+
+        let mut blob_mgr = BlobManager::new(build_ctx.digester);
+        let mut bootstrap_mgr = BootstrapManager::new(Some(storage), None);
+        let output = Generator::generate(
+            &mut build_ctx,
+            &mut bootstrap_mgr,
+            &mut blob_mgr,
+            chunkdict_chunks,
+            chunkdict_blobs,
+        )?;
+        OutputSerializer::dump(
+            matches,
+            output,
+            build_info,
+            build_ctx.compressor,
+            build_ctx.fs_version,
+        )?;
+        info!("Chunkdict metadata is saved at: {:?}", output_path);
+
+        Ok(())
+    }
+
     fn merge(matches: &ArgMatches, build_info: &BuildTimeInfo) -> Result<()> {
         let source_bootstrap_paths: Vec<PathBuf> = matches
             .get_many::<String>("SOURCE")
@@ -1454,13 +2724,11 @@ impl Command {
                     .collect()
             });
         let target_bootstrap_path = Self::get_bootstrap_storage(matches)?;
-        let chunk_dict_path = if let Some(arg) = matches.get_one::<String>("chunk-dict") {
-            Some(parse_chunk_dict_arg(arg)?)
-        } else {
-            None
-        };
         let config =
             Self::get_configuration(matches).context("failed to get configuration information")?;
+        let pull_config = Self::get_pull_configuration(matches, &config);
+        let (_chunk_dict_dirs, chunk_dict_paths) =
+            Self::get_chunk_dict_paths(matches, &pull_config)?;
         config
             .internal
             .set_blob_accessible(matches.get_one::<String>("config").is_some());
@@ -1470,7 +2738,19 @@ impl Command {
         };
         ctx.configuration = config.clone();
 
-        let parent_bootstrap_path = Self::get_parent_bootstrap(matches)?;
+        let filter = PathFilter {
+            keep_layers: matches
+                .get_one::<usize>("filter-keep-layers")
+                .copied()
+                .unwrap_or_default(),
+            excludes: matches
+                .get_many::<PathBuf>("filter-exclude")
+                .map(|paths| paths.cloned().collect())
+                .unwrap_or_default(),
+        };
+
+        let (_parent_dir, parent_bootstrap_path) =
+            Self::pull_parent_bootstrap(matches, &pull_config)?;
         let meta = RafsSuper::load_from_file(&source_bootstrap_paths[0], config.clone(), false)?
             .0
             .meta;
@@ -1485,8 +2765,9 @@ impl Command {
             blob_toc_digests,
             blob_toc_sizes,
             target_bootstrap_path,
-            chunk_dict_path,
+            chunk_dict_paths,
             config,
+            &filter,
         )?;
         OutputSerializer::dump(
             matches,
@@ -1508,13 +2789,15 @@ impl Command {
 
         let (rs, _) = RafsSuper::load_from_file(&bootstrap_path, config.clone(), false)?;
         info!("load bootstrap {:?} successfully", bootstrap_path);
-        let chunk_dict = match matches.get_one::<String>("chunk-dict") {
-            None => None,
-            Some(args) => Some(HashChunkDict::from_commandline_arg(
-                args,
+        let (_chunk_dict_dirs, chunk_dict_paths) = Self::get_chunk_dict_paths(matches, &config)?;
+        let chunk_dict = if chunk_dict_paths.is_empty() {
+            None
+        } else {
+            Some(HashChunkDict::from_bootstrap_files(
+                &chunk_dict_paths,
                 config,
                 &rs.meta.get_config(),
-            )?),
+            )?)
         };
 
         let config_file_path = matches.get_one::<String>("config").unwrap();
@@ -1534,29 +2817,58 @@ impl Command {
     }
 
     fn unpack(matches: &ArgMatches) -> Result<()> {
-        let bootstrap = Self::get_bootstrap(matches)?;
         let output = matches.get_one::<String>("output").expect("pass in output");
         if output.is_empty() {
             return Err(anyhow!("invalid empty --output option"));
         }
         let (config, backend) = Self::get_backend(matches, "unpacker")?;
+        let whiteout_spec: WhiteoutSpec = matches
+            .get_one::<String>("whiteout-spec")
+            .map(|s| s.as_str())
+            .unwrap_or_default()
+            .parse()?;
+
+        if let Some(bootstraps) = matches.get_many::<String>("layer-bootstraps") {
+            let bootstraps = bootstraps.map(PathBuf::from).collect();
+            let layers = OCILayerUnpacker::new(bootstraps, Some(backend), output, whiteout_spec)
+                .with_context(|| "fail to create layer unpacker")?
+                .unpack_layers(config)
+                .with_context(|| "fail to unpack layers")?;
+            for (idx, layer) in layers.iter().enumerate() {
+                println!(
+                    "\t {}: {}, digest {}, diff_id {}, size 0x{:x}",
+                    idx,
+                    layer.path.display(),
+                    layer.digest,
+                    layer.diff_id,
+                    layer.size
+                );
+            }
+            return Ok(());
+        }
+
+        let bootstrap = Self::get_bootstrap(matches)?;
 
-        OCIUnpacker::new(bootstrap, Some(backend), output)
+        OCIUnpacker::new(bootstrap, Some(backend), output, whiteout_spec)
             .with_context(|| "fail to create unpacker")?
             .unpack(config)
             .with_context(|| "fail to unpack")
     }
 
     fn check(matches: &ArgMatches, build_info: &BuildTimeInfo) -> Result<()> {
-        let bootstrap_path = Self::get_bootstrap(matches)?;
+        let (source_path, _work_dir, bootstrap_path) = Self::get_source_bootstrap(matches)?;
+        let bootstrap_path = bootstrap_path.as_path();
         let verbose = matches.get_flag("verbose");
-        let config = Self::get_configuration(matches)?;
+        let config = match matches.get_one::<String>("blob") {
+            Some(blob) => Self::get_blob_file_configuration(Path::new(blob))?,
+            None => Self::get_configuration(matches)?,
+        };
         // For backward compatibility with v2.1
         config
             .internal
             .set_blob_accessible(matches.get_one::<String>("bootstrap").is_none());
 
-        let mut validator = Validator::new(bootstrap_path, config)?;
+        let mut validator = Validator::new(bootstrap_path, config.clone())?;
         let (blobs, compressor, fs_version) = validator
             .check(verbose)
             .with_context(|| format!("failed to check bootstrap {:?}", bootstrap_path))?;
@@ -1577,11 +2889,166 @@ impl Command {
             blob_ids.push(blob.blob_id().to_string());
         }
 
+        if let Some(public_key) = matches.get_one::<String>("public-key") {
+            let signer = signature::verify_bootstrap(bootstrap_path, Path::new(public_key))
+                .with_context(|| {
+                    format!(
+                        "failed to verify signature of bootstrap {:?}",
+                        bootstrap_path
+                    )
+                })?;
+            println!(
+                "RAFS filesystem metadata signature is valid, signer: {}",
+                signer
+            );
+        }
+
+        if let Some(other_path) = matches.get_one::<String>("equivalent-to") {
+            let other = Validator::new(Path::new(other_path), config.clone())
+                .with_context(|| format!("failed to load bootstrap {}", other_path))?;
+            let diffs = validator.compare(&other).with_context(|| {
+                format!(
+                    "failed to compare bootstrap {:?} with {}",
+                    bootstrap_path, other_path
+                )
+            })?;
+            if !diffs.is_empty() {
+                for diff in diffs.iter() {
+                    error!("{}", diff);
+                }
+                bail!(
+                    "RAFS filesystem {:?} isn't equivalent to {}, {} differences found",
+                    bootstrap_path,
+                    other_path,
+                    diffs.len()
+                );
+            }
+            println!("RAFS filesystem metadata is equivalent to {}", other_path);
+        }
+
+        if matches.get_flag("duplicate-chunks") {
+            let issues = validator.check_duplicate_chunks().with_context(|| {
+                format!("failed to check duplicate chunks in {:?}", bootstrap_path)
+            })?;
+            if !issues.is_empty() {
+                for issue in issues.iter() {
+                    error!("{}", issue);
+                }
+                bail!(
+                    "RAFS filesystem {:?} has {} chunks with inconsistent information",
+                    bootstrap_path,
+                    issues.len()
+                );
+            }
+            println!("No chunk with duplicate digest but inconsistent information found");
+        }
+
+        if matches.get_flag("symlinks") {
+            let issues = validator
+                .check_symlinks()
+                .with_context(|| format!("failed to check symlinks in {:?}", bootstrap_path))?;
+            if !issues.is_empty() {
+                for issue in issues.iter() {
+                    error!("{}", issue);
+                }
+                bail!(
+                    "RAFS filesystem {:?} has {} broken symlinks",
+                    bootstrap_path,
+                    issues.len()
+                );
+            }
+            println!("All symlinks resolve within the filesystem");
+        }
+
+        let (_chunk_dict_dirs, chunk_dict_paths) = Self::get_chunk_dict_paths(matches, &config)?;
+        if !chunk_dict_paths.is_empty() {
+            // Use private internal state, which is shared by clones of the configuration.
+            let mut dict_config = config.as_ref().clone();
+            dict_config.internal = ConfigV2Internal::default();
+            dict_config.internal.set_blob_accessible(false);
+            let dict = HashChunkDict::from_bootstrap_files(
+                &chunk_dict_paths,
+                Arc::new(dict_config),
+                &validator.rafs_config(),
+            )
+            .context("failed to load chunk dictionary")?;
+            let issues = validator.check_chunk_dict(dict.as_ref()).with_context(|| {
+                format!("failed to check chunk dictionary for {:?}", bootstrap_path)
+            })?;
+            if !issues.is_empty() {
+                for issue in issues.iter() {
+                    error!("{}", issue);
+                }
+                bail!(
+                    "RAFS filesystem {:?} has {} chunks unresolved in chunk dictionary",
+                    bootstrap_path,
+                    issues.len()
+                );
+            }
+            println!("All deduplicated chunks resolve in the chunk dictionary");
+        }
+
+        if matches.get_flag("data") {
+            let chunks = validator
+                .data_chunks()
+                .with_context(|| format!("failed to load data chunks of {:?}", bootstrap_path))?;
+            let mut checker = DataChecker::new(config.clone(), blobs.clone());
+            if let Some(path) = matches.get_one::<PathBuf>("data-checkpoint") {
+                checker.set_checkpoint(path.clone());
+            }
+            if let Some(rate) = matches.get_one::<u64>("data-bandwidth") {
+                checker.set_bandwidth(*rate);
+            }
+            if let Some(secs) = matches.get_one::<u64>("data-time-limit") {
+                checker.set_time_limit(Duration::from_secs(*secs));
+            }
+            let digest = oci::file_digest(bootstrap_path)?;
+            let progress = checker
+                .check(&digest, &chunks)
+                .with_context(|| format!("failed to verify data of {:?}", bootstrap_path))?;
+            if progress.chunks < chunks.len() as u64 {
+                println!(
+                    "Data verification paused at chunk {}/{}, {} issues found so far, run again with the same checkpoint file to resume",
+                    progress.chunks,
+                    chunks.len(),
+                    progress.issues.len()
+                );
+            } else {
+                if !progress.issues.is_empty() {
+                    for issue in progress.issues.iter() {
+                        error!("{}", issue);
+                    }
+                    bail!(
+                        "RAFS filesystem {:?} has {} corrupted data chunks",
+                        bootstrap_path,
+                        progress.issues.len()
+                    );
+                }
+                println!(
+                    "All data chunks are valid, 0x{:x} bytes verified, {} batch, encrypted or ZRAN chunks skipped",
+                    progress.bytes, progress.skipped
+                );
+            }
+        }
+
+        let capabilities = validator.capabilities();
+        println!("Image capabilities: {}", capabilities);
+
+        let extra_infos = validator.blob_extra_infos()?;
+        let blob_infos = blobs
+            .iter()
+            .map(|blob| BlobInfoOutput {
+                info: blob.clone(),
+                extra: extra_infos.get(&blob.blob_id()).cloned(),
+            })
+            .collect();
         OutputSerializer::dump_for_check(
             matches,
             build_info,
             blob_ids,
-            bootstrap_path,
+            blob_infos,
+            capabilities,
+            source_path,
             compressor,
             fs_version,
         )?;
@@ -1589,9 +3056,173 @@ impl Command {
         Ok(())
     }
 
-    fn inspect(matches: &ArgMatches) -> Result<()> {
+    fn analyze(matches: &ArgMatches) -> Result<()> {
+        let source = matches.get_one::<PathBuf>("SOURCE").unwrap();
+        Self::ensure_directory(source)?;
+        let sample_size = *matches.get_one::<u64>("sample-size").unwrap();
+
+        let report = chunk_advisor::analyze(source, sample_size)
+            .with_context(|| format!("failed to analyze source directory {:?}", source))?;
+        println!(
+            "Sampled {} of {} files, 0x{:x} of 0x{:x} bytes",
+            report.sampled_files, report.files, report.sampled_bytes, report.bytes
+        );
+        for o in report.options.iter() {
+            println!(
+                "\t chunk size 0x{:x}, batch size 0x{:x}, compressor {}: chunks {}, dedup rate {:.2}%, blob size 0x{:x}, metadata size 0x{:x}",
+                o.chunk_size,
+                o.batch_size,
+                o.compressor,
+                o.chunks,
+                o.dedup_rate * 100.0,
+                o.blob_size,
+                o.meta_size
+            );
+        }
+        match report.recommended.as_ref() {
+            Some(o) => println!(
+                "Recommended: --chunk-size 0x{:x} --batch-size 0x{:x} --compressor {}",
+                o.chunk_size, o.batch_size, o.compressor
+            ),
+            None => println!("No data to analyze in {:?}", source),
+        }
+
+        if let Some(f) = matches.get_one::<String>("output-json") {
+            report.dump_json(Path::new(f))?;
+        }
+
+        Ok(())
+    }
+
+    fn selftest(matches: &ArgMatches) -> Result<()> {
         let bootstrap_path = Self::get_bootstrap(matches)?;
-        let mut config = Self::get_configuration(matches)?;
+        let config = Self::get_configuration(matches)?;
+        config.internal.set_blob_accessible(true);
+        let samples = *matches.get_one::<u32>("samples").unwrap() as usize;
+        let seed = matches.get_one::<u64>("seed").copied();
+
+        let mut tester = SelfTester::new(bootstrap_path, config)?;
+        let report = tester
+            .run(samples, seed)
+            .with_context(|| format!("self test of bootstrap {:?} failed", bootstrap_path))?;
+        println!(
+            "RAFS filesystem serves reads correctly, seed {}, verified {} chunks of 0x{:x} bytes, skipped {} unsupported chunks",
+            report.seed, report.chunks, report.bytes, report.skipped_chunks
+        );
+        println!("Inode resolving latency: {}", report.inode_latency);
+        println!("Chunk reading latency: {}", report.chunk_latency);
+
+        if let Some(f) = matches.get_one::<String>("output-json") {
+            report.dump_json(Path::new(f))?;
+        }
+
+        Ok(())
+    }
+
+    fn sign(matches: &ArgMatches) -> Result<()> {
+        let bootstrap_path = Self::get_bootstrap(matches)?;
+        let private_key = matches.get_one::<String>("private-key").unwrap();
+        let signer = matches.get_one::<String>("signer").unwrap();
+
+        signature::sign_bootstrap(bootstrap_path, Path::new(private_key), signer)
+            .with_context(|| format!("failed to sign bootstrap {:?}", bootstrap_path))?;
+        info!("successfully signed bootstrap {:?}", bootstrap_path);
+        Ok(())
+    }
+
+    fn regen_meta(matches: &ArgMatches) -> Result<()> {
+        let bootstrap_path = Self::get_bootstrap(matches)?;
+        let cache_dir = Path::new(matches.get_one::<String>("blob-cache-dir").unwrap());
+        if !cache_dir.is_dir() {
+            bail!("blob cache directory {} doesn't exist", cache_dir.display());
+        }
+        let blob_ids: Vec<String> = matches
+            .get_many::<String>("blob-id")
+            .map(|ids| ids.cloned().collect())
+            .unwrap_or_default();
+
+        let (rs, _) =
+            RafsSuper::load_from_file(bootstrap_path, Arc::new(ConfigV2::default()), false)
+                .with_context(|| format!("failed to load bootstrap {:?}", bootstrap_path))?;
+        let generator = BlobMetaGenerator::new(&rs)?;
+        for id in blob_ids.iter() {
+            if !generator.blobs().iter().any(|b| &b.blob_id() == id) {
+                bail!(
+                    "blob {} isn't referenced by bootstrap {:?}",
+                    id,
+                    bootstrap_path
+                );
+            }
+        }
+        for blob in generator.blobs() {
+            if !blob_ids.is_empty() && !blob_ids.contains(&blob.blob_id()) {
+                continue;
+            }
+            let path = generator
+                .generate_to_dir(blob, cache_dir)
+                .with_context(|| {
+                    format!("failed to reconstruct blob meta for {}", blob.blob_id())
+                })?;
+            println!(
+                "Reconstructed blob meta of blob {} into {}",
+                blob.blob_id(),
+                path.display()
+            );
+        }
+
+        Ok(())
+    }
+
+    fn push(matches: &ArgMatches) -> Result<()> {
+        let bootstrap_path = Self::get_bootstrap(matches)?;
+        let target = matches.get_one::<String>("target").unwrap();
+        let config = Self::get_configuration(matches)?;
+        let blob_dir = matches.get_one::<String>("blob-dir").map(PathBuf::from);
+
+        let (rs, _) =
+            RafsSuper::load_from_file(bootstrap_path, Arc::new(ConfigV2::default()), false)
+                .with_context(|| format!("failed to load bootstrap {:?}", bootstrap_path))?;
+        let mut blobs = Vec::new();
+        for blob in rs.superblock.get_blob_infos() {
+            let path = blob_dir
+                .as_ref()
+                .map(|dir| dir.join(blob.blob_id()))
+                .filter(|p| p.is_file());
+            // Sizes of blobs not available locally are queried from the registry.
+            let size = match &path {
+                Some(p) => metadata(p)
+                    .with_context(|| format!("failed to get size of blob {}", p.display()))?
+                    .len(),
+                None => 0,
+            };
+            blobs.push(oci::PushBlob {
+                blob_id: blob.blob_id(),
+                size,
+                path,
+            });
+        }
+
+        let fs_version =
+            RafsVersion::try_from(rs.meta.version).context("failed to get RAFS version number")?;
+        let digest = oci::push_image(target, bootstrap_path, fs_version, &blobs, &config)
+            .with_context(|| format!("failed to push image {}", target))?;
+        info!(
+            "successfully pushed image {}, manifest digest {}",
+            target, digest
+        );
+        Ok(())
+    }
+
+    fn inspect(matches: &ArgMatches) -> Result<()> {
+        let cmd = matches.get_one::<String>("request");
+        if cmd.is_none() && Self::get_bootstrap(matches).ok() == Some(Path::new(STDIN_PATH)) {
+            bail!("reading bootstrap from stdin is only supported in request mode");
+        }
+        let (_, _work_dir, bootstrap_path) = Self::get_source_bootstrap(matches)?;
+        let mut config = match matches.get_one::<String>("blob") {
+            Some(blob) => Self::get_blob_file_configuration(Path::new(blob))?,
+            None => Self::get_configuration(matches)?,
+        };
         // For backward compatibility with v2.1
         config
             .internal
@@ -1600,8 +3231,7 @@ impl Command {
             cache.cache_validate = true;
         }
 
-        let cmd = matches.get_one::<String>("request");
-        let mut inspector = inspect::RafsInspector::new(bootstrap_path, cmd.is_some(), config)
+        let mut inspector = inspect::RafsInspector::new(&bootstrap_path, cmd.is_some(), config)
             .map_err(|e| {
                 error!("failed to create inspector, {:?}", e);
                 e
@@ -1637,7 +3267,19 @@ impl Command {
             .internal
             .set_blob_accessible(matches.get_one::<String>("config").is_some());
 
-        if let Some(blob) = matches.get_one::<String>("bootstrap").map(PathBuf::from) {
+        if let Some(access_list) = matches.get_one::<String>("access-list") {
+            let (_spool_dir, bootstrap) = Self::spool_bootstrap(Self::get_bootstrap(matches)?)?;
+            let cost = stat::AccessCost::estimate(&bootstrap, Path::new(access_list), config)?;
+            if let Some(path) = matches.get_one::<String>("output-json").map(PathBuf::from) {
+                cost.dump_json(&path)?;
+            } else {
+                cost.dump();
+            }
+            return Ok(());
+        }
+
+        if let Some(blob) = matches.get_one::<String>("bootstrap").map(Path::new) {
+            let (_spool_dir, blob) = Self::spool_bootstrap(blob)?;
             stat.stat(&blob, true, config.clone())?;
         } else if let Some(d) = matches.get_one::<String>("blob-dir").map(PathBuf::from) {
             Self::ensure_directory(d.clone())?;
@@ -1663,7 +3305,8 @@ impl Command {
             bail!("one of `--bootstrap` and `--blob-dir` must be specified");
         }
 
-        if let Some(blob) = matches.get_one::<String>("target").map(PathBuf::from) {
+        if let Some(blob) = matches.get_one::<String>("target").map(Path::new) {
+            let (_spool_dir, blob) = Self::spool_bootstrap(blob)?;
             stat.target_enabled = true;
             stat.stat(&blob, false, config)?;
         }
@@ -1689,6 +3332,103 @@ impl Command {
         }
     }
 
+    /// Make the bootstrap randomly accessible for loading.
+    ///
+    /// Bootstraps read from stdin (`-`) or non-seekable streams, such as pipes, are spooled into a
+    /// temporary file in the returned directory, which must be kept alive until the bootstrap is
+    /// no longer needed.
+    fn spool_bootstrap(path: &Path) -> Result<(Option<oci::WorkDir>, PathBuf)> {
+        let mut reader: Box<dyn Read> = if path == Path::new(STDIN_PATH) {
+            Box::new(std::io::stdin().lock())
+        } else {
+            match metadata(path) {
+                Ok(m) if m.file_type().is_fifo() || m.file_type().is_socket() => Box::new(
+                    File::open(path)
+                        .with_context(|| format!("failed to open bootstrap {:?}", path))?,
+                ),
+                _ => return Ok((None, path.to_path_buf())),
+            }
+        };
+
+        let dir = oci::WorkDir::new()?;
+        let spool_path = dir.path().join("bootstrap");
+        let mut file = File::create(&spool_path)
+            .with_context(|| format!("failed to create file {}", spool_path.display()))?;
+        std::io::copy(&mut reader, &mut file)
+            .with_context(|| format!("failed to read bootstrap from {:?}", path))?;
+        Ok((Some(dir), spool_path))
+    }
+
+    /// Get the RAFS metadata to check or inspect, from `--blob` or the bootstrap arguments.
+    ///
+    /// RAFS metadata inlined in the data blob given by `--blob` is extracted, and bootstraps read
+    /// from stdin or pipes are spooled, into the returned temporary directory, which must be kept
+    /// alive until the bootstrap is no longer needed.
+    fn get_source_bootstrap(
+        matches: &ArgMatches,
+    ) -> Result<(&Path, Option<oci::WorkDir>, PathBuf)> {
+        match matches.get_one::<String>("blob").map(Path::new) {
+            Some(blob) => {
+                let (dir, path) = Self::extract_inlined_bootstrap(blob)?;
+                Ok((blob, Some(dir), path))
+            }
+            None => {
+                let source = Self::get_bootstrap(matches)?;
+                let (dir, path) = Self::spool_bootstrap(source)?;
+                Ok((source, dir, path))
+            }
+        }
+    }
+
+    /// Extract RAFS metadata inlined in the data blob into a temporary directory, located by the
+    /// ToC or the tar header at the end of the blob.
+    ///
+    /// The extracted bootstrap is named after the blob file, so the blob id of the inlined-meta
+    /// blob is derived from the blob file name.
+    fn extract_inlined_bootstrap(blob_path: &Path) -> Result<(oci::WorkDir, PathBuf)> {
+        let config = Self::get_blob_file_configuration(blob_path)?;
+        let backend = BlobFactory::new_backend(config.get_backend_config()?, "inlined_meta")?;
+        let file_name = blob_path
+            .file_name()
+            .ok_or_else(|| anyhow!("invalid blob file path {}", blob_path.display()))?;
+        let reader = backend
+            .get_reader(&file_name.to_string_lossy())
+            .map_err(|e| anyhow!("failed to get reader for blob {:?}, {}", blob_path, e))?;
+
+        let dir = oci::WorkDir::new()?;
+        let path = dir
+            .path()
+            .join(file_name)
+            .with_extension(TOC_ENTRY_BOOTSTRAP);
+        TocEntryList::extract_rafs_meta_from_blob(reader, &path).with_context(|| {
+            format!(
+                "failed to extract inlined RAFS metadata from blob {}",
+                blob_path.display()
+            )
+        })?;
+        Ok((dir, path))
+    }
+
+    /// Get configuration to read data from the local data blob file.
+    fn get_blob_file_configuration(blob_path: &Path) -> Result<Arc<ConfigV2>> {
+        let blob_file = blob_path
+            .to_str()
+            .ok_or_else(|| anyhow!("invalid blob file path {}", blob_path.display()))?;
+        let config = ConfigV2 {
+            backend: Some(BackendConfigV2 {
+                backend_type: "localfs".to_string(),
+                localfs: Some(LocalFsConfig {
+                    blob_file: blob_file.to_string(),
+                    dir: String::new(),
+                    alt_dirs: Vec::new(),
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        Ok(Arc::new(config))
+    }
+
     fn get_bootstrap_storage(matches: &ArgMatches) -> Result<ArtifactStorage> {
         if let Some(s) = matches.get_one::<String>("bootstrap") {
             Ok(ArtifactStorage::SingleFile(s.into()))
@@ -1702,19 +3442,111 @@ impl Command {
         }
     }
 
+    fn pull_image_layers(
+        ctx: &BuildContext,
+        config: &ConfigV2,
+    ) -> Result<(oci::WorkDir, Vec<PathBuf>)> {
+        let source = ctx
+            .source_path
+            .to_str()
+            .ok_or_else(|| anyhow!("invalid image reference {}", ctx.source_path.display()))?;
+        oci::pull_image_layers(source, config)
+    }
+
+    /// Pull the parent bootstrap if it's specified as `registry://<image>`.
+    ///
+    /// The bootstrap is cached in the bootstrap cache directory for later builds. If there's no
+    /// cache directory, it's saved in the returned temporary directory instead, which must be kept
+    /// alive until the parent bootstrap has been loaded.
+    fn pull_parent_bootstrap(
+        matches: &ArgMatches,
+        config: &ConfigV2,
+    ) -> Result<(Option<oci::WorkDir>, Option<String>)> {
+        match Self::get_parent_bootstrap(matches)? {
+            Some(reference) if reference.starts_with(REGISTRY_SCHEME) => {
+                let cache_dir = matches
+                    .get_one::<PathBuf>("bootstrap-cache-dir")
+                    .cloned()
+                    .or_else(Self::default_bootstrap_cache_dir);
+                let (dir, path) = timing_tracer!(
+                    {
+                        match cache_dir.as_ref() {
+                            Some(cache_dir) => {
+                                oci::pull_bootstrap_cached(&reference, config, cache_dir)
+                                    .map(|path| (None, path))
+                            }
+                            None => oci::pull_bootstrap(&reference, config)
+                                .map(|(dir, path)| (Some(dir), path)),
+                        }
+                    },
+                    "pull_parent_bootstrap"
+                )
+                .with_context(|| format!("failed to pull parent bootstrap {}", reference))?;
+                let path = path
+                    .to_str()
+                    .ok_or_else(|| anyhow!("invalid parent bootstrap path {}", path.display()))?
+                    .to_string();
+                Ok((dir, Some(path)))
+            }
+            parent_path => Ok((None, parent_path)),
+        }
+    }
+
+    /// Get the default directory to cache parent bootstraps pulled from registry, following the
+    /// XDG base directory specification.
+    fn default_bootstrap_cache_dir() -> Option<PathBuf> {
+        let dir = match std::env::var_os("XDG_CACHE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(std::env::var_os("HOME").filter(|h| !h.is_empty())?).join(".cache"),
+        };
+        Some(dir.join("nydus-image").join("bootstraps"))
+    }
+
+    /// Resolve `--chunk-dict` arguments into local bootstrap files, pulling remote ones if needed.
+    ///
+    /// Bootstraps pulled from registry live in the returned directories, which must be kept
+    /// alive until the chunk dictionaries have been loaded.
+    fn get_chunk_dict_paths(
+        matches: &ArgMatches,
+        config: &ConfigV2,
+    ) -> Result<(Vec<oci::WorkDir>, Vec<PathBuf>)> {
+        let mut dirs = Vec::new();
+        let mut paths = Vec::new();
+        if let Some(args) = matches.get_many::<String>("chunk-dict") {
+            for arg in args {
+                match parse_chunk_dict_arg(arg)? {
+                    ChunkDictSource::Registry(reference) => {
+                        let (dir, path) = timing_tracer!(
+                            { oci::pull_bootstrap(&reference, config) },
+                            "pull_chunk_dict"
+                        )
+                        .with_context(|| format!("failed to pull chunk dict {}", reference))?;
+                        dirs.push(dir);
+                        paths.push(path);
+                    }
+                    source => paths.extend(source.local_bootstrap_paths()?),
+                }
+            }
+        }
+        Ok((dirs, paths))
+    }
+
     fn get_blob_cache_storage(
         matches: &ArgMatches,
+        name: &str,
         conversion_type: ConversionType,
     ) -> Result<Option<ArtifactStorage>> {
-        if let Some(p) = matches.get_one::<PathBuf>("blob-cache-dir") {
+        if let Some(p) = matches.get_one::<PathBuf>(name) {
             if conversion_type == ConversionType::TarToTarfs
                 || conversion_type == ConversionType::EStargzIndexToRef
+                || conversion_type == ConversionType::ZstdChunkedToRef
                 || conversion_type == ConversionType::EStargzToRafs
                 || conversion_type == ConversionType::EStargzToRef
             {
                 bail!(
-                    "conversion type `{}` conflicts with `--blob-cache-dir`",
-                    conversion_type
+                    "conversion type `{}` conflicts with `--{}`",
+                    conversion_type,
+                    name
                 );
             }
 
@@ -1737,7 +3569,9 @@ impl Command {
         // Must specify a path to blob file.
         // For cli/binary interface compatibility sake, keep option `backend-config`, but
         // it only receives "localfs" backend type and it will be REMOVED in the future
-        if conversion_type == ConversionType::EStargzIndexToRef {
+        if conversion_type == ConversionType::EStargzIndexToRef
+            || conversion_type == ConversionType::ZstdChunkedToRef
+        {
             Ok(None)
         } else if let Some(p) = matches
             .get_one::<String>("blob")
@@ -1789,7 +3623,7 @@ impl Command {
     }
 
     fn get_configuration(matches: &ArgMatches) -> Result<Arc<ConfigV2>> {
-        let config = if let Some(config_file) = matches.get_one::<String>("config") {
+        let mut config = if let Some(config_file) = matches.get_one::<String>("config") {
             ConfigV2::from_file(config_file)?
         } else if let Some(dir) = matches.get_one::<String>("blob-dir") {
             ConfigV2::new_localfs("", dir)?
@@ -1799,10 +3633,59 @@ impl Command {
         if !config.validate() {
             return Err(anyhow!("invalid configuration: {:?}", config));
         }
+        if let Some(proxy) = Self::get_proxy_config(matches) {
+            match config.backend.as_mut() {
+                Some(b) if b.backend_type == "registry" => {
+                    b.registry.as_mut().unwrap().proxy = proxy
+                }
+                Some(b) if b.backend_type == "oss" => b.oss.as_mut().unwrap().proxy = proxy,
+                Some(b) if b.backend_type == "s3" => b.s3.as_mut().unwrap().proxy = proxy,
+                _ => {}
+            }
+        }
 
         Ok(Arc::new(config))
     }
 
+    /// Get options of the P2P proxy to access remote storage backends and registries through.
+    fn get_proxy_config(matches: &ArgMatches) -> Option<ProxyConfig> {
+        // Not all subcommands support the P2P proxy.
+        let url = matches.try_get_one::<String>("proxy-url").ok().flatten()?;
+        Some(ProxyConfig {
+            url: url.to_string(),
+            ping_url: matches
+                .get_one::<String>("proxy-ping-url")
+                .cloned()
+                .unwrap_or_default(),
+            fallback: !matches.get_flag("proxy-no-fallback"),
+            ..Default::default()
+        })
+    }
+
+    /// Get configuration to pull bootstraps and image layers from registries by reference.
+    ///
+    /// Registry options come from the registry storage backend if configured, otherwise default
+    /// options are used, with the P2P proxy applied in both cases.
+    fn get_pull_configuration(matches: &ArgMatches, config: &ConfigV2) -> ConfigV2 {
+        let mut config = config.clone();
+        let is_registry = config
+            .backend
+            .as_ref()
+            .map(|b| b.backend_type == "registry")
+            .unwrap_or_default();
+        if let Some(proxy) = Self::get_proxy_config(matches).filter(|_| !is_registry) {
+            config.backend = Some(BackendConfigV2 {
+                backend_type: "registry".to_string(),
+                registry: Some(RegistryConfig {
+                    proxy,
+                    ..oci::default_registry_config()
+                }),
+                ..Default::default()
+            });
+        }
+        config
+    }
+
     fn get_backend(
         matches: &ArgMatches,
         blob_id: &str,
@@ -1864,12 +3747,53 @@ impl Command {
         Ok(blob_id)
     }
 
-    fn get_blob_size(matches: &ArgMatches, ty: ConversionType) -> Result<u64> {
-        if ty != ConversionType::EStargzIndexToRef {
+    fn get_hardlink_dev_map(matches: &ArgMatches) -> Result<HashMap<u64, u64>> {
+        let mut dev_map = HashMap::new();
+        if let Some(v) = matches.get_one::<String>("hardlink-dev-map") {
+            for pair in v.split(',').filter(|s| !s.is_empty()) {
+                let (from, to) = pair
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("invalid device map entry '{}'", pair))?;
+                let from = from
+                    .trim()
+                    .parse::<u64>()
+                    .with_context(|| format!("invalid device number '{}'", from))?;
+                let to = to
+                    .trim()
+                    .parse::<u64>()
+                    .with_context(|| format!("invalid device number '{}'", to))?;
+                dev_map.insert(from, to);
+            }
+        }
+
+        Ok(dev_map)
+    }
+
+    fn get_access_trace(matches: &ArgMatches) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        if let Some(trace) = matches.get_one::<String>("access-trace") {
+            let content = fs::read_to_string(trace)
+                .with_context(|| format!("failed to read access trace {}", trace))?;
+            for line in content.lines().map(|l| l.trim()) {
+                if !line.is_empty() && !line.starts_with('#') {
+                    files.push(Path::new("/").join(line));
+                }
+            }
+        }
+
+        Ok(files)
+    }
+
+    fn get_blob_size(matches: &ArgMatches, ty: ConversionType, source: &Path) -> Result<u64> {
+        if ty != ConversionType::EStargzIndexToRef && ty != ConversionType::ZstdChunkedToRef {
             return Ok(0);
         }
 
         match matches.get_one::<String>("blob-data-size") {
+            // The zstd:chunked layer itself is used as the data blob.
+            None if ty == ConversionType::ZstdChunkedToRef => Ok(fs::metadata(source)
+                .with_context(|| format!("failed to get size of {}", source.display()))?
+                .len()),
             None => bail!("no value specified for '--blob-data-size'"),
             Some(v) => {
                 let param = v.trim_start_matches("0x").trim_start_matches("0X");
@@ -1883,7 +3807,8 @@ impl Command {
     fn get_chunk_size(matches: &ArgMatches, ty: ConversionType) -> Result<u32> {
         match matches.get_one::<String>("chunk-size") {
             None => {
-                if ty == ConversionType::EStargzIndexToRef {
+                if ty == ConversionType::EStargzIndexToRef || ty == ConversionType::ZstdChunkedToRef
+                {
                     Ok(0x400000u32)
                 } else {
                     Ok(RAFS_DEFAULT_CHUNK_SIZE as u32)
@@ -1930,7 +3855,8 @@ impl Command {
                         ConversionType::DirectoryToRafs
                         | ConversionType::EStargzToRafs
                         | ConversionType::TargzToRafs
-                        | ConversionType::TarToRafs => {
+                        | ConversionType::TarToRafs
+                        | ConversionType::OciRefToRafs => {
                             if batch_size as u64 > RAFS_MAX_CHUNK_SIZE
                                 || batch_size < 0x1000
                                 || !batch_size.is_power_of_two()
@@ -1953,6 +3879,28 @@ impl Command {
         }
     }
 
+    fn get_alignment_size(matches: &ArgMatches, name: &str) -> Result<u64> {
+        match matches.get_one::<String>(name) {
+            None => Ok(0),
+            Some(v) => {
+                let size = if v.starts_with("0x") || v.starts_with("0X") {
+                    u64::from_str_radix(&v[2..], 16)
+                        .context(format!("invalid value {} for --{}", v, name))?
+                } else {
+                    v.parse::<u64>()
+                        .context(format!("invalid value {} for --{}", v, name))?
+                };
+                if size > BLOB_ALIGNMENT_MAXIMUM_SIZE
+                    || size < BLOB_ALIGNMENT_MINIMUM_SIZE
+                    || !size.is_power_of_two()
+                {
+                    bail!("invalid value 0x{:x} for --{}", size, name);
+                }
+                Ok(size)
+            }
+        }
+    }
+
     fn get_prefetch(matches: &ArgMatches) -> Result<Prefetch> {
         let prefetch_policy = matches
             .get_one::<String>("prefetch-policy")
@@ -2093,9 +4041,152 @@ impl Command {
 
 #[cfg(test)]
 mod tests {
-    use super::Command;
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
     #[test]
     fn test_ensure_file() {
         Command::ensure_file("/dev/stdin").unwrap();
     }
+
+    fn check_matches(args: &[&str]) -> ArgMatches {
+        let mut argv = vec!["nydus-image", "check", "bootstrap"];
+        argv.extend_from_slice(args);
+        let cmd = prepare_cmd_args("").try_get_matches_from(argv).unwrap();
+        cmd.subcommand_matches("check").unwrap().clone()
+    }
+
+    #[test]
+    fn test_get_proxy_config() {
+        let matches = check_matches(&[]);
+        assert!(Command::get_proxy_config(&matches).is_none());
+
+        let matches = check_matches(&["--proxy-url", "http://127.0.0.1:65001"]);
+        let proxy = Command::get_proxy_config(&matches).unwrap();
+        assert_eq!(proxy.url, "http://127.0.0.1:65001");
+        assert!(proxy.ping_url.is_empty());
+        assert!(proxy.fallback);
+
+        let matches = check_matches(&[
+            "--proxy-url",
+            "http://127.0.0.1:65001",
+            "--proxy-ping-url",
+            "http://127.0.0.1:40901/server/ping",
+            "--proxy-no-fallback",
+        ]);
+        let proxy = Command::get_proxy_config(&matches).unwrap();
+        assert_eq!(proxy.ping_url, "http://127.0.0.1:40901/server/ping");
+        assert!(!proxy.fallback);
+
+        // Subcommands without the proxy options don't use the P2P proxy.
+        let cmd = prepare_cmd_args("")
+            .try_get_matches_from(["nydus-image", "analyze", "/"])
+            .unwrap();
+        let matches = cmd.subcommand_matches("analyze").unwrap();
+        assert!(Command::get_proxy_config(matches).is_none());
+
+        // The ping URL and no fallback options require the proxy URL.
+        assert!(prepare_cmd_args("")
+            .try_get_matches_from(["nydus-image", "check", "bootstrap", "--proxy-no-fallback"])
+            .is_err());
+    }
+
+    #[test]
+    fn test_get_configuration_with_proxy() {
+        let backends = [
+            (
+                "registry",
+                "host = \"registry.example.com\"\nrepo = \"library/nginx\"",
+            ),
+            (
+                "oss",
+                "endpoint = \"oss.example.com\"\nbucket_name = \"bucket\"",
+            ),
+            (
+                "s3",
+                "endpoint = \"s3.example.com\"\nregion = \"us-east-1\"\nbucket_name = \"bucket\"",
+            ),
+        ];
+        for (backend_type, options) in backends {
+            let file = TempFile::new().unwrap();
+            let content = format!(
+                "version = 2\n[backend]\ntype = \"{}\"\n[backend.{}]\n{}\n",
+                backend_type, backend_type, options
+            );
+            fs::write(file.as_path(), content).unwrap();
+            let config_path = file.as_path().to_str().unwrap();
+
+            let matches = check_matches(&["--config", config_path]);
+            let config = Command::get_configuration(&matches).unwrap();
+            let backend = config.backend.as_ref().unwrap();
+            let proxy = match backend_type {
+                "registry" => &backend.registry.as_ref().unwrap().proxy,
+                "oss" => &backend.oss.as_ref().unwrap().proxy,
+                _ => &backend.s3.as_ref().unwrap().proxy,
+            };
+            assert!(proxy.url.is_empty());
+
+            let matches = check_matches(&[
+                "--config",
+                config_path,
+                "--proxy-url",
+                "http://127.0.0.1:65001",
+                "--proxy-no-fallback",
+            ]);
+            let config = Command::get_configuration(&matches).unwrap();
+            let backend = config.backend.as_ref().unwrap();
+            assert_eq!(backend.backend_type, backend_type);
+            let proxy = match backend_type {
+                "registry" => &backend.registry.as_ref().unwrap().proxy,
+                "oss" => &backend.oss.as_ref().unwrap().proxy,
+                _ => &backend.s3.as_ref().unwrap().proxy,
+            };
+            assert_eq!(proxy.url, "http://127.0.0.1:65001");
+            assert!(!proxy.fallback);
+
+            // Registry options are kept for pulling, other backends are replaced by a registry
+            // backend through the P2P proxy.
+            let pull_config = Command::get_pull_configuration(&matches, &config);
+            let backend = pull_config.backend.as_ref().unwrap();
+            assert_eq!(backend.backend_type, "registry");
+            let registry = backend.registry.as_ref().unwrap();
+            assert_eq!(registry.proxy.url, "http://127.0.0.1:65001");
+            assert!(!registry.proxy.fallback);
+            if backend_type == "registry" {
+                assert_eq!(registry.host, "registry.example.com");
+            } else {
+                assert!(registry.host.is_empty());
+                assert_eq!(registry.scheme, "https");
+            }
+        }
+    }
+
+    #[test]
+    fn test_get_pull_configuration() {
+        // No registry backend is synthesized without the P2P proxy.
+        let matches = check_matches(&[]);
+        let config = Command::get_configuration(&matches).unwrap();
+        let pull_config = Command::get_pull_configuration(&matches, &config);
+        assert!(pull_config.backend.is_none());
+
+        let matches = check_matches(&[
+            "--proxy-url",
+            "http://127.0.0.1:65001",
+            "--proxy-ping-url",
+            "http://127.0.0.1:40901/server/ping",
+        ]);
+        let config = Command::get_configuration(&matches).unwrap();
+        assert!(config.backend.is_none());
+        let pull_config = Command::get_pull_configuration(&matches, &config);
+        let backend = pull_config.backend.as_ref().unwrap();
+        assert_eq!(backend.backend_type, "registry");
+        let registry = backend.registry.as_ref().unwrap();
+        assert_eq!(registry.scheme, "https");
+        assert_eq!(registry.proxy.url, "http://127.0.0.1:65001");
+        assert_eq!(
+            registry.proxy.ping_url,
+            "http://127.0.0.1:40901/server/ping"
+        );
+        assert!(registry.proxy.fallback);
+    }
 }
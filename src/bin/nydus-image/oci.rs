@@ -0,0 +1,822 @@
+// Copyright (C) 2024 Nydus Developers. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Pull OCI image layers and nydus bootstraps from container registries, and push nydus images
+//! to container registries.
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+#[cfg(feature = "backend-registry")]
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+#[cfg(feature = "backend-registry")]
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use nydus_api::{ConfigV2, RegistryConfig};
+use nydus_rafs::metadata::RafsVersion;
+#[cfg(feature = "backend-registry")]
+use nydus_storage::backend::registry::Registry;
+#[cfg(feature = "backend-registry")]
+use nydus_storage::backend::{BlobBackend, BlobBufReader, BlobReader};
+use nydus_utils::digest::{self, DigestHasher, RafsDigest};
+use serde::Deserialize;
+use vmm_sys_util::tempdir::TempDir;
+
+const OCI_REF_SCHEMES: [&str; 2] = ["docker://", "registry://"];
+const DOCKER_HUB_REGISTRY: &str = "registry-1.docker.io";
+const DOCKER_HUB_ALIASES: [&str; 2] = ["docker.io", "index.docker.io"];
+const LAYER_READER_BUF_SIZE: usize = 0x10_0000;
+const NYDUS_BOOTSTRAP_ANNOTATION: &str = "containerd.io/snapshot/nydus-bootstrap";
+const NYDUS_BOOTSTRAP_PATH: &str = "image/image.boot";
+const NYDUS_BLOB_ANNOTATION: &str = "containerd.io/snapshot/nydus-blob";
+const NYDUS_FS_VERSION_ANNOTATION: &str = "containerd.io/snapshot/nydus-fs-version";
+const MEDIA_TYPE_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+const MEDIA_TYPE_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+const MEDIA_TYPE_LAYER_GZIP: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+const MEDIA_TYPE_NYDUS_BLOB: &str = "application/vnd.oci.image.layer.nydus.blob.v1";
+
+/// Reference to an image hosted on a container registry, like `docker://host/repo:tag` or
+/// `registry://host/repo:tag`.
+#[derive(Debug, Eq, PartialEq)]
+pub struct OciReference {
+    /// Host of the registry server.
+    pub host: String,
+    /// Repository of the image.
+    pub repo: String,
+    /// Tag or digest of the image.
+    pub reference: String,
+}
+
+impl FromStr for OciReference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let name = OCI_REF_SCHEMES
+            .iter()
+            .find_map(|scheme| s.strip_prefix(scheme))
+            .ok_or_else(|| {
+                anyhow!(
+                    "image reference {} doesn't start with {}",
+                    s,
+                    OCI_REF_SCHEMES.join(" or ")
+                )
+            })?;
+        let (name, reference) = if let Some((name, digest)) = name.split_once('@') {
+            (name, digest.to_string())
+        } else {
+            match name.rsplit_once(':') {
+                Some((name, tag)) if !tag.contains('/') => (name, tag.to_string()),
+                _ => (name, "latest".to_string()),
+            }
+        };
+        if name.is_empty() || reference.is_empty() {
+            bail!("invalid image reference {}", s);
+        }
+        let (host, repo) = match name.split_once('/') {
+            Some((host, repo))
+                if host.contains('.') || host.contains(':') || host == "localhost" =>
+            {
+                (host.to_string(), repo.to_string())
+            }
+            _ => (DOCKER_HUB_REGISTRY.to_string(), name.to_string()),
+        };
+        let host = if DOCKER_HUB_ALIASES.contains(&host.as_str()) {
+            DOCKER_HUB_REGISTRY.to_string()
+        } else {
+            host
+        };
+        let repo = if host == DOCKER_HUB_REGISTRY && !repo.contains('/') {
+            format!("library/{}", repo)
+        } else {
+            repo
+        };
+
+        Ok(OciReference {
+            host,
+            repo,
+            reference,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Platform {
+    #[serde(default)]
+    architecture: String,
+    #[serde(default)]
+    os: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Descriptor {
+    #[serde(rename = "mediaType", default)]
+    media_type: String,
+    digest: String,
+    size: u64,
+    #[serde(default)]
+    platform: Option<Platform>,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+/// Image manifest or image index, only fields needed to pull layers are parsed.
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+}
+
+/// Temporary directory to host files pulled from registry, which will be removed on drop.
+///
+/// The directory is created by `mkdtemp()` with a random name and mode 0700, so other users can't
+/// predict its path or tamper with files in it.
+pub struct WorkDir(TempDir);
+
+impl WorkDir {
+    pub fn new() -> Result<Self> {
+        Self::new_in(&std::env::temp_dir())
+    }
+
+    /// Create a temporary directory within directory `parent`.
+    pub fn new_in(parent: &Path) -> Result<Self> {
+        let prefix = parent.join("nydus-image-");
+        let dir = TempDir::new_with_prefix(&prefix).with_context(|| {
+            format!("failed to create temporary directory {}", prefix.display())
+        })?;
+        Ok(WorkDir(dir))
+    }
+
+    pub fn path(&self) -> &Path {
+        self.0.as_path()
+    }
+}
+
+/// Pull all layers of image `source`, ordered from the lowest layer to the uppermost layer.
+///
+/// Registry options other than host and repository, such as auth and proxy, are taken from the
+/// registry backend configuration of `config` if available.
+#[cfg(feature = "backend-registry")]
+pub fn pull_image_layers(source: &str, config: &ConfigV2) -> Result<(WorkDir, Vec<PathBuf>)> {
+    let (oci_ref, registry) = connect_registry(source, config)?;
+    let layers = get_image_layers(&registry, &oci_ref)?;
+    for layer in layers.iter() {
+        if layer.media_type.contains("zstd") {
+            bail!(
+                "unsupported media type {} for layer {}",
+                layer.media_type,
+                layer.digest
+            );
+        }
+    }
+
+    let dir = WorkDir::new()?;
+    let mut paths = Vec::with_capacity(layers.len());
+    for (idx, layer) in layers.iter().enumerate() {
+        let path = dir.path().join(format!("layer-{}", idx));
+        pull_layer(&registry, layer, &path)
+            .with_context(|| format!("failed to pull layer {} of {}", layer.digest, source))?;
+        paths.push(path);
+    }
+    registry.shutdown();
+
+    Ok((dir, paths))
+}
+
+#[cfg(not(feature = "backend-registry"))]
+pub fn pull_image_layers(source: &str, _config: &ConfigV2) -> Result<(WorkDir, Vec<PathBuf>)> {
+    bail!(
+        "failed to pull {}, the 'backend-registry' feature is disabled",
+        source
+    )
+}
+
+/// Pull the RAFS bootstrap of nydus image `source`.
+///
+/// The bootstrap layer is identified by the nydus bootstrap annotation, or the uppermost layer
+/// if there's no such annotation.
+#[cfg(feature = "backend-registry")]
+pub fn pull_bootstrap(source: &str, config: &ConfigV2) -> Result<(WorkDir, PathBuf)> {
+    let (oci_ref, registry) = connect_registry(source, config)?;
+    let layers = get_image_layers(&registry, &oci_ref)?;
+    let layer = find_bootstrap_layer(&layers, source)?;
+
+    let dir = WorkDir::new()?;
+    let layer_path = dir.path().join("bootstrap-layer");
+    pull_layer(&registry, layer, &layer_path).with_context(|| {
+        format!(
+            "failed to pull bootstrap layer {} of {}",
+            layer.digest, source
+        )
+    })?;
+    registry.shutdown();
+
+    let path = dir.path().join("image.boot");
+    extract_bootstrap(&layer_path, &path)
+        .with_context(|| format!("failed to extract bootstrap from image {}", source))?;
+    fs::remove_file(&layer_path)?;
+
+    Ok((dir, path))
+}
+
+#[cfg(not(feature = "backend-registry"))]
+pub fn pull_bootstrap(source: &str, _config: &ConfigV2) -> Result<(WorkDir, PathBuf)> {
+    bail!(
+        "failed to pull {}, the 'backend-registry' feature is disabled",
+        source
+    )
+}
+
+/// Pull the RAFS bootstrap of nydus image `source` into the persistent cache directory
+/// `cache_dir`, and return path of the cached bootstrap.
+///
+/// Cached bootstraps are named by digest of the bootstrap layer, so only the image manifest is
+/// fetched from registry if the bootstrap layer has been pulled before, even if the image has been
+/// pulled by another reference.
+#[cfg(feature = "backend-registry")]
+pub fn pull_bootstrap_cached(source: &str, config: &ConfigV2, cache_dir: &Path) -> Result<PathBuf> {
+    let (oci_ref, registry) = connect_registry(source, config)?;
+    let layers = get_image_layers(&registry, &oci_ref)?;
+    let layer = find_bootstrap_layer(&layers, source)?;
+    let digest = layer
+        .digest
+        .strip_prefix("sha256:")
+        .filter(|d| d.len() == 64 && d.chars().all(|c| c.is_ascii_hexdigit()))
+        .ok_or_else(|| anyhow!("invalid digest {} of bootstrap layer", layer.digest))?;
+    let path = cache_dir.join(format!("{}.boot", digest));
+    if path.is_file() {
+        info!("use cached bootstrap {} for {}", path.display(), source);
+        registry.shutdown();
+        return Ok(path);
+    }
+
+    fs::create_dir_all(cache_dir)
+        .with_context(|| format!("failed to create directory {}", cache_dir.display()))?;
+    // Pull into the cache directory, so the bootstrap can be renamed into place atomically.
+    let dir = WorkDir::new_in(cache_dir)?;
+    let layer_path = dir.path().join("bootstrap-layer");
+    pull_layer(&registry, layer, &layer_path).with_context(|| {
+        format!(
+            "failed to pull bootstrap layer {} of {}",
+            layer.digest, source
+        )
+    })?;
+    registry.shutdown();
+    let tmp_path = dir.path().join("image.boot");
+    extract_bootstrap(&layer_path, &tmp_path)
+        .with_context(|| format!("failed to extract bootstrap from image {}", source))?;
+    fs::rename(&tmp_path, &path)
+        .with_context(|| format!("failed to save bootstrap to {}", path.display()))?;
+
+    Ok(path)
+}
+
+#[cfg(not(feature = "backend-registry"))]
+pub fn pull_bootstrap_cached(
+    source: &str,
+    _config: &ConfigV2,
+    _cache_dir: &Path,
+) -> Result<PathBuf> {
+    bail!(
+        "failed to pull {}, the 'backend-registry' feature is disabled",
+        source
+    )
+}
+
+/// Find the bootstrap layer of nydus image `source`, identified by the nydus bootstrap annotation,
+/// or the uppermost layer if there's no such annotation.
+#[cfg(feature = "backend-registry")]
+fn find_bootstrap_layer<'a>(layers: &'a [Descriptor], source: &str) -> Result<&'a Descriptor> {
+    layers
+        .iter()
+        .find(|l| {
+            l.annotations
+                .get(NYDUS_BOOTSTRAP_ANNOTATION)
+                .map(|v| v == "true")
+                .unwrap_or(false)
+        })
+        .or_else(|| layers.last())
+        .ok_or_else(|| anyhow!("no bootstrap layer found in image {}", source))
+}
+
+/// A data blob to be pushed as a layer of nydus image.
+#[derive(Clone)]
+pub struct PushBlob {
+    /// Blob id, which is the sha256 digest of the blob.
+    pub blob_id: String,
+    /// Size of the local blob file, queried from the registry if `path` is `None`.
+    pub size: u64,
+    /// Local file of the blob, the blob must exist in the registry if it's `None`.
+    pub path: Option<PathBuf>,
+}
+
+/// Content of a layer or config to be pushed.
+struct PushContent {
+    digest: String,
+    diff_id: String,
+    size: u64,
+    path: PathBuf,
+}
+
+impl PushContent {
+    fn new(path: PathBuf, diff_id: Option<String>) -> Result<Self> {
+        let digest = format!("sha256:{}", file_digest(&path)?);
+        let size = fs::metadata(&path)
+            .with_context(|| format!("failed to get size of {}", path.display()))?
+            .len();
+        Ok(PushContent {
+            diff_id: diff_id.unwrap_or_else(|| digest.clone()),
+            digest,
+            size,
+            path,
+        })
+    }
+}
+
+/// Reader to upload file content, which can be cloned to retry from the start.
+#[cfg(feature = "backend-registry")]
+#[derive(Clone)]
+struct FileBody {
+    file: Arc<File>,
+    offset: u64,
+}
+
+#[cfg(feature = "backend-registry")]
+impl Read for FileBody {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let sz = self.file.read_at(buf, self.offset)?;
+        self.offset += sz as u64;
+        Ok(sz)
+    }
+}
+
+/// Push a nydus image composed of RAFS `bootstrap` and data `blobs` to image reference `target`.
+///
+/// Registry options other than host and repository, such as auth and proxy, are taken from the
+/// registry backend configuration of `config` if available.
+#[cfg(feature = "backend-registry")]
+pub fn push_image(
+    target: &str,
+    bootstrap: &Path,
+    fs_version: RafsVersion,
+    blobs: &[PushBlob],
+    config: &ConfigV2,
+) -> Result<String> {
+    let (oci_ref, registry) = connect_registry(target, config)?;
+    let dir = WorkDir::new()?;
+    let (bootstrap_layer, config_blob) = prepare_bootstrap_layer(&dir, bootstrap, blobs)?;
+
+    let mut blobs = blobs.to_vec();
+    for blob in blobs.iter_mut() {
+        let digest = format!("sha256:{}", blob.blob_id);
+        match &blob.path {
+            Some(path) => push_file(&registry, &digest, blob.size, path)?,
+            None => {
+                // Layer descriptors must carry the exact blob size, which isn't recorded in RAFS
+                // metadata for all kinds of blobs.
+                blob.size = registry
+                    .get_reader(&blob.blob_id)
+                    .and_then(|r| r.blob_size())
+                    .map_err(|e| {
+                        anyhow!(
+                            "data blob {} is neither available locally nor in {}, {:?}",
+                            blob.blob_id,
+                            target,
+                            e
+                        )
+                    })?;
+                info!(
+                    "data blob {} of size 0x{:x} exists in {}, skip pushing",
+                    blob.blob_id, blob.size, target
+                );
+            }
+        }
+    }
+    push_file(
+        &registry,
+        &bootstrap_layer.digest,
+        bootstrap_layer.size,
+        &bootstrap_layer.path,
+    )?;
+    push_file(
+        &registry,
+        &config_blob.digest,
+        config_blob.size,
+        &config_blob.path,
+    )?;
+
+    let manifest = generate_manifest(&bootstrap_layer, &config_blob, fs_version, &blobs)?;
+    let manifest_digest = format!(
+        "sha256:{}",
+        RafsDigest::from_buf(&manifest, digest::Algorithm::Sha256)
+    );
+    registry
+        .put_manifest(&oci_ref.reference, MEDIA_TYPE_MANIFEST, manifest)
+        .map_err(|e| anyhow!("failed to push manifest to {}, {:?}", target, e))?;
+    registry.shutdown();
+
+    Ok(manifest_digest)
+}
+
+#[cfg(not(feature = "backend-registry"))]
+pub fn push_image(
+    target: &str,
+    _bootstrap: &Path,
+    _fs_version: RafsVersion,
+    _blobs: &[PushBlob],
+    _config: &ConfigV2,
+) -> Result<String> {
+    bail!(
+        "failed to push {}, the 'backend-registry' feature is disabled",
+        target
+    )
+}
+
+#[cfg(feature = "backend-registry")]
+fn push_file(registry: &Registry, digest: &str, size: u64, path: &Path) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let body = FileBody {
+        file: Arc::new(file),
+        offset: 0,
+    };
+    let uploaded = registry
+        .push_blob(digest, size, body)
+        .map_err(|e| anyhow!("failed to push blob {}, {:?}", digest, e))?;
+    if uploaded {
+        info!("pushed blob {} of size 0x{:x}", digest, size);
+    } else {
+        info!("blob {} already exists, skip pushing", digest);
+    }
+    Ok(())
+}
+
+/// Generate the gzip compressed nydus bootstrap layer and the image config in `dir`.
+fn prepare_bootstrap_layer(
+    dir: &WorkDir,
+    bootstrap: &Path,
+    blobs: &[PushBlob],
+) -> Result<(PushContent, PushContent)> {
+    let tar_path = dir.path().join("bootstrap.tar");
+    let mut builder = tar::Builder::new(
+        File::create(&tar_path)
+            .with_context(|| format!("failed to create {}", tar_path.display()))?,
+    );
+    let mut file = File::open(bootstrap)
+        .with_context(|| format!("failed to open bootstrap {}", bootstrap.display()))?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(file.metadata()?.len());
+    header.set_mode(0o444);
+    header.set_cksum();
+    builder.append_data(&mut header, NYDUS_BOOTSTRAP_PATH, &mut file)?;
+    builder.into_inner()?.flush()?;
+    let diff_id = format!("sha256:{}", file_digest(&tar_path)?);
+
+    let layer_path = dir.path().join("bootstrap.tar.gz");
+    let mut encoder = GzEncoder::new(
+        File::create(&layer_path)
+            .with_context(|| format!("failed to create {}", layer_path.display()))?,
+        flate2::Compression::default(),
+    );
+    std::io::copy(&mut File::open(&tar_path)?, &mut encoder)?;
+    encoder.finish()?.flush()?;
+    let layer = PushContent::new(layer_path, Some(diff_id))?;
+
+    let arch = match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        v => v,
+    };
+    let mut diff_ids: Vec<String> = blobs
+        .iter()
+        .map(|b| format!("sha256:{}", b.blob_id))
+        .collect();
+    diff_ids.push(layer.diff_id.clone());
+    let image_config = json!({
+        "architecture": arch,
+        "os": "linux",
+        "config": {},
+        "rootfs": {
+            "type": "layers",
+            "diff_ids": diff_ids,
+        },
+    });
+    let config_path = dir.path().join("config.json");
+    fs::write(&config_path, serde_json::to_vec(&image_config)?)
+        .with_context(|| format!("failed to write {}", config_path.display()))?;
+    let config = PushContent::new(config_path, None)?;
+
+    Ok((layer, config))
+}
+
+/// Generate the OCI image manifest of a nydus image, with data blobs as lower layers and the
+/// bootstrap layer as the uppermost layer.
+fn generate_manifest(
+    bootstrap_layer: &PushContent,
+    config: &PushContent,
+    fs_version: RafsVersion,
+    blobs: &[PushBlob],
+) -> Result<Vec<u8>> {
+    let mut layers: Vec<serde_json::Value> = blobs
+        .iter()
+        .map(|b| {
+            json!({
+                "mediaType": MEDIA_TYPE_NYDUS_BLOB,
+                "digest": format!("sha256:{}", b.blob_id),
+                "size": b.size,
+                "annotations": {
+                    NYDUS_BLOB_ANNOTATION: "true",
+                },
+            })
+        })
+        .collect();
+    layers.push(json!({
+        "mediaType": MEDIA_TYPE_LAYER_GZIP,
+        "digest": bootstrap_layer.digest,
+        "size": bootstrap_layer.size,
+        "annotations": {
+            NYDUS_BOOTSTRAP_ANNOTATION: "true",
+            NYDUS_FS_VERSION_ANNOTATION: fs_version.to_string(),
+        },
+    }));
+    let manifest = json!({
+        "schemaVersion": 2,
+        "mediaType": MEDIA_TYPE_MANIFEST,
+        "config": {
+            "mediaType": MEDIA_TYPE_CONFIG,
+            "digest": config.digest,
+            "size": config.size,
+        },
+        "layers": layers,
+    });
+
+    serde_json::to_vec(&manifest).context("failed to serialize image manifest")
+}
+
+/// Calculate sha256 digest of a file, in hex string.
+pub fn file_digest(path: &Path) -> Result<String> {
+    let mut file =
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut hasher = RafsDigest::hasher(digest::Algorithm::Sha256);
+    let mut buf = vec![0u8; LAYER_READER_BUF_SIZE];
+    loop {
+        let sz = file.read(&mut buf)?;
+        if sz == 0 {
+            break;
+        }
+        hasher.digest_update(&buf[..sz]);
+    }
+    Ok(hasher.digest_finalize().to_string())
+}
+
+/// Extract the RAFS bootstrap from a nydus bootstrap layer tarball, which may be gzip compressed.
+fn extract_bootstrap(layer: &Path, target: &Path) -> Result<()> {
+    let mut file = File::open(layer)?;
+    let mut magic = [0u8; 2];
+    let is_gzip = file.read_exact(&mut magic).is_ok() && magic == [0x1f, 0x8b];
+    let file = File::open(layer)?;
+    let reader: Box<dyn Read> = if is_gzip {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.as_ref() == Path::new(NYDUS_BOOTSTRAP_PATH) {
+            let mut output = File::create(target)
+                .with_context(|| format!("failed to create {}", target.display()))?;
+            std::io::copy(&mut entry, &mut output)?;
+            return Ok(());
+        }
+    }
+
+    bail!("no {} found in bootstrap layer", NYDUS_BOOTSTRAP_PATH)
+}
+
+/// Default registry options to pull and push images with if no registry backend is configured.
+pub fn default_registry_config() -> RegistryConfig {
+    RegistryConfig {
+        scheme: "https".to_string(),
+        timeout: 30,
+        connect_timeout: 30,
+        retry_limit: 2,
+        ..Default::default()
+    }
+}
+
+#[cfg(feature = "backend-registry")]
+fn get_registry_config(oci_ref: &OciReference, config: &ConfigV2) -> RegistryConfig {
+    let mut registry_config = config
+        .backend
+        .as_ref()
+        .and_then(|b| b.get_registry_config().ok())
+        .cloned()
+        .unwrap_or_else(default_registry_config);
+    registry_config.host = oci_ref.host.clone();
+    registry_config.repo = oci_ref.repo.clone();
+    registry_config
+}
+
+#[cfg(feature = "backend-registry")]
+fn connect_registry(source: &str, config: &ConfigV2) -> Result<(OciReference, Registry)> {
+    let oci_ref = OciReference::from_str(source)?;
+    let registry_config = get_registry_config(&oci_ref, config);
+    let registry = Registry::new(&registry_config, Some(source))
+        .with_context(|| format!("failed to create registry backend for {}", source))?;
+    Ok((oci_ref, registry))
+}
+
+#[cfg(feature = "backend-registry")]
+fn get_manifest(registry: &Registry, reference: &str) -> Result<Manifest> {
+    let data = registry
+        .get_manifest(reference)
+        .map_err(|e| anyhow!("failed to fetch manifest {}, {:?}", reference, e))?;
+    serde_json::from_slice(&data).with_context(|| format!("invalid manifest {}", reference))
+}
+
+#[cfg(feature = "backend-registry")]
+fn get_image_layers(registry: &Registry, oci_ref: &OciReference) -> Result<Vec<Descriptor>> {
+    let mut manifest = get_manifest(registry, &oci_ref.reference)?;
+
+    // Select the manifest for current platform from image index.
+    if !manifest.manifests.is_empty() {
+        let arch = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "aarch64" => "arm64",
+            v => v,
+        };
+        let desc = manifest
+            .manifests
+            .iter()
+            .find(|m| {
+                m.platform
+                    .as_ref()
+                    .map(|p| p.os == "linux" && p.architecture == arch)
+                    .unwrap_or(false)
+            })
+            .ok_or_else(|| anyhow!("no image manifest for platform linux/{}", arch))?;
+        manifest = get_manifest(registry, &desc.digest)?;
+    }
+
+    if manifest.layers.is_empty() {
+        bail!("no layer found in image manifest {}", oci_ref.reference);
+    }
+
+    Ok(manifest.layers)
+}
+
+#[cfg(feature = "backend-registry")]
+fn pull_layer(registry: &Registry, layer: &Descriptor, path: &Path) -> Result<()> {
+    let digest = layer
+        .digest
+        .strip_prefix("sha256:")
+        .ok_or_else(|| anyhow!("unsupported digest algorithm for layer {}", layer.digest))?;
+    let reader = registry
+        .get_reader(digest)
+        .map_err(|e| anyhow!("failed to create reader, {:?}", e))?;
+    let mut reader = BlobBufReader::new(LAYER_READER_BUF_SIZE, reader, 0, layer.size);
+    let mut file =
+        File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    let mut hasher = RafsDigest::hasher(digest::Algorithm::Sha256);
+    let mut buf = vec![0u8; LAYER_READER_BUF_SIZE];
+    loop {
+        let sz = reader.read(&mut buf)?;
+        if sz == 0 {
+            break;
+        }
+        hasher.digest_update(&buf[..sz]);
+        file.write_all(&buf[..sz])?;
+    }
+
+    let actual = hasher.digest_finalize().to_string();
+    if actual != digest {
+        bail!(
+            "digest mismatch, expect {} but got sha256:{}",
+            layer.digest,
+            actual
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_oci_reference() {
+        let r = OciReference::from_str("docker://ubuntu").unwrap();
+        assert_eq!(r.host, DOCKER_HUB_REGISTRY);
+        assert_eq!(r.repo, "library/ubuntu");
+        assert_eq!(r.reference, "latest");
+
+        let r = OciReference::from_str("docker://docker.io/user/app:v1").unwrap();
+        assert_eq!(r.host, DOCKER_HUB_REGISTRY);
+        assert_eq!(r.repo, "user/app");
+        assert_eq!(r.reference, "v1");
+
+        let r = OciReference::from_str("docker://localhost:5000/test/repo:tag").unwrap();
+        assert_eq!(r.host, "localhost:5000");
+        assert_eq!(r.repo, "test/repo");
+        assert_eq!(r.reference, "tag");
+
+        let r = OciReference::from_str("docker://ghcr.io/org/app@sha256:1234").unwrap();
+        assert_eq!(r.host, "ghcr.io");
+        assert_eq!(r.repo, "org/app");
+        assert_eq!(r.reference, "sha256:1234");
+
+        let r = OciReference::from_str("registry://example.com/dict:v1").unwrap();
+        assert_eq!(r.host, "example.com");
+        assert_eq!(r.repo, "dict");
+        assert_eq!(r.reference, "v1");
+
+        assert!(OciReference::from_str("ubuntu:22.04").is_err());
+        assert!(OciReference::from_str("docker://").is_err());
+    }
+
+    #[test]
+    fn test_extract_bootstrap() {
+        let dir = WorkDir::new().unwrap();
+        let layer = dir.path().join("layer.tar");
+        let mut builder = tar::Builder::new(File::create(&layer).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, NYDUS_BOOTSTRAP_PATH, "boot".as_bytes())
+            .unwrap();
+        builder.finish().unwrap();
+        drop(builder);
+
+        let target = dir.path().join("image.boot");
+        extract_bootstrap(&layer, &target).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"boot");
+
+        let empty = dir.path().join("empty.tar");
+        tar::Builder::new(File::create(&empty).unwrap())
+            .finish()
+            .unwrap();
+        assert!(extract_bootstrap(&empty, &target).is_err());
+
+        let path = dir.path().to_path_buf();
+        drop(dir);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_generate_manifest() {
+        let dir = WorkDir::new().unwrap();
+        let bootstrap = dir.path().join("bootstrap");
+        fs::write(&bootstrap, b"bootstrap").unwrap();
+        let blobs = vec![PushBlob {
+            blob_id: "a".repeat(64),
+            size: 0x1000,
+            path: None,
+        }];
+
+        let (layer, config) = prepare_bootstrap_layer(&dir, &bootstrap, &blobs).unwrap();
+        assert_ne!(layer.digest, layer.diff_id);
+        let target = dir.path().join("image.boot");
+        extract_bootstrap(&layer.path, &target).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"bootstrap");
+
+        let image_config: serde_json::Value =
+            serde_json::from_slice(&fs::read(&config.path).unwrap()).unwrap();
+        let diff_ids = image_config["rootfs"]["diff_ids"].as_array().unwrap();
+        assert_eq!(diff_ids.len(), 2);
+        assert_eq!(diff_ids[0], format!("sha256:{}", "a".repeat(64)));
+        assert_eq!(diff_ids[1], layer.diff_id.as_str());
+
+        let manifest = generate_manifest(&layer, &config, RafsVersion::V6, &blobs).unwrap();
+        let manifest: Manifest = serde_json::from_slice(&manifest).unwrap();
+        assert_eq!(manifest.layers.len(), 2);
+        assert_eq!(manifest.layers[0].size, 0x1000);
+        assert_eq!(manifest.layers[0].media_type, MEDIA_TYPE_NYDUS_BLOB);
+        assert_eq!(
+            manifest.layers[0].annotations.get(NYDUS_BLOB_ANNOTATION),
+            Some(&"true".to_string())
+        );
+        assert_eq!(manifest.layers[1].digest, layer.digest);
+        assert_eq!(
+            manifest.layers[1]
+                .annotations
+                .get(NYDUS_BOOTSTRAP_ANNOTATION),
+            Some(&"true".to_string())
+        );
+        assert_eq!(
+            manifest.layers[1]
+                .annotations
+                .get(NYDUS_FS_VERSION_ANNOTATION),
+            Some(&"6".to_string())
+        );
+    }
+}
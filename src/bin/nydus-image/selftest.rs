@@ -0,0 +1,372 @@
+// Copyright (C) 2024 Nydus Developers. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Exercise the read path of a RAFS filesystem in-process, without mounting it.
+//!
+//! Random inodes are resolved from the RAFS metadata, and random chunk ranges of regular files are
+//! located through the blob compression context, read from storage backends, decompressed and
+//! verified against chunk digests recorded in the RAFS metadata.
+
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+use nydus_api::ConfigV2;
+use nydus_rafs::metadata::{RafsSuper, RafsSuperFlags};
+use nydus_storage::backend::BlobReader;
+use nydus_storage::device::{BlobChunkInfo, BlobFeatures, BlobInfo};
+use nydus_storage::factory::BlobFactory;
+use nydus_storage::meta::BlobCompressionContextInfo;
+use nydus_utils::compress;
+use nydus_utils::digest::RafsDigest;
+use serde::Serialize;
+
+use crate::oci::WorkDir;
+
+/// Maximum number of continuous chunks to read from a file at once.
+const MAX_RANGE_CHUNKS: u32 = 4;
+
+/// Latency percentiles of a class of operations, in microseconds.
+#[derive(Default, Serialize)]
+pub struct LatencySummary {
+    pub samples: usize,
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+impl LatencySummary {
+    fn new(mut latencies: Vec<Duration>) -> Self {
+        if latencies.is_empty() {
+            return Self::default();
+        }
+        latencies.sort_unstable();
+        let percentile = |p: usize| {
+            let idx = ((latencies.len() * p + 99) / 100).max(1) - 1;
+            latencies[idx].as_micros() as u64
+        };
+        LatencySummary {
+            samples: latencies.len(),
+            p50_us: percentile(50),
+            p90_us: percentile(90),
+            p99_us: percentile(99),
+            max_us: latencies[latencies.len() - 1].as_micros() as u64,
+        }
+    }
+}
+
+impl std::fmt::Display for LatencySummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "samples {}, p50 {}us, p90 {}us, p99 {}us, max {}us",
+            self.samples, self.p50_us, self.p90_us, self.p99_us, self.max_us
+        )
+    }
+}
+
+/// Result of a self test run.
+#[derive(Default, Serialize)]
+pub struct SelfTestReport {
+    /// Seed of the random generator, to reproduce the run.
+    pub seed: u64,
+    /// Number of chunks read and verified.
+    pub chunks: usize,
+    /// Number of chunks skipped, such as batched, encrypted or ZRAN chunks.
+    pub skipped_chunks: usize,
+    /// Total uncompressed size of verified chunks.
+    pub bytes: u64,
+    pub inode_latency: LatencySummary,
+    pub chunk_latency: LatencySummary,
+}
+
+impl SelfTestReport {
+    pub fn dump_json(&self, path: &Path) -> Result<()> {
+        let w = OpenOptions::new()
+            .truncate(true)
+            .create(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Output file {:?} can't be opened", path))?;
+
+        serde_json::to_writer(w, self).context("Write output file failed")?;
+
+        Ok(())
+    }
+}
+
+/// Simple xorshift generator, good enough to sample inodes and chunks.
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // Zero is a fixed point of xorshift.
+        XorShift(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+struct BlobState {
+    info: Arc<BlobInfo>,
+    reader: Arc<dyn BlobReader>,
+    meta: Option<BlobCompressionContextInfo>,
+}
+
+pub struct SelfTester {
+    rs: RafsSuper,
+    config: Arc<ConfigV2>,
+    blobs: HashMap<u32, BlobState>,
+    verify_digest: bool,
+    // Cache directory for blob compression context files, removed on drop.
+    work_dir: WorkDir,
+}
+
+impl SelfTester {
+    pub fn new(bootstrap: &Path, config: Arc<ConfigV2>) -> Result<Self> {
+        let (rs, _) = RafsSuper::load_from_file(bootstrap, config.clone(), false)
+            .with_context(|| format!("failed to load bootstrap {}", bootstrap.display()))?;
+        // Chunk digests of tarfs images are placeholders.
+        let verify_digest = !rs.meta.flags.contains(RafsSuperFlags::TARTFS_MODE);
+
+        Ok(SelfTester {
+            rs,
+            config,
+            blobs: HashMap::new(),
+            verify_digest,
+            work_dir: WorkDir::new()?,
+        })
+    }
+
+    /// Resolve `samples` random inodes and read random chunk ranges of regular files among them.
+    pub fn run(&mut self, samples: usize, seed: Option<u64>) -> Result<SelfTestReport> {
+        let seed = match seed {
+            Some(v) => v,
+            None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64,
+        };
+        let mut rng = XorShift::new(seed);
+        let mut report = SelfTestReport {
+            seed,
+            ..Default::default()
+        };
+
+        let mut inos = Vec::new();
+        self.rs
+            .walk_directory::<&Path>(self.rs.superblock.root_ino(), None, &mut |inode, _| {
+                inos.push(inode.ino());
+                Ok(())
+            })?;
+        // Hardlinks are visited multiple times.
+        inos.sort_unstable();
+        inos.dedup();
+
+        let mut inode_latencies = Vec::with_capacity(samples);
+        let mut chunk_latencies = Vec::new();
+        for _ in 0..samples {
+            let ino = inos[rng.below(inos.len() as u64) as usize];
+            let start = Instant::now();
+            let inode = self
+                .rs
+                .get_extended_inode(ino, true)
+                .with_context(|| format!("failed to resolve inode {}", ino))?;
+            let path = self
+                .rs
+                .path_from_ino(ino)
+                .with_context(|| format!("failed to resolve path of inode {}", ino))?;
+            inode_latencies.push(start.elapsed());
+
+            let count = inode.get_chunk_count();
+            if !inode.is_reg() || count == 0 {
+                continue;
+            }
+            let first = rng.below(count as u64) as u32;
+            let len = 1 + rng.below(MAX_RANGE_CHUNKS.min(count - first) as u64) as u32;
+            for idx in first..first + len {
+                let chunk = inode.get_chunk_info(idx)?;
+                let start = Instant::now();
+                let size = self.read_chunk(chunk.as_ref()).with_context(|| {
+                    format!("failed to read chunk {} of {}", idx, path.display())
+                })?;
+                match size {
+                    Some(size) => {
+                        chunk_latencies.push(start.elapsed());
+                        report.chunks += 1;
+                        report.bytes += size as u64;
+                    }
+                    None => report.skipped_chunks += 1,
+                }
+            }
+        }
+
+        report.inode_latency = LatencySummary::new(inode_latencies);
+        report.chunk_latency = LatencySummary::new(chunk_latencies);
+
+        Ok(report)
+    }
+
+    /// Read and verify a data chunk, return uncompressed size of the chunk, or `None` if the chunk
+    /// isn't supported.
+    fn read_chunk(&mut self, chunk: &dyn BlobChunkInfo) -> Result<Option<usize>> {
+        if chunk.is_batch() || chunk.is_encrypted() {
+            return Ok(None);
+        }
+        let verify_digest = self.verify_digest;
+        let digester = self.rs.meta.get_digester();
+        let blob = self.get_blob(chunk.blob_index())?;
+        if blob.info.has_feature(BlobFeatures::ZRAN) {
+            return Ok(None);
+        }
+
+        // Locate the chunk through the blob compression context as the storage subsystem does,
+        // and make sure it matches the chunk recorded in RAFS metadata.
+        let located = match blob.meta.as_ref() {
+            None => None,
+            Some(meta) => {
+                let chunks = meta
+                    .get_chunks_uncompressed(
+                        chunk.uncompressed_offset(),
+                        chunk.uncompressed_size() as u64,
+                        0,
+                    )
+                    .map_err(|e| anyhow!("failed to locate chunk in blob meta, {}", e))?;
+                if chunks.len() != 1
+                    || chunks[0].compressed_offset() != chunk.compressed_offset()
+                    || chunks[0].compressed_size() != chunk.compressed_size()
+                {
+                    bail!(
+                        "chunk at compressed offset 0x{:x} of blob {} is inconsistent with blob meta",
+                        chunk.compressed_offset(),
+                        blob.info.blob_id()
+                    );
+                }
+                Some(chunks[0].clone())
+            }
+        };
+        let target = located.as_deref().unwrap_or(chunk);
+
+        let mut buf = vec![0u8; target.compressed_size() as usize];
+        let size = blob
+            .reader
+            .read(&mut buf, target.compressed_offset())
+            .map_err(|e| anyhow!("failed to read blob {}, {:?}", blob.info.blob_id(), e))?;
+        if size != buf.len() {
+            bail!(
+                "short read of blob {} at 0x{:x}, expect 0x{:x}, got 0x{:x}",
+                blob.info.blob_id(),
+                target.compressed_offset(),
+                buf.len(),
+                size
+            );
+        }
+
+        let data = if chunk.is_compressed() {
+            let compressor = chunk.compressor().unwrap_or_else(|| blob.info.compressor());
+            let mut data = vec![0u8; chunk.uncompressed_size() as usize];
+            compress::decompress(&buf, &mut data, compressor)
+                .context("failed to decompress chunk")?;
+            data
+        } else {
+            buf
+        };
+
+        if verify_digest {
+            let digest = RafsDigest::from_buf(&data, digester);
+            if &digest != chunk.chunk_id() {
+                bail!(
+                    "digest of chunk at compressed offset 0x{:x} of blob {} doesn't match, expect {}, got {}",
+                    chunk.compressed_offset(),
+                    blob.info.blob_id(),
+                    chunk.chunk_id(),
+                    digest
+                );
+            }
+        }
+
+        Ok(Some(data.len()))
+    }
+
+    fn get_blob(&mut self, blob_index: u32) -> Result<&BlobState> {
+        if !self.blobs.contains_key(&blob_index) {
+            let info = self
+                .rs
+                .superblock
+                .get_blob_infos()
+                .into_iter()
+                .find(|b| b.blob_index() == blob_index)
+                .ok_or_else(|| anyhow!("can not find blob by index: {}", blob_index))?;
+            let blob_id = info.blob_id();
+            let backend = BlobFactory::new_backend(self.config.get_backend_config()?, &blob_id)
+                .with_context(|| format!("failed to create backend for blob {}", blob_id))?;
+            let reader = backend
+                .get_reader(&blob_id)
+                .map_err(|e| anyhow!("failed to get reader for blob {}, {:?}", blob_id, e))?;
+            let meta = if info.meta_ci_is_valid() && !info.has_feature(BlobFeatures::SEPARATE) {
+                let path = self.work_dir.path().join(&blob_id);
+                let meta = BlobCompressionContextInfo::new(
+                    &path.display().to_string(),
+                    &info,
+                    Some(&reader),
+                    false,
+                )
+                .map_err(|e| anyhow!("failed to load blob meta of blob {}, {}", blob_id, e))?;
+                Some(meta)
+            } else {
+                None
+            };
+            self.blobs
+                .insert(blob_index, BlobState { info, reader, meta });
+        }
+
+        Ok(&self.blobs[&blob_index])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_selftest_v6_image() {
+        let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
+        let mut bootstrap = PathBuf::from(root_dir);
+        bootstrap.push("tests/texture/bootstrap/rafs-v6-2.2.boot");
+        let blob_dir = PathBuf::from(root_dir).join("tests/texture/blobs");
+        let config = ConfigV2::new_localfs("", blob_dir.to_str().unwrap()).unwrap();
+
+        let mut tester = SelfTester::new(&bootstrap, Arc::new(config)).unwrap();
+        let report = tester.run(64, Some(1)).unwrap();
+        assert_eq!(report.seed, 1);
+        assert_eq!(report.inode_latency.samples, 64);
+        assert!(report.chunks > 0);
+        assert_eq!(report.chunk_latency.samples, report.chunks);
+    }
+
+    #[test]
+    fn test_latency_summary() {
+        let latencies = (1..=100).map(Duration::from_micros).collect();
+        let summary = LatencySummary::new(latencies);
+        assert_eq!(summary.samples, 100);
+        assert_eq!(summary.p50_us, 50);
+        assert_eq!(summary.p90_us, 90);
+        assert_eq!(summary.p99_us, 99);
+        assert_eq!(summary.max_us, 100);
+        assert_eq!(LatencySummary::new(Vec::new()).samples, 0);
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright (C) 2024 Nydus Developers. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Embed signatures into RAFS v6 bootstraps and verify embedded signatures.
+//!
+//! The signature region is appended to the bootstrap at a 4K aligned offset and recorded in the
+//! signature slot of the extended superblock. The signature covers bootstrap content in range
+//! `[0, signature offset)` with the signature slot cleared, so a bootstrap may be re-signed and
+//! verified without any external files.
+
+use std::fs::{self, File, OpenOptions};
+use std::mem::size_of;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use nydus_api::ConfigV2;
+use nydus_rafs::metadata::layout::v6::{
+    RafsV6SignatureHeader, RafsV6SuperBlockExt, EROFS_BLOCK_SIZE_4096, EROFS_SUPER_BLOCK_SIZE,
+    EROFS_SUPER_OFFSET, RAFS_V6_SIGNATURE_SLOT_OFFSET, RAFS_V6_SIGNATURE_SLOT_SIZE,
+};
+use nydus_rafs::metadata::RafsSuper;
+use nydus_rafs::RafsIoReader;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::{Signer, Verifier};
+
+const SIGNATURE_READ_BUF_SIZE: usize = 0x10_0000;
+const EXT_SUPER_BLOCK_OFFSET: u64 = (EROFS_SUPER_OFFSET + EROFS_SUPER_BLOCK_SIZE) as u64;
+
+/// Sign the RAFS v6 bootstrap with a PEM encoded private key, and embed the signature together
+/// with the signer identity into the bootstrap. Existing signature will be replaced.
+pub fn sign_bootstrap(bootstrap: &Path, private_key: &Path, signer: &str) -> Result<()> {
+    let key = fs::read(private_key)
+        .with_context(|| format!("failed to read private key {}", private_key.display()))?;
+    let key = PKey::private_key_from_pem(&key)
+        .with_context(|| format!("failed to parse private key {}", private_key.display()))?;
+
+    let file = open_bootstrap(bootstrap, true)?;
+    let mut ext_sb = load_ext_superblock(&file)?;
+    let content_size = if ext_sb.has_signature() {
+        ext_sb.signature_offset()
+    } else {
+        file.metadata()?.len()
+    };
+    // Pad bootstrap content to 4K boundary, the padding is covered by the signature.
+    let sig_offset = round_up_4k(content_size);
+    file.set_len(content_size)?;
+    file.set_len(sig_offset)?;
+    ext_sb.set_signature_offset(0);
+    ext_sb.set_signature_size(0);
+    file.write_all_at(ext_sb.as_ref(), EXT_SUPER_BLOCK_OFFSET)?;
+
+    let mut s = Signer::new(MessageDigest::sha256(), &key)?;
+    read_signed_content(&file, sig_offset, |buf| Ok(s.update(buf)?))?;
+    let sig = s.sign_to_vec().context("failed to sign bootstrap")?;
+
+    let header = RafsV6SignatureHeader::new(signer.len() as u32, sig.len() as u32);
+    let mut region = Vec::with_capacity(size_of::<RafsV6SignatureHeader>() + signer.len());
+    region.extend_from_slice(header.as_ref());
+    region.extend_from_slice(signer.as_bytes());
+    region.extend_from_slice(&sig);
+    file.write_all_at(&region, sig_offset)?;
+    file.set_len(sig_offset + round_up_4k(region.len() as u64))?;
+
+    ext_sb.set_signature_offset(sig_offset);
+    ext_sb.set_signature_size(region.len() as u32);
+    file.write_all_at(ext_sb.as_ref(), EXT_SUPER_BLOCK_OFFSET)?;
+    file.sync_all()?;
+
+    Ok(())
+}
+
+/// Verify the signature embedded in the RAFS v6 bootstrap with a PEM encoded public key, and
+/// return the signer identity on success.
+pub fn verify_bootstrap(bootstrap: &Path, public_key: &Path) -> Result<String> {
+    let key = fs::read(public_key)
+        .with_context(|| format!("failed to read public key {}", public_key.display()))?;
+    let key = PKey::public_key_from_pem(&key)
+        .with_context(|| format!("failed to parse public key {}", public_key.display()))?;
+
+    let file = open_bootstrap(bootstrap, false)?;
+    let ext_sb = load_ext_superblock(&file)?;
+    if !ext_sb.has_signature() {
+        bail!("no signature embedded in bootstrap {}", bootstrap.display());
+    }
+    let sig_offset = ext_sb.signature_offset();
+    let sig_size = ext_sb.signature_size() as u64;
+    if sig_offset % EROFS_BLOCK_SIZE_4096 != 0
+        || sig_offset < EROFS_BLOCK_SIZE_4096
+        || sig_offset + sig_size > file.metadata()?.len()
+    {
+        bail!(
+            "invalid signature offset 0x{:x}/size 0x{:x} in bootstrap {}",
+            sig_offset,
+            sig_size,
+            bootstrap.display()
+        );
+    }
+
+    let mut header = RafsV6SignatureHeader::default();
+    file.read_exact_at(header.as_mut(), sig_offset)?;
+    header.validate(sig_size)?;
+    let mut signer = vec![0u8; header.signer_size() as usize];
+    let mut sig = vec![0u8; header.data_size() as usize];
+    let pos = sig_offset + size_of::<RafsV6SignatureHeader>() as u64;
+    file.read_exact_at(&mut signer, pos)?;
+    file.read_exact_at(&mut sig, pos + signer.len() as u64)?;
+    let signer = String::from_utf8(signer).context("invalid signer identity in bootstrap")?;
+
+    let mut v = Verifier::new(MessageDigest::sha256(), &key)?;
+    read_signed_content(&file, sig_offset, |buf| Ok(v.update(buf)?))?;
+    if !v
+        .verify(&sig)
+        .context("failed to verify bootstrap signature")?
+    {
+        bail!(
+            "signature of bootstrap {} doesn't match the public key",
+            bootstrap.display()
+        );
+    }
+
+    Ok(signer)
+}
+
+fn open_bootstrap(bootstrap: &Path, writable: bool) -> Result<File> {
+    let (rs, _) = RafsSuper::load_from_file(bootstrap, Arc::new(ConfigV2::default()), false)
+        .with_context(|| format!("failed to load bootstrap {}", bootstrap.display()))?;
+    if !rs.meta.is_v6() {
+        bail!("only RAFS v6 bootstrap supports embedded signature");
+    }
+    OpenOptions::new()
+        .read(true)
+        .write(writable)
+        .open(bootstrap)
+        .with_context(|| format!("failed to open bootstrap {}", bootstrap.display()))
+}
+
+fn load_ext_superblock(file: &File) -> Result<RafsV6SuperBlockExt> {
+    let mut ext_sb = RafsV6SuperBlockExt::new();
+    let mut reader = Box::new(file.try_clone()?) as RafsIoReader;
+    ext_sb.load(&mut reader)?;
+    Ok(ext_sb)
+}
+
+/// Feed bootstrap content in range `[0, size)` to `f`, with the signature slot cleared.
+fn read_signed_content<F>(file: &File, size: u64, mut f: F) -> Result<()>
+where
+    F: FnMut(&[u8]) -> Result<()>,
+{
+    let mut buf = vec![0u8; SIGNATURE_READ_BUF_SIZE];
+    let mut pos = 0u64;
+    while pos < size {
+        let len = std::cmp::min(size - pos, buf.len() as u64) as usize;
+        file.read_exact_at(&mut buf[..len], pos)?;
+        if pos == 0 {
+            let slot = RAFS_V6_SIGNATURE_SLOT_OFFSET as usize;
+            buf[slot..slot + RAFS_V6_SIGNATURE_SLOT_SIZE].fill(0);
+        }
+        f(&buf[..len])?;
+        pos += len as u64;
+    }
+    Ok(())
+}
+
+fn round_up_4k(size: u64) -> u64 {
+    (size + EROFS_BLOCK_SIZE_4096 - 1) & !(EROFS_BLOCK_SIZE_4096 - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use std::path::PathBuf;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_sign_and_verify_bootstrap() {
+        let tmpdir = TempDir::new().unwrap();
+        let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
+        let mut source_path = PathBuf::from(root_dir);
+        source_path.push("tests/texture/bootstrap/rafs-v6-2.2.boot");
+        let bootstrap = tmpdir.as_path().join("bootstrap");
+        fs::copy(&source_path, &bootstrap).unwrap();
+
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = EcKey::generate(&group).unwrap();
+        let private_key = tmpdir.as_path().join("key.pem");
+        let public_key = tmpdir.as_path().join("key.pub");
+        fs::write(&private_key, key.private_key_to_pem().unwrap()).unwrap();
+        fs::write(&public_key, key.public_key_to_pem().unwrap()).unwrap();
+        let other = EcKey::generate(&group).unwrap();
+        let other_key = tmpdir.as_path().join("other.pub");
+        fs::write(&other_key, other.public_key_to_pem().unwrap()).unwrap();
+
+        assert!(verify_bootstrap(&bootstrap, &public_key).is_err());
+        sign_bootstrap(&bootstrap, &private_key, "builder@example.com").unwrap();
+        assert_eq!(
+            verify_bootstrap(&bootstrap, &public_key).unwrap(),
+            "builder@example.com"
+        );
+        assert!(verify_bootstrap(&bootstrap, &other_key).is_err());
+
+        // Re-signing replaces the existing signature.
+        let size = fs::metadata(&bootstrap).unwrap().len();
+        sign_bootstrap(&bootstrap, &private_key, "another").unwrap();
+        assert_eq!(fs::metadata(&bootstrap).unwrap().len(), size);
+        assert_eq!(
+            verify_bootstrap(&bootstrap, &public_key).unwrap(),
+            "another"
+        );
+
+        // Tampering with signed content invalidates the signature.
+        let file = OpenOptions::new().write(true).open(&bootstrap).unwrap();
+        file.write_all_at(&[0xffu8], EROFS_BLOCK_SIZE_4096 + 1)
+            .unwrap();
+        assert!(verify_bootstrap(&bootstrap, &public_key).is_err());
+    }
+}
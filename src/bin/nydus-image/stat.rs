@@ -2,17 +2,17 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::collections::HashSet;
-use std::fs::OpenOptions;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use anyhow::{Context, Result};
-use nydus_api::ConfigV2;
+use nydus_api::{default_user_io_batch_size, ConfigV2};
 use nydus_builder::{ChunkDict, HashChunkDict, Tree};
 use nydus_rafs::metadata::RafsSuper;
-use nydus_utils::digest;
+use nydus_utils::digest::{self, RafsDigest};
 use serde::Serialize;
 
 #[derive(Copy, Clone, Default, Serialize)]
@@ -27,6 +27,19 @@ struct DedupInfo {
     uncomp_image_size: u64,
 }
 
+/// Chunks of the target image deduplicated against a data blob of base images.
+#[derive(Default, Serialize)]
+struct RefBlobInfo {
+    // Path of the base image bootstrap referring to the data blob.
+    base_image: String,
+    // Number of target image chunks found in the data blob.
+    chunks: u64,
+    // Sum of compressed size of the chunks.
+    comp_size: u64,
+    // Sum of uncompressed size of the chunks.
+    uncomp_size: u64,
+}
+
 #[derive(Serialize)]
 struct ImageInfo {
     dirs: u32,
@@ -59,6 +72,8 @@ struct ImageInfo {
     ref_comp_size: u64,
     // Sum of uncompressed size of all reference chunks.
     ref_uncomp_size: u64,
+    // Reference chunks of the target image indexed by id of the base image data blob.
+    ref_blobs: BTreeMap<String, RefBlobInfo>,
 }
 
 impl ImageInfo {
@@ -83,6 +98,7 @@ impl ImageInfo {
             ref_chunks: 0,
             ref_comp_size: 0,
             ref_uncomp_size: 0,
+            ref_blobs: BTreeMap::new(),
         }
     }
 
@@ -129,6 +145,13 @@ Compressed Size:        {comp_size}"#,
         println!("Referenced Comp Size:\t{}", self.ref_comp_size);
         println!("Referenced Uncomp Size:\t{}", self.ref_uncomp_size);
         println!("Referenced Chunk Count:\t{}", self.ref_chunks);
+
+        for (blob_id, info) in self.ref_blobs.iter() {
+            println!(
+                "Referenced Blob:\t{}, chunks {}, comp size {}, uncomp size {}, base image {}",
+                blob_id, info.chunks, info.comp_size, info.uncomp_size, info.base_image
+            );
+        }
     }
 }
 
@@ -143,6 +166,12 @@ pub(crate) struct ImageStat {
     dedup_dict: HashChunkDict,
     #[serde(skip)]
     dedup_info: [DedupInfo; 20],
+    // (blob id, base image path) of data blobs of base images.
+    #[serde(skip)]
+    base_blobs: Vec<(String, String)>,
+    // Index into `base_blobs` of the data blob first providing each base image chunk.
+    #[serde(skip)]
+    chunk_sources: HashMap<(RafsDigest, u32), usize>,
 }
 
 impl ImageStat {
@@ -155,6 +184,8 @@ impl ImageStat {
             target_image: ImageInfo::new(),
             dedup_dict: HashChunkDict::new(digester),
             dedup_info: [Default::default(); 20],
+            base_blobs: Vec::new(),
+            chunk_sources: HashMap::new(),
         }
     }
 
@@ -208,12 +239,24 @@ impl ImageStat {
         tree.walk_dfs_pre(pre)?;
 
         if is_base {
+            let blobs = rs.superblock.get_blob_infos();
+            let base_idx = self.base_blobs.len();
+            for blob in blobs.iter() {
+                self.base_blobs
+                    .push((blob.blob_id(), path.display().to_string()));
+            }
             for entry in dict.hashmap().values() {
                 image.own_chunks += 1;
                 image.own_comp_size += entry.0.compressed_size() as u64;
                 image.own_uncomp_size += entry.0.uncompressed_size() as u64;
                 self.dedup_dict
                     .add_chunk(entry.0.clone(), rs.meta.get_digester());
+                let blob_index = entry.0.blob_index() as usize;
+                if blob_index < blobs.len() {
+                    self.chunk_sources
+                        .entry((*entry.0.id(), entry.0.uncompressed_size()))
+                        .or_insert(base_idx + blob_index);
+                }
             }
         } else {
             for entry in dict.hashmap().values() {
@@ -225,6 +268,17 @@ impl ImageStat {
                     image.ref_chunks += 1;
                     image.ref_comp_size += entry.0.compressed_size() as u64;
                     image.ref_uncomp_size += entry.0.uncompressed_size() as u64;
+                    let key = (*entry.0.id(), entry.0.uncompressed_size());
+                    if let Some(idx) = self.chunk_sources.get(&key) {
+                        let (blob_id, base_image) = &self.base_blobs[*idx];
+                        let info = image.ref_blobs.entry(blob_id.clone()).or_default();
+                        if info.base_image.is_empty() {
+                            info.base_image = base_image.clone();
+                        }
+                        info.chunks += 1;
+                        info.comp_size += entry.0.compressed_size() as u64;
+                        info.uncomp_size += entry.0.uncompressed_size() as u64;
+                    }
                 } else {
                     image.own_chunks += 1;
                     image.own_comp_size += entry.0.compressed_size() as u64;
@@ -321,3 +375,227 @@ impl ImageStat {
         }
     }
 }
+
+/// Estimated amount of data a lazy-loading runtime downloads to serve a set of files.
+#[derive(Default, Serialize)]
+pub(crate) struct AccessCost {
+    /// Number of accessed files found in the filesystem.
+    files: u64,
+    /// Accessed paths not found in the filesystem.
+    missing_files: Vec<String>,
+    /// Number of distinct data chunks of accessed files.
+    chunks: u64,
+    /// Sum of compressed size of the data chunks.
+    chunk_comp_size: u64,
+    /// Sum of uncompressed size of the data chunks.
+    chunk_uncomp_size: u64,
+    /// Size of data downloaded after amplifying each request to the amplified IO size.
+    amplified_size: u64,
+    /// Amplified IO size used for estimation.
+    amplify_io: u64,
+    /// Number of data blobs referenced by accessed files.
+    blobs: u64,
+    /// Size of chunk information arrays of referenced data blobs.
+    blob_meta_size: u64,
+    /// Size of the RAFS filesystem metadata.
+    bootstrap_size: u64,
+    /// Total size to download, including metadata and amplified data.
+    total_size: u64,
+}
+
+impl AccessCost {
+    /// Estimate the amount of data to download for accessing files listed in `access_list`.
+    ///
+    /// The access list contains a path per line, empty lines and lines starting with `#` are
+    /// ignored. Each data request is assumed to be amplified to the `amplify_io` size of the
+    /// RAFS configuration, and chunks covered by previous requests are not downloaded again.
+    pub fn estimate(bootstrap: &Path, access_list: &Path, config: Arc<ConfigV2>) -> Result<Self> {
+        let amplify_io = config
+            .rafs
+            .as_ref()
+            .map(|c| c.user_io_batch_size)
+            .unwrap_or_else(default_user_io_batch_size) as u64;
+        let (rs, _) = RafsSuper::load_from_file(bootstrap, config, false)
+            .with_context(|| format!("failed to load bootstrap {}", bootstrap.display()))?;
+        let content = fs::read_to_string(access_list)
+            .with_context(|| format!("failed to read access list {}", access_list.display()))?;
+        let mut cost = AccessCost {
+            amplify_io,
+            bootstrap_size: fs::metadata(bootstrap)?.len(),
+            ..Default::default()
+        };
+
+        // Chunks indexed by blob index and then by compressed offset.
+        let mut blob_chunks: BTreeMap<u32, BTreeMap<u64, (u32, u32)>> = BTreeMap::new();
+        for line in content.lines().map(|l| l.trim()) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let path = PathBuf::from("/").join(line);
+            let inode = match rs.ino_from_path(&path) {
+                Ok(ino) => rs.get_extended_inode(ino, false)?,
+                Err(_) => {
+                    cost.missing_files.push(line.to_string());
+                    continue;
+                }
+            };
+            cost.files += 1;
+            if !inode.is_reg() {
+                continue;
+            }
+            for idx in 0..inode.get_chunk_count() {
+                let chunk = inode.get_chunk_info(idx)?;
+                blob_chunks.entry(chunk.blob_index()).or_default().insert(
+                    chunk.compressed_offset(),
+                    (chunk.compressed_size(), chunk.uncompressed_size()),
+                );
+            }
+        }
+
+        let blobs = rs.superblock.get_blob_infos();
+        for (blob_index, chunks) in blob_chunks.iter() {
+            let blob_size = blobs
+                .get(*blob_index as usize)
+                .map(|b| {
+                    if b.meta_ci_is_valid() {
+                        cost.blob_meta_size += b.meta_ci_compressed_size();
+                    }
+                    b.compressed_data_size()
+                })
+                .unwrap_or_default();
+            cost.blobs += 1;
+            cost.amplified_size += Self::amplify(chunks, amplify_io, blob_size);
+            for (comp_size, uncomp_size) in chunks.values() {
+                cost.chunks += 1;
+                cost.chunk_comp_size += *comp_size as u64;
+                cost.chunk_uncomp_size += *uncomp_size as u64;
+            }
+        }
+        cost.total_size = cost.bootstrap_size + cost.blob_meta_size + cost.amplified_size;
+
+        Ok(cost)
+    }
+
+    /// Get size of data downloaded for `chunks` with requests amplified to `amplify_io`.
+    fn amplify(chunks: &BTreeMap<u64, (u32, u32)>, amplify_io: u64, blob_size: u64) -> u64 {
+        let mut size = 0;
+        let mut end = 0;
+        for (offset, (comp_size, _)) in chunks.iter() {
+            let chunk_end = offset + *comp_size as u64;
+            if chunk_end <= end {
+                continue;
+            }
+            let mut req_end = std::cmp::max(chunk_end, offset + amplify_io);
+            if blob_size > 0 {
+                req_end = std::cmp::max(chunk_end, std::cmp::min(req_end, blob_size));
+            }
+            size += req_end - std::cmp::max(*offset, end);
+            end = req_end;
+        }
+        size
+    }
+
+    pub fn dump_json(&self, path: &Path) -> Result<()> {
+        let w = OpenOptions::new()
+            .truncate(true)
+            .create(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Output file {:?} can't be opened", path))?;
+
+        serde_json::to_writer(w, self).context("Write output file failed")?;
+
+        Ok(())
+    }
+
+    pub fn dump(&self) {
+        println!(
+            r#"
+Accessed Files:         {files}
+Missing Files:          {missing}
+Data Blobs:             {blobs}
+Chunks:                 {chunks}
+Chunk Comp Size:        {chunk_comp_size}
+Chunk Uncomp Size:      {chunk_uncomp_size}
+Amplify IO Size:        {amplify_io}
+Amplified Data Size:    {amplified_size}
+Blob Meta Size:         {blob_meta_size}
+Bootstrap Size:         {bootstrap_size}
+Total Download Size:    {total_size}"#,
+            files = self.files,
+            missing = self.missing_files.len(),
+            blobs = self.blobs,
+            chunks = self.chunks,
+            chunk_comp_size = self.chunk_comp_size,
+            chunk_uncomp_size = self.chunk_uncomp_size,
+            amplify_io = self.amplify_io,
+            amplified_size = self.amplified_size,
+            blob_meta_size = self.blob_meta_size,
+            bootstrap_size = self.bootstrap_size,
+            total_size = self.total_size,
+        );
+        for path in self.missing_files.iter() {
+            println!("Missing: {}", path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access_cost_amplify() {
+        let mut chunks = BTreeMap::new();
+        chunks.insert(0, (0x100, 0x1000));
+        chunks.insert(0x200, (0x100, 0x1000));
+        chunks.insert(0x3000, (0x800, 0x1000));
+        // The first request covers the second chunk too.
+        assert_eq!(AccessCost::amplify(&chunks, 0x1000, 0), 0x1000 + 0x1000);
+        // Requests are limited by blob size, but always cover the whole chunk.
+        assert_eq!(AccessCost::amplify(&chunks, 0x1000, 0x3400), 0x1000 + 0x800);
+        // No amplification.
+        assert_eq!(AccessCost::amplify(&chunks, 0, 0), 0x100 + 0x100 + 0x800);
+    }
+
+    #[test]
+    fn test_image_stat_ref_blobs() {
+        let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
+        let bootstrap = PathBuf::from(root_dir).join("tests/texture/bootstrap/rafs-v6-2.2.boot");
+        let config = Arc::new(ConfigV2::default());
+        let mut stat = ImageStat::new(digest::Algorithm::Sha256);
+        stat.stat(&bootstrap, true, config.clone()).unwrap();
+        stat.target_enabled = true;
+        stat.stat(&bootstrap, false, config).unwrap();
+        stat.finalize();
+
+        // All chunks of the target image come from the identical base image.
+        let target = &stat.target_image;
+        assert_eq!(target.own_chunks, 0);
+        assert_eq!(target.ref_chunks, stat.base_image.own_chunks);
+        let chunks: u64 = target.ref_blobs.values().map(|b| b.chunks).sum();
+        let comp_size: u64 = target.ref_blobs.values().map(|b| b.comp_size).sum();
+        assert_eq!(chunks, target.ref_chunks);
+        assert_eq!(comp_size, target.ref_comp_size);
+        for info in target.ref_blobs.values() {
+            assert_eq!(info.base_image, bootstrap.display().to_string());
+        }
+    }
+
+    #[test]
+    fn test_access_cost_estimate() {
+        let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
+        let bootstrap = PathBuf::from(root_dir).join("tests/texture/bootstrap/rafs-v6-2.2.boot");
+        let tmpfile = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        fs::write(tmpfile.as_path(), "# comment\n\n/\nnonexistent\n").unwrap();
+        let cost =
+            AccessCost::estimate(&bootstrap, tmpfile.as_path(), Arc::new(ConfigV2::default()))
+                .unwrap();
+        assert_eq!(cost.files, 1);
+        assert_eq!(cost.missing_files, vec!["nonexistent".to_string()]);
+        assert_eq!(cost.chunks, 0);
+        assert_eq!(cost.amplify_io, default_user_io_batch_size() as u64);
+        assert_eq!(cost.bootstrap_size, fs::metadata(&bootstrap).unwrap().len());
+        assert_eq!(cost.total_size, cost.bootstrap_size);
+    }
+}
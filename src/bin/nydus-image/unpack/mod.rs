@@ -3,22 +3,32 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::Read;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::str;
 use std::sync::Arc;
 
 use anyhow::{Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use nydus_api::ConfigV2;
+use nydus_builder::{
+    WhiteoutSpec, OCISPEC_WHITEOUT_OPAQUE, OCISPEC_WHITEOUT_PREFIX, OVERLAYFS_WHITEOUT_OPAQUE,
+};
+use nydus_rafs::metadata::layout::{XattrName, XattrValue};
 use nydus_rafs::{
     metadata::{RafsInodeExt, RafsSuper},
     RafsIterator,
 };
 use nydus_storage::backend::BlobBackend;
 use nydus_storage::device::BlobInfo;
-use tar::{Builder, Header};
+use nydus_utils::digest::{self, DigestHasher, RafsDigest, RafsDigestHasher};
+use serde::Serialize;
+use tar::{Builder, EntryType, Header};
 
 use self::pax::{
     OCIBlockBuilder, OCICharBuilder, OCIDirBuilder, OCIFifoBuilder, OCILinkBuilder, OCIRegBuilder,
@@ -28,6 +38,8 @@ use self::pax::{
 
 mod pax;
 
+pub(crate) use self::pax::ChunkReader;
+
 pub trait Unpacker {
     fn unpack(&self, config: Arc<ConfigV2>) -> Result<()>;
 }
@@ -46,11 +58,12 @@ impl OCIUnpacker {
         bootstrap: &Path,
         blob_backend: Option<Arc<dyn BlobBackend + Send + Sync>>,
         output: &str,
+        whiteout_spec: WhiteoutSpec,
     ) -> Result<Self> {
         let bootstrap = bootstrap.to_path_buf();
         let output = PathBuf::from(output);
 
-        let builder_factory = OCITarBuilderFactory::new();
+        let builder_factory = OCITarBuilderFactory::new(whiteout_spec);
 
         Ok(OCIUnpacker {
             builder_factory,
@@ -87,6 +100,231 @@ impl Unpacker for OCIUnpacker {
     }
 }
 
+/// Information about an image layer tarball generated by [OCILayerUnpacker].
+#[derive(Serialize)]
+pub struct LayerInfo {
+    /// Path of the gzip compressed layer tarball.
+    pub path: PathBuf,
+    /// Digest of the compressed layer tarball.
+    pub digest: String,
+    /// Digest of the uncompressed layer tarball.
+    pub diff_id: String,
+    /// Size of the compressed layer tarball.
+    pub size: u64,
+}
+
+/// An unpacker to convert a chain of per-layer bootstraps, ordered from the lowest layer to the
+/// uppermost layer, into OCI image layers.
+///
+/// The bootstrap of each layer contains the whole filesystem merged with all its lower layers,
+/// so a layer tarball contains files added or modified since the lower layer, plus whiteouts for
+/// files removed from the lower layer.
+pub struct OCILayerUnpacker {
+    bootstraps: Vec<PathBuf>,
+    blob_backend: Option<Arc<dyn BlobBackend + Send + Sync>>,
+    output: PathBuf,
+
+    builder_factory: OCITarBuilderFactory,
+}
+
+impl OCILayerUnpacker {
+    pub fn new(
+        bootstraps: Vec<PathBuf>,
+        blob_backend: Option<Arc<dyn BlobBackend + Send + Sync>>,
+        output: &str,
+        whiteout_spec: WhiteoutSpec,
+    ) -> Result<Self> {
+        if bootstraps.is_empty() {
+            bail!("no layer bootstrap specified");
+        }
+        let output = PathBuf::from(output);
+        fs::create_dir_all(&output)
+            .with_context(|| format!("fail to create output directory {:?}", output))?;
+
+        Ok(OCILayerUnpacker {
+            bootstraps,
+            blob_backend,
+            output,
+            builder_factory: OCITarBuilderFactory::new(whiteout_spec),
+        })
+    }
+
+    /// Generate a gzip compressed tarball for each layer.
+    pub fn unpack_layers(&self, config: Arc<ConfigV2>) -> Result<Vec<LayerInfo>> {
+        let mut layers = Vec::with_capacity(self.bootstraps.len());
+        let mut lower = None;
+
+        for (idx, bootstrap) in self.bootstraps.iter().enumerate() {
+            let (rs, _) = RafsSuper::load_from_file(bootstrap, config.clone(), false)
+                .with_context(|| format!("fail to load bootstrap {:?}", bootstrap))?;
+            let output = self.output.join(format!("layer-{}.tar.gz", idx));
+            let (layer, states) = self
+                .unpack_layer(&rs, lower.as_ref(), &output)
+                .with_context(|| format!("fail to unpack layer from {:?}", bootstrap))?;
+            debug!(
+                "oci layer unpacker, bootstrap file: {:?}, output file: {:?}",
+                bootstrap, output
+            );
+            layers.push(layer);
+            lower = Some(states);
+        }
+
+        Ok(layers)
+    }
+
+    fn unpack_layer(
+        &self,
+        rs: &RafsSuper,
+        lower: Option<&HashMap<PathBuf, InodeState>>,
+        output: &Path,
+    ) -> Result<(LayerInfo, HashMap<PathBuf, InodeState>)> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(output)
+            .with_context(|| format!("fail to open output file {:?}", output))?;
+        let writer = DigestWriter::new(file);
+        let writer = DigestWriter::new(GzEncoder::new(writer, Compression::default()));
+        let builders = self
+            .builder_factory
+            .create_builders(rs, &self.blob_backend)?;
+        let mut builder = OCITarBuilder::new(
+            builders,
+            Builder::new(writer),
+            self.builder_factory.whiteout_spec,
+        );
+
+        let mut states = HashMap::new();
+        for (node, path) in RafsIterator::new(rs) {
+            let state = InodeState::new(&node)?;
+            if lower.map(|l| l.get(&path) != Some(&state)).unwrap_or(true) {
+                builder.append(node, &path)?;
+            }
+            states.insert(path, state);
+        }
+
+        // Generate whiteouts for the topmost removed files, whose parent directory still exists.
+        if let Some(lower) = lower {
+            let mut removed = lower
+                .keys()
+                .filter(|p| {
+                    !states.contains_key(*p)
+                        && p.parent()
+                            .and_then(|p| states.get(p))
+                            .map(|s| s.is_dir())
+                            .unwrap_or(false)
+                })
+                .collect::<Vec<_>>();
+            removed.sort();
+            for path in removed {
+                builder.append_whiteout(path)?;
+            }
+        }
+
+        let (gz_writer, diff_id, _) = builder.writer.into_inner()?.finish();
+        let (file, digest, size) = gz_writer.finish()?.finish();
+        file.sync_all()?;
+
+        let layer = LayerInfo {
+            path: output.to_path_buf(),
+            digest: format!("sha256:{}", digest),
+            diff_id: format!("sha256:{}", diff_id),
+            size,
+        };
+        Ok((layer, states))
+    }
+}
+
+/// Attributes used to detect modifications of a file between two layers.
+#[derive(PartialEq, Eq)]
+struct InodeState {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    mtime: u64,
+    rdev: u32,
+    symlink: Option<OsString>,
+    xattrs: Vec<(XattrName, Option<XattrValue>)>,
+    chunks: Vec<RafsDigest>,
+}
+
+impl InodeState {
+    fn new(inode: &Arc<dyn RafsInodeExt>) -> Result<Self> {
+        let attr = inode.get_attr();
+        let symlink = if inode.is_symlink() {
+            Some(inode.get_symlink()?)
+        } else {
+            None
+        };
+        let mut xattrs = Vec::new();
+        if inode.has_xattr() {
+            for name in inode.get_xattrs()? {
+                let value = inode.get_xattr(OsStr::from_bytes(&name))?;
+                xattrs.push((name, value));
+            }
+            xattrs.sort();
+        }
+        let mut chunks = Vec::new();
+        if inode.is_reg() {
+            for idx in 0..inode.get_chunk_count() {
+                chunks.push(*inode.get_chunk_info(idx)?.chunk_id());
+            }
+        }
+
+        Ok(InodeState {
+            mode: attr.mode,
+            uid: attr.uid,
+            gid: attr.gid,
+            size: attr.size,
+            mtime: attr.mtime,
+            rdev: attr.rdev,
+            symlink,
+            xattrs,
+            chunks,
+        })
+    }
+
+    fn is_dir(&self) -> bool {
+        self.mode & libc::S_IFMT as u32 == libc::S_IFDIR as u32
+    }
+}
+
+/// A writer to calculate sha256 digest and size of data written through it.
+struct DigestWriter<W: Write> {
+    inner: W,
+    hasher: RafsDigestHasher,
+    size: u64,
+}
+
+impl<W: Write> DigestWriter<W> {
+    fn new(inner: W) -> Self {
+        DigestWriter {
+            inner,
+            hasher: RafsDigest::hasher(digest::Algorithm::Sha256),
+            size: 0,
+        }
+    }
+
+    fn finish(self) -> (W, RafsDigest, u64) {
+        (self.inner, self.hasher.digest_finalize(), self.size)
+    }
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let sz = self.inner.write(buf)?;
+        self.hasher.digest_update(&buf[..sz]);
+        self.size += sz as u64;
+        Ok(sz)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 trait TarBuilder {
     fn append(&mut self, node: Arc<dyn RafsInodeExt>, path: &Path) -> Result<()>;
 }
@@ -101,11 +339,15 @@ trait SectionBuilder {
     fn build(&self, inode: Arc<dyn RafsInodeExt>, path: &Path) -> Result<Vec<TarSection>>;
 }
 
-struct OCITarBuilderFactory {}
+/// Factory to create tar builders, converting whiteouts in the RAFS filesystem into
+/// `whiteout_spec`, or keeping them as is for [WhiteoutSpec::None].
+struct OCITarBuilderFactory {
+    whiteout_spec: WhiteoutSpec,
+}
 
 impl OCITarBuilderFactory {
-    fn new() -> Self {
-        OCITarBuilderFactory {}
+    fn new(whiteout_spec: WhiteoutSpec) -> Self {
+        OCITarBuilderFactory { whiteout_spec }
     }
 
     fn create(
@@ -118,7 +360,7 @@ impl OCITarBuilderFactory {
 
         let builders = self.create_builders(meta, blob_backend)?;
 
-        let builder = OCITarBuilder::new(builders, writer);
+        let builder = OCITarBuilder::new(builders, writer, self.whiteout_spec);
 
         Ok(Box::new(builder) as Box<dyn TarBuilder>)
     }
@@ -151,7 +393,7 @@ impl OCITarBuilderFactory {
         let sock_builder = OCISocketBuilder::new();
         let hard_link_builder = OCILinkBuilder::new(link_builder.clone());
         let symlink_builder = OCISymlinkBuilder::new(link_builder);
-        let dir_builder = OCIDirBuilder::new(ext_builder);
+        let dir_builder = OCIDirBuilder::new(ext_builder, self.whiteout_spec);
         let fifo_builder = OCIFifoBuilder::new(special_builder.clone());
         let char_builder = OCICharBuilder::new(special_builder.clone());
         let block_builder = OCIBlockBuilder::new(special_builder);
@@ -201,19 +443,101 @@ impl OCITarBuilderFactory {
     }
 }
 
-struct OCITarBuilder {
-    writer: Builder<File>,
+struct OCITarBuilder<W: Write> {
+    writer: Builder<W>,
     builders: Vec<Box<dyn SectionBuilder>>,
+    whiteout_spec: WhiteoutSpec,
 }
 
-impl OCITarBuilder {
-    fn new(builders: Vec<Box<dyn SectionBuilder>>, writer: Builder<File>) -> Self {
-        Self { builders, writer }
+impl<W: Write> OCITarBuilder<W> {
+    fn new(
+        builders: Vec<Box<dyn SectionBuilder>>,
+        writer: Builder<W>,
+        whiteout_spec: WhiteoutSpec,
+    ) -> Self {
+        Self {
+            builders,
+            writer,
+            whiteout_spec,
+        }
+    }
+
+    /// Append a whiteout for the removed `path`, as a `.wh.` prefixed empty file by default or
+    /// as a 0/0 character device for overlayfs.
+    fn append_whiteout(&mut self, path: &Path) -> Result<()> {
+        if self.whiteout_spec == WhiteoutSpec::Overlayfs {
+            let wh_path = path.strip_prefix("/").unwrap_or(path);
+            let mut header = Header::new_gnu();
+            header.set_entry_type(EntryType::Char);
+            header.set_size(0);
+            header.set_mode(0);
+            header.set_mtime(0);
+            header.set_device_major(0)?;
+            header.set_device_minor(0)?;
+            return self
+                .writer
+                .append_data(&mut header, wh_path, io::empty())
+                .with_context(|| format!("fail to append whiteout {:?}", wh_path));
+        }
+
+        let name = path
+            .file_name()
+            .with_context(|| format!("invalid path {:?} for whiteout", path))?;
+        let mut wh_name = OsString::from(OCISPEC_WHITEOUT_PREFIX);
+        wh_name.push(name);
+        self.append_oci_marker(&path.with_file_name(wh_name))
+    }
+
+    fn append_oci_marker(&mut self, path: &Path) -> Result<()> {
+        let wh_path = path.strip_prefix("/").unwrap_or(path);
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Regular);
+        header.set_size(0);
+        header.set_mode(0o644);
+        header.set_mtime(0);
+        self.writer
+            .append_data(&mut header, wh_path, io::empty())
+            .with_context(|| format!("fail to append whiteout {:?}", wh_path))
+    }
+
+    /// Convert whiteouts stored in the RAFS filesystem into the target whiteout specification,
+    /// return true if the inode has been converted.
+    ///
+    /// Opaque directories are converted by the directory section builder and here.
+    fn convert_whiteout(&mut self, inode: &Arc<dyn RafsInodeExt>, path: &Path) -> Result<bool> {
+        match self.whiteout_spec {
+            WhiteoutSpec::Oci => {
+                if inode.is_chrdev() && inode.rdev() == 0 {
+                    self.append_whiteout(path)?;
+                    return Ok(true);
+                }
+            }
+            WhiteoutSpec::Overlayfs => {
+                let name = path.file_name().unwrap_or_default();
+                if name == OCISPEC_WHITEOUT_OPAQUE {
+                    // Converted into xattr of the parent directory.
+                    return Ok(true);
+                }
+                if let Some(origin) = name
+                    .as_bytes()
+                    .strip_prefix(OCISPEC_WHITEOUT_PREFIX.as_bytes())
+                {
+                    self.append_whiteout(&path.with_file_name(OsStr::from_bytes(origin)))?;
+                    return Ok(true);
+                }
+            }
+            WhiteoutSpec::None => {}
+        }
+        Ok(false)
     }
 }
 
-impl TarBuilder for OCITarBuilder {
+impl<W: Write> TarBuilder for OCITarBuilder<W> {
     fn append(&mut self, inode: Arc<dyn RafsInodeExt>, path: &Path) -> Result<()> {
+        if self.convert_whiteout(&inode, path)? {
+            return Ok(());
+        }
+
         for builder in &mut self.builders {
             // Useless one, just go !!!!!
             if !builder.can_handle(inode.clone(), path) {
@@ -224,9 +548,211 @@ impl TarBuilder for OCITarBuilder {
                 self.writer.append(&sect.header, sect.data)?;
             }
 
+            if self.whiteout_spec == WhiteoutSpec::Oci && is_overlayfs_opaque(inode.as_ref()) {
+                self.append_oci_marker(&path.join(OCISPEC_WHITEOUT_OPAQUE))?;
+            }
+
             return Ok(());
         }
 
         bail!("node {:?} can not be unpacked", path)
     }
 }
+
+/// Check whether the directory is made opaque by the overlayfs `trusted.overlay.opaque` xattr.
+fn is_overlayfs_opaque(inode: &dyn RafsInodeExt) -> bool {
+    inode.is_dir()
+        && inode.has_xattr()
+        && matches!(
+            inode.get_xattr(OsStr::new(OVERLAYFS_WHITEOUT_OPAQUE)),
+            Ok(Some(v)) if v == b"y"
+        )
+}
+
+/// Check whether the directory is made opaque by an OCI `.wh..wh..opq` whiteout entry.
+fn is_oci_opaque(inode: &dyn RafsInodeExt) -> bool {
+    inode.is_dir()
+        && inode
+            .get_child_by_name(OsStr::new(OCISPEC_WHITEOUT_OPAQUE))
+            .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use nydus_builder::{
+        ArtifactStorage, BlobManager, BootstrapManager, BuildContext, Builder as _, ConversionType,
+        DirectoryBuilder, Features, Prefetch,
+    };
+    use nydus_rafs::metadata::RafsVersion;
+    use nydus_storage::factory::BlobFactory;
+    use nydus_utils::compress;
+    use tar::Archive;
+    use vmm_sys_util::tempdir::TempDir;
+
+    fn build_bootstrap(source: &Path, blob_dir: &Path, name: &str) -> PathBuf {
+        let bootstrap = blob_dir.join(name);
+        let mut ctx = BuildContext::new(
+            String::new(),
+            false,
+            0,
+            compress::Algorithm::Zstd,
+            digest::Algorithm::Sha256,
+            true,
+            WhiteoutSpec::Oci,
+            ConversionType::DirectoryToRafs,
+            source.to_path_buf(),
+            Prefetch::default(),
+            Some(ArtifactStorage::FileDir(blob_dir.to_path_buf())),
+            false,
+            Features::new(),
+            false,
+        );
+        ctx.set_fs_version(RafsVersion::V6);
+        let mut bootstrap_mgr =
+            BootstrapManager::new(Some(ArtifactStorage::SingleFile(bootstrap.clone())), None);
+        let mut blob_mgr = BlobManager::new(digest::Algorithm::Sha256);
+        DirectoryBuilder::new()
+            .build(&mut ctx, &mut bootstrap_mgr, &mut blob_mgr)
+            .unwrap();
+        bootstrap
+    }
+
+    /// Path, type, size and link name of a tar entry.
+    type TarEntry = (PathBuf, EntryType, u64, Option<PathBuf>);
+
+    fn layer_entries(layer: &LayerInfo) -> Vec<TarEntry> {
+        let file = File::open(&layer.path).unwrap();
+        let mut archive = Archive::new(GzDecoder::new(file));
+        archive
+            .entries()
+            .unwrap()
+            .map(|e| {
+                let e = e.unwrap();
+                let header = e.header();
+                (
+                    e.path().unwrap().to_path_buf(),
+                    header.entry_type(),
+                    header.size().unwrap(),
+                    e.link_name().unwrap().map(|p| p.to_path_buf()),
+                )
+            })
+            .collect()
+    }
+
+    fn find_entry(entries: &[TarEntry], path: &str) -> Option<TarEntry> {
+        entries.iter().find(|e| e.0 == Path::new(path)).cloned()
+    }
+
+    fn link_name(entry: TarEntry) -> PathBuf {
+        assert_eq!(entry.1, EntryType::Link);
+        let link = entry.3.unwrap();
+        link.strip_prefix("/").unwrap_or(&link).to_path_buf()
+    }
+
+    #[test]
+    fn test_unpack_layers() {
+        let source = TempDir::new().unwrap();
+        let blob_dir = TempDir::new().unwrap();
+        let output = TempDir::new().unwrap();
+        let root = source.as_path();
+        fs::create_dir(root.join("dir")).unwrap();
+        fs::write(root.join("a"), vec![0x5au8; 0x2800]).unwrap();
+        fs::hard_link(root.join("a"), root.join("b")).unwrap();
+        fs::write(root.join("dir/c"), b"removed in upper layer").unwrap();
+        fs::write(root.join("dir/d"), b"kept in upper layer").unwrap();
+        let lower = build_bootstrap(root, blob_dir.as_path(), "bootstrap-0");
+
+        // The upper layer removes "/dir/c" and adds a new pair of hardlinks.
+        fs::remove_file(root.join("dir/c")).unwrap();
+        fs::write(root.join("e"), vec![0xa5u8; 0x1800]).unwrap();
+        fs::hard_link(root.join("e"), root.join("f")).unwrap();
+        let upper = build_bootstrap(root, blob_dir.as_path(), "bootstrap-1");
+
+        let config =
+            Arc::new(ConfigV2::new_localfs("", blob_dir.as_path().to_str().unwrap()).unwrap());
+        let backend =
+            BlobFactory::new_backend(config.backend.as_ref().unwrap(), "unpacker").unwrap();
+        let layers = OCILayerUnpacker::new(
+            vec![lower, upper],
+            Some(backend),
+            output.as_path().to_str().unwrap(),
+            WhiteoutSpec::Oci,
+        )
+        .unwrap()
+        .unpack_layers(config)
+        .unwrap();
+        assert_eq!(layers.len(), 2);
+        for layer in layers.iter() {
+            assert_eq!(fs::metadata(&layer.path).unwrap().len(), layer.size);
+            assert_ne!(layer.digest, layer.diff_id);
+        }
+
+        // The lowest layer contains all files, with hardlinks to the first path of the inode.
+        let entries = layer_entries(&layers[0]);
+        let a = find_entry(&entries, "a").unwrap();
+        assert_eq!((a.1, a.2), (EntryType::Regular, 0x2800));
+        assert_eq!(
+            link_name(find_entry(&entries, "b").unwrap()),
+            Path::new("a")
+        );
+        assert_eq!(find_entry(&entries, "dir/c").unwrap().1, EntryType::Regular);
+        assert_eq!(find_entry(&entries, "dir/d").unwrap().1, EntryType::Regular);
+        assert!(entries
+            .iter()
+            .all(|e| !e.0.to_string_lossy().contains(OCISPEC_WHITEOUT_PREFIX)));
+
+        // The upper layer contains changes only, with a whiteout for the removed file.
+        let entries = layer_entries(&layers[1]);
+        assert!(find_entry(&entries, "a").is_none());
+        assert!(find_entry(&entries, "b").is_none());
+        assert!(find_entry(&entries, "dir/c").is_none());
+        assert!(find_entry(&entries, "dir/d").is_none());
+        let wh = find_entry(&entries, "dir/.wh.c").unwrap();
+        assert_eq!((wh.1, wh.2), (EntryType::Regular, 0));
+        let e = find_entry(&entries, "e").unwrap();
+        assert_eq!((e.1, e.2), (EntryType::Regular, 0x1800));
+        assert_eq!(
+            link_name(find_entry(&entries, "f").unwrap()),
+            Path::new("e")
+        );
+    }
+
+    fn whiteout_entries(spec: WhiteoutSpec) -> Vec<(PathBuf, EntryType, u64)> {
+        let mut builder = OCITarBuilder::new(Vec::new(), Builder::new(Vec::new()), spec);
+        builder.append_whiteout(Path::new("/dir/file")).unwrap();
+        let data = builder.writer.into_inner().unwrap();
+
+        let mut archive = Archive::new(data.as_slice());
+        archive
+            .entries()
+            .unwrap()
+            .map(|e| {
+                let e = e.unwrap();
+                let path = e.path().unwrap().to_path_buf();
+                let header = e.header();
+                (path, header.entry_type(), header.size().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_append_whiteout() {
+        let entries = whiteout_entries(WhiteoutSpec::Oci);
+        assert_eq!(
+            entries,
+            vec![(PathBuf::from("dir/.wh.file"), EntryType::Regular, 0)]
+        );
+        let entries = whiteout_entries(WhiteoutSpec::None);
+        assert_eq!(
+            entries,
+            vec![(PathBuf::from("dir/.wh.file"), EntryType::Regular, 0)]
+        );
+        let entries = whiteout_entries(WhiteoutSpec::Overlayfs);
+        assert_eq!(
+            entries,
+            vec![(PathBuf::from("dir/file"), EntryType::Char, 0)]
+        );
+    }
+}
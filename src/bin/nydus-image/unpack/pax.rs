@@ -14,13 +14,14 @@ use std::{
 };
 
 use anyhow::{Context, Result};
+use nydus_builder::{WhiteoutSpec, OVERLAYFS_WHITEOUT_OPAQUE};
 use nydus_rafs::metadata::inode::InodeWrapper;
 use nydus_rafs::metadata::RafsInodeExt;
 use nydus_storage::{backend::BlobReader, device::BlobChunkInfo, utils::alloc_buf};
 use nydus_utils::compress::{self, Algorithm};
 use tar::{EntryType, Header};
 
-use super::{SectionBuilder, TarSection};
+use super::{is_oci_opaque, SectionBuilder, TarSection};
 
 static PAX_SEP1: &[u8; 1] = b" ";
 static PAX_SEP2: &[u8; 1] = b"=";
@@ -83,11 +84,31 @@ impl SectionBuilder for OCILinkBuilder {
 
 pub struct OCIDirBuilder {
     ext_builder: Rc<PAXExtensionSectionBuilder>,
+    whiteout_spec: WhiteoutSpec,
 }
 
 impl OCIDirBuilder {
-    pub fn new(ext_builder: Rc<PAXExtensionSectionBuilder>) -> Self {
-        OCIDirBuilder { ext_builder }
+    pub fn new(ext_builder: Rc<PAXExtensionSectionBuilder>, whiteout_spec: WhiteoutSpec) -> Self {
+        OCIDirBuilder {
+            ext_builder,
+            whiteout_spec,
+        }
+    }
+
+    /// Adjust the overlayfs opaque xattr according to the target whiteout specification.
+    fn convert_opaque(&self, inode: &dyn RafsInodeExt, extensions: &mut Vec<PAXRecord>) {
+        let key = [PAX_PREFIX.as_slice(), OVERLAYFS_WHITEOUT_OPAQUE.as_bytes()].concat();
+        match self.whiteout_spec {
+            WhiteoutSpec::Oci => extensions.retain(|r| r.k != key),
+            WhiteoutSpec::Overlayfs if is_oci_opaque(inode) => {
+                extensions.retain(|r| r.k != key);
+                extensions.push(PAXRecord {
+                    k: key,
+                    v: b"y".to_vec(),
+                });
+            }
+            _ => {}
+        }
     }
 
     fn is_root(&self, path: &Path) -> bool {
@@ -147,6 +168,7 @@ impl SectionBuilder for OCIDirBuilder {
         if let Some(extension) = PAXUtil::get_xattr_as_extensions(inode.deref()) {
             extensions.extend(extension);
         }
+        self.convert_opaque(inode.deref(), &mut extensions);
 
         Util::set_cksum(&mut header);
 
@@ -698,7 +720,7 @@ impl Util {
     }
 }
 
-struct ChunkReader {
+pub(crate) struct ChunkReader {
     compressors: HashMap<u32, Algorithm>,
     readers: HashMap<u32, Arc<dyn BlobReader>>,
 
@@ -707,7 +729,7 @@ struct ChunkReader {
 }
 
 impl ChunkReader {
-    fn new(
+    pub(crate) fn new(
         compressors: HashMap<u32, Algorithm>,
         readers: HashMap<u32, Arc<dyn BlobReader>>,
         chunks: Vec<Arc<dyn BlobChunkInfo>>,
@@ -740,10 +762,12 @@ impl ChunkReader {
             return Ok(());
         }
 
-        let compressor = *self
-            .compressors
-            .get(&chunk.blob_index())
-            .expect("No valid compressor");
+        let compressor = chunk.compressor().unwrap_or_else(|| {
+            *self
+                .compressors
+                .get(&chunk.blob_index())
+                .expect("No valid compressor")
+        });
 
         let mut data = vec![0u8; chunk.uncompressed_size() as usize];
         compress::decompress(buf.as_mut_slice(), data.as_mut_slice(), compressor)
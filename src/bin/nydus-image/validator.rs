@@ -4,25 +4,341 @@
 
 //! Validator for RAFS format
 
-use std::path::Path;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use nydus_api::ConfigV2;
-use nydus_builder::Tree;
-use nydus_rafs::metadata::{RafsSuper, RafsVersion};
-use nydus_storage::device::BlobInfo;
+use nydus_builder::{ChunkDict, NodeChunk, Tree};
+use nydus_rafs::metadata::layout::RafsXAttrs;
+use nydus_rafs::metadata::{
+    RafsBlobExtraInfo, RafsSuper, RafsSuperConfig, RafsSuperFlags, RafsVersion,
+};
+use nydus_rafs::RafsIoReader;
+use nydus_storage::device::{BlobFeatures, BlobInfo};
 use nydus_utils::compress;
+use nydus_utils::digest::RafsDigest;
+
+use crate::capability::ImageCapabilities;
+use crate::data_checker::DataChunk;
+
+const ALIGNMENT_4K: u64 = 0x1000;
+
+/// Check data chunks of a regular file.
+///
+/// Empty files must have no data chunks, so reading them never touches data blobs. Old builders
+/// generate a zero-length chunk for empty files, which is accepted because it carries no data.
+/// Chunks of non-empty files must not be zero-length and must cover the whole file, because holes
+/// are stored as chunks of zeros instead of missing chunks.
+fn check_file_chunks(path: &Path, size: u64, chunks: &[NodeChunk]) -> Result<()> {
+    if size == 0 {
+        ensure!(
+            chunks.iter().all(|c| c.inner.uncompressed_size() == 0),
+            "empty file {:?} has {} data chunks",
+            path,
+            chunks.len()
+        );
+        return Ok(());
+    }
+
+    let mut total = 0u64;
+    for chunk in chunks {
+        let c = &chunk.inner;
+        ensure!(
+            c.uncompressed_size() > 0,
+            "chunk {} of {:?} in blob {} has zero length",
+            c.index(),
+            path,
+            c.blob_index()
+        );
+        total += c.uncompressed_size() as u64;
+    }
+    ensure!(
+        total == size,
+        "data chunks of {:?} cover 0x{:x} bytes, but file size is 0x{:x}",
+        path,
+        total,
+        size
+    );
+
+    Ok(())
+}
+
+/// Check prefetch ranges of a data blob, which must be non-empty, sorted, disjoint and within the
+/// compressed blob.
+///
+/// Ranges inherited from parent layers may cover chunks of files removed by upper layers, so they
+/// are not required to match chunks referenced by the filesystem.
+fn check_prefetch_ranges(blob: &BlobInfo) -> Result<()> {
+    let mut prev_end = 0u64;
+    for (idx, (offset, size)) in blob.prefetch_ranges().iter().enumerate() {
+        let end = offset
+            .checked_add(*size)
+            .filter(|end| *size > 0 && *end <= blob.compressed_size())
+            .ok_or_else(|| {
+                anyhow!(
+                    "prefetch range {} 0x{:x}/0x{:x} of blob {} is invalid for compressed blob size 0x{:x}",
+                    idx,
+                    offset,
+                    size,
+                    blob.blob_id(),
+                    blob.compressed_size()
+                )
+            })?;
+        ensure!(
+            *offset >= prev_end,
+            "prefetch range {} 0x{:x}/0x{:x} of blob {} is unsorted or overlaps with previous range",
+            idx,
+            offset,
+            size,
+            blob.blob_id()
+        );
+        prev_end = end;
+    }
+
+    Ok(())
+}
+
+/// Logical content of an inode, independent of the RAFS on-disk format.
+#[derive(Debug, PartialEq, Eq)]
+struct InodeEntry {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    /// Size of non-directory inodes, directory size depends on the on-disk format.
+    size: u64,
+    /// Modification time, `None` if the inode doesn't record it, such as RAFS v6 compact inodes.
+    mtime: Option<(u64, u32)>,
+    rdev: u32,
+    nlink: u32,
+    symlink: Option<OsString>,
+    xattrs: RafsXAttrs,
+    /// Tuples of (file offset, uncompressed size, digest) of data chunks.
+    chunks: Vec<(u64, u32, RafsDigest)>,
+}
+
+impl InodeEntry {
+    fn diff(&self, other: &InodeEntry) -> Vec<&'static str> {
+        let mut fields = Vec::new();
+        if self.mode != other.mode {
+            fields.push("mode");
+        }
+        if self.uid != other.uid || self.gid != other.gid {
+            fields.push("owner");
+        }
+        if self.size != other.size {
+            fields.push("size");
+        }
+        if let (Some(m1), Some(m2)) = (self.mtime, other.mtime) {
+            if m1 != m2 {
+                fields.push("mtime");
+            }
+        }
+        if self.rdev != other.rdev {
+            fields.push("rdev");
+        }
+        if self.nlink != other.nlink {
+            fields.push("nlink");
+        }
+        if self.symlink != other.symlink {
+            fields.push("symlink");
+        }
+        if self.xattrs != other.xattrs {
+            fields.push("xattrs");
+        }
+        if self.chunks != other.chunks {
+            fields.push("chunks");
+        }
+        fields
+    }
+}
+
+/// Information about a data chunk, which should be consistent among chunks with the same digest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ChunkEntry {
+    compressed_offset: u64,
+    compressed_size: u32,
+    uncompressed_offset: u64,
+    uncompressed_size: u32,
+    compressed: bool,
+    compressor: Option<compress::Algorithm>,
+    encrypted: bool,
+    batch: bool,
+}
+
+impl ChunkEntry {
+    /// Compressed data of chunks in the same blob should be identical if they have the same digest.
+    fn same_compressed_data(&self, other: &ChunkEntry) -> bool {
+        self.compressed_size == other.compressed_size
+            && self.compressed == other.compressed
+            && self.compressor == other.compressor
+            && self.encrypted == other.encrypted
+            && self.batch == other.batch
+    }
+}
+
+/// Detector for chunks with identical digests but inconsistent information.
+#[derive(Default)]
+struct ChunkDigestChecker {
+    /// Chunks indexed by digest and then by tuple of (blob index, chunk index).
+    chunks: HashMap<RafsDigest, BTreeMap<(u32, u32), ChunkEntry>>,
+    /// Chunks referenced by different files with inconsistent information.
+    inconsistent: BTreeSet<(u32, u32)>,
+}
+
+impl ChunkDigestChecker {
+    fn add(&mut self, digest: RafsDigest, key: (u32, u32), entry: ChunkEntry) {
+        let chunks = self.chunks.entry(digest).or_default();
+        match chunks.get(&key) {
+            None => {
+                chunks.insert(key, entry);
+            }
+            Some(e) if *e != entry => {
+                self.inconsistent.insert(key);
+            }
+            Some(_) => {}
+        }
+    }
+
+    /// Return descriptions of found inconsistencies, together with the affected chunks.
+    fn conflicts(&self) -> Vec<(String, Vec<(u32, u32)>)> {
+        let mut conflicts = Vec::new();
+
+        for (blob_index, index) in self.inconsistent.iter() {
+            conflicts.push((
+                format!(
+                    "chunk {} of blob {} is referenced with inconsistent information",
+                    index, blob_index
+                ),
+                vec![(*blob_index, *index)],
+            ));
+        }
+
+        for (digest, chunks) in self.chunks.iter().filter(|(_, c)| c.len() > 1) {
+            let (_, first) = chunks.iter().next().unwrap();
+            if chunks
+                .values()
+                .any(|c| c.uncompressed_size != first.uncompressed_size)
+            {
+                let sizes = chunks
+                    .iter()
+                    .map(|((b, i), c)| {
+                        format!("blob {} chunk {} 0x{:x}", b, i, c.uncompressed_size)
+                    })
+                    .collect::<Vec<_>>();
+                conflicts.push((
+                    format!(
+                        "chunk digest {} has different uncompressed sizes: {}",
+                        digest,
+                        sizes.join(", ")
+                    ),
+                    chunks.keys().copied().collect(),
+                ));
+                continue;
+            }
+
+            // Chunks in different blobs may be compressed with different algorithms or levels.
+            let mut blobs: BTreeMap<u32, Vec<(u32, &ChunkEntry)>> = BTreeMap::new();
+            for ((b, i), c) in chunks.iter() {
+                blobs.entry(*b).or_default().push((*i, c));
+            }
+            for (blob_index, entries) in blobs.iter() {
+                let first = entries[0].1;
+                if entries.iter().any(|(_, c)| !c.same_compressed_data(first)) {
+                    let indexes = entries
+                        .iter()
+                        .map(|(i, _)| i.to_string())
+                        .collect::<Vec<_>>();
+                    conflicts.push((
+                        format!(
+                            "chunk digest {} has inconsistent compressed size or flags in blob {}: chunks {}",
+                            digest,
+                            blob_index,
+                            indexes.join(", ")
+                        ),
+                        entries.iter().map(|(i, _)| (*blob_index, *i)).collect(),
+                    ));
+                }
+            }
+        }
+
+        conflicts.sort();
+        conflicts
+    }
+}
+
+/// Maximum number of symlinks to follow when resolving a path, same as Linux `MAXSYMLINKS`.
+const MAX_SYMLINK_FOLLOWS: u32 = 40;
+
+enum PathEntry {
+    Dir,
+    Symlink(PathBuf),
+    Other,
+}
+
+/// Resolve symlink `link` with `target` within the filesystem namespace represented by
+/// `entries`, and return the resolved path or reason of failure.
+fn resolve_symlink(
+    entries: &HashMap<PathBuf, PathEntry>,
+    link: &Path,
+    target: &Path,
+) -> std::result::Result<PathBuf, String> {
+    let mut cur = link.parent().unwrap_or(Path::new("/")).to_path_buf();
+    let mut components = target
+        .components()
+        .map(|c| c.as_os_str().to_os_string())
+        .collect::<VecDeque<_>>();
+    let mut follows = 0;
+
+    while let Some(comp) = components.pop_front() {
+        match Path::new(&comp).components().next() {
+            Some(Component::RootDir) => cur = PathBuf::from("/"),
+            Some(Component::ParentDir) => {
+                if !cur.pop() {
+                    return Err("points out of the filesystem root".to_string());
+                }
+            }
+            Some(Component::Normal(name)) => {
+                let next = cur.join(name);
+                match entries.get(&next) {
+                    None => return Err(format!("{:?} doesn't exist", next)),
+                    Some(PathEntry::Dir) => cur = next,
+                    Some(PathEntry::Symlink(t)) => {
+                        follows += 1;
+                        if follows > MAX_SYMLINK_FOLLOWS {
+                            return Err("has too many levels of symbolic links".to_string());
+                        }
+                        for c in t.components().rev() {
+                            components.push_front(c.as_os_str().to_os_string());
+                        }
+                    }
+                    Some(PathEntry::Other) => {
+                        if !components.is_empty() {
+                            return Err(format!("{:?} is not a directory", next));
+                        }
+                        cur = next;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(cur)
+}
 
 pub struct Validator {
     sb: RafsSuper,
+    reader: RafsIoReader,
 }
 
 impl Validator {
     pub fn new(bootstrap_path: &Path, config: Arc<ConfigV2>) -> Result<Self> {
-        let (sb, _) = RafsSuper::load_from_file(bootstrap_path, config, false)?;
+        let (sb, reader) = RafsSuper::load_from_file(bootstrap_path, config, false)?;
 
-        Ok(Self { sb })
+        Ok(Self { sb, reader })
     }
 
     pub fn check(
@@ -31,6 +347,12 @@ impl Validator {
     ) -> Result<(Vec<Arc<BlobInfo>>, compress::Algorithm, RafsVersion)> {
         let err = "failed to load bootstrap for validator";
         let tree = Tree::from_bootstrap(&self.sb, &mut ()).context(err)?;
+        let blobs = self.sb.superblock.get_blob_infos();
+        self.check_blob_alignment(&blobs);
+        self.sb
+            .get_annotations(&mut self.reader)
+            .context("invalid annotations")?;
+        let is_v6 = self.sb.meta.is_v6();
 
         let pre = &mut |t: &Tree| -> Result<()> {
             let node = t.borrow_mut_node();
@@ -40,16 +362,951 @@ impl Validator {
                     println!("\t chunk: {}", chunk);
                 }
             }
+            // Extended attributes, including those in the shared xattr area, have been loaded
+            // when building the tree, make sure they are within limits of the on-disk format.
+            if is_v6 {
+                node.info
+                    .xattrs
+                    .validate_v6()
+                    .with_context(|| format!("invalid xattrs of {:?}", node.target()))?;
+            }
+            if node.is_reg() {
+                check_file_chunks(node.target(), node.inode.size(), &node.chunks)?;
+            }
+            for chunk in &node.chunks {
+                let blob_index = chunk.inner.blob_index();
+                let aligned = blobs
+                    .get(blob_index as usize)
+                    .map(|b| b.has_feature(BlobFeatures::ALIGNED))
+                    .unwrap_or_default();
+                if aligned && chunk.inner.uncompressed_offset() & (ALIGNMENT_4K - 1) != 0 {
+                    bail!(
+                        "chunk {} of {:?} in 4K aligned blob {} has unaligned uncompressed offset 0x{:x}",
+                        chunk.inner.index(),
+                        node.target(),
+                        blob_index,
+                        chunk.inner.uncompressed_offset()
+                    );
+                }
+            }
             Ok(())
         };
         tree.walk_dfs_pre(pre)?;
+        self.check_prefetch_table(&tree)?;
+        for blob in blobs.iter() {
+            check_prefetch_ranges(blob)?;
+        }
         let compressor = self.sb.meta.get_compressor();
         let rafs_version: RafsVersion = self.sb.meta.version.try_into().unwrap();
 
-        Ok((
-            self.sb.superblock.get_blob_infos(),
-            compressor,
-            rafs_version,
-        ))
+        Ok((blobs, compressor, rafs_version))
+    }
+
+    /// Entries of the prefetch table must reference existing inodes without duplication.
+    fn check_prefetch_table(&mut self, tree: &Tree) -> Result<()> {
+        let inos = self
+            .sb
+            .get_prefetched_inos(&mut self.reader)
+            .context("failed to load prefetch table")?;
+        if inos.is_empty() {
+            return Ok(());
+        }
+
+        let mut ino_paths = HashMap::new();
+        tree.walk_dfs_pre(&mut |t| {
+            let node = t.borrow_mut_node();
+            ino_paths
+                .entry(node.info.src_ino)
+                .or_insert_with(|| node.target().clone());
+            Ok(())
+        })?;
+
+        let mut entries = HashSet::new();
+        for ino in inos {
+            let path = ino_paths
+                .get(&(ino as u64))
+                .ok_or_else(|| anyhow!("prefetch table references nonexistent inode {}", ino))?;
+            if !entries.insert(ino) {
+                bail!(
+                    "prefetch table has duplicated entries for inode {} {:?}",
+                    ino,
+                    path
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compare logical content of two RAFS filesystems, such as RAFS v5 and v6 filesystems built
+    /// from the same source, and return descriptions of found differences.
+    ///
+    /// Paths, inode metadata, extended attributes, hardlink groups and chunk digests are compared,
+    /// while format specific information such as inode numbers, directory sizes and chunk
+    /// locations in data blobs are ignored.
+    pub fn compare(&self, other: &Validator) -> Result<Vec<String>> {
+        let (entries1, hardlinks1) = Self::collect_entries(&self.sb)?;
+        let (entries2, hardlinks2) = Self::collect_entries(&other.sb)?;
+        let mut diffs = Vec::new();
+
+        for (path, entry1) in entries1.iter() {
+            match entries2.get(path) {
+                None => diffs.push(format!("{:?} only exists in the first filesystem", path)),
+                Some(entry2) => {
+                    let fields = entry1.diff(entry2);
+                    if !fields.is_empty() {
+                        diffs.push(format!("{:?} has different {}", path, fields.join(", ")));
+                    }
+                }
+            }
+        }
+        for path in entries2.keys() {
+            if !entries1.contains_key(path) {
+                diffs.push(format!("{:?} only exists in the second filesystem", path));
+            }
+        }
+        if hardlinks1 != hardlinks2 {
+            diffs.push("hardlink groups are different".to_string());
+        }
+
+        Ok(diffs)
+    }
+
+    /// Find chunks with identical digests but inconsistent sizes or flags across data blobs,
+    /// which indicates digest collisions or builder bugs, and return descriptions of found
+    /// inconsistencies with affected files.
+    ///
+    /// Only chunk information recorded in the bootstrap is checked, data blobs are not accessed.
+    pub fn check_duplicate_chunks(&self) -> Result<Vec<String>> {
+        let tree = Tree::from_bootstrap(&self.sb, &mut ()).context("failed to load bootstrap")?;
+        let mut checker = ChunkDigestChecker::default();
+        tree.walk_dfs_pre(&mut |t| {
+            let node = t.borrow_mut_node();
+            for chunk in node.chunks.iter() {
+                let c = &chunk.inner;
+                let entry = ChunkEntry {
+                    compressed_offset: c.compressed_offset(),
+                    compressed_size: c.compressed_size(),
+                    uncompressed_offset: c.uncompressed_offset(),
+                    uncompressed_size: c.uncompressed_size(),
+                    compressed: c.is_compressed(),
+                    compressor: c.compressor(),
+                    encrypted: c.is_encrypted(),
+                    batch: c.is_batch(),
+                };
+                checker.add(*c.id(), (c.blob_index(), c.index()), entry);
+            }
+            Ok(())
+        })?;
+
+        let conflicts = checker.conflicts();
+        if conflicts.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut files: HashMap<(u32, u32), BTreeSet<PathBuf>> = conflicts
+            .iter()
+            .flat_map(|(_, keys)| keys.iter().map(|k| (*k, BTreeSet::new())))
+            .collect();
+        tree.walk_dfs_pre(&mut |t| {
+            let node = t.borrow_mut_node();
+            for chunk in node.chunks.iter() {
+                let key = (chunk.inner.blob_index(), chunk.inner.index());
+                if let Some(paths) = files.get_mut(&key) {
+                    paths.insert(node.target().clone());
+                }
+            }
+            Ok(())
+        })?;
+
+        Ok(conflicts
+            .into_iter()
+            .map(|(msg, keys)| {
+                let paths = keys
+                    .iter()
+                    .flat_map(|k| files[k].iter())
+                    .collect::<BTreeSet<_>>();
+                let paths = paths.iter().map(|p| format!("{:?}", p)).collect::<Vec<_>>();
+                format!("{}, affected files: {}", msg, paths.join(", "))
+            })
+            .collect())
+    }
+
+    /// Collect unique data chunks of the filesystem, sorted by blob index and compressed offset.
+    ///
+    /// Chunks are sorted so data blobs are read sequentially, and chunks are handled in the same
+    /// order across runs to resume data verification from a checkpoint.
+    pub fn data_chunks(&self) -> Result<Vec<DataChunk>> {
+        let tree = Tree::from_bootstrap(&self.sb, &mut ()).context("failed to load bootstrap")?;
+        let mut chunks = BTreeMap::new();
+        tree.walk_dfs_pre(&mut |t| {
+            let node = t.borrow_mut_node();
+            for chunk in node.chunks.iter() {
+                let c = &chunk.inner;
+                chunks
+                    .entry((c.blob_index(), c.compressed_offset(), c.index()))
+                    .or_insert_with(|| DataChunk {
+                        chunk: c.clone(),
+                        path: node.target().clone(),
+                    });
+            }
+            Ok(())
+        })?;
+        Ok(chunks.into_values().collect())
+    }
+
+    /// Get configuration of the RAFS filesystem, to load compatible chunk dictionaries.
+    pub fn rafs_config(&self) -> RafsSuperConfig {
+        self.sb.meta.get_config()
+    }
+
+    /// Verify that chunks deduplicated against chunk dictionary `dict` resolve to chunks with
+    /// matching digest and size in the dictionary, and return descriptions of unresolved chunks
+    /// with affected files.
+    ///
+    /// A chunk is considered deduplicated if it refers to a data blob of the dictionary, and it
+    /// must be found in the same data blob at the same compressed offset and size in the
+    /// dictionary. So a stale dictionary, whose data blobs have been rebuilt or its chunks have
+    /// been changed, is detected before the image ships. Data blobs are not accessed.
+    pub fn check_chunk_dict(&self, dict: &dyn ChunkDict) -> Result<Vec<String>> {
+        if dict.digester() != self.sb.meta.get_digester() {
+            bail!(
+                "chunk dictionary uses digest algorithm {}, but the filesystem uses {}",
+                dict.digester(),
+                self.sb.meta.get_digester()
+            );
+        }
+        let dict_blobs = dict
+            .get_blobs()
+            .iter()
+            .map(|b| b.blob_id())
+            .collect::<HashSet<_>>();
+        let blobs = self.sb.superblock.get_blob_infos();
+        let tree = Tree::from_bootstrap(&self.sb, &mut ()).context("failed to load bootstrap")?;
+        let mut issues: BTreeMap<String, BTreeSet<PathBuf>> = BTreeMap::new();
+        tree.walk_dfs_pre(&mut |t| {
+            let node = t.borrow_mut_node();
+            for chunk in node.chunks.iter() {
+                let c = &chunk.inner;
+                let blob_id = match blobs.get(c.blob_index() as usize) {
+                    Some(blob) => blob.blob_id(),
+                    None => bail!(
+                        "chunk {} of {:?} refers to invalid blob index {}",
+                        c.id(),
+                        node.target(),
+                        c.blob_index()
+                    ),
+                };
+                if !dict_blobs.contains(&blob_id) {
+                    continue;
+                }
+                let d = match dict.get_chunk(c.id(), c.uncompressed_size()) {
+                    None => None,
+                    Some(d) => {
+                        let dict_blob_id = dict
+                            .get_blob_by_inner_idx(d.blob_index())
+                            .map(|b| b.blob_id())
+                            .unwrap_or_default();
+                        Some((d, dict_blob_id))
+                    }
+                };
+                let msg = match d {
+                    None => format!(
+                        "chunk {} with uncompressed size 0x{:x} in blob {} is not found in chunk dictionary",
+                        c.id(),
+                        c.uncompressed_size(),
+                        blob_id
+                    ),
+                    Some((_, dict_blob_id)) if dict_blob_id != blob_id => format!(
+                        "chunk {} is in blob {}, but in blob {} in chunk dictionary",
+                        c.id(),
+                        blob_id,
+                        dict_blob_id
+                    ),
+                    Some((d, _)) if d.compressed_offset() != c.compressed_offset() => format!(
+                        "chunk {} in blob {} has compressed offset 0x{:x}, but 0x{:x} in chunk dictionary",
+                        c.id(),
+                        blob_id,
+                        c.compressed_offset(),
+                        d.compressed_offset()
+                    ),
+                    Some((d, _)) if d.compressed_size() != c.compressed_size() => format!(
+                        "chunk {} in blob {} has compressed size 0x{:x}, but 0x{:x} in chunk dictionary",
+                        c.id(),
+                        blob_id,
+                        c.compressed_size(),
+                        d.compressed_size()
+                    ),
+                    Some(_) => continue,
+                };
+                issues
+                    .entry(msg)
+                    .or_default()
+                    .insert(node.target().clone());
+            }
+            Ok(())
+        })?;
+
+        Ok(issues
+            .into_iter()
+            .map(|(msg, paths)| {
+                let paths = paths.iter().map(|p| format!("{:?}", p)).collect::<Vec<_>>();
+                format!("{}, affected files: {}", msg, paths.join(", "))
+            })
+            .collect())
+    }
+
+    /// Resolve all symlinks within the filesystem namespace, and return descriptions of dangling
+    /// symlinks and symlinks pointing out of the filesystem root.
+    ///
+    /// Absolute symlink targets are resolved against the filesystem root instead of the host
+    /// root, the same way as in a container.
+    pub fn check_symlinks(&self) -> Result<Vec<String>> {
+        let tree = Tree::from_bootstrap(&self.sb, &mut ()).context("failed to load bootstrap")?;
+        let mut entries = HashMap::new();
+        tree.walk_dfs_pre(&mut |t| {
+            let node = t.borrow_mut_node();
+            let entry = if node.is_dir() {
+                PathEntry::Dir
+            } else if let Some(symlink) = node.info.symlink.as_ref() {
+                PathEntry::Symlink(PathBuf::from(symlink))
+            } else {
+                PathEntry::Other
+            };
+            entries.insert(node.target().clone(), entry);
+            Ok(())
+        })?;
+
+        let mut issues = Vec::new();
+        let mut links = entries
+            .iter()
+            .filter_map(|(path, entry)| match entry {
+                PathEntry::Symlink(target) => Some((path, target)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        links.sort();
+        for (path, target) in links {
+            if let Err(reason) = resolve_symlink(&entries, path, target) {
+                issues.push(format!("symlink {:?} -> {:?} {}", path, target, reason));
+            }
+        }
+
+        Ok(issues)
+    }
+
+    /// Get extra information of data blobs from RAFS v6 device table, indexed by blob id.
+    /// Collect features the image depends on at runtime.
+    pub fn capabilities(&self) -> ImageCapabilities {
+        ImageCapabilities::new(&self.sb)
+    }
+
+    pub fn blob_extra_infos(&self) -> Result<HashMap<String, RafsBlobExtraInfo>> {
+        self.sb
+            .superblock
+            .get_blob_extra_infos()
+            .context("failed to get blob extra information")
+    }
+
+    fn collect_entries(
+        sb: &RafsSuper,
+    ) -> Result<(BTreeMap<PathBuf, InodeEntry>, Vec<Vec<PathBuf>>)> {
+        let tree = Tree::from_bootstrap(sb, &mut ()).context("failed to load bootstrap")?;
+        let mut entries = BTreeMap::new();
+        let mut hardlinks: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+
+        tree.walk_dfs_pre(&mut |t| {
+            let node = t.borrow_mut_node();
+            let inode = &node.inode;
+            let (mtime, mtime_nsec) = (inode.mtime(), inode.mtime_nsec());
+            let entry = InodeEntry {
+                mode: inode.mode(),
+                uid: inode.uid(),
+                gid: inode.gid(),
+                size: if node.is_dir() { 0 } else { inode.size() },
+                mtime: if mtime == 0 && mtime_nsec == 0 {
+                    None
+                } else {
+                    Some((mtime, mtime_nsec))
+                },
+                rdev: if inode.is_chrdev() || inode.is_blkdev() {
+                    inode.rdev()
+                } else {
+                    0
+                },
+                nlink: if node.is_dir() { 0 } else { inode.nlink() },
+                symlink: node.info.symlink.clone(),
+                xattrs: node.info.xattrs.clone(),
+                chunks: node
+                    .chunks
+                    .iter()
+                    .map(|c| {
+                        (
+                            c.inner.file_offset(),
+                            c.inner.uncompressed_size(),
+                            *c.inner.id(),
+                        )
+                    })
+                    .collect(),
+            };
+            if !node.is_dir() && inode.nlink() > 1 {
+                hardlinks
+                    .entry(node.info.src_ino)
+                    .or_default()
+                    .push(node.target().clone());
+            }
+            entries.insert(node.target().clone(), entry);
+            Ok(())
+        })?;
+
+        let mut hardlinks = hardlinks
+            .into_values()
+            .map(|mut paths| {
+                paths.sort();
+                paths
+            })
+            .collect::<Vec<_>>();
+        hardlinks.sort();
+
+        Ok((entries, hardlinks))
+    }
+
+    /// RAFS v6 images generally have 4K aligned uncompressed chunks, except for tarfs mode.
+    ///
+    /// Blobs generated by other tools or old versions may not declare the feature consistently,
+    /// which is harmless as long as chunks of blobs declared as aligned are really aligned, so
+    /// only warn about it. Offsets of chunks are verified when walking the filesystem tree.
+    fn check_blob_alignment(&self, blobs: &[Arc<BlobInfo>]) {
+        if !self.sb.meta.is_v6() {
+            return;
+        }
+        let tarfs_mode = self.sb.meta.flags.contains(RafsSuperFlags::TARTFS_MODE);
+        for blob in blobs {
+            let aligned = blob.has_feature(BlobFeatures::ALIGNED);
+            if aligned == tarfs_mode {
+                warn!(
+                    "blob {} has unexpected 4K aligned feature: aligned {}, tarfs mode {}",
+                    blob.blob_id(),
+                    aligned,
+                    tarfs_mode
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nydus_builder::{
+        ArtifactStorage, BlobManager, Bootstrap, BootstrapManager, BuildContext, Builder,
+        ChunkSource, ConversionType, DirectoryBuilder, Features, HashChunkDict, Prefetch,
+        PrefetchPolicy, WhiteoutSpec,
+    };
+    use nydus_rafs::metadata::chunk::ChunkWrapper;
+    use nydus_utils::digest;
+    use std::fs;
+    use vmm_sys_util::tempdir::TempDir;
+
+    /// Options to build a test bootstrap from a source directory.
+    struct BuildOptions {
+        version: RafsVersion,
+        chunk_dict: Option<Arc<dyn ChunkDict>>,
+        prefetch: Prefetch,
+        annotations: Vec<(&'static str, &'static str)>,
+    }
+
+    impl BuildOptions {
+        fn new(version: RafsVersion) -> Self {
+            Self {
+                version,
+                chunk_dict: None,
+                prefetch: Prefetch::default(),
+                annotations: Vec::new(),
+            }
+        }
+
+        /// Build the bootstrap into `work_dir` and return its path.
+        fn build(self, source: &Path, work_dir: &Path) -> PathBuf {
+            let bootstrap = work_dir.join(format!("bootstrap-{}", u32::from(self.version)));
+            let mut ctx = BuildContext::new(
+                String::new(),
+                false,
+                0,
+                compress::Algorithm::Zstd,
+                digest::Algorithm::Sha256,
+                true,
+                WhiteoutSpec::Oci,
+                ConversionType::DirectoryToRafs,
+                source.to_path_buf(),
+                self.prefetch,
+                Some(ArtifactStorage::FileDir(work_dir.to_path_buf())),
+                false,
+                Features::new(),
+                false,
+            );
+            ctx.set_fs_version(self.version);
+            ctx.set_chunk_size(0x1000);
+            for (key, value) in self.annotations {
+                ctx.add_annotation(key, value).unwrap();
+            }
+            let mut bootstrap_mgr =
+                BootstrapManager::new(Some(ArtifactStorage::SingleFile(bootstrap.clone())), None);
+            let mut blob_mgr = BlobManager::new(digest::Algorithm::Sha256);
+            if let Some(dict) = self.chunk_dict {
+                blob_mgr.set_chunk_dict(dict);
+            }
+            DirectoryBuilder::new()
+                .build(&mut ctx, &mut bootstrap_mgr, &mut blob_mgr)
+                .unwrap();
+            bootstrap
+        }
+
+        /// Build the bootstrap into `work_dir` and return a validator for it.
+        fn validator(self, source: &Path, work_dir: &Path) -> Validator {
+            let bootstrap = self.build(source, work_dir);
+            Validator::new(&bootstrap, Arc::new(ConfigV2::default())).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_bootstrap_annotations() {
+        let source = TempDir::new().unwrap();
+        let work_dir = TempDir::new().unwrap();
+        fs::write(source.as_path().join("file"), vec![0x5au8; 0x2800]).unwrap();
+        let config = Arc::new(ConfigV2::default());
+
+        let bootstrap = BuildOptions {
+            annotations: vec![("org.example.label", "value"), ("org.example.empty", "")],
+            ..BuildOptions::new(RafsVersion::V6)
+        }
+        .build(source.as_path(), work_dir.as_path());
+        let mut validator = Validator::new(&bootstrap, config.clone()).unwrap();
+        validator.check(false).unwrap();
+        let (rs, mut reader) =
+            RafsSuper::load_from_file(&bootstrap, config.clone(), false).unwrap();
+        let annotations = rs.get_annotations(&mut reader).unwrap();
+        assert_eq!(annotations.entries().len(), 2);
+        assert_eq!(annotations.get("org.example.label"), Some("value"));
+        assert_eq!(annotations.get("org.example.empty"), Some(""));
+
+        let bootstrap =
+            BuildOptions::new(RafsVersion::V6).build(source.as_path(), work_dir.as_path());
+        let (rs, mut reader) = RafsSuper::load_from_file(&bootstrap, config, false).unwrap();
+        assert!(rs.get_annotations(&mut reader).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_compare_v5_v6() {
+        let source = TempDir::new().unwrap();
+        let work_dir = TempDir::new().unwrap();
+        let root = source.as_path();
+        fs::create_dir(root.join("dir")).unwrap();
+        fs::write(root.join("dir/file"), vec![0x5au8; 0x2800]).unwrap();
+        fs::write(root.join("empty"), b"").unwrap();
+        fs::hard_link(root.join("dir/file"), root.join("hardlink")).unwrap();
+        std::os::unix::fs::symlink("dir/file", root.join("symlink")).unwrap();
+
+        let v5 = BuildOptions::new(RafsVersion::V5).validator(root, work_dir.as_path());
+        let v6 = BuildOptions::new(RafsVersion::V6).validator(root, work_dir.as_path());
+        assert!(v5.compare(&v6).unwrap().is_empty());
+        assert!(v6.compare(&v5).unwrap().is_empty());
+
+        fs::write(root.join("dir/file"), vec![0xa5u8; 0x2800]).unwrap();
+        fs::remove_file(root.join("empty")).unwrap();
+        let v6 = BuildOptions::new(RafsVersion::V6).validator(root, work_dir.as_path());
+        let diffs = v5.compare(&v6).unwrap();
+        assert!(diffs
+            .iter()
+            .any(|d| d.contains("dir/file") && d.contains("chunks")));
+        assert!(diffs.iter().any(|d| d.contains("empty")));
+    }
+
+    #[test]
+    fn test_check_big_xattrs() {
+        let source = TempDir::new().unwrap();
+        let work_dir = TempDir::new().unwrap();
+        let root = source.as_path();
+        fs::write(root.join("file1"), vec![0x5au8; 0x2800]).unwrap();
+        fs::write(root.join("file2"), vec![0x5au8; 0x2800]).unwrap();
+        // Skip if the filesystem doesn't support user xattrs.
+        if xattr::set(root.join("file1"), "user.big", &[0xa5u8; 0x2000]).is_err() {
+            return;
+        }
+        xattr::set(root.join("file2"), "user.big", &[0xa5u8; 0x2000]).unwrap();
+        xattr::set(root.join("file2"), "user.small", b"small").unwrap();
+
+        let v5 = BuildOptions::new(RafsVersion::V5).validator(root, work_dir.as_path());
+        let mut v6 = BuildOptions::new(RafsVersion::V6).validator(root, work_dir.as_path());
+        assert_ne!(v6.sb.meta.xattr_blkaddr, 0);
+        v6.check(false).unwrap();
+        assert!(v5.compare(&v6).unwrap().is_empty());
+        assert!(v6.compare(&v5).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_check_file_chunks() {
+        let source = TempDir::new().unwrap();
+        let work_dir = TempDir::new().unwrap();
+        let root = source.as_path();
+        fs::write(root.join("empty"), b"").unwrap();
+        let file = fs::File::create(root.join("hole")).unwrap();
+        file.set_len(0x2800).unwrap();
+
+        for version in [RafsVersion::V5, RafsVersion::V6] {
+            let mut v = BuildOptions::new(version).validator(root, work_dir.as_path());
+            v.check(false).unwrap();
+        }
+
+        let mut chunk = ChunkWrapper::new(RafsVersion::V6);
+        chunk.set_uncompressed_size(0x1000);
+        let chunks = vec![NodeChunk {
+            source: ChunkSource::Build,
+            inner: Arc::new(chunk.clone()),
+        }];
+        let path = Path::new("/file");
+        check_file_chunks(path, 0, &[]).unwrap();
+        check_file_chunks(path, 0x1000, &chunks).unwrap();
+        assert!(check_file_chunks(path, 0, &chunks).is_err());
+        assert!(check_file_chunks(path, 0x1800, &chunks).is_err());
+        assert!(check_file_chunks(path, 0x1000, &[]).is_err());
+        chunk.set_uncompressed_size(0);
+        let chunks = vec![NodeChunk {
+            source: ChunkSource::Build,
+            inner: Arc::new(chunk),
+        }];
+        assert!(check_file_chunks(path, 0x1000, &chunks).is_err());
+        // Zero-length chunks of empty files generated by old builders are accepted.
+        check_file_chunks(path, 0, &chunks).unwrap();
+    }
+
+    #[test]
+    fn test_check_prefetch_ranges() {
+        let source = TempDir::new().unwrap();
+        let work_dir = TempDir::new().unwrap();
+        let root = source.as_path();
+        fs::write(root.join("a"), vec![0x5au8; 0x2000]).unwrap();
+        fs::write(root.join("b"), vec![0xa5u8; 0x1000]).unwrap();
+        // Chunks of `c` are deduplicated against `a`, so no extra range is needed for it.
+        fs::write(root.join("c"), vec![0x5au8; 0x1000]).unwrap();
+
+        let mut prefetch = Prefetch::default();
+        prefetch.policy = PrefetchPolicy::Blob;
+        prefetch.add_patterns(vec![PathBuf::from("/a"), PathBuf::from("/c")]);
+        let path = BuildOptions {
+            prefetch,
+            ..BuildOptions::new(RafsVersion::V6)
+        }
+        .build(root, work_dir.as_path());
+        let mut v6 = Validator::new(&path, Arc::new(ConfigV2::default())).unwrap();
+        let (blobs, _, _) = v6.check(false).unwrap();
+        assert_eq!(blobs.len(), 1);
+        let ranges = blobs[0].prefetch_ranges();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].0, 0);
+
+        let mut blob = blobs[0].as_ref().clone();
+        blob.set_prefetch_ranges(vec![(0, 0x100), (0x100, 0x100)]);
+        check_prefetch_ranges(&blob).unwrap();
+        blob.set_prefetch_ranges(vec![(0x100, 0x100), (0, 0x100)]);
+        assert!(check_prefetch_ranges(&blob).is_err());
+        blob.set_prefetch_ranges(vec![(0, 0x100), (0x80, 0x100)]);
+        assert!(check_prefetch_ranges(&blob).is_err());
+        blob.set_prefetch_ranges(vec![(0, 0)]);
+        assert!(check_prefetch_ranges(&blob).is_err());
+        blob.set_prefetch_ranges(vec![(0, blob.compressed_size() + 1)]);
+        assert!(check_prefetch_ranges(&blob).is_err());
+    }
+
+    #[test]
+    fn test_check_duplicate_chunks() {
+        let source = TempDir::new().unwrap();
+        let work_dir = TempDir::new().unwrap();
+        let root = source.as_path();
+        fs::write(root.join("file1"), vec![0x5au8; 0x2800]).unwrap();
+        fs::write(root.join("file2"), vec![0x5au8; 0x2800]).unwrap();
+
+        let v5 = BuildOptions::new(RafsVersion::V5).validator(root, work_dir.as_path());
+        assert!(v5.check_duplicate_chunks().unwrap().is_empty());
+        let v6 = BuildOptions::new(RafsVersion::V6).validator(root, work_dir.as_path());
+        assert!(v6.check_duplicate_chunks().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_check_data() {
+        use crate::data_checker::DataChecker;
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        let source = TempDir::new().unwrap();
+        let work_dir = TempDir::new().unwrap();
+        let root = source.as_path();
+        let data = |seed: usize| {
+            (0..0x2000usize)
+                .map(|i| (i * seed % 251) as u8)
+                .collect::<Vec<_>>()
+        };
+        fs::write(root.join("file1"), data(7)).unwrap();
+        fs::write(root.join("file2"), data(13)).unwrap();
+
+        let bootstrap = BuildOptions::new(RafsVersion::V6).build(root, work_dir.as_path());
+        let blob_dir = work_dir.as_path().to_str().unwrap();
+        let config = Arc::new(ConfigV2::new_localfs("", blob_dir).unwrap());
+        let mut validator = Validator::new(&bootstrap, config.clone()).unwrap();
+        let (blobs, _, _) = validator.check(false).unwrap();
+        assert_eq!(blobs.len(), 1);
+        let chunks = validator.data_chunks().unwrap();
+        assert_eq!(chunks.len(), 4);
+
+        let mut checker = DataChecker::new(config.clone(), blobs.clone());
+        let progress = checker.check("digest", &chunks).unwrap();
+        assert_eq!(progress.chunks, 4);
+        assert_eq!(progress.skipped, 0);
+        assert!(progress.issues.is_empty());
+
+        // Corrupt the first byte of the last chunk of "/file2".
+        let corrupted = chunks
+            .iter()
+            .rfind(|c| c.path == Path::new("/file2"))
+            .unwrap();
+        let mut blob = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(work_dir.as_path().join(blobs[0].blob_id()))
+            .unwrap();
+        let mut byte = [0u8; 1];
+        let offset = SeekFrom::Start(corrupted.chunk.compressed_offset());
+        blob.seek(offset).unwrap();
+        blob.read_exact(&mut byte).unwrap();
+        blob.seek(offset).unwrap();
+        blob.write_all(&[!byte[0]]).unwrap();
+
+        let mut checker = DataChecker::new(config, blobs.clone());
+        let progress = checker.check("digest", &chunks).unwrap();
+        assert_eq!(progress.chunks, 4);
+        assert_eq!(progress.issues.len(), 1);
+        let issue = &progress.issues[0];
+        assert!(issue.contains(&format!(
+            "chunk {} at offset 0x{:x} of blob {}",
+            corrupted.chunk.id(),
+            corrupted.chunk.compressed_offset(),
+            blobs[0].blob_id()
+        )));
+        assert!(issue.ends_with("affected file: \"/file2\""));
+    }
+
+    #[test]
+    fn test_check_chunk_dict() {
+        let source = TempDir::new().unwrap();
+        let dict_dir = TempDir::new().unwrap();
+        let stale_dir = TempDir::new().unwrap();
+        let work_dir = TempDir::new().unwrap();
+        let root = source.as_path();
+        let mut data = vec![0x5au8; 0x1000];
+        data.extend_from_slice(&[0xa5u8; 0x1000]);
+        fs::write(root.join("file"), &data).unwrap();
+        let config = Arc::new(ConfigV2::default());
+        let load_dict = |path: &Path, v: &Validator| {
+            HashChunkDict::from_bootstrap_files(
+                &[path.to_path_buf()],
+                config.clone(),
+                &v.rafs_config(),
+            )
+            .unwrap()
+        };
+
+        let dict_path = BuildOptions::new(RafsVersion::V6).build(root, dict_dir.as_path());
+        let dict = load_dict(
+            &dict_path,
+            &Validator::new(&dict_path, config.clone()).unwrap(),
+        );
+        let path = BuildOptions {
+            chunk_dict: Some(dict.clone()),
+            ..BuildOptions::new(RafsVersion::V6)
+        }
+        .build(root, work_dir.as_path());
+        let v6 = Validator::new(&path, config.clone()).unwrap();
+        assert!(v6.check_chunk_dict(dict.as_ref()).unwrap().is_empty());
+
+        // The stale dictionary refers to the same data blob, but has lost one of the chunks.
+        fs::write(root.join("file"), &data[..0x1000]).unwrap();
+        let stale_path = BuildOptions {
+            chunk_dict: Some(dict),
+            ..BuildOptions::new(RafsVersion::V6)
+        }
+        .build(root, stale_dir.as_path());
+        let stale = load_dict(&stale_path, &v6);
+        let issues = v6.check_chunk_dict(stale.as_ref()).unwrap();
+        assert_eq!(issues.len(), 1);
+        assert!(issues[0].contains("not found in chunk dictionary"));
+        assert!(issues[0].contains("\"/file\""));
+
+        // Chunks are found in another data blob taking precedence in the dictionary.
+        fs::write(root.join("file"), &data).unwrap();
+        fs::write(root.join("a"), vec![0x11u8; 0x1000]).unwrap();
+        let other_dir = TempDir::new().unwrap();
+        let other_path = BuildOptions::new(RafsVersion::V6).build(root, other_dir.as_path());
+        let merged = HashChunkDict::from_bootstrap_files(
+            &[other_path, dict_path],
+            config.clone(),
+            &v6.rafs_config(),
+        )
+        .unwrap();
+        let issues = v6.check_chunk_dict(merged.as_ref()).unwrap();
+        assert_eq!(issues.len(), 2);
+        for issue in issues.iter() {
+            assert!(issue.contains("in chunk dictionary"));
+            assert!(issue.contains(", but in blob"));
+            assert!(issue.contains("\"/file\""));
+        }
+    }
+
+    #[test]
+    fn test_check_symlinks() {
+        let source = TempDir::new().unwrap();
+        let work_dir = TempDir::new().unwrap();
+        let root = source.as_path();
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        fs::create_dir_all(root.join("usr/lib")).unwrap();
+        fs::write(root.join("usr/bin/bash"), b"bash").unwrap();
+        std::os::unix::fs::symlink("usr/bin", root.join("bin")).unwrap();
+        std::os::unix::fs::symlink("/bin/bash", root.join("usr/bin/sh")).unwrap();
+        std::os::unix::fs::symlink("../bin/sh", root.join("usr/lib/sh")).unwrap();
+        std::os::unix::fs::symlink("/usr/bin/zsh", root.join("usr/bin/zsh-link")).unwrap();
+        std::os::unix::fs::symlink("../../../etc", root.join("usr/lib/etc")).unwrap();
+        std::os::unix::fs::symlink("loop2", root.join("loop1")).unwrap();
+        std::os::unix::fs::symlink("loop1", root.join("loop2")).unwrap();
+        std::os::unix::fs::symlink("bin/bash/sh", root.join("notdir")).unwrap();
+
+        let v6 = BuildOptions::new(RafsVersion::V6).validator(root, work_dir.as_path());
+        let issues = v6.check_symlinks().unwrap();
+        assert_eq!(issues.len(), 5);
+        assert!(issues
+            .iter()
+            .any(|i| i.contains("\"/loop1\"") && i.contains("too many levels")));
+        assert!(issues
+            .iter()
+            .any(|i| i.contains("\"/loop2\"") && i.contains("too many levels")));
+        assert!(issues
+            .iter()
+            .any(|i| i.contains("\"/notdir\"") && i.contains("not a directory")));
+        assert!(issues
+            .iter()
+            .any(|i| i.contains("\"/usr/bin/zsh-link\"") && i.contains("doesn't exist")));
+        assert!(issues
+            .iter()
+            .any(|i| i.contains("\"/usr/lib/etc\"") && i.contains("out of the filesystem root")));
+    }
+
+    #[test]
+    fn test_check_old_builder_empty_files() {
+        let source = TempDir::new().unwrap();
+        let work_dir = TempDir::new().unwrap();
+        let root = source.as_path();
+        fs::write(root.join("data"), vec![0x5au8; 0x1000]).unwrap();
+        fs::write(root.join("empty"), b"").unwrap();
+        let bootstrap = BuildOptions::new(RafsVersion::V5).build(root, work_dir.as_path());
+
+        // Rewrite the filesystem as old builders did, with a zero-length chunk for the empty file.
+        let config = Arc::new(ConfigV2::default());
+        let (rs, _) = RafsSuper::load_from_file(&bootstrap, config.clone(), false).unwrap();
+        let tree = Tree::from_bootstrap(&rs, &mut ()).unwrap();
+        tree.walk_dfs_pre(&mut |t| {
+            let mut node = t.borrow_mut_node();
+            if node.target() == Path::new("/empty") {
+                let mut chunk = ChunkWrapper::new(RafsVersion::V5);
+                chunk.set_id(RafsDigest::from_buf(&[], digest::Algorithm::Sha256));
+                node.chunks.push(NodeChunk {
+                    source: ChunkSource::Build,
+                    inner: Arc::new(chunk),
+                });
+                node.inode.set_child_count(1);
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        let old_bootstrap = work_dir.as_path().join("bootstrap-old");
+        let mut ctx = BuildContext::new(
+            String::new(),
+            false,
+            0,
+            rs.meta.get_compressor(),
+            rs.meta.get_digester(),
+            rs.meta.explicit_uidgid(),
+            WhiteoutSpec::None,
+            ConversionType::DirectoryToRafs,
+            PathBuf::new(),
+            Prefetch::default(),
+            None,
+            false,
+            Features::new(),
+            false,
+        );
+        ctx.set_fs_version(RafsVersion::V5);
+        let mut storage = Some(ArtifactStorage::SingleFile(old_bootstrap.clone()));
+        let bootstrap_mgr = BootstrapManager::new(storage.clone(), None);
+        let mut bootstrap_ctx = bootstrap_mgr.create_ctx().unwrap();
+        let mut blob_mgr = BlobManager::new(rs.meta.get_digester());
+        blob_mgr
+            .extend_from_blob_table(&ctx, rs.superblock.get_blob_infos())
+            .unwrap();
+        let blob_table = blob_mgr.to_blob_table(&ctx).unwrap();
+        let mut rebuilt = Bootstrap::new(tree).unwrap();
+        rebuilt.build(&mut ctx, &mut bootstrap_ctx).unwrap();
+        rebuilt
+            .dump(&mut ctx, &mut storage, &mut bootstrap_ctx, &blob_table)
+            .unwrap();
+
+        let mut validator = Validator::new(&old_bootstrap, config).unwrap();
+        validator.check(false).unwrap();
+    }
+
+    #[test]
+    fn test_chunk_digest_checker() {
+        let entry = ChunkEntry {
+            compressed_offset: 0,
+            compressed_size: 0x100,
+            uncompressed_offset: 0,
+            uncompressed_size: 0x1000,
+            compressed: true,
+            compressor: None,
+            encrypted: false,
+            batch: false,
+        };
+        let digest1 = RafsDigest::from_buf(b"chunk1", digest::Algorithm::Sha256);
+        let digest2 = RafsDigest::from_buf(b"chunk2", digest::Algorithm::Sha256);
+
+        let mut checker = ChunkDigestChecker::default();
+        checker.add(digest1, (0, 0), entry.clone());
+        checker.add(digest1, (0, 0), entry.clone());
+        // Chunks in different blobs may be compressed differently.
+        let mut other = entry.clone();
+        other.compressed_size = 0x200;
+        other.compressor = Some(compress::Algorithm::Lz4Block);
+        checker.add(digest1, (1, 0), other.clone());
+        assert!(checker.conflicts().is_empty());
+
+        checker.add(digest1, (1, 1), entry.clone());
+        let conflicts = checker.conflicts();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].1, vec![(1, 0), (1, 1)]);
+
+        let mut checker = ChunkDigestChecker::default();
+        checker.add(digest2, (0, 1), entry.clone());
+        other = entry.clone();
+        other.uncompressed_size = 0x800;
+        checker.add(digest2, (1, 2), other.clone());
+        checker.add(digest1, (0, 0), entry.clone());
+        checker.add(digest1, (0, 0), other);
+        let conflicts = checker.conflicts();
+        assert_eq!(conflicts.len(), 2);
+        assert!(
+            conflicts
+                .iter()
+                .any(|(msg, keys)| msg.contains("uncompressed sizes")
+                    && keys == &vec![(0, 1), (1, 2)])
+        );
+        assert!(conflicts
+            .iter()
+            .any(|(msg, keys)| msg.contains("inconsistent information") && keys == &vec![(0, 0)]));
     }
 }
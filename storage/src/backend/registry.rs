@@ -15,7 +15,7 @@ use arc_swap::{ArcSwap, ArcSwapOption};
 use base64::Engine;
 use reqwest::blocking::Response;
 pub use reqwest::header::HeaderMap;
-use reqwest::header::{HeaderValue, CONTENT_LENGTH};
+use reqwest::header::{HeaderValue, ACCEPT, CONTENT_LENGTH, CONTENT_TYPE, LOCATION};
 use reqwest::{Method, StatusCode};
 use url::{ParseError, Url};
 
@@ -23,7 +23,7 @@ use nydus_api::RegistryConfig;
 use nydus_utils::metrics::BackendMetrics;
 
 use crate::backend::connection::{
-    is_success_status, respond, Connection, ConnectionConfig, ConnectionError, ReqBody,
+    is_success_status, respond, Connection, ConnectionConfig, ConnectionError, Progress, ReqBody,
 };
 use crate::backend::{BackendError, BackendResult, BlobBackend, BlobReader};
 
@@ -38,6 +38,11 @@ const REDIRECTED_STATUS_CODE: [StatusCode; 2] = [
 
 const REGISTRY_DEFAULT_TOKEN_EXPIRATION: u64 = 10 * 60; // in seconds
 
+const MANIFEST_ACCEPT_TYPES: &str = "application/vnd.oci.image.manifest.v1+json, \
+application/vnd.oci.image.index.v1+json, \
+application/vnd.docker.distribution.manifest.v2+json, \
+application/vnd.docker.distribution.manifest.list.v2+json";
+
 /// Error codes related to registry storage backend operations.
 #[derive(Debug)]
 pub enum RegistryError {
@@ -617,6 +622,113 @@ impl RegistryReader {
         respond(resp, catch_status).map_err(RegistryError::Request)
     }
 
+    /// Fetch image manifest or image index from registry server
+    ///
+    /// Request:  GET /manifests/<reference>
+    ///           header: accept: <OCI and docker manifest media types>
+    /// Response: status: 200 Ok
+    ///           body: <manifest or index in json>
+    fn _get_manifest(&self, reference: &str) -> RegistryResult<Vec<u8>> {
+        let url = format!("/manifests/{}", reference);
+        let url = self
+            .state
+            .url(url.as_str(), &[])
+            .map_err(|e| RegistryError::Url(url, e))?;
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static(MANIFEST_ACCEPT_TYPES));
+
+        let resp = self.request::<&[u8]>(Method::GET, url.as_str(), None, headers, true)?;
+        resp.bytes()
+            .map(|v| v.to_vec())
+            .map_err(RegistryError::Transport)
+    }
+
+    /// Upload a blob to registry server, return false if the blob already exists.
+    ///
+    /// Request:  POST /blobs/uploads/?mount=<digest>&from=<repo>
+    /// Response: status: 201 Created (blob exists) / 202 Accepted
+    ///           header: location: <upload url>
+    ///
+    /// Request:  PUT <upload url>?digest=<digest>
+    ///           body: <blob data>
+    /// Response: status: 201 Created
+    fn _push_blob<R: Read + Clone + Send + 'static>(
+        &self,
+        digest: &str,
+        size: u64,
+        data: R,
+    ) -> RegistryResult<bool> {
+        // Try to mount the blob from the same repository to check whether it already exists,
+        // it also acquires the authorization to push.
+        let from = format!("from={}", self.state.repo);
+        let mount = format!("mount={}", digest);
+        let url = "/blobs/uploads/";
+        let url = self
+            .state
+            .url(url, &[mount.as_str(), from.as_str()])
+            .map_err(|e| RegistryError::Url(url.to_string(), e))?;
+        let resp =
+            self.request::<&[u8]>(Method::POST, url.as_str(), None, HeaderMap::new(), true)?;
+        if resp.status() == StatusCode::CREATED {
+            return Ok(false);
+        }
+
+        let location = resp
+            .headers()
+            .get(LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| RegistryError::Common("no location in upload response".to_string()))?;
+        let base = format!("{}://{}", self.state.scheme, self.state.host);
+        let mut url = Url::parse(base.as_str())
+            .and_then(|u| u.join(location))
+            .map_err(|e| RegistryError::Url(location.to_string(), e))?;
+        url.query_pairs_mut().append_pair("digest", digest);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        );
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(size));
+        let body = ReqBody::Read(Progress::new(data, size as usize, |_| {}), size as usize);
+        self.request(Method::PUT, url.as_str(), Some(body), headers, true)?;
+
+        Ok(true)
+    }
+
+    /// Upload image manifest to registry server
+    ///
+    /// Request:  PUT /manifests/<reference>
+    ///           header: content-type: <media type>
+    ///           body: <manifest in json>
+    /// Response: status: 201 Created
+    fn _put_manifest(
+        &self,
+        reference: &str,
+        media_type: &str,
+        manifest: Vec<u8>,
+    ) -> RegistryResult<()> {
+        let url = format!("/manifests/{}", reference);
+        let url = self
+            .state
+            .url(url.as_str(), &[])
+            .map_err(|e| RegistryError::Url(url, e))?;
+        let mut headers = HeaderMap::new();
+        let content_type = HeaderValue::from_str(media_type)
+            .map_err(|e| RegistryError::Common(format!("invalid media type, {}", e)))?;
+        headers.insert(CONTENT_TYPE, content_type);
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(manifest.len()));
+        self.request::<&[u8]>(
+            Method::PUT,
+            url.as_str(),
+            Some(ReqBody::Buf(manifest)),
+            headers,
+            true,
+        )?;
+
+        Ok(())
+    }
+
     /// Read data from registry server
     ///
     /// Step:
@@ -898,6 +1010,55 @@ impl Registry {
         Ok(registry)
     }
 
+    /// Fetch the image manifest or image index identified by `reference`, a tag or a digest.
+    pub fn get_manifest(&self, reference: &str) -> BackendResult<Vec<u8>> {
+        let reader = self.reader(reference);
+        self.first.handle_force(&mut || -> BackendResult<Vec<u8>> {
+            reader
+                ._get_manifest(reference)
+                .map_err(BackendError::Registry)
+        })
+    }
+
+    /// Upload blob `digest` of `size` bytes to the registry, return false if it already exists.
+    ///
+    /// Authorization to push to the repository is acquired when uploading blobs, so blobs should
+    /// be pushed before pushing the manifest referring to them.
+    pub fn push_blob<R: Read + Clone + Send + 'static>(
+        &self,
+        digest: &str,
+        size: u64,
+        data: R,
+    ) -> BackendResult<bool> {
+        let reader = self.reader(digest);
+        reader
+            ._push_blob(digest, size, data)
+            .map_err(BackendError::Registry)
+    }
+
+    /// Upload an image manifest of `media_type` as `reference`, a tag or a digest.
+    pub fn put_manifest(
+        &self,
+        reference: &str,
+        media_type: &str,
+        manifest: Vec<u8>,
+    ) -> BackendResult<()> {
+        let reader = self.reader(reference);
+        reader
+            ._put_manifest(reference, media_type, manifest)
+            .map_err(BackendError::Registry)
+    }
+
+    fn reader(&self, blob_id: &str) -> RegistryReader {
+        RegistryReader {
+            blob_id: blob_id.to_owned(),
+            state: self.state.clone(),
+            connection: self.connection.clone(),
+            metrics: self.metrics.clone(),
+            first: self.first.clone(),
+        }
+    }
+
     fn get_authorization_info(auth: &Option<String>) -> Result<(String, String)> {
         if let Some(auth) = &auth {
             let auth: Vec<u8> = base64::engine::general_purpose::STANDARD
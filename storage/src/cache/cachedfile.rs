@@ -232,6 +232,21 @@ impl FileCacheEntry {
         Ok(size)
     }
 
+    // Blob meta of legacy data blobs may be regenerated from RAFS metadata, fall back to data
+    // paths without blob meta if the regenerated blob meta is unusable.
+    pub(crate) fn get_regenerated_blob_info(
+        blob_file: &str,
+        blob_info: &BlobInfo,
+    ) -> Option<BlobInfo> {
+        match BlobCompressionContextInfo::regenerated_blob_info(blob_file, blob_info) {
+            Ok(info) => info,
+            Err(e) => {
+                warn!("ignore regenerated blob meta of blob {}, {}", blob_file, e);
+                None
+            }
+        }
+    }
+
     fn delay_persist_chunk_data(&self, chunk: Arc<dyn BlobChunkInfo>, buffer: Arc<DataBuffer>) {
         let delayed_chunk_map = self.chunk_map.clone();
         let file = self.file.clone();
@@ -1383,19 +1398,20 @@ impl FileCacheEntry {
                 chunk.compressed_size() as u64
             };
             let mut reader = FileRangeReader::new(&self.file, offset, size);
+            let compressor = self.chunk_compressor(chunk);
             if !chunk.is_compressed() {
                 reader.read_exact(buffer)?;
-            } else if self.blob_compressor() == compress::Algorithm::Lz4Block {
+            } else if compressor.is_lz4_block() {
                 let mut buf = alloc_buf(size as usize);
                 reader.read_exact(&mut buf)?;
-                let size = compress::decompress(&buf, buffer, self.blob_compressor())?;
+                let size = compress::decompress(&buf, buffer, compressor)?;
                 if size != buffer.len() {
                     return Err(einval!(
                         "data size decoded by lz4_block doesn't match expected"
                     ));
                 }
             } else {
-                let mut decoder = Decoder::new(reader, self.blob_compressor())?;
+                let mut decoder = Decoder::new(reader, compressor)?;
                 decoder.read_exact(buffer)?;
             }
         } else if self.is_cache_encrypted {
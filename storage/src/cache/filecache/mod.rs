@@ -23,6 +23,7 @@ use crate::cache::state::{
 use crate::cache::worker::{AsyncPrefetchConfig, AsyncWorkerMgr};
 use crate::cache::{BlobCache, BlobCacheMgr};
 use crate::device::{BlobFeatures, BlobInfo};
+use crate::meta::BlobCompressionContextInfo;
 
 pub const BLOB_RAW_FILE_SUFFIX: &str = ".blob.raw";
 pub const BLOB_DATA_FILE_SUFFIX: &str = ".blob.data";
@@ -266,7 +267,13 @@ impl FileCacheEntry {
                 );
                 return Err(einval!(msg));
             }
-            let meta = if blob_info.meta_ci_is_valid()
+            let regenerated = Self::get_regenerated_blob_info(&blob_file_path, &blob_info);
+            let meta = if let Some(info) = regenerated {
+                // Regenerated blob meta is only available from the cache file.
+                let meta =
+                    FileCacheMeta::new(blob_file_path, Arc::new(info), None, None, true, false)?;
+                Some(meta)
+            } else if blob_info.meta_ci_is_valid()
                 || blob_info.has_feature(BlobFeatures::IS_CHUNKDICT_GENERATED)
             {
                 let meta = FileCacheMeta::new(
@@ -364,15 +371,37 @@ impl FileCacheEntry {
             direct_chunkmap = false;
             Arc::new(BlobStateMap::from(DigestedChunkMap::new()))
         } else {
-            Arc::new(BlobStateMap::from(IndexedChunkMap::new(
+            let chunk_map = IndexedChunkMap::with_stamp(
                 &format!("{}{}", blob_file, BLOB_DATA_FILE_SUFFIX),
                 blob_info.chunk_count(),
                 true,
-            )?))
+                &blob_info.content_digest(),
+            )?;
+            // The blob has been replaced with new content, other cache files are stale too.
+            if chunk_map.is_rebuilt() {
+                Self::remove_stale_cache_files(blob_file)?;
+            }
+            Arc::new(BlobStateMap::from(chunk_map))
         };
 
         Ok((chunk_map, direct_chunkmap))
     }
+
+    fn remove_stale_cache_files(blob_file: &str) -> Result<()> {
+        warn!("remove stale cache files of blob {}", blob_file);
+        BlobCompressionContextInfo::remove_cache_files(blob_file)?;
+        for suffix in [BLOB_DATA_FILE_SUFFIX, BLOB_RAW_FILE_SUFFIX] {
+            let path = format!("{}{}", blob_file, suffix);
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(eio!(format!("failed to remove cache file {}, {}", path, e)))
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
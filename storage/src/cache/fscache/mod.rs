@@ -244,7 +244,18 @@ impl FileCacheEntry {
             && !blob_info.is_legacy_stargz()
             && blob_info.has_feature(BlobFeatures::INLINED_CHUNK_DIGEST);
         let blob_file_path = format!("{}/{}", mgr.work_dir, blob_meta_id);
-        let meta = if blob_info.meta_ci_is_valid() {
+        let regenerated = Self::get_regenerated_blob_info(&blob_file_path, &blob_info);
+        let meta = if let Some(info) = regenerated {
+            // Regenerated blob meta is only available from the cache file.
+            FileCacheMeta::new(
+                blob_file_path.clone(),
+                Arc::new(info),
+                None,
+                None,
+                true,
+                false,
+            )?
+        } else if blob_info.meta_ci_is_valid() {
             FileCacheMeta::new(
                 blob_file_path.clone(),
                 blob_info.clone(),
@@ -259,10 +270,11 @@ impl FileCacheEntry {
             ));
         };
 
-        let chunk_map = Arc::new(BlobStateMap::from(IndexedChunkMap::new(
+        let chunk_map = Arc::new(BlobStateMap::from(IndexedChunkMap::with_stamp(
             &format!("{}{}", blob_file_path, BLOB_DATA_FILE_SUFFIX),
             blob_info.chunk_count(),
             false,
+            &blob_info.content_digest(),
         )?));
         Self::restore_chunk_map(blob_info.clone(), file.clone(), &meta, &chunk_map);
 
@@ -152,6 +152,11 @@ pub trait BlobCache: Send + Sync {
     /// Get data compression algorithm to handle chunks in the blob.
     fn blob_compressor(&self) -> compress::Algorithm;
 
+    /// Get data compression algorithm to handle the chunk, which may differ from the blob's.
+    fn chunk_compressor(&self, chunk: &dyn BlobChunkInfo) -> compress::Algorithm {
+        chunk.compressor().unwrap_or_else(|| self.blob_compressor())
+    }
+
     /// Get data encryption algorithm to handle chunks in the blob.
     fn blob_cipher(&self) -> crypt::Algorithm;
 
@@ -325,7 +330,12 @@ pub trait BlobCache: Send + Sync {
                 &self.blob_cipher_context(),
                 chunk.is_encrypted(),
             )?;
-            self.decompress_chunk_data(&decrypted_buffer, buffer, chunk.is_compressed())?;
+            self.decompress_chunk_data(
+                &decrypted_buffer,
+                buffer,
+                chunk.is_compressed(),
+                self.chunk_compressor(chunk),
+            )?;
             c_buf = Some(raw_buffer);
         }
 
@@ -352,9 +362,9 @@ pub trait BlobCache: Send + Sync {
         raw_buffer: &[u8],
         buffer: &mut [u8],
         is_compressed: bool,
+        compressor: compress::Algorithm,
     ) -> Result<()> {
         if is_compressed {
-            let compressor = self.blob_compressor();
             let ret = compress::decompress(raw_buffer, buffer, compressor).map_err(|e| {
                 error!("failed to decompress chunk: {}", e);
                 e
@@ -467,8 +477,12 @@ impl<'a, 'b> ChunkDecompressState<'a, 'b> {
         )?;
         let mut output = alloc_buf(d_size as usize);
 
-        self.cache
-            .decompress_chunk_data(&decrypted_buffer, &mut output, c_size != d_size)?;
+        self.cache.decompress_chunk_data(
+            &decrypted_buffer,
+            &mut output,
+            c_size != d_size,
+            self.cache.blob_compressor(),
+        )?;
 
         if output.len() != d_size as usize {
             return Err(einval!(format!(
@@ -594,8 +608,12 @@ impl<'a, 'b> ChunkDecompressState<'a, 'b> {
             chunk.is_encrypted(),
         )?;
         let mut buffer = alloc_buf(d_size);
-        self.cache
-            .decompress_chunk_data(&decrypted_buffer, &mut buffer, chunk.is_compressed())?;
+        self.cache.decompress_chunk_data(
+            &decrypted_buffer,
+            &mut buffer,
+            chunk.is_compressed(),
+            self.cache.chunk_compressor(chunk),
+        )?;
         self.cache
             .validate_chunk_data(chunk, &buffer, false)
             .map_err(|e| {
@@ -37,7 +37,29 @@ impl IndexedChunkMap {
     pub fn new(blob_path: &str, chunk_count: u32, persist: bool) -> Result<Self> {
         let filename = format!("{}.{}", blob_path, FILE_SUFFIX);
 
-        PersistMap::open(&filename, chunk_count, true, persist).map(|map| IndexedChunkMap { map })
+        PersistMap::open(&filename, chunk_count, true, persist, None)
+            .map(|map| IndexedChunkMap { map })
+    }
+
+    /// Create a new instance of `IndexedChunkMap` for blob content identified by `stamp`.
+    ///
+    /// Chunk state recorded for different blob content is discarded, check `is_rebuilt()` to
+    /// find out whether other cache files of the blob are stale too.
+    pub fn with_stamp(
+        blob_path: &str,
+        chunk_count: u32,
+        persist: bool,
+        stamp: &[u8; 32],
+    ) -> Result<Self> {
+        let filename = format!("{}.{}", blob_path, FILE_SUFFIX);
+
+        PersistMap::open(&filename, chunk_count, true, persist, Some(stamp))
+            .map(|map| IndexedChunkMap { map })
+    }
+
+    /// Check whether the chunk map has been reset because the blob content has changed.
+    pub fn is_rebuilt(&self) -> bool {
+        self.map.rebuilt
     }
 }
 
@@ -271,6 +293,8 @@ mod tests {
             version: 1,
             magic2: MAGIC2,
             all_ready: MAGIC_ALL_READY,
+            stamp: [0u8; 32],
+            generation: 0,
             reserved: [0x0u8; HEADER_RESERVED_SIZE],
         };
 
@@ -316,6 +340,8 @@ mod tests {
             version: 0,
             magic2: 0,
             all_ready: 0,
+            stamp: [0u8; 32],
+            generation: 0,
             reserved: [0x0u8; HEADER_RESERVED_SIZE],
         };
 
@@ -335,4 +361,54 @@ mod tests {
         map.set_ready_and_clear_pending(chunk.as_base()).unwrap();
         assert!(map.is_ready(chunk.as_base()).unwrap());
     }
+
+    #[test]
+    fn test_indexed_with_stamp() {
+        let dir = TempDir::new().unwrap();
+        let blob_path = dir.as_path().join("blob-1");
+        let blob_path = blob_path.as_os_str().to_str().unwrap().to_string();
+        let cache_path = format!("{}.{}", blob_path, FILE_SUFFIX);
+        let read_header = || {
+            let data = std::fs::read(&cache_path).unwrap();
+            let header = unsafe { std::ptr::read_unaligned(data.as_ptr() as *const Header) };
+            (header.version, header.stamp, header.generation)
+        };
+        let chunk = MockChunkInfo::new();
+
+        // Legacy chunk map files without blob content digest are adopted.
+        let map = IndexedChunkMap::new(&blob_path, 2, true).unwrap();
+        map.set_ready_and_clear_pending(chunk.as_base()).unwrap();
+        drop(map);
+        let map = IndexedChunkMap::with_stamp(&blob_path, 2, true, &[1u8; 32]).unwrap();
+        assert!(!map.is_rebuilt());
+        assert!(map.is_ready(chunk.as_base()).unwrap());
+        drop(map);
+        assert_eq!(read_header(), (HEADER_VERSION, [1u8; 32], 0));
+
+        // Same blob content, chunk state is kept.
+        let map = IndexedChunkMap::with_stamp(&blob_path, 2, true, &[1u8; 32]).unwrap();
+        assert!(!map.is_rebuilt());
+        assert!(map.is_ready(chunk.as_base()).unwrap());
+        drop(map);
+
+        // Blob content changed, chunk state is discarded.
+        let map = IndexedChunkMap::with_stamp(&blob_path, 2, true, &[2u8; 32]).unwrap();
+        assert!(map.is_rebuilt());
+        assert!(!map.is_ready(chunk.as_base()).unwrap());
+        assert_eq!(map.map.not_ready_count.load(Ordering::Acquire), 2);
+        assert_eq!(map.map.size(), 0x1001);
+        drop(map);
+        assert_eq!(read_header(), (HEADER_VERSION, [2u8; 32], 1));
+
+        // Blob content changed with different chunk count, the file is recreated with new size.
+        let map = IndexedChunkMap::with_stamp(&blob_path, 20, true, &[3u8; 32]).unwrap();
+        assert!(map.is_rebuilt());
+        assert_eq!(map.map.not_ready_count.load(Ordering::Acquire), 20);
+        assert_eq!(map.map.size(), 0x1003);
+        drop(map);
+        assert_eq!(read_header(), (HEADER_VERSION, [3u8; 32], 2));
+
+        // Size mismatch without stamp mismatch is still treated as corruption.
+        assert!(IndexedChunkMap::with_stamp(&blob_path, 2, true, &[3u8; 32]).is_err());
+    }
 }
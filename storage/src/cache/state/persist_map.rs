@@ -4,7 +4,8 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::fs::{File, OpenOptions};
-use std::io::{Result, Write};
+use std::io::Result;
+use std::os::unix::fs::FileExt;
 use std::os::unix::io::AsRawFd;
 use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
 
@@ -17,7 +18,9 @@ pub(crate) const MAGIC1: u32 = 0x424D_4150;
 pub(crate) const MAGIC2: u32 = 0x434D_4150;
 pub(crate) const MAGIC_ALL_READY: u32 = 0x4D4D_4150;
 pub(crate) const HEADER_SIZE: usize = 4096;
-pub(crate) const HEADER_RESERVED_SIZE: usize = HEADER_SIZE - 16;
+pub(crate) const HEADER_RESERVED_SIZE: usize = HEADER_SIZE - 56;
+/// Version 2 adds the blob content digest and generation counter.
+pub(crate) const HEADER_VERSION: u32 = 2;
 
 /// The blob chunk map file header, 4096 bytes.
 #[repr(C)]
@@ -27,6 +30,10 @@ pub(crate) struct Header {
     pub version: u32,
     pub magic2: u32,
     pub all_ready: u32,
+    /// Digest of the blob content the chunk map is generated for, all zero if unknown.
+    pub stamp: [u8; 32],
+    /// Number of times the chunk map has been rebuilt because the blob content changed.
+    pub generation: u64,
     pub reserved: [u8; HEADER_RESERVED_SIZE],
 }
 
@@ -44,16 +51,30 @@ impl Header {
 pub(crate) struct PersistMap {
     pub count: u32,
     pub not_ready_count: AtomicU32,
+    /// Whether the chunk map has been reset because it's generated for different blob content.
+    pub rebuilt: bool,
     filemap: FileMapState,
 }
 
 impl PersistMap {
-    pub fn open(filename: &str, chunk_count: u32, create: bool, persist: bool) -> Result<Self> {
+    /// Open or create the chunk map file.
+    ///
+    /// If `stamp` is given, it's compared with the blob content digest recorded in the file
+    /// header. A mismatch means the blob has been replaced with new content, so all chunks are
+    /// marked as not ready and the generation counter is increased. Files without a recorded
+    /// digest are adopted as is.
+    pub fn open(
+        filename: &str,
+        chunk_count: u32,
+        create: bool,
+        persist: bool,
+        stamp: Option<&[u8; 32]>,
+    ) -> Result<Self> {
         if chunk_count == 0 {
             return Err(einval!("chunk count should be greater than 0"));
         }
 
-        let mut file = OpenOptions::new()
+        let file = OpenOptions::new()
             .read(true)
             .write(create)
             .create(create)
@@ -66,10 +87,42 @@ impl PersistMap {
                 ))
             })?;
 
-        let file_size = file.metadata()?.len();
+        let mut file_size = file.metadata()?.len();
         let bitmap_size = div_round_up(chunk_count as u64, 8u64);
         let expected_size = HEADER_SIZE as u64 + bitmap_size;
         let mut new_content = false;
+        let mut rebuilt = false;
+
+        // Compare the blob content digest before checking file size, the chunk count may change
+        // when the blob is replaced with new content.
+        if let Some(stamp) = stamp {
+            if file_size >= HEADER_SIZE as u64 {
+                let header = Self::read_header(&file)?;
+                if header.magic == MAGIC1
+                    && header.version >= HEADER_VERSION
+                    && header.stamp != [0u8; 32]
+                    && header.stamp != *stamp
+                {
+                    if !create {
+                        return Err(einval!(format!(
+                            "blob chunk_map file {:?} is generated for different blob content",
+                            filename
+                        )));
+                    }
+                    let generation = header.generation + 1;
+                    warn!(
+                        "blob chunk_map file {:?} is stale, rebuild it as generation {}",
+                        filename, generation
+                    );
+                    // Drop the bitmap and recreate the file with all chunks not ready.
+                    file.set_len(0)?;
+                    Self::write_header(&file, expected_size, Some(stamp), generation)?;
+                    file_size = expected_size;
+                    new_content = true;
+                    rebuilt = true;
+                }
+            }
+        }
 
         if file_size == 0 {
             if !create {
@@ -77,7 +130,7 @@ impl PersistMap {
             }
 
             new_content = true;
-            Self::write_header(&mut file, expected_size)?;
+            Self::write_header(&file, expected_size, stamp, 0)?;
         } else if file_size != expected_size {
             // File size doesn't match, it's too risky to accept the chunk state file. Fallback to
             // always mark chunk data as not ready.
@@ -107,7 +160,18 @@ impl PersistMap {
             }
 
             new_content = true;
-            Self::write_header(&mut file, expected_size)?;
+            Self::write_header(&file, expected_size, stamp, 0)?;
+        }
+
+        // Adopt chunk map files generated without blob content digest.
+        let header = filemap.get_mut::<Header>(0)?;
+        if let Some(stamp) = stamp {
+            if create && (header.version < HEADER_VERSION || header.stamp == [0u8; 32]) {
+                header.version = HEADER_VERSION;
+                header.stamp = *stamp;
+                header.generation = 0;
+                filemap.sync_data()?;
+            }
         }
 
         let header = filemap.get_mut::<Header>(0)?;
@@ -150,16 +214,31 @@ impl PersistMap {
         Ok(Self {
             count: chunk_count,
             not_ready_count: AtomicU32::new(not_ready_count),
+            rebuilt,
             filemap,
         })
     }
 
-    fn write_header(file: &mut File, size: u64) -> Result<()> {
+    fn read_header(file: &File) -> Result<Header> {
+        let mut buf = [0u8; HEADER_SIZE];
+        file.read_exact_at(&mut buf, 0)?;
+        // Safe because `Header` is plain old data with the same size as the buffer.
+        Ok(unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const Header) })
+    }
+
+    fn write_header(
+        file: &File,
+        size: u64,
+        stamp: Option<&[u8; 32]>,
+        generation: u64,
+    ) -> Result<()> {
         let header = Header {
             magic: MAGIC1,
-            version: 1,
+            version: HEADER_VERSION,
             magic2: MAGIC2,
             all_ready: 0,
+            stamp: stamp.copied().unwrap_or_default(),
+            generation,
             reserved: [0x0u8; HEADER_RESERVED_SIZE],
         };
 
@@ -167,7 +246,7 @@ impl PersistMap {
         file.set_len(size)?;
         file.sync_all()?;
         // write file header and sync to disk.
-        file.write_all(header.as_slice())?;
+        file.write_all_at(header.as_slice(), 0)?;
         file.sync_all()?;
 
         Ok(())
@@ -39,13 +39,15 @@ use fuse_backend_rs::file_traits::FileReadWriteVolatile;
 use nydus_api::ConfigV2;
 use nydus_utils::compress;
 use nydus_utils::crypt::{self, Cipher, CipherContext};
-use nydus_utils::digest::{self, RafsDigest};
+use nydus_utils::digest::{self, DigestHasher, RafsDigest};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
 
 use crate::cache::BlobCache;
 use crate::factory::BLOB_FACTORY;
+use crate::meta::format_blob_features;
 
 pub(crate) const BLOB_FEATURE_INCOMPAT_MASK: u32 = 0x0000_ffff;
-pub(crate) const BLOB_FEATURE_INCOMPAT_VALUE: u32 = 0x0000_0fff;
+pub(crate) const BLOB_FEATURE_INCOMPAT_VALUE: u32 = 0x0000_1fff;
 
 bitflags! {
     /// Features bits for blob management.
@@ -79,6 +81,11 @@ bitflags! {
         const _V5_NO_EXT_BLOB_TABLE = 0x8000_0000;
         /// Blob is generated with chunkdict.
         const IS_CHUNKDICT_GENERATED = 0x0000_0200;
+        /// Some chunks are compressed by algorithms other than the blob compression algorithm.
+        ///
+        /// It's an incompatible feature, so runtimes unaware of per-chunk compression algorithms
+        /// reject the blob instead of decompressing chunks with the wrong algorithm.
+        const CHUNK_COMPRESSOR = 0x0000_1000;
     }
 }
 
@@ -142,6 +149,8 @@ pub struct BlobInfo {
     prefetch_offset: u32,
     /// Size of blob data to prefetch.
     prefetch_size: u32,
+    /// V6: compressed data ranges `(offset, size)` to prefetch, sorted by offset.
+    prefetch_ranges: Vec<(u64, u64)>,
     /// The blob is for a legacy estargz image.
     is_legacy_stargz: bool,
 
@@ -205,6 +214,7 @@ impl BlobInfo {
             digester: digest::Algorithm::Blake3,
             prefetch_offset: 0,
             prefetch_size: 0,
+            prefetch_ranges: Vec::new(),
             is_legacy_stargz: false,
             meta_ci_compressor: 0,
             meta_ci_offset: 0,
@@ -244,6 +254,11 @@ impl BlobInfo {
         self.blob_index
     }
 
+    /// Set the blob index in the blob array.
+    pub fn set_blob_index(&mut self, index: u32) {
+        self.blob_index = index;
+    }
+
     /// Get the id of the blob, with special handling of `inlined-meta` case.
     pub fn blob_id(&self) -> String {
         if (self.has_feature(BlobFeatures::INLINED_FS_META)
@@ -385,6 +400,18 @@ impl BlobInfo {
         self.prefetch_size = size as u32;
     }
 
+    /// Get compressed data ranges `(offset, size)` for blob data prefetching.
+    pub fn prefetch_ranges(&self) -> &[(u64, u64)] {
+        &self.prefetch_ranges
+    }
+
+    /// Set compressed data ranges `(offset, size)` for blob data prefetching.
+    ///
+    /// The ranges take precedence over the prefetch range set by `set_prefetch_info()`.
+    pub fn set_prefetch_ranges(&mut self, ranges: Vec<(u64, u64)>) {
+        self.prefetch_ranges = ranges;
+    }
+
     /// Check whether this blob is for an stargz image.
     pub fn is_legacy_stargz(&self) -> bool {
         self.is_legacy_stargz
@@ -409,15 +436,7 @@ impl BlobInfo {
 
     /// Get compression algorithm for chunk information array.
     pub fn meta_ci_compressor(&self) -> compress::Algorithm {
-        if self.meta_ci_compressor == compress::Algorithm::Lz4Block as u32 {
-            compress::Algorithm::Lz4Block
-        } else if self.meta_ci_compressor == compress::Algorithm::GZip as u32 {
-            compress::Algorithm::GZip
-        } else if self.meta_ci_compressor == compress::Algorithm::Zstd as u32 {
-            compress::Algorithm::Zstd
-        } else {
-            compress::Algorithm::None
-        }
+        compress::Algorithm::try_from(self.meta_ci_compressor).unwrap_or(compress::Algorithm::None)
     }
 
     /// Get offset of chunk information array in the compressed blob.
@@ -519,6 +538,27 @@ impl BlobInfo {
         self.blob_meta_size = size;
     }
 
+    /// Get SHA256 digest of information identifying content of the blob.
+    ///
+    /// Blobs may be replaced by new content with the same blob id, for example after compaction.
+    /// The digest helps to detect cache files generated for the old content.
+    pub fn content_digest(&self) -> [u8; 32] {
+        let mut hasher = RafsDigest::hasher(digest::Algorithm::Sha256);
+        hasher.digest_update(&self.blob_features.bits().to_le_bytes());
+        hasher.digest_update(&(self.compressor as u32).to_le_bytes());
+        hasher.digest_update(&self.compressed_size.to_le_bytes());
+        hasher.digest_update(&self.uncompressed_size.to_le_bytes());
+        hasher.digest_update(&self.chunk_size.to_le_bytes());
+        hasher.digest_update(&self.chunk_count.to_le_bytes());
+        hasher.digest_update(&self.meta_ci_compressor.to_le_bytes());
+        hasher.digest_update(&self.meta_ci_offset.to_le_bytes());
+        hasher.digest_update(&self.meta_ci_compressed_size.to_le_bytes());
+        hasher.digest_update(&self.meta_ci_uncompressed_size.to_le_bytes());
+        hasher.digest_update(&self.blob_toc_digest);
+        hasher.digest_update(&self.blob_meta_digest);
+        hasher.digest_finalize().data
+    }
+
     /// Set path for meta blob file, which will be used by `get_blob_id()` and `get_blob_meta_id()`.
     pub fn set_blob_id_from_meta_path(&self, path: &Path) -> Result<(), Error> {
         *self.meta_path.lock().unwrap() = Self::get_blob_id_from_meta_path(path)?;
@@ -586,6 +626,41 @@ impl BlobInfo {
     }
 }
 
+// Serialize blob parameters for external tools, runtime states such as fscache file and cipher
+// objects are skipped.
+impl Serialize for BlobInfo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let features = format_blob_features(self.blob_features);
+        let feature_names = features.split_whitespace().collect::<Vec<_>>();
+        let mut state = serializer.serialize_struct("BlobInfo", 24)?;
+        state.serialize_field("blob_index", &self.blob_index)?;
+        state.serialize_field("blob_id", &self.blob_id)?;
+        state.serialize_field("features", &self.blob_features.bits())?;
+        state.serialize_field("feature_names", &feature_names)?;
+        state.serialize_field("compressed_size", &self.compressed_size)?;
+        state.serialize_field("compressed_data_size", &self.compressed_data_size())?;
+        state.serialize_field("uncompressed_size", &self.uncompressed_size)?;
+        state.serialize_field("chunk_size", &self.chunk_size)?;
+        state.serialize_field("chunk_count", &self.chunk_count)?;
+        state.serialize_field("compressor", &self.compressor.to_string())?;
+        state.serialize_field("cipher", &self.cipher.to_string())?;
+        state.serialize_field("digester", &self.digester.to_string())?;
+        state.serialize_field("prefetch_offset", &self.prefetch_offset)?;
+        state.serialize_field("prefetch_size", &self.prefetch_size)?;
+        state.serialize_field("prefetch_ranges", &self.prefetch_ranges)?;
+        state.serialize_field("is_legacy_stargz", &self.is_legacy_stargz)?;
+        state.serialize_field("meta_ci_compressor", &self.meta_ci_compressor().to_string())?;
+        state.serialize_field("meta_ci_offset", &self.meta_ci_offset)?;
+        state.serialize_field("meta_ci_compressed_size", &self.meta_ci_compressed_size)?;
+        state.serialize_field("meta_ci_uncompressed_size", &self.meta_ci_uncompressed_size)?;
+        state.serialize_field("blob_toc_digest", &hex::encode(self.blob_toc_digest))?;
+        state.serialize_field("blob_toc_size", &self.blob_toc_size)?;
+        state.serialize_field("blob_meta_digest", &hex::encode(self.blob_meta_digest))?;
+        state.serialize_field("blob_meta_size", &self.blob_meta_size)?;
+        state.end()
+    }
+}
+
 bitflags! {
     /// Blob chunk flags.
     pub struct BlobChunkFlags: u32 {
@@ -597,15 +672,45 @@ bitflags! {
         const ENCYPTED = 0x0000_0004;
         /// Chunk data is merged into a batch chunk.
         const BATCH = 0x0000_0008;
+        /// Compression algorithm of chunk data if it differs from the blob compression algorithm.
+        const COMPRESSOR = 0x0000_0070;
     }
 }
 
+const BLOB_CHUNK_FLAG_COMPRESSOR_SHIFT: u32 = 4;
+
 impl Default for BlobChunkFlags {
     fn default() -> Self {
         BlobChunkFlags::empty()
     }
 }
 
+impl BlobChunkFlags {
+    /// Get compression algorithm of the chunk, `None` means the blob compression algorithm.
+    pub fn compressor(&self) -> Option<compress::Algorithm> {
+        match (*self & BlobChunkFlags::COMPRESSOR).bits() >> BLOB_CHUNK_FLAG_COMPRESSOR_SHIFT {
+            0 => None,
+            v => compress::Algorithm::try_from(v).ok(),
+        }
+    }
+
+    /// Set compression algorithm of the chunk, `None` means the blob compression algorithm.
+    ///
+    /// Only builtin algorithms fit in the chunk flags, custom algorithms can't be used per chunk.
+    pub fn set_compressor(&mut self, compressor: Option<compress::Algorithm>) -> io::Result<()> {
+        let algo = compressor.map(|v| v as u32).unwrap_or_default();
+        if algo > BlobChunkFlags::COMPRESSOR.bits() >> BLOB_CHUNK_FLAG_COMPRESSOR_SHIFT {
+            return Err(einval!(format!(
+                "compressor {} can't be recorded in chunk flags",
+                compressor.unwrap_or_default()
+            )));
+        }
+        let bits = BlobChunkFlags::from_bits_truncate(algo << BLOB_CHUNK_FLAG_COMPRESSOR_SHIFT);
+        *self = (*self - BlobChunkFlags::COMPRESSOR) | bits;
+        Ok(())
+    }
+}
+
 /// Trait to provide basic information for a chunk.
 ///
 /// A `BlobChunkInfo` object describes how a chunk is located within the compressed and
@@ -664,6 +769,14 @@ pub trait BlobChunkInfo: Any + Sync + Send {
     /// Check whether the chunk is encrypted or not.
     fn is_encrypted(&self) -> bool;
 
+    /// Get compression algorithm of the chunk if it differs from the blob compression algorithm.
+    ///
+    /// Chunks in a blob may be compressed by different algorithms, `None` means the chunk is
+    /// compressed by the blob compression algorithm.
+    fn compressor(&self) -> Option<compress::Algorithm> {
+        None
+    }
+
     fn as_any(&self) -> &dyn Any;
 }
 
@@ -721,6 +834,10 @@ impl BlobChunkInfo for BlobIoChunk {
         self.0.is_encrypted()
     }
 
+    fn compressor(&self) -> Option<compress::Algorithm> {
+        self.0.compressor()
+    }
+
     fn as_any(&self) -> &dyn Any {
         self
     }
@@ -1452,6 +1569,42 @@ mod tests {
         assert_eq!(iochunk.uncompressed_offset(), 0x2000);
         assert_eq!(iochunk.uncompressed_size(), 0x200);
         assert!(!iochunk.is_compressed());
+        assert_eq!(iochunk.compressor(), None);
+    }
+
+    #[test]
+    fn test_blob_chunk_flags_compressor() {
+        let mut flags = BlobChunkFlags::COMPRESSED | BlobChunkFlags::BATCH;
+        assert_eq!(flags.compressor(), None);
+
+        flags
+            .set_compressor(Some(compress::Algorithm::Lz4Block))
+            .unwrap();
+        assert_eq!(flags.compressor(), Some(compress::Algorithm::Lz4Block));
+        assert_eq!(flags.bits(), 0x19);
+        flags
+            .set_compressor(Some(compress::Algorithm::Zstd))
+            .unwrap();
+        assert_eq!(flags.compressor(), Some(compress::Algorithm::Zstd));
+        assert_eq!(flags.bits(), 0x39);
+        assert!(flags.contains(BlobChunkFlags::COMPRESSED | BlobChunkFlags::BATCH));
+
+        // Custom algorithms don't fit in the chunk flags.
+        assert!(flags
+            .set_compressor(Some(compress::Algorithm::Custom0))
+            .is_err());
+        assert_eq!(flags.compressor(), Some(compress::Algorithm::Zstd));
+
+        flags.set_compressor(None).unwrap();
+        assert_eq!(flags.compressor(), None);
+        assert_eq!(flags, BlobChunkFlags::COMPRESSED | BlobChunkFlags::BATCH);
+
+        let chunk: Arc<dyn BlobChunkInfo> = Arc::new(MockChunkInfo {
+            flags: BlobChunkFlags::COMPRESSED | BlobChunkFlags::from_bits_truncate(0x50),
+            ..Default::default()
+        });
+        let iochunk: BlobIoChunk = chunk.into();
+        assert_eq!(iochunk.compressor(), Some(compress::Algorithm::Lz4Frame));
     }
 
     #[test]
@@ -1641,6 +1794,48 @@ mod tests {
         assert_eq!(chunk_count, iovec.len() as u32);
     }
 
+    #[test]
+    fn test_blob_info_serialize() {
+        let mut blob_info = BlobInfo::new(
+            1,
+            "blob_id".to_owned(),
+            0x4000,
+            0x2000,
+            0x1000,
+            4,
+            BlobFeatures::ALIGNED | BlobFeatures::CHUNK_INFO_V2,
+        );
+        blob_info.set_compressor(compress::Algorithm::Zstd);
+        blob_info.set_blob_meta_info(0x2000, 0x100, 0x200, compress::Algorithm::Lz4Block as u32);
+        blob_info.set_prefetch_info(0, 0x1000);
+        blob_info.set_prefetch_ranges(vec![(0, 0x800), (0x1000, 0x400)]);
+
+        let value = serde_json::to_value(&blob_info).unwrap();
+        assert_eq!(value["blob_index"], 1);
+        assert_eq!(value["blob_id"], "blob_id");
+        assert_eq!(
+            value["features"],
+            (BlobFeatures::ALIGNED | BlobFeatures::CHUNK_INFO_V2).bits()
+        );
+        assert_eq!(
+            value["feature_names"],
+            serde_json::json!(["aligned", "chunk-v2"])
+        );
+        assert_eq!(value["chunk_size"], 0x1000);
+        assert_eq!(value["chunk_count"], 4);
+        assert_eq!(value["compressor"], "Zstd");
+        assert_eq!(value["meta_ci_compressor"], "Lz4Block");
+        assert_eq!(value["meta_ci_offset"], 0x2000);
+        assert_eq!(value["meta_ci_compressed_size"], 0x100);
+        assert_eq!(value["meta_ci_uncompressed_size"], 0x200);
+        assert_eq!(value["prefetch_size"], 0x1000);
+        assert_eq!(
+            value["prefetch_ranges"],
+            serde_json::json!([[0, 0x800], [0x1000, 0x400]])
+        );
+        assert_eq!(value["blob_toc_digest"], hex::encode([0u8; 32]));
+    }
+
     #[test]
     fn test_blob_info_blob_meta_id() {
         let blob_info = BlobInfo::new(
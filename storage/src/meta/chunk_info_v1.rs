@@ -2,6 +2,8 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use nydus_utils::compress;
+
 use crate::meta::{BlobCompressionContext, BlobMetaChunkInfo, BLOB_CCT_CHUNK_SIZE_MASK};
 use std::io::Result;
 
@@ -92,6 +94,10 @@ impl BlobMetaChunkInfo for BlobChunkInfoV1Ondisk {
         self.compressed_size() != self.uncompressed_size()
     }
 
+    fn compressor(&self) -> Option<compress::Algorithm> {
+        None
+    }
+
     fn is_zran(&self) -> bool {
         false
     }
@@ -5,6 +5,8 @@
 use std::fmt::{Display, Formatter};
 use std::io::{Error, ErrorKind, Result};
 
+use nydus_utils::compress;
+
 use crate::device::BlobFeatures;
 use crate::meta::{BlobCompressionContext, BlobMetaChunkInfo, BLOB_CCT_CHUNK_SIZE_MASK};
 
@@ -18,7 +20,10 @@ const CHUNK_V2_FLAG_COMPRESSED: u64 = 0x1 << 56;
 const CHUNK_V2_FLAG_ZRAN: u64 = 0x2 << 56;
 const CHUNK_V2_FLAG_BATCH: u64 = 0x4 << 56;
 const CHUNK_V2_FLAG_ENCRYPTED: u64 = 0x8 << 56;
-const CHUNK_V2_FLAG_VALID: u64 = 0xf << 56;
+// 3bits: compression algorithm of the chunk, zero means the blob compression algorithm.
+const CHUNK_V2_FLAG_COMPRESSOR_MASK: u64 = 0x70 << 56;
+const CHUNK_V2_FLAG_COMPRESSOR_SHIFT: u64 = 60;
+const CHUNK_V2_FLAG_VALID: u64 = 0x7f << 56;
 
 /// Chunk compression information on disk format V2.
 #[repr(C, packed)]
@@ -65,6 +70,20 @@ impl BlobChunkInfoV2Ondisk {
         }
     }
 
+    /// Set compression algorithm of the chunk, `None` means the blob compression algorithm.
+    pub(crate) fn set_compressor(&mut self, compressor: Option<compress::Algorithm>) -> Result<()> {
+        let algo = compressor.map(|v| v as u64).unwrap_or_default();
+        if algo > CHUNK_V2_FLAG_COMPRESSOR_MASK >> CHUNK_V2_FLAG_COMPRESSOR_SHIFT {
+            return Err(einval!(format!(
+                "compressor {} can't be recorded in chunk information",
+                compressor.unwrap_or_default()
+            )));
+        }
+        self.uncomp_info &= u64::to_le(!CHUNK_V2_FLAG_COMPRESSOR_MASK);
+        self.uncomp_info |= u64::to_le(algo << CHUNK_V2_FLAG_COMPRESSOR_SHIFT);
+        Ok(())
+    }
+
     pub(crate) fn set_data(&mut self, data: u64) {
         self.data = u64::to_le(data);
     }
@@ -97,6 +116,11 @@ impl BlobChunkInfoV2Ondisk {
         self.data = u64::to_le(data);
     }
 
+    fn compressor_bits(&self) -> u64 {
+        (u64::from_le(self.uncomp_info) & CHUNK_V2_FLAG_COMPRESSOR_MASK)
+            >> CHUNK_V2_FLAG_COMPRESSOR_SHIFT
+    }
+
     fn flags(&self) -> u8 {
         ((u64::from_le(self.uncomp_info) & CHUNK_V2_FLAG_MASK) >> 56) as u8
     }
@@ -169,6 +193,13 @@ impl BlobMetaChunkInfo for BlobChunkInfoV2Ondisk {
         u64::from_le(self.uncomp_info) & CHUNK_V2_FLAG_BATCH != 0
     }
 
+    fn compressor(&self) -> Option<compress::Algorithm> {
+        match self.compressor_bits() {
+            0 => None,
+            v => compress::Algorithm::try_from(v).ok(),
+        }
+    }
+
     fn get_zran_index(&self) -> Result<u32> {
         if !self.is_zran() {
             return Err(einval!("Failed to get zran_index: not a ZRan chunk"));
@@ -236,6 +267,12 @@ impl BlobMetaChunkInfo for BlobChunkInfoV2Ondisk {
                 format!("unknown chunk flags 0x{:x}", invalid_flags),
             ));
         }
+        if self.compressor_bits() != 0 && self.compressor().is_none() {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("unknown chunk compressor {}", self.compressor_bits()),
+            ));
+        }
 
         if state.blob_features & BlobFeatures::ZRAN.bits() == 0 && self.is_zran() {
             return Err(Error::new(
@@ -415,6 +452,42 @@ mod tests {
         assert_eq!(chunk.get_zran_offset().unwrap(), 5);
     }
 
+    #[test]
+    fn test_chunk_compressor() {
+        let mut chunk = BlobChunkInfoV2Ondisk::default();
+        assert_eq!(chunk.compressor(), None);
+
+        chunk.set_compressed(true);
+        chunk.set_encrypted(true);
+        chunk
+            .set_compressor(Some(compress::Algorithm::Lz4Block))
+            .unwrap();
+        assert_eq!(chunk.compressor(), Some(compress::Algorithm::Lz4Block));
+        chunk
+            .set_compressor(Some(compress::Algorithm::Zstd))
+            .unwrap();
+        assert_eq!(chunk.compressor(), Some(compress::Algorithm::Zstd));
+        assert!(chunk
+            .set_compressor(Some(compress::Algorithm::Custom1))
+            .is_err());
+        assert_eq!(chunk.compressor(), Some(compress::Algorithm::Zstd));
+        assert!(chunk.is_compressed());
+        assert!(chunk.is_encrypted());
+        assert_eq!(chunk.flags(), 0x39);
+        assert_eq!(chunk.check_flags(), 0);
+
+        chunk.set_compressor(None).unwrap();
+        assert_eq!(chunk.compressor(), None);
+        assert_eq!(chunk.flags(), 0x9);
+
+        // Unknown compression algorithm.
+        chunk.uncomp_info |= u64::to_le(0x70 << 56);
+        assert_eq!(chunk.compressor(), None);
+        assert_eq!(chunk.check_flags(), 0);
+        chunk.uncomp_info |= u64::to_le(0x80 << 56);
+        assert_eq!(chunk.check_flags(), 0x80);
+    }
+
     #[test]
     fn test_get_chunk_index_with_hole() {
         let state = BlobCompressionContext {
@@ -30,6 +30,7 @@ use std::fs::OpenOptions;
 use std::io::Result;
 use std::mem::{size_of, ManuallyDrop};
 use std::ops::{Add, BitAnd, Not};
+use std::os::unix::fs::FileExt;
 use std::path::PathBuf;
 use std::sync::Arc;
 
@@ -66,7 +67,7 @@ const BLOB_CCT_CHUNK_SIZE_MASK: u64 = 0xff_ffff;
 const BLOB_CCT_V1_MAX_SIZE: u64 = RAFS_MAX_CHUNK_SIZE * 16;
 const BLOB_CCT_V2_MAX_SIZE: u64 = RAFS_MAX_CHUNK_SIZE * 24;
 //const BLOB_CCT_V1_RESERVED_SIZE: u64 = BLOB_METADATA_HEADER_SIZE - 44;
-const BLOB_CCT_V2_RESERVED_SIZE: u64 = BLOB_CCT_HEADER_SIZE - 64;
+const BLOB_CCT_V2_RESERVED_SIZE: u64 = BLOB_CCT_HEADER_SIZE - 96;
 
 /// File suffix for blob meta file.
 const BLOB_CCT_FILE_SUFFIX: &str = "blob.meta";
@@ -110,6 +111,11 @@ pub struct BlobCompressionContextHeader {
     s_ci_zran_count: u32,
 
     s_reserved: [u8; BLOB_CCT_V2_RESERVED_SIZE as usize],
+    /// Digest of the blob information a cached blob meta file is generated for.
+    ///
+    /// It's only used by blob meta cache files to detect blobs replaced with new content, and
+    /// it's always zero in data blobs.
+    s_cache_stamp: [u8; 32],
     /// Second magic number to identify the blob meta data header.
     s_magic2: u32,
 }
@@ -128,6 +134,7 @@ impl Default for BlobCompressionContextHeader {
             s_ci_zran_size: 0,
             s_ci_zran_count: 0,
             s_reserved: [0u8; BLOB_CCT_V2_RESERVED_SIZE as usize],
+            s_cache_stamp: [0u8; 32],
             s_magic2: BLOB_CCT_MAGIC,
         }
     }
@@ -141,15 +148,7 @@ impl BlobCompressionContextHeader {
 
     /// Get compression algorithm to process chunk compression information array.
     pub fn ci_compressor(&self) -> compress::Algorithm {
-        if self.s_ci_compressor == compress::Algorithm::Lz4Block as u32 {
-            compress::Algorithm::Lz4Block
-        } else if self.s_ci_compressor == compress::Algorithm::GZip as u32 {
-            compress::Algorithm::GZip
-        } else if self.s_ci_compressor == compress::Algorithm::Zstd as u32 {
-            compress::Algorithm::Zstd
-        } else {
-            compress::Algorithm::None
-        }
+        compress::Algorithm::try_from(self.s_ci_compressor).unwrap_or(compress::Algorithm::None)
     }
 
     /// Set compression algorithm to process chunk compression information array.
@@ -345,6 +344,11 @@ impl BlobCompressionContextHeader {
         self.s_features
     }
 
+    /// Set blob meta feature flags.
+    pub fn set_features(&mut self, features: u32) {
+        self.s_features = features;
+    }
+
     /// Convert the header as an `&[u8]`.
     pub fn as_bytes(&self) -> &[u8] {
         unsafe {
@@ -363,6 +367,15 @@ impl BlobCompressionContextHeader {
             self.s_features &= !BlobFeatures::IS_CHUNKDICT_GENERATED.bits();
         }
     }
+
+    /// Set flag indicating some chunks are compressed by other algorithms than the blob's.
+    pub fn set_chunk_compressor(&mut self, enable: bool) {
+        if enable {
+            self.s_features |= BlobFeatures::CHUNK_COMPRESSOR.bits();
+        } else {
+            self.s_features &= !BlobFeatures::CHUNK_COMPRESSOR.bits();
+        }
+    }
 }
 
 /// Struct to manage blob chunk compression information, a wrapper over [BlobCompressionContext].
@@ -426,6 +439,15 @@ impl BlobCompressionContextInfo {
         let aligned_uncompressed_size = round_up_4k(uncompressed_size);
         let expected_size = BLOB_CCT_HEADER_SIZE as usize + aligned_uncompressed_size;
         let mut file_size = file.metadata()?.len();
+        if file_size != 0 && file_size != expected_size as u64 && enable_write {
+            // The blob may have been replaced with new content, regenerate the blob meta file.
+            warn!(
+                "size of blob meta file '{}' doesn't match, expect {:x}, got {:x}, regenerate it",
+                meta_path, expected_size, file_size
+            );
+            file.set_len(0)?;
+            file_size = 0;
+        }
         if file_size == 0 && enable_write {
             file.set_len(expected_size as u64)?;
             file_size = expected_size as u64;
@@ -437,29 +459,50 @@ impl BlobCompressionContextInfo {
             )));
         }
 
+        let stamp = blob_info.content_digest();
         let mut filemap = FileMapState::new(file, 0, expected_size, enable_write)?;
         let base = filemap.validate_range(0, expected_size)?;
         let header =
             filemap.get_mut::<BlobCompressionContextHeader>(aligned_uncompressed_size as usize)?;
-        if !Self::validate_header(blob_info, header)? {
+        // Blob meta files without stamp are generated by old versions, adopt them if valid.
+        let stale = header.s_cache_stamp != [0u8; 32] && header.s_cache_stamp != stamp;
+        if stale || !Self::validate_header(blob_info, header)? {
             if let Some(reader) = reader {
+                if stale {
+                    warn!(
+                        "blob meta file '{}' is generated for different blob content, regenerate it",
+                        meta_path
+                    );
+                }
                 let buffer =
                     unsafe { std::slice::from_raw_parts_mut(base as *mut u8, expected_size) };
                 Self::read_metadata(blob_info, reader, buffer)?;
                 if !Self::validate_header(blob_info, header)? {
                     return Err(enoent!(format!("double check blob_info still invalid",)));
                 }
+                header.s_cache_stamp = stamp;
                 filemap.sync_data()?;
+            } else if stale {
+                return Err(enoent!(format!(
+                    "blob meta file '{}' is generated for different blob content",
+                    meta_path
+                )));
             } else {
                 return Err(enoent!(format!(
                     "blob meta header from file '{}' is invalid",
                     meta_path
                 )));
             }
+        } else if enable_write && header.s_cache_stamp != stamp {
+            header.s_cache_stamp = stamp;
+            filemap.sync_data()?;
         }
 
         let chunk_infos = BlobMetaChunkArray::from_file_map(&filemap, blob_info)?;
         let chunk_infos = ManuallyDrop::new(chunk_infos);
+        if blob_info.has_feature(BlobFeatures::ALIGNED) {
+            Self::validate_aligned_chunks(blob_info, &chunk_infos)?;
+        }
         let mut state = BlobCompressionContext {
             blob_index: blob_info.blob_index(),
             blob_features: blob_info.features().bits(),
@@ -576,6 +619,84 @@ impl BlobCompressionContextInfo {
         })
     }
 
+    /// Get information of the blob with blob meta regenerated from RAFS metadata.
+    ///
+    /// Legacy data blobs have no blob meta information recorded in RAFS metadata, but their blob
+    /// meta may be regenerated by `nydus-image regen-meta` as a cache file, with an uncompressed
+    /// chunk compression information array which doesn't exist in the data blob. Return a copy of
+    /// `blob_info` with blob meta information from the cache file, or `None` if there's no cache
+    /// file.
+    pub(crate) fn regenerated_blob_info(
+        blob_path: &str,
+        blob_info: &BlobInfo,
+    ) -> Result<Option<BlobInfo>> {
+        if blob_info.meta_ci_is_valid() {
+            return Ok(None);
+        }
+        let meta_path = format!("{}.{}", blob_path, BLOB_CCT_FILE_SUFFIX);
+        let file = match OpenOptions::new().read(true).open(&meta_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let file_size = file.metadata()?.len();
+        if file_size < BLOB_CCT_HEADER_SIZE {
+            return Err(einval!(format!(
+                "blob meta file '{}' is too small",
+                meta_path
+            )));
+        }
+
+        let mut header = BlobCompressionContextHeader::default();
+        // Safe because the header is plain old data.
+        let buf = unsafe {
+            std::slice::from_raw_parts_mut(
+                &mut header as *mut BlobCompressionContextHeader as *mut u8,
+                size_of::<BlobCompressionContextHeader>(),
+            )
+        };
+        file.read_exact_at(buf, file_size - BLOB_CCT_HEADER_SIZE)?;
+        let ci_size = header.ci_uncompressed_size();
+        if u32::from_le(header.s_magic) != BLOB_CCT_MAGIC
+            || u32::from_le(header.s_magic2) != BLOB_CCT_MAGIC
+            || header.ci_compressor() != compress::Algorithm::None
+            || header.ci_compressed_offset() != 0
+            || header.ci_compressed_size() != ci_size
+            || ci_size == 0
+            || round_up_4k(ci_size) + BLOB_CCT_HEADER_SIZE != file_size
+        {
+            return Err(einval!(format!(
+                "blob meta file '{}' isn't regenerated for legacy blob {}",
+                meta_path,
+                blob_info.blob_id()
+            )));
+        }
+
+        let mut info = blob_info.clone();
+        info.set_blob_meta_info(0, ci_size, ci_size, compress::Algorithm::None as u32);
+        Ok(Some(info))
+    }
+
+    /// Remove blob meta, chunk digest and ToC cache files of the blob, so they will be generated
+    /// again on next access.
+    pub(crate) fn remove_cache_files(blob_path: &str) -> Result<()> {
+        for suffix in [
+            BLOB_CCT_FILE_SUFFIX,
+            BLOB_DIGEST_FILE_SUFFIX,
+            BLOB_TOC_FILE_SUFFIX,
+        ] {
+            let path = format!("{}.{}", blob_path, suffix);
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(eio!(format!("failed to remove cache file {}, {}", path, e)))
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
     /// Get data chunks covering uncompressed data range `[start, start + size)`.
     ///
     /// For 4k-aligned uncompressed data chunks, there may be padding areas between data chunks.
@@ -857,6 +978,26 @@ impl BlobCompressionContextInfo {
         Ok(())
     }
 
+    /// Check that uncompressed data of all chunks is 4K aligned, as claimed by the blob features.
+    fn validate_aligned_chunks(
+        blob_info: &BlobInfo,
+        chunk_infos: &BlobMetaChunkArray,
+    ) -> Result<()> {
+        for idx in 0..chunk_infos.len() {
+            let offset = chunk_infos.uncompressed_offset(idx);
+            if offset & 0xfff != 0 {
+                return Err(einval!(format!(
+                    "blob {} is 4K aligned, but chunk {} has unaligned uncompressed offset 0x{:x}",
+                    blob_info.blob_id(),
+                    idx,
+                    offset
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
     fn validate_header(
         blob_info: &BlobInfo,
         header: &BlobCompressionContextHeader,
@@ -1190,10 +1331,11 @@ impl BlobMetaChunkArray {
         uncompressed_offset: u64,
         uncompressed_size: u32,
         compressed: bool,
+        compressor: Option<compress::Algorithm>,
         encrypted: bool,
         is_batch: bool,
         data: u64,
-    ) {
+    ) -> Result<()> {
         match self {
             BlobMetaChunkArray::V2(v) => {
                 let mut meta = BlobChunkInfoV2Ondisk::default();
@@ -1202,6 +1344,7 @@ impl BlobMetaChunkArray {
                 meta.set_uncompressed_offset(uncompressed_offset);
                 meta.set_uncompressed_size(uncompressed_size);
                 meta.set_compressed(compressed);
+                meta.set_compressor(compressor)?;
                 meta.set_encrypted(encrypted);
                 meta.set_batch(is_batch);
                 meta.set_data(data);
@@ -1209,6 +1352,7 @@ impl BlobMetaChunkArray {
             }
             BlobMetaChunkArray::V1(_v) => unimplemented!(),
         }
+        Ok(())
     }
 
     /// Add an entry of pre-built v2 chunk compression information into the array.
@@ -1390,6 +1534,13 @@ impl BlobMetaChunkArray {
         }
     }
 
+    fn compressor(&self, index: usize) -> Option<compress::Algorithm> {
+        match self {
+            BlobMetaChunkArray::V1(v) => v[index].compressor(),
+            BlobMetaChunkArray::V2(v) => v[index].compressor(),
+        }
+    }
+
     fn _get_chunk_index_nocheck<T: BlobMetaChunkInfo>(
         state: &BlobCompressionContext,
         chunks: &[T],
@@ -1888,6 +2039,10 @@ impl BlobChunkInfo for BlobMetaChunk {
         self.meta.chunk_info_array.is_encrypted(self.chunk_index)
     }
 
+    fn compressor(&self) -> Option<compress::Algorithm> {
+        self.meta.chunk_info_array.compressor(self.chunk_index)
+    }
+
     fn as_any(&self) -> &dyn Any {
         self
     }
@@ -1908,6 +2063,8 @@ impl BlobV5ChunkInfo for BlobMetaChunk {
         if self.is_compressed() {
             flags |= BlobChunkFlags::COMPRESSED;
         }
+        // Compressors decoded from the chunk information always fit in the chunk flags.
+        let _ = flags.set_compressor(self.compressor());
         flags
     }
 
@@ -1966,6 +2123,9 @@ pub trait BlobMetaChunkInfo {
     /// compressed.
     fn is_compressed(&self) -> bool;
 
+    /// Get compression algorithm of the chunk, `None` means the blob compression algorithm.
+    fn compressor(&self) -> Option<compress::Algorithm>;
+
     /// Check whether the chunk has associated Batch context data.
     fn is_batch(&self) -> bool;
 
@@ -2049,6 +2209,7 @@ pub(crate) mod tests {
     use std::fs::File;
     use std::os::unix::io::AsRawFd;
     use std::path::PathBuf;
+    use vmm_sys_util::tempdir::TempDir;
 
     pub(crate) struct DummyBlobReader {
         pub metrics: Arc<BackendMetrics>,
@@ -2080,6 +2241,31 @@ pub(crate) mod tests {
         assert_eq!(round_up_4k(0x1fff), 0x2000u64);
     }
 
+    #[test]
+    fn test_validate_aligned_chunks() {
+        let blob_info = BlobInfo::new(
+            0,
+            "blob".to_string(),
+            0x3000,
+            0x2000,
+            RAFS_DEFAULT_CHUNK_SIZE as u32,
+            2,
+            BlobFeatures::ALIGNED | BlobFeatures::CHUNK_INFO_V2,
+        );
+        let mut chunks = BlobMetaChunkArray::new_v2();
+        chunks
+            .add_v2(0, 0x100, 0, 0x800, true, None, false, false, 0)
+            .unwrap();
+        chunks
+            .add_v2(0x100, 0x100, 0x1000, 0x800, true, None, false, false, 0)
+            .unwrap();
+        assert!(BlobCompressionContextInfo::validate_aligned_chunks(&blob_info, &chunks).is_ok());
+        chunks
+            .add_v2(0x200, 0x100, 0x1800, 0x800, true, None, false, false, 0)
+            .unwrap();
+        assert!(BlobCompressionContextInfo::validate_aligned_chunks(&blob_info, &chunks).is_err());
+    }
+
     #[test]
     fn test_load_meta_ci_zran_add_more_chunks() {
         let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
@@ -2135,6 +2321,50 @@ pub(crate) mod tests {
         assert_eq!(chunks.len(), 12);
     }
 
+    #[test]
+    fn test_load_meta_ci_cache_stamp() {
+        let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
+        let blob_id = "233c72f2b6b698c07021c4da367cfe2dff4f049efbaa885ca0ff760ea297865a";
+        let source = PathBuf::from(root_dir)
+            .join("../tests/texture/zran")
+            .join(format!("{}.{}", blob_id, BLOB_CCT_FILE_SUFFIX));
+        let tmp_dir = TempDir::new().unwrap();
+        let path = tmp_dir.as_path().join(blob_id);
+        let meta_path = tmp_dir
+            .as_path()
+            .join(format!("{}.{}", blob_id, BLOB_CCT_FILE_SUFFIX));
+        std::fs::copy(source, &meta_path).unwrap();
+
+        let features = BlobFeatures::ALIGNED
+            | BlobFeatures::INLINED_FS_META
+            | BlobFeatures::CHUNK_INFO_V2
+            | BlobFeatures::ZRAN;
+        let mut blob_info = BlobInfo::new(
+            0,
+            blob_id.to_string(),
+            0x16c6000,
+            9839040,
+            RAFS_DEFAULT_CHUNK_SIZE as u32,
+            0xa3,
+            features,
+        );
+        blob_info.set_blob_meta_info(0, 0xa1290, 0xa1290, compress::Algorithm::None as u32);
+        let stamp_offset = round_up_4k(0xa1290u64) + BLOB_CCT_HEADER_SIZE - 36;
+        let write_stamp = |stamp: &[u8; 32]| {
+            let file = OpenOptions::new().write(true).open(&meta_path).unwrap();
+            file.write_all_at(stamp, stamp_offset).unwrap();
+        };
+        let path = path.display().to_string();
+
+        // Files without stamp are adopted.
+        assert!(BlobCompressionContextInfo::new(&path, &blob_info, None, false).is_ok());
+        write_stamp(&blob_info.content_digest());
+        assert!(BlobCompressionContextInfo::new(&path, &blob_info, None, false).is_ok());
+        // Files generated for different blob content are rejected.
+        write_stamp(&[1u8; 32]);
+        assert!(BlobCompressionContextInfo::new(&path, &blob_info, None, false).is_err());
+    }
+
     #[test]
     fn test_load_meta_ci_zran_get_chunks_uncompressed() {
         let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
@@ -2309,6 +2539,10 @@ pub(crate) mod tests {
         assert_eq!(header.ci_compressor(), compress::Algorithm::Lz4Block);
         header.set_ci_compressor(compress::Algorithm::GZip);
         assert_eq!(header.ci_compressor(), compress::Algorithm::GZip);
+        header.set_ci_compressor(compress::Algorithm::Lz4Hc);
+        assert_eq!(header.ci_compressor(), compress::Algorithm::Lz4Hc);
+        header.set_ci_compressor(compress::Algorithm::Lz4Frame);
+        assert_eq!(header.ci_compressor(), compress::Algorithm::Lz4Frame);
         header.set_ci_compressor(compress::Algorithm::Zstd);
         assert_eq!(header.ci_compressor(), compress::Algorithm::Zstd);
 
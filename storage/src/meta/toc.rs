@@ -34,6 +34,10 @@ pub const TOC_ENTRY_BLOB_META_HEADER: &str = "blob.meta.header";
 pub const TOC_ENTRY_BLOB_DIGEST: &str = "blob.digest";
 /// File name for RAFS blob ToC table.
 pub const TOC_ENTRY_BLOB_TOC: &str = "rafs.blob.toc";
+/// File name for zero padding to align the tail of the data blob.
+pub const TOC_ENTRY_BLOB_PADDING: &str = "blob.padding";
+/// File name for zero padding to align the blob compression context table.
+pub const TOC_ENTRY_BLOB_META_PADDING: &str = "blob.meta.pad";
 
 bitflags! {
     #[derive(Serialize)]
@@ -45,6 +49,8 @@ bitflags! {
         const COMPRESSION_ZSTD = 0x0002;
         /// Entry data is compressed with lz4.
         const COMPRESSION_LZ4_BLOCK = 0x0004;
+        /// Entry data is compressed with lz4 in frame format.
+        const COMPRESSION_LZ4_FRAME = 0x0008;
         /// Bit mask for compression algorithms.
         const COMPRESSION_MASK = 0x000f;
     }
@@ -57,7 +63,17 @@ impl TryFrom<compress::Algorithm> for TocEntryFlags {
         match c {
             compress::Algorithm::None => Ok(Self::COMPRESSION_NONE),
             compress::Algorithm::Zstd => Ok(Self::COMPRESSION_ZSTD),
-            compress::Algorithm::Lz4Block => Ok(Self::COMPRESSION_LZ4_BLOCK),
+            // Data compressed by lz4hc is in lz4 block format too.
+            compress::Algorithm::Lz4Block | compress::Algorithm::Lz4Hc => {
+                Ok(Self::COMPRESSION_LZ4_BLOCK)
+            }
+            compress::Algorithm::Lz4Frame => Ok(Self::COMPRESSION_LZ4_FRAME),
+            // ToC entries are decoded without the compressor registry, so there's no way to
+            // decompress entries compressed by custom algorithms.
+            c if c.is_custom() => Err(einval!(format!(
+                "custom compressor {} isn't supported by blob ToC entries",
+                c
+            ))),
             _ => Err(eother!(format!("unsupported compressor {}", c,))),
         }
     }
@@ -137,6 +153,7 @@ impl TocEntry {
         let algo = match flags & TocEntryFlags::COMPRESSION_MASK {
             TocEntryFlags::COMPRESSION_ZSTD => compress::Algorithm::Zstd,
             TocEntryFlags::COMPRESSION_LZ4_BLOCK => compress::Algorithm::Lz4Block,
+            TocEntryFlags::COMPRESSION_LZ4_FRAME => compress::Algorithm::Lz4Frame,
             TocEntryFlags::COMPRESSION_NONE => compress::Algorithm::None,
             _ => return Err(einval!("unknown compression algorithm for TOC entry")),
         };
@@ -169,8 +186,11 @@ impl TocEntry {
             self.compressed_size,
         );
 
-        if self.flags & TocEntryFlags::COMPRESSION_ZSTD.bits() != 0 {
-            let mut decoder = Decoder::new(buf_reader, compress::Algorithm::Zstd)
+        if self.flags
+            & (TocEntryFlags::COMPRESSION_ZSTD | TocEntryFlags::COMPRESSION_LZ4_FRAME).bits()
+            != 0
+        {
+            let mut decoder = Decoder::new(buf_reader, self.compressor()?)
                 .map_err(|_| eother!("failed to create decoder"))?;
             let mut buf = alloc_buf(0x40000);
             loop {
@@ -228,8 +248,11 @@ impl TocEntry {
         let mut hasher = digest::RafsDigest::hasher(digest::Algorithm::Sha256);
         let mut count = 0;
 
-        if self.flags & TocEntryFlags::COMPRESSION_ZSTD.bits() != 0 {
-            let mut decoder = Decoder::new(buf, compress::Algorithm::Zstd)
+        if self.flags
+            & (TocEntryFlags::COMPRESSION_ZSTD | TocEntryFlags::COMPRESSION_LZ4_FRAME).bits()
+            != 0
+        {
+            let mut decoder = Decoder::new(buf, self.compressor()?)
                 .map_err(|_| eother!("failed to create decoder"))?;
             let mut buf = alloc_buf(0x40000);
             loop {
@@ -629,11 +652,20 @@ impl TocEntryList {
         let reader = blob_mgr
             .get_reader(id)
             .map_err(|e| eother!(format!("failed to get reader for blob {}, {}", id, e)))?;
+        Self::extract_rafs_meta_from_blob(reader, &path)?;
+
+        Ok(path)
+    }
+
+    /// Extract inlined RAFS metadata from a [BlobReader] into file `path`.
+    ///
+    /// The RAFS metadata is located by the ToC, or by the tar header at the end of the data blob.
+    pub fn extract_rafs_meta_from_blob(reader: Arc<dyn BlobReader>, path: &Path) -> Result<()> {
         let location = TocLocation::default();
         let (buf, blob_size) = Self::read_toc_header(reader.as_ref(), &location)?;
 
         if let Ok(toc) = Self::parse_toc_header(&buf, &location) {
-            toc.extract_from_blob(reader, Some(path.clone()), None)?;
+            toc.extract_from_blob(reader, Some(path), None)?;
         } else {
             if buf.len() < 512 {
                 return Err(einval!(format!("blob ToC size {} is too small", buf.len())));
@@ -678,10 +710,10 @@ impl TocEntryList {
                 entry_size,
                 entry_size,
             )?;
-            toc.extract_from_blob(reader, Some(path.clone()), None)?;
+            toc.extract_from_blob(reader, Some(path), None)?;
         }
 
-        Ok(path)
+        Ok(())
     }
 }
 
@@ -902,7 +934,26 @@ mod tests {
         assert_eq!(flags, TocEntryFlags::COMPRESSION_LZ4_BLOCK);
         let flags = TocEntryFlags::try_from(compress::Algorithm::Zstd).unwrap();
         assert_eq!(flags, TocEntryFlags::COMPRESSION_ZSTD);
+        let flags = TocEntryFlags::try_from(compress::Algorithm::Lz4Hc).unwrap();
+        assert_eq!(flags, TocEntryFlags::COMPRESSION_LZ4_BLOCK);
+        let flags = TocEntryFlags::try_from(compress::Algorithm::Lz4Frame).unwrap();
+        assert_eq!(flags, TocEntryFlags::COMPRESSION_LZ4_FRAME);
+
+        let mut entry = TocEntry::default();
+        entry.set_compressor(compress::Algorithm::Lz4Frame).unwrap();
+        assert_eq!(entry.compressor().unwrap(), compress::Algorithm::Lz4Frame);
+        let data = compress::compress(&[0x5au8; 0x1000], compress::Algorithm::Lz4Frame)
+            .unwrap()
+            .0;
+        entry.compressed_size = data.len() as u64;
+        entry.uncompressed_size = 0x1000;
+        entry.uncompressed_digest =
+            RafsDigest::from_buf(&[0x5au8; 0x1000], digest::Algorithm::Sha256).data;
+        let mut output = Vec::new();
+        entry.extract_from_buf(&data, &mut output).unwrap();
+        assert_eq!(output, vec![0x5au8; 0x1000]);
         let _e = TocEntryFlags::try_from(compress::Algorithm::GZip).unwrap_err();
+        let _e = TocEntryFlags::try_from(compress::Algorithm::Custom0).unwrap_err();
     }
 
     fn extract_from_buf_with_different_flags(entry: &TocEntry, buf: &[u8]) -> Result<String> {
@@ -5,6 +5,7 @@
 
 use std::sync::Arc;
 
+use nydus_utils::compress;
 use nydus_utils::digest::RafsDigest;
 use nydus_utils::metrics::BackendMetrics;
 
@@ -97,6 +98,10 @@ impl BlobChunkInfo for MockChunkInfo {
         false
     }
 
+    fn compressor(&self) -> Option<compress::Algorithm> {
+        self.flags.compressor()
+    }
+
     fn as_any(&self) -> &dyn Any {
         self
     }
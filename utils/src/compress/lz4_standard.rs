@@ -1,10 +1,15 @@
 // Copyright (C) 2020 Alibaba Cloud. All rights reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::io::Result;
+use std::io::{Read, Result, Write};
 
-use libc::c_char;
-use lz4_sys::{LZ4_compressBound, LZ4_compress_default, LZ4_decompress_safe};
+use libc::{c_char, c_int};
+use lz4_sys::{LZ4_compressBound, LZ4_compress_HC, LZ4_compress_default, LZ4_decompress_safe};
+
+/// Default compression level for lz4hc, same as `LZ4HC_CLEVEL_DEFAULT`.
+pub(super) const LZ4HC_DEFAULT_LEVEL: u32 = 9;
+/// Maximum compression level for lz4hc, same as `LZ4HC_CLEVEL_MAX`.
+pub(super) const LZ4HC_MAX_LEVEL: u32 = 12;
 
 pub(super) fn lz4_compress(src: &[u8]) -> Result<Vec<u8>> {
     // 0 iff src too large
@@ -33,6 +38,58 @@ pub(super) fn lz4_compress(src: &[u8]) -> Result<Vec<u8>> {
     Ok(dst_buf)
 }
 
+pub(super) fn lz4hc_compress(src: &[u8], level: u32) -> Result<Vec<u8>> {
+    if level > LZ4HC_MAX_LEVEL {
+        return Err(einval!(format!(
+            "lz4hc compression level should be in range [0, {}]",
+            LZ4HC_MAX_LEVEL
+        )));
+    }
+    // 0 iff src too large
+    let compress_bound: i32 = unsafe { LZ4_compressBound(src.len() as i32) };
+
+    if src.len() > (i32::max_value() as usize) || compress_bound <= 0 {
+        return Err(einval!("compression input data is too big"));
+    }
+
+    let mut dst_buf = Vec::with_capacity(compress_bound as usize);
+    let cmp_size = unsafe {
+        LZ4_compress_HC(
+            src.as_ptr() as *const c_char,
+            dst_buf.as_mut_ptr() as *mut c_char,
+            src.len() as i32,
+            compress_bound,
+            level as c_int,
+        )
+    };
+    if cmp_size <= 0 {
+        return Err(eio!("compression failed"));
+    }
+
+    assert!(cmp_size as usize <= dst_buf.capacity());
+    unsafe { dst_buf.set_len(cmp_size as usize) };
+
+    Ok(dst_buf)
+}
+
+pub(super) fn lz4_frame_compress(src: &[u8], level: u32) -> Result<Vec<u8>> {
+    let mut encoder = lz4::EncoderBuilder::new()
+        .level(level)
+        .build(Vec::with_capacity(src.len()))?;
+    encoder.write_all(src)?;
+    let (dst_buf, result) = encoder.finish();
+    result?;
+
+    Ok(dst_buf)
+}
+
+pub(super) fn lz4_frame_decompress(src: &[u8], dst: &mut [u8]) -> Result<usize> {
+    let mut decoder = lz4::Decoder::new(src)?;
+    decoder.read_exact(dst)?;
+
+    Ok(dst.len())
+}
+
 pub(super) fn lz4_decompress(src: &[u8], dst: &mut [u8]) -> Result<usize> {
     if dst.len() >= std::i32::MAX as usize {
         return Err(einval!("the destination buffer is big than i32::MAX"));
@@ -69,5 +126,7 @@ mod tests {
         let mock_compressed = vec![0x0u8; 32];
         assert!(lz4_compress(&big_buf).is_err());
         assert!(lz4_decompress(&mock_compressed, big_buf.as_mut_slice()).is_err());
+        assert!(lz4hc_compress(&big_buf, LZ4HC_DEFAULT_LEVEL).is_err());
+        assert!(lz4hc_compress(&mock_compressed, LZ4HC_MAX_LEVEL + 1).is_err());
     }
 }
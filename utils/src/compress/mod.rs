@@ -3,10 +3,12 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt;
 use std::io::{BufReader, Error, Read, Result, Write};
 use std::str::FromStr;
+use std::sync::{Arc, RwLock};
 
 mod lz4_standard;
 use self::lz4_standard::*;
@@ -16,7 +18,13 @@ pub mod zlib_random;
 
 const COMPRESSION_MINIMUM_RATIO: usize = 100;
 
+/// First algorithm ID reserved for compression algorithms registered by downstream crates.
+pub const CUSTOM_ALGORITHM_BASE: u32 = 0x100;
+
 /// Supported compression algorithms.
+///
+/// Algorithms `Custom0` to `Custom3` have reserved IDs in the on-disk format, and are only usable
+/// after registering an implementation by [register_compressor()].
 #[repr(u32)]
 #[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
 pub enum Algorithm {
@@ -25,11 +33,33 @@ pub enum Algorithm {
     Lz4Block = 1,
     GZip = 2,
     Zstd = 3,
+    Lz4Hc = 4,
+    Lz4Frame = 5,
+    Custom0 = CUSTOM_ALGORITHM_BASE,
+    Custom1 = CUSTOM_ALGORITHM_BASE + 1,
+    Custom2 = CUSTOM_ALGORITHM_BASE + 2,
+    Custom3 = CUSTOM_ALGORITHM_BASE + 3,
 }
 
+const ALGORITHMS: [Algorithm; 10] = [
+    Algorithm::None,
+    Algorithm::Lz4Block,
+    Algorithm::GZip,
+    Algorithm::Zstd,
+    Algorithm::Lz4Hc,
+    Algorithm::Lz4Frame,
+    Algorithm::Custom0,
+    Algorithm::Custom1,
+    Algorithm::Custom2,
+    Algorithm::Custom3,
+];
+
 impl fmt::Display for Algorithm {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match get_compressor(*self) {
+            Some(c) => write!(f, "{}", c.name()),
+            None => write!(f, "{:?}", self),
+        }
     }
 }
 
@@ -42,7 +72,19 @@ impl FromStr for Algorithm {
             "lz4_block" => Ok(Self::Lz4Block),
             "gzip" => Ok(Self::GZip),
             "zstd" => Ok(Self::Zstd),
-            _ => Err(einval!("compression algorithm should be none or lz4_block")),
+            "lz4hc" => Ok(Self::Lz4Hc),
+            "lz4_frame" => Ok(Self::Lz4Frame),
+            _ => COMPRESSORS
+                .read()
+                .unwrap()
+                .iter()
+                .find(|(_, c)| c.name() == s)
+                .map(|(algo, _)| *algo)
+                .ok_or_else(|| {
+                    einval!(
+                        "compression algorithm should be none, lz4_block, lz4hc, lz4_frame, gzip, zstd or a registered one"
+                    )
+                }),
         }
     }
 }
@@ -50,18 +92,17 @@ impl FromStr for Algorithm {
 impl TryFrom<u32> for Algorithm {
     type Error = ();
 
+    /// Convert an on-disk algorithm ID, custom algorithms are valid only if registered.
     fn try_from(value: u32) -> std::result::Result<Self, Self::Error> {
-        if value == Algorithm::None as u32 {
-            Ok(Algorithm::None)
-        } else if value == Algorithm::Lz4Block as u32 {
-            Ok(Algorithm::Lz4Block)
-        } else if value == Algorithm::GZip as u32 {
-            Ok(Algorithm::GZip)
-        } else if value == Algorithm::Zstd as u32 {
-            Ok(Algorithm::Zstd)
-        } else {
-            Err(())
+        let algo = ALGORITHMS
+            .iter()
+            .find(|algo| **algo as u32 == value)
+            .copied()
+            .ok_or(())?;
+        if algo.is_custom() && get_compressor(algo).is_none() {
+            return Err(());
         }
+        Ok(algo)
     }
 }
 
@@ -69,17 +110,8 @@ impl TryFrom<u64> for Algorithm {
     type Error = ();
 
     fn try_from(value: u64) -> std::result::Result<Self, Self::Error> {
-        if value == Algorithm::None as u64 {
-            Ok(Algorithm::None)
-        } else if value == Algorithm::Lz4Block as u64 {
-            Ok(Algorithm::Lz4Block)
-        } else if value == Algorithm::GZip as u64 {
-            Ok(Algorithm::GZip)
-        } else if value == Algorithm::Zstd as u64 {
-            Ok(Algorithm::Zstd)
-        } else {
-            Err(())
-        }
+        let value = u32::try_from(value).map_err(|_| ())?;
+        Self::try_from(value)
     }
 }
 
@@ -88,10 +120,108 @@ impl Algorithm {
     pub fn is_none(self) -> bool {
         self == Self::None
     }
+
+    /// Check whether the compressed data is in lz4 raw block format.
+    ///
+    /// Data compressed by lz4hc is in lz4 block format too, so it shares the same decompressor.
+    pub fn is_lz4_block(self) -> bool {
+        self == Self::Lz4Block || self == Self::Lz4Hc
+    }
+
+    /// Check whether the compression algorithm supports the compression level option.
+    pub fn has_level(self) -> bool {
+        match self {
+            Self::Lz4Hc | Self::Lz4Frame | Self::Zstd => true,
+            algo if algo.is_custom() => get_compressor(algo).is_some_and(|c| c.has_level()),
+            _ => false,
+        }
+    }
+
+    /// Check whether the algorithm ID is reserved for algorithms registered by downstream crates.
+    pub fn is_custom(self) -> bool {
+        self as u32 >= CUSTOM_ALGORITHM_BASE
+    }
+}
+
+/// Compression algorithm implemented by downstream crates.
+pub trait Compressor: Send + Sync {
+    /// Name of the algorithm, used to parse and display the algorithm.
+    fn name(&self) -> &str;
+
+    /// Compress data with an optional compression level.
+    fn compress(&self, src: &[u8], level: Option<u32>) -> Result<Vec<u8>>;
+
+    /// Decompress data into `dst`, which is sized to the uncompressed data, return the size of
+    /// decompressed data.
+    fn decompress(&self, src: &[u8], dst: &mut [u8]) -> Result<usize>;
+
+    /// Whether the algorithm supports the compression level option.
+    fn has_level(&self) -> bool {
+        false
+    }
+}
+
+lazy_static! {
+    static ref COMPRESSORS: RwLock<HashMap<Algorithm, Arc<dyn Compressor>>> =
+        RwLock::new(HashMap::new());
+}
+
+/// Register an implementation for the custom compression algorithm `algorithm`.
+///
+/// The algorithm ID is persisted into RAFS metadata and blob meta, so the same algorithm must be
+/// registered with the same implementation by every program accessing the generated images.
+#[cfg(feature = "custom-algorithm")]
+pub fn register_compressor(algorithm: Algorithm, compressor: Arc<dyn Compressor>) -> Result<()> {
+    if !algorithm.is_custom() {
+        return Err(einval!(format!(
+            "can't register builtin compression algorithm {}",
+            algorithm
+        )));
+    }
+    let mut compressors = COMPRESSORS.write().unwrap();
+    if compressors.contains_key(&algorithm)
+        || compressors.values().any(|c| c.name() == compressor.name())
+    {
+        return Err(einval!(format!(
+            "compression algorithm {:?}/{} has already been registered",
+            algorithm,
+            compressor.name()
+        )));
+    }
+    compressors.insert(algorithm, compressor);
+    Ok(())
+}
+
+fn get_compressor(algorithm: Algorithm) -> Option<Arc<dyn Compressor>> {
+    if !algorithm.is_custom() {
+        return None;
+    }
+    COMPRESSORS.read().unwrap().get(&algorithm).cloned()
+}
+
+fn custom_compressor(algorithm: Algorithm) -> Result<Arc<dyn Compressor>> {
+    get_compressor(algorithm).ok_or_else(|| {
+        einval!(format!(
+            "compression algorithm {:?} isn't registered",
+            algorithm
+        ))
+    })
 }
 
 /// Compress data with the specified compression algorithm.
 pub fn compress(src: &[u8], algorithm: Algorithm) -> Result<(Cow<[u8]>, bool)> {
+    compress_with_level(src, algorithm, None)
+}
+
+/// Compress data with the specified compression algorithm and compression level.
+///
+/// The `level` is only used by lz4hc, lz4_frame and zstd, the default level of the algorithm is
+/// used if it's `None`.
+pub fn compress_with_level(
+    src: &[u8],
+    algorithm: Algorithm,
+    level: Option<u32>,
+) -> Result<(Cow<[u8]>, bool)> {
     let src_size = src.len();
     if src_size == 0 {
         return Ok((Cow::Borrowed(src), false));
@@ -106,7 +236,10 @@ pub fn compress(src: &[u8], algorithm: Algorithm) -> Result<(Cow<[u8]>, bool)> {
             gz.write_all(src)?;
             gz.finish()?
         }
-        Algorithm::Zstd => zstd_compress(src)?,
+        Algorithm::Zstd => zstd_compress(src, level)?,
+        Algorithm::Lz4Hc => lz4hc_compress(src, level.unwrap_or(LZ4HC_DEFAULT_LEVEL))?,
+        Algorithm::Lz4Frame => lz4_frame_compress(src, level.unwrap_or(0))?,
+        _ => custom_compressor(algorithm)?.compress(src, level)?,
     };
 
     // Abandon compressed data when compression ratio greater than COMPRESSION_MINIMUM_RATIO
@@ -128,22 +261,25 @@ pub fn decompress(src: &[u8], dst: &mut [u8], algorithm: Algorithm) -> Result<us
             dst.copy_from_slice(src);
             Ok(dst.len())
         }
-        Algorithm::Lz4Block => lz4_decompress(src, dst),
+        Algorithm::Lz4Block | Algorithm::Lz4Hc => lz4_decompress(src, dst),
+        Algorithm::Lz4Frame => lz4_frame_decompress(src, dst),
         Algorithm::GZip => {
             let mut gz = flate2::bufread::GzDecoder::new(src);
             gz.read_exact(dst)?;
             Ok(dst.len())
         }
         Algorithm::Zstd => zstd::bulk::decompress_to_buffer(src, dst),
+        _ => custom_compressor(algorithm)?.decompress(src, dst),
     }
 }
 
 #[allow(clippy::large_enum_variant)]
-/// Stream decoder for gzip/lz4/zstd.
+/// Stream decoder for gzip/lz4_frame/zstd.
 pub enum Decoder<'a, R: Read> {
     None(R),
     Gzip(flate2::bufread::MultiGzDecoder<BufReader<R>>),
     Zstd(zstd::stream::Decoder<'a, BufReader<R>>),
+    Lz4Frame(lz4::Decoder<R>),
 }
 
 impl<'a, R: Read> Decoder<'a, R> {
@@ -155,7 +291,15 @@ impl<'a, R: Read> Decoder<'a, R> {
                 Decoder::Gzip(flate2::bufread::MultiGzDecoder::new(BufReader::new(reader)))
             }
             Algorithm::Lz4Block => panic!("Decoder doesn't support lz4_block"),
+            Algorithm::Lz4Hc => panic!("Decoder doesn't support lz4hc"),
+            Algorithm::Lz4Frame => Decoder::Lz4Frame(lz4::Decoder::new(reader)?),
             Algorithm::Zstd => Decoder::Zstd(zstd::stream::Decoder::new(reader)?),
+            _ => {
+                return Err(einval!(format!(
+                    "stream decoder doesn't support compression algorithm {}",
+                    algorithm
+                )))
+            }
         };
         Ok(decoder)
     }
@@ -167,6 +311,7 @@ impl<'a, R: Read> Read for Decoder<'a, R> {
             Decoder::None(r) => r.read(buf),
             Decoder::Gzip(r) => r.read(buf),
             Decoder::Zstd(r) => r.read(buf),
+            Decoder::Lz4Frame(r) => r.read(buf),
         }
     }
 }
@@ -240,8 +385,11 @@ pub fn compute_compressed_gzip_size(size: usize, max_size: usize) -> usize {
     std::cmp::min(size, max_size)
 }
 
-fn zstd_compress(src: &[u8]) -> Result<Vec<u8>> {
-    zstd::bulk::compress(src, zstd::DEFAULT_COMPRESSION_LEVEL)
+fn zstd_compress(src: &[u8], level: Option<u32>) -> Result<Vec<u8>> {
+    let level = level
+        .map(|l| l as i32)
+        .unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL);
+    zstd::bulk::compress(src, level)
 }
 
 #[cfg(test)]
@@ -592,5 +740,123 @@ mod tests {
         assert!(!Algorithm::Lz4Block.is_none());
         assert!(!Algorithm::GZip.is_none());
         assert!(!Algorithm::Zstd.is_none());
+        assert_eq!(
+            Algorithm::try_from(Algorithm::Lz4Hc as u32).unwrap(),
+            Algorithm::Lz4Hc
+        );
+        assert_eq!(
+            Algorithm::try_from(Algorithm::Lz4Frame as u64).unwrap(),
+            Algorithm::Lz4Frame
+        );
+        assert!(Algorithm::Lz4Hc.is_lz4_block());
+        assert!(!Algorithm::Lz4Frame.is_lz4_block());
+        assert_eq!(Algorithm::from_str("lz4hc").unwrap(), Algorithm::Lz4Hc);
+        assert_eq!(
+            Algorithm::from_str("lz4_frame").unwrap(),
+            Algorithm::Lz4Frame
+        );
+    }
+
+    #[test]
+    fn test_compress_algorithm_lz4hc() {
+        let buf = vec![0x3u8; 4097];
+        let mut decompressed = vec![0; buf.len()];
+        for level in [None, Some(1), Some(12)] {
+            let (compressed, is_compressed) =
+                compress_with_level(&buf, Algorithm::Lz4Hc, level).unwrap();
+            assert!(is_compressed);
+            // Data compressed by lz4hc can be decompressed as lz4_block.
+            let sz = decompress(
+                &compressed,
+                decompressed.as_mut_slice(),
+                Algorithm::Lz4Block,
+            )
+            .unwrap();
+            assert_eq!(sz, 4097);
+            assert_eq!(buf, decompressed);
+        }
+        assert!(compress_with_level(&buf, Algorithm::Lz4Hc, Some(13)).is_err());
+    }
+
+    #[test]
+    fn test_compress_algorithm_lz4_frame() {
+        let buf = vec![0x4u8; 4097];
+        let (compressed, is_compressed) =
+            compress_with_level(&buf, Algorithm::Lz4Frame, Some(9)).unwrap();
+        assert!(is_compressed);
+
+        let mut decompressed = vec![0; buf.len()];
+        let sz = decompress(
+            &compressed,
+            decompressed.as_mut_slice(),
+            Algorithm::Lz4Frame,
+        )
+        .unwrap();
+        assert_eq!(sz, 4097);
+        assert_eq!(buf, decompressed);
+
+        let mut decompressed = vec![0; buf.len()];
+        let mut decoder = Decoder::new(compressed.as_ref(), Algorithm::Lz4Frame).unwrap();
+        decoder.read_exact(decompressed.as_mut_slice()).unwrap();
+        assert_eq!(buf, decompressed);
+    }
+
+    #[test]
+    fn test_unregistered_custom_algorithm() {
+        let buf = vec![0x5u8; 4097];
+        assert!(Algorithm::Custom2.is_custom());
+        assert!(!Algorithm::Zstd.is_custom());
+        assert!(Algorithm::try_from(Algorithm::Custom2 as u32).is_err());
+        assert!(compress(&buf, Algorithm::Custom2).is_err());
+        let mut decompressed = vec![0; buf.len()];
+        assert!(decompress(&buf, &mut decompressed, Algorithm::Custom2).is_err());
+        assert_eq!(Algorithm::Custom2.to_string(), "Custom2");
+    }
+
+    #[cfg(feature = "custom-algorithm")]
+    #[test]
+    fn test_register_compressor() {
+        struct ZstdCompressor;
+
+        impl Compressor for ZstdCompressor {
+            fn name(&self) -> &str {
+                "test_zstd"
+            }
+
+            fn compress(&self, src: &[u8], level: Option<u32>) -> Result<Vec<u8>> {
+                zstd_compress(src, level)
+            }
+
+            fn decompress(&self, src: &[u8], dst: &mut [u8]) -> Result<usize> {
+                zstd::bulk::decompress_to_buffer(src, dst)
+            }
+
+            fn has_level(&self) -> bool {
+                true
+            }
+        }
+
+        assert!(register_compressor(Algorithm::Zstd, Arc::new(ZstdCompressor)).is_err());
+        register_compressor(Algorithm::Custom3, Arc::new(ZstdCompressor)).unwrap();
+        assert!(register_compressor(Algorithm::Custom3, Arc::new(ZstdCompressor)).is_err());
+        assert_eq!(
+            Algorithm::try_from(Algorithm::Custom3 as u32).unwrap(),
+            Algorithm::Custom3
+        );
+        assert_eq!(
+            Algorithm::from_str("test_zstd").unwrap(),
+            Algorithm::Custom3
+        );
+        assert_eq!(Algorithm::Custom3.to_string(), "test_zstd");
+        assert!(Algorithm::Custom3.has_level());
+
+        let buf = vec![0x6u8; 4097];
+        let (compressed, is_compressed) =
+            compress_with_level(&buf, Algorithm::Custom3, Some(3)).unwrap();
+        assert!(is_compressed);
+        let mut decompressed = vec![0; buf.len()];
+        let sz = decompress(&compressed, &mut decompressed, Algorithm::Custom3).unwrap();
+        assert_eq!(sz, 4097);
+        assert_eq!(buf, decompressed);
     }
 }
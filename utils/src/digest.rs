@@ -5,10 +5,12 @@
 
 //! Fast message digest algorithms for Rafs and Nydus, including Blake3 and SHA256.
 
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt;
 use std::io::{Error, Read};
 use std::str::FromStr;
+use std::sync::{Arc, RwLock};
 
 use sha2::digest::Digest;
 use sha2::Sha256;
@@ -16,20 +18,43 @@ use sha2::Sha256;
 /// Size in bytes of chunk digest value.
 pub const RAFS_DIGEST_LENGTH: usize = 32;
 
+/// First algorithm ID reserved for digest algorithms registered by downstream crates.
+pub const CUSTOM_ALGORITHM_BASE: u32 = 0x100;
+
 /// Type alias for digest data.
 pub type DigestData = [u8; RAFS_DIGEST_LENGTH];
 
+/// Supported digest algorithms.
+///
+/// Algorithms `Custom0` to `Custom3` have reserved IDs in the on-disk format, and are only usable
+/// after registering an implementation by [register_digester()].
 #[repr(u32)]
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
 pub enum Algorithm {
     #[default]
     Blake3 = 0,
     Sha256 = 1,
+    Custom0 = CUSTOM_ALGORITHM_BASE,
+    Custom1 = CUSTOM_ALGORITHM_BASE + 1,
+    Custom2 = CUSTOM_ALGORITHM_BASE + 2,
+    Custom3 = CUSTOM_ALGORITHM_BASE + 3,
 }
 
+const ALGORITHMS: [Algorithm; 6] = [
+    Algorithm::Blake3,
+    Algorithm::Sha256,
+    Algorithm::Custom0,
+    Algorithm::Custom1,
+    Algorithm::Custom2,
+    Algorithm::Custom3,
+];
+
 impl fmt::Display for Algorithm {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match get_digester(*self) {
+            Some(d) => write!(f, "{}", d.name()),
+            None => write!(f, "{:?}", self),
+        }
     }
 }
 
@@ -40,7 +65,15 @@ impl FromStr for Algorithm {
         match s {
             "blake3" => Ok(Self::Blake3),
             "sha256" => Ok(Self::Sha256),
-            _ => Err(einval!("digest algorithm should be blake3 or sha256")),
+            _ => DIGESTERS
+                .read()
+                .unwrap()
+                .iter()
+                .find(|(_, d)| d.name() == s)
+                .map(|(algo, _)| *algo)
+                .ok_or_else(|| {
+                    einval!("digest algorithm should be blake3, sha256 or a registered one")
+                }),
         }
     }
 }
@@ -48,14 +81,17 @@ impl FromStr for Algorithm {
 impl TryFrom<u32> for Algorithm {
     type Error = ();
 
+    /// Convert an on-disk algorithm ID, custom algorithms are valid only if registered.
     fn try_from(value: u32) -> Result<Self, Self::Error> {
-        if value == Algorithm::Sha256 as u32 {
-            Ok(Algorithm::Sha256)
-        } else if value == Algorithm::Blake3 as u32 {
-            Ok(Algorithm::Blake3)
-        } else {
-            Err(())
+        let algo = ALGORITHMS
+            .iter()
+            .find(|algo| **algo as u32 == value)
+            .copied()
+            .ok_or(())?;
+        if algo.is_custom() && get_digester(algo).is_none() {
+            return Err(());
         }
+        Ok(algo)
     }
 }
 
@@ -63,16 +99,85 @@ impl TryFrom<u64> for Algorithm {
     type Error = ();
 
     fn try_from(value: u64) -> Result<Self, Self::Error> {
-        if value == Algorithm::Sha256 as u64 {
-            Ok(Algorithm::Sha256)
-        } else if value == Algorithm::Blake3 as u64 {
-            Ok(Algorithm::Blake3)
-        } else {
-            Err(())
-        }
+        let value = u32::try_from(value).map_err(|_| ())?;
+        Self::try_from(value)
+    }
+}
+
+impl Algorithm {
+    /// Check whether the algorithm ID is reserved for algorithms registered by downstream crates.
+    pub fn is_custom(self) -> bool {
+        self as u32 >= CUSTOM_ALGORITHM_BASE
+    }
+}
+
+/// Digest algorithm implemented by downstream crates.
+///
+/// The digest value must be [RAFS_DIGEST_LENGTH] bytes, as stored in RAFS metadata.
+pub trait Digester: Send + Sync {
+    /// Name of the algorithm, used to parse and display the algorithm.
+    fn name(&self) -> &str;
+
+    /// Create a hasher to digest data incrementally.
+    fn hasher(&self) -> Box<dyn CustomHasher>;
+}
+
+/// Incremental hasher of digest algorithms implemented by downstream crates.
+pub trait CustomHasher: Send + Sync {
+    fn update(&mut self, buf: &[u8]);
+    fn finalize(self: Box<Self>) -> DigestData;
+    fn box_clone(&self) -> Box<dyn CustomHasher>;
+}
+
+impl Clone for Box<dyn CustomHasher> {
+    fn clone(&self) -> Self {
+        self.box_clone()
     }
 }
 
+impl fmt::Debug for dyn CustomHasher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CustomHasher")
+    }
+}
+
+lazy_static! {
+    static ref DIGESTERS: RwLock<HashMap<Algorithm, Arc<dyn Digester>>> =
+        RwLock::new(HashMap::new());
+}
+
+/// Register an implementation for the custom digest algorithm `algorithm`.
+///
+/// The algorithm ID is persisted into RAFS metadata, so the same algorithm must be registered
+/// with the same implementation by every program accessing the generated images.
+#[cfg(feature = "custom-algorithm")]
+pub fn register_digester(algorithm: Algorithm, digester: Arc<dyn Digester>) -> std::io::Result<()> {
+    if !algorithm.is_custom() {
+        return Err(einval!(format!(
+            "can't register builtin digest algorithm {}",
+            algorithm
+        )));
+    }
+    let mut digesters = DIGESTERS.write().unwrap();
+    if digesters.contains_key(&algorithm) || digesters.values().any(|d| d.name() == digester.name())
+    {
+        return Err(einval!(format!(
+            "digest algorithm {:?}/{} has already been registered",
+            algorithm,
+            digester.name()
+        )));
+    }
+    digesters.insert(algorithm, digester);
+    Ok(())
+}
+
+fn get_digester(algorithm: Algorithm) -> Option<Arc<dyn Digester>> {
+    if !algorithm.is_custom() {
+        return None;
+    }
+    DIGESTERS.read().unwrap().get(&algorithm).cloned()
+}
+
 pub trait DigestHasher {
     fn digest_update(&mut self, buf: &[u8]);
     fn digest_finalize(self) -> RafsDigest;
@@ -95,6 +200,7 @@ pub trait DigestHasher {
 pub enum RafsDigestHasher {
     Blake3(Box<blake3::Hasher>),
     Sha256(Sha256),
+    Custom(Box<dyn CustomHasher>),
 }
 
 impl DigestHasher for RafsDigestHasher {
@@ -106,6 +212,9 @@ impl DigestHasher for RafsDigestHasher {
             RafsDigestHasher::Sha256(hasher) => {
                 hasher.update(buf);
             }
+            RafsDigestHasher::Custom(hasher) => {
+                hasher.update(buf);
+            }
         }
     }
 
@@ -113,6 +222,7 @@ impl DigestHasher for RafsDigestHasher {
         let data = match self {
             RafsDigestHasher::Blake3(hasher) => hasher.finalize().into(),
             RafsDigestHasher::Sha256(hasher) => hasher.finalize().into(),
+            RafsDigestHasher::Custom(hasher) => hasher.finalize(),
         };
 
         RafsDigest { data }
@@ -158,6 +268,11 @@ impl RafsDigest {
                 hasher.update(buf);
                 hasher.finalize().into()
             }
+            _ => {
+                let mut hasher = Self::hasher(algorithm);
+                hasher.digest_update(buf);
+                return hasher.digest_finalize();
+            }
         };
 
         RafsDigest { data }
@@ -188,10 +303,19 @@ impl RafsDigest {
         digest
     }
 
+    /// Create a hasher for the digest algorithm.
+    ///
+    /// # Panics
+    /// Panics if `algorithm` is a custom algorithm which hasn't been registered. Custom algorithms
+    /// decoded from on-disk IDs are always registered.
     pub fn hasher(algorithm: Algorithm) -> RafsDigestHasher {
         match algorithm {
             Algorithm::Blake3 => RafsDigestHasher::Blake3(Box::new(blake3::Hasher::new())),
             Algorithm::Sha256 => RafsDigestHasher::Sha256(Sha256::new()),
+            _ => match get_digester(algorithm) {
+                Some(d) => RafsDigestHasher::Custom(d.hasher()),
+                None => panic!("digest algorithm {:?} isn't registered", algorithm),
+            },
         }
     }
 }
@@ -335,4 +459,76 @@ mod test {
         assert_eq!(s1, s2);
         print!("{:?}, {:?}", Algorithm::Blake3, Algorithm::Sha256);
     }
+
+    #[test]
+    fn test_unregistered_custom_algorithm() {
+        assert!(Algorithm::Custom2.is_custom());
+        assert!(!Algorithm::Sha256.is_custom());
+        assert!(Algorithm::try_from(Algorithm::Custom2 as u32).is_err());
+        assert_eq!(Algorithm::Custom2.to_string(), "Custom2");
+    }
+
+    #[cfg(feature = "custom-algorithm")]
+    #[test]
+    fn test_register_digester() {
+        #[derive(Clone)]
+        struct Sha256Hasher(Sha256);
+
+        impl CustomHasher for Sha256Hasher {
+            fn update(&mut self, buf: &[u8]) {
+                self.0.update(buf);
+            }
+
+            fn finalize(self: Box<Self>) -> DigestData {
+                self.0.finalize().into()
+            }
+
+            fn box_clone(&self) -> Box<dyn CustomHasher> {
+                Box::new(self.clone())
+            }
+        }
+
+        struct Sha256Digester;
+
+        impl Digester for Sha256Digester {
+            fn name(&self) -> &str {
+                "test_sha256"
+            }
+
+            fn hasher(&self) -> Box<dyn CustomHasher> {
+                Box::new(Sha256Hasher(Sha256::new()))
+            }
+        }
+
+        assert!(register_digester(Algorithm::Sha256, Arc::new(Sha256Digester)).is_err());
+        register_digester(Algorithm::Custom3, Arc::new(Sha256Digester)).unwrap();
+        assert!(register_digester(Algorithm::Custom3, Arc::new(Sha256Digester)).is_err());
+        assert_eq!(
+            Algorithm::try_from(Algorithm::Custom3 as u32).unwrap(),
+            Algorithm::Custom3
+        );
+        assert_eq!(
+            Algorithm::from_str("test_sha256").unwrap(),
+            Algorithm::Custom3
+        );
+        assert_eq!(Algorithm::Custom3.to_string(), "test_sha256");
+
+        let text = b"The quick brown fox jumps over the lazy dog";
+        assert_eq!(
+            RafsDigest::from_buf(text, Algorithm::Custom3),
+            RafsDigest::from_buf(text, Algorithm::Sha256)
+        );
+        let mut hasher = RafsDigest::hasher(Algorithm::Custom3);
+        hasher.digest_update(&text[..10]);
+        let cloned = hasher.clone();
+        hasher.digest_update(&text[10..]);
+        assert_eq!(
+            hasher.digest_finalize(),
+            RafsDigest::from_buf(text, Algorithm::Sha256)
+        );
+        assert_eq!(
+            cloned.digest_finalize(),
+            RafsDigest::from_buf(&text[..10], Algorithm::Sha256)
+        );
+    }
 }
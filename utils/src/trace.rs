@@ -8,7 +8,9 @@ use std::any::Any;
 use std::cmp::{Eq, PartialEq};
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter, Result as FmtResult};
-use std::sync::{atomic::AtomicU64, Arc, Mutex, RwLock};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::SystemTime;
 
 use serde::Serialize;
@@ -67,6 +69,22 @@ pub struct TimingTracerClass {
     // So `Mutex` should fill our requirements.
     #[serde(flatten)]
     records: Mutex<HashMap<String, f32>>,
+    // Depth of directories to attribute consumed time to, zero means disabled.
+    #[serde(skip)]
+    dir_depth: AtomicUsize,
+}
+
+impl TimingTracerClass {
+    /// Set depth of directories to attribute consumed time to by [trace_dir_timing], zero
+    /// disables the per-directory breakdown.
+    pub fn set_dir_depth(&self, depth: usize) {
+        self.dir_depth.store(depth, Ordering::Relaxed);
+    }
+
+    /// Get depth of directories to attribute consumed time to.
+    pub fn dir_depth(&self) -> usize {
+        self.dir_depth.load(Ordering::Relaxed)
+    }
 }
 
 pub trait TracerClass: Send + Sync + 'static {
@@ -103,6 +121,47 @@ pub fn trace_timing<F: FnOnce() -> T, T>(
     r
 }
 
+/// Measure time consumed by `f` for the file at `path` in the image, and accumulate it to the
+/// ancestor directory of the file at the configured depth, as tracing point `point:directory`.
+///
+/// For example, time consumed for `/usr/lib/python3/os.py` is attributed to `point:/usr/lib` with
+/// depth 2, and `f` is simply called if the per-directory breakdown is disabled.
+pub fn trace_dir_timing<F: FnOnce() -> T, T>(
+    point: &str,
+    path: &Path,
+    tracer: Option<&TimingTracerClass>,
+    f: F,
+) -> T {
+    let depth = match tracer {
+        Some(t) if t.dir_depth() > 0 => t.dir_depth(),
+        _ => return f(),
+    };
+
+    let begin = SystemTime::now();
+    let r = f();
+    let elapsed = SystemTime::now().duration_since(begin).unwrap();
+
+    // Count the root directory as a component of absolute paths.
+    let depth = depth + path.has_root() as usize;
+    let dir = path
+        .parent()
+        .unwrap_or(path)
+        .components()
+        .take(depth)
+        .collect::<PathBuf>();
+    let key = format!("{}:{}", point, dir.display());
+    // Safe to unwrap because we have checked it above.
+    *tracer
+        .unwrap()
+        .records
+        .lock()
+        .unwrap()
+        .entry(key)
+        .or_default() += elapsed.as_secs_f32();
+
+    r
+}
+
 /// The root tracer manages all kinds of tracers registered to it.
 /// The statistics/events/records can be printed out or persisted from the root
 /// tracer. When building procedure is finished, root tracer can dump all tracing
@@ -263,7 +322,8 @@ macro_rules! event_tracer {
 pub mod tests {
     use crate::trace::TimingTracerClass;
 
-    use super::{EventTracerClass, TraceClass};
+    use super::{trace_dir_timing, EventTracerClass, TraceClass};
+    use std::path::Path;
     use std::thread;
 
     #[test]
@@ -339,4 +399,31 @@ pub mod tests {
         t3.join().unwrap();
         assert_eq!(timing_tracer!().unwrap().records.lock().unwrap().len(), 300);
     }
+
+    #[test]
+    fn test_trace_dir_timing() {
+        let tracer = TimingTracerClass::default();
+        trace_dir_timing(
+            "dir",
+            Path::new("/usr/lib/python3/os.py"),
+            Some(&tracer),
+            || (),
+        );
+        assert!(tracer.records.lock().unwrap().is_empty());
+
+        tracer.set_dir_depth(2);
+        for path in [
+            "/usr/lib/python3/os.py",
+            "/usr/lib/libc.so",
+            "/usr/bin/ls",
+            "/etc/passwd",
+        ] {
+            trace_dir_timing("dir", Path::new(path), Some(&tracer), || ());
+        }
+        let records = tracer.records.lock().unwrap();
+        assert_eq!(records.len(), 3);
+        assert!(records.contains_key("dir:/usr/lib"));
+        assert!(records.contains_key("dir:/usr/bin"));
+        assert!(records.contains_key("dir:/etc"));
+    }
 }